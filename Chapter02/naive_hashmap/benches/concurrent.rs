@@ -0,0 +1,91 @@
+#[macro_use]
+extern crate criterion;
+extern crate naive_hashmap;
+extern crate rand;
+
+use criterion::{Criterion, Fun};
+use naive_hashmap::ConcurrentHashMap;
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const THREADS: u32 = 4;
+
+// Each thread runs its own insert/lookup mix, seeded distinctly, against the
+// one shared map. The standard library comparison is the obvious baseline: a
+// single HashMap behind a Mutex.
+
+fn insert_and_lookup_concurrent(n: u64) {
+    let hash_map = Arc::new(ConcurrentHashMap::new());
+
+    let joins: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let hash_map = Arc::clone(&hash_map);
+            thread::spawn(move || {
+                let mut rng: XorShiftRng = SeedableRng::from_seed([1981, 1986, 2003, 2011 + t]);
+                for _ in 0..n {
+                    let key = rng.gen::<u16>();
+                    if rng.gen::<bool>() {
+                        let value = rng.gen::<u32>();
+                        hash_map.insert(key, value);
+                    } else {
+                        let _ = hash_map.get_cloned(&key);
+                    }
+                }
+            })
+        })
+        .collect();
+    for jh in joins {
+        jh.join().unwrap();
+    }
+}
+
+fn insert_and_lookup_standard(n: u64) {
+    let hash_map = Arc::new(Mutex::new(HashMap::new()));
+
+    let joins: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let hash_map = Arc::clone(&hash_map);
+            thread::spawn(move || {
+                let mut rng: XorShiftRng = SeedableRng::from_seed([1981, 1986, 2003, 2011 + t]);
+                for _ in 0..n {
+                    let key = rng.gen::<u16>();
+                    if rng.gen::<bool>() {
+                        let value = rng.gen::<u32>();
+                        hash_map.lock().unwrap().insert(key, value);
+                    } else {
+                        let _ = hash_map.lock().unwrap().get(&key).cloned();
+                    }
+                }
+            })
+        })
+        .collect();
+    for jh in joins {
+        jh.join().unwrap();
+    }
+}
+
+macro_rules! insert_lookup {
+    ($fn:ident, $s:expr) => {
+        fn $fn(c: &mut Criterion) {
+            let concurrent = Fun::new("concurrent", |b, i| b.iter(|| insert_and_lookup_concurrent(*i)));
+            let standard = Fun::new("mutex_standard", |b, i| b.iter(|| insert_and_lookup_standard(*i)));
+
+            let functions = vec![concurrent, standard];
+
+            c.bench_functions(&format!("ConcurrentHashMap/{}", $s), functions, &$s);
+        }
+    }
+}
+
+insert_lookup!(insert_lookup_100000, 100_000);
+insert_lookup!(insert_lookup_10000, 10_000);
+insert_lookup!(insert_lookup_1000, 1_000);
+
+criterion_group!{
+    name = benches;
+    config = Criterion::default();
+    targets = insert_lookup_100000, insert_lookup_10000, insert_lookup_1000
+}
+criterion_main!(benches);
@@ -0,0 +1,78 @@
+#[macro_use]
+extern crate criterion;
+extern crate naive_hashmap;
+extern crate rand;
+
+use criterion::{Criterion, Fun};
+use naive_hashmap::HashMap;
+use naive_hashmap::hashers::{FnvBuildHasher, IdentityBuildHasher, SeaBuildHasher};
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+// Key distributions. Uniform keys are kind to every hasher. Sequential keys
+// are the identity hasher's best case. Adversarial keys share their low 32
+// bits, the bits the table indexes by, so they all collide under the
+// identity hasher; the mixing hashers should shrug them off.
+
+fn uniform(n: u64) -> Vec<u64> {
+    let mut rng: XorShiftRng = SeedableRng::from_seed([1981, 1986, 2003, 2011]);
+    (0..n).map(|_| rng.gen::<u64>()).collect()
+}
+
+fn sequential(n: u64) -> Vec<u64> {
+    (0..n).collect()
+}
+
+fn adversarial(n: u64) -> Vec<u64> {
+    (0..n).map(|i| i << 32).collect()
+}
+
+fn insert_and_lookup<S>(keys: &[u64])
+where
+    S: BuildHasher + Default,
+{
+    let mut hash_map = HashMap::with_hasher(S::default());
+    for key in keys {
+        hash_map.insert(*key, *key);
+    }
+    for key in keys {
+        hash_map.get(key);
+    }
+}
+
+macro_rules! hashers {
+    ($fn:ident, $distribution:ident) => {
+        fn $fn(c: &mut Criterion) {
+            fn fun<S>(name: &str) -> Fun<u64>
+            where
+                S: BuildHasher + Default,
+            {
+                Fun::new(name, |b, n| {
+                    let keys = $distribution(*n);
+                    b.iter(|| insert_and_lookup::<S>(&keys))
+                })
+            }
+
+            let functions = vec![
+                fun::<RandomState>("sip"),
+                fun::<FnvBuildHasher>("fnv"),
+                fun::<SeaBuildHasher>("sea"),
+                fun::<IdentityBuildHasher>("identity"),
+            ];
+
+            c.bench_functions(&format!("Hasher/{}/{}", stringify!($distribution), 1_000), functions, &1_000);
+        }
+    }
+}
+
+hashers!(hashers_uniform, uniform);
+hashers!(hashers_sequential, sequential);
+hashers!(hashers_adversarial, adversarial);
+
+criterion_group!{
+    name = benches;
+    config = Criterion::default();
+    targets = hashers_uniform, hashers_sequential, hashers_adversarial
+}
+criterion_main!(benches);
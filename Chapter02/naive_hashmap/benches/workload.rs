@@ -0,0 +1,85 @@
+#[macro_use]
+extern crate criterion;
+extern crate naive_hashmap;
+
+use criterion::{Criterion, Fun};
+use naive_hashmap::workload::{Key, KeySize, Replay, Skew, Spec};
+use naive_hashmap::{ConcurrentHashMap, DirectMap, HashMap, SmallKey};
+use std::collections;
+
+// Every map replays the same trace, generated once outside the timed loop,
+// starting each iteration from an empty map. The operations go through
+// `Replay::apply` rather than `workload::replay`: timing each one would
+// cost more than the cheaper maps take to carry it out. `workload replay`
+// reports latency percentiles for any saved trace.
+
+/// The book's workload: uniform u8 keys, half inserts and half lookups.
+fn book(ops: usize) -> Spec {
+    Spec {
+        ops,
+        ..Spec::default()
+    }
+}
+
+/// Something closer to a cache: zipfian u16 keys, mostly looked up, now and
+/// then removed.
+fn skewed(ops: usize) -> Spec {
+    Spec {
+        ops,
+        lookups: 8,
+        inserts: 3,
+        removes: 1,
+        skew: Skew::Zipf(1.0),
+        key_size: KeySize::U16,
+        keys: 1 << 16,
+        ..Spec::default()
+    }
+}
+
+fn replayed<M, F>(name: &str, spec: fn(usize) -> Spec, new: F) -> Fun<usize>
+where
+    M: Replay,
+    F: Fn() -> M + 'static,
+{
+    Fun::new(name, move |b, n| {
+        let trace = spec(*n).generate().expect("invalid workload");
+        b.iter(|| {
+            let mut map = new();
+            for op in &trace.ops {
+                map.apply(*op);
+            }
+            map
+        })
+    })
+}
+
+fn maps<K>(spec: fn(usize) -> Spec) -> Vec<Fun<usize>>
+where
+    K: Key + SmallKey + 'static,
+{
+    vec![
+        replayed("naive", spec, HashMap::<K, u32>::new),
+        replayed("specialized", spec, DirectMap::<K, u32>::new),
+        replayed("standard", spec, collections::HashMap::<K, u32>::new),
+        replayed("concurrent", spec, ConcurrentHashMap::<K, u32>::new),
+    ]
+}
+
+fn book_workload(c: &mut Criterion) {
+    for &n in &[1, 100, 10_000, 100_000] {
+        c.bench_functions(&format!("Workload/book/{}", n), maps::<u8>(book), &n);
+    }
+}
+
+fn skewed_workload(c: &mut Criterion) {
+    for &n in &[10_000, 100_000] {
+        c.bench_functions(&format!("Workload/skewed/{}", n), maps::<u16>(skewed), &n);
+    }
+}
+
+criterion_group!{
+    name = benches;
+    config = Criterion::default();
+    targets = book_workload, skewed_workload
+}
+criterion_main!(benches);
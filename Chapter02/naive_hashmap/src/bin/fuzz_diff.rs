@@ -0,0 +1,41 @@
+extern crate naive_hashmap;
+
+use naive_hashmap::fuzz;
+use std::io;
+use std::io::prelude::*;
+use std::process;
+
+// Reads one fuzzer input from stdin and runs it against every backend. On
+// divergence the trace is minimized and printed -- both as command lines for
+// the interpreters and as bytes for this binary -- before aborting, which is
+// what AFL and friends look for.
+fn main() {
+    let mut bytes = Vec::new();
+    io::stdin()
+        .read_to_end(&mut bytes)
+        .expect("could not read stdin");
+
+    let ops = fuzz::decode(&bytes);
+    let divergence = match fuzz::first_divergence(&ops) {
+        Some(divergence) => divergence,
+        None => return,
+    };
+
+    let ops = &ops[..divergence.index + 1];
+    let minimized = fuzz::minimize(ops, |ops| fuzz::first_divergence(ops).is_some());
+    let divergence = fuzz::first_divergence(&minimized).expect("minimized trace diverges");
+
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    let _ = write!(stderr, "{}", divergence);
+    let _ = writeln!(stderr, "minimized trace, {} of {} ops:", minimized.len(), ops.len());
+    for op in &minimized {
+        let _ = writeln!(stderr, "  {}", op);
+    }
+    let _ = write!(stderr, "replay bytes:");
+    for byte in fuzz::encode(&minimized) {
+        let _ = write!(stderr, " {:02x}", byte);
+    }
+    let _ = writeln!(stderr);
+    process::abort();
+}
@@ -1,45 +1,18 @@
 extern crate naive_hashmap;
 
-use std::io;
-use std::io::prelude::*;
-use std::str::FromStr;
+use naive_hashmap::command;
+use std::{env, io, process};
 
 fn main() {
-    let mut hash_map = naive_hashmap::HashMapU8::new();
-
-    let n = io::stdin();
-    for line in n.lock().lines() {
-        if let Ok(line) = line {
-            let mut cmd = line.split(" ");
-            match cmd.next() {
-                Some("LOOKUP") => {
-                    if let Some(key) = cmd.next() {
-                        if let Ok(key) = u8::from_str(key) {
-                            let _ = hash_map.get(&key);
-                        } else {
-                            continue;
-                        }
-                    } else {
-                        continue;
-                    }
-                }
-                Some("INSERT") => {
-                    if let Some(key) = cmd.next() {
-                        if let Ok(key) = u8::from_str(key) {
-                            if let Some(val) = cmd.next() {
-                                let _ = hash_map.insert(key, val.to_string());
-                            } else {
-                                continue;
-                            }
-                        }
-                    } else {
-                        continue;
-                    }
-                }
-                _ => continue,
-            }
-        } else {
-            break;
+    let mut backend = match command::backend_from_args(env::args().skip(1), "specialized") {
+        Ok(backend) => backend,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
         }
-    }
+    };
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    command::run(&mut *backend, stdin.lock(), stdout.lock()).expect("could not write response");
 }
@@ -0,0 +1,176 @@
+extern crate naive_hashmap;
+
+use naive_hashmap::workload::{self, KeySize, Op, Report, Spec};
+use naive_hashmap::{ConcurrentHashMap, DirectMap, HashMap};
+use std::collections;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::process;
+
+const USAGE: &str = "\
+usage: workload generate [--ops N] [--mix LOOKUPS:INSERTS:REMOVES] [--skew uniform|zipf[:S]]
+                         [--key-size u8|u16|u32|u64] [--keys N] [--seed N]
+       workload replay TRACE...
+
+generate writes a trace to stdout; replay runs each trace against every map
+and reports throughput and latency percentiles. Build with --release.";
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let result = match args.next().as_deref() {
+        Some("generate") => generate(args),
+        Some("replay") => replay(args),
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(2);
+    }
+}
+
+fn generate<I>(args: I) -> Result<(), String>
+where
+    I: Iterator<Item = String>,
+{
+    let mut spec = Spec::default();
+    let mut keys = None;
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        let val = args
+            .next()
+            .ok_or_else(|| format!("{} requires a value\n\n{}", arg, USAGE))?;
+        let bad = |what: &str| format!("bad {} {}", what, val);
+        match arg.as_str() {
+            "--ops" => spec.ops = val.parse().map_err(|_| bad("count"))?,
+            "--mix" => {
+                let weights: Vec<u32> = val.split(':')
+                    .map(|w| w.parse::<u32>())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| bad("mix"))?;
+                if weights.len() != 3 {
+                    return Err(bad("mix"));
+                }
+                spec.lookups = weights[0];
+                spec.inserts = weights[1];
+                spec.removes = weights[2];
+            }
+            "--skew" => spec.skew = val.parse()?,
+            "--key-size" => spec.key_size = val.parse()?,
+            "--keys" => keys = Some(val.parse().map_err(|_| bad("key count"))?),
+            "--seed" => {
+                let seed: u64 = val.parse().map_err(|_| bad("seed"))?;
+                spec.seed = [seed as u32, (seed >> 32) as u32, 2003, 2011];
+            }
+            _ => return Err(format!("unrecognized argument {}\n\n{}", arg, USAGE)),
+        }
+    }
+    // Without --keys, draw from the whole key space, as far as the skew
+    // allows.
+    spec.keys = keys.unwrap_or_else(|| {
+        let space = spec.key_size.max().saturating_add(1);
+        match spec.skew {
+            workload::Skew::Uniform => space,
+            workload::Skew::Zipf(_) => space.min(workload::MAX_ZIPF_KEYS),
+        }
+    });
+    let trace = spec.generate()?;
+    let stdout = io::stdout();
+    trace
+        .write_to(stdout.lock())
+        .map_err(|e| format!("could not write trace: {}", e))
+}
+
+fn replay<I>(paths: I) -> Result<(), String>
+where
+    I: Iterator<Item = String>,
+{
+    let paths: Vec<String> = paths.collect();
+    if paths.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    for path in &paths {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let trace = workload::Trace::read_from(BufReader::new(file))
+            .map_err(|e| format!("{}:{}", path, e))?;
+        println!("{}: {} ops, {} keys", path, trace.ops.len(), trace.key_size);
+        println!(
+            "  {:<12} {:>12} {:>8} {:>8} {:>8} {:>8} {:>8}",
+            "map", "ops/s", "p50", "p90", "p99", "p99.9", "max"
+        );
+        let reports = match trace.key_size {
+            KeySize::U8 => replay_all::<u8>(&trace.ops),
+            KeySize::U16 => replay_all::<u16>(&trace.ops),
+            KeySize::U32 => replay_all::<u32>(&trace.ops),
+            KeySize::U64 => replay_all::<u64>(&trace.ops),
+        };
+        for &(name, ref report) in &reports {
+            println!(
+                "  {:<12} {:>12.0} {:>8} {:>8} {:>8} {:>8} {:>8}",
+                name,
+                report.throughput(),
+                report.percentile(0.5),
+                report.percentile(0.9),
+                report.percentile(0.99),
+                report.percentile(0.999),
+                report.percentile(1.0)
+            );
+        }
+        // A disagreement here is a bug, not noise.
+        let hits = reports[0].1.hits;
+        if let Some(&(name, ref report)) = reports.iter().find(|r| r.1.hits != hits) {
+            return Err(format!(
+                "{}: {} found {} keys present, {} found {}",
+                path, name, report.hits, reports[0].0, hits
+            ));
+        }
+        println!("  latencies in ns");
+    }
+    Ok(())
+}
+
+fn replay_all<K>(ops: &[Op]) -> Vec<(&'static str, Report)>
+where
+    K: workload::Key + DirectKey,
+{
+    let mut reports = vec![
+        ("naive", workload::replay(&mut HashMap::<K, u32>::new(), ops)),
+        ("std", workload::replay(&mut collections::HashMap::<K, u32>::new(), ops)),
+        ("concurrent", workload::replay(&mut ConcurrentHashMap::<K, u32>::new(), ops)),
+    ];
+    if let Some(report) = K::replay_direct(ops) {
+        reports.push(("specialized", report));
+    }
+    reports
+}
+
+/// Lets `replay_all` replay against a `DirectMap` for the key types that
+/// have one, without requiring `SmallKey` of the rest.
+trait DirectKey {
+    /// `None` if there is no `DirectMap` for this key type.
+    fn replay_direct(ops: &[Op]) -> Option<Report>;
+}
+
+impl DirectKey for u8 {
+    fn replay_direct(ops: &[Op]) -> Option<Report> {
+        Some(workload::replay(&mut DirectMap::<u8, u32>::new(), ops))
+    }
+}
+
+impl DirectKey for u16 {
+    fn replay_direct(ops: &[Op]) -> Option<Report> {
+        Some(workload::replay(&mut DirectMap::<u16, u32>::new(), ops))
+    }
+}
+
+impl DirectKey for u32 {
+    fn replay_direct(_: &[Op]) -> Option<Report> {
+        None
+    }
+}
+
+impl DirectKey for u64 {
+    fn replay_direct(_: &[Op]) -> Option<Report> {
+        None
+    }
+}
@@ -0,0 +1,465 @@
+//! The line-oriented command language spoken by the interpreter binaries.
+//!
+//! Each input line holds one command:
+//!
+//! ```text
+//! INSERT <key> <value>
+//! LOOKUP <key>
+//! REMOVE <key>
+//! LEN
+//! CLEAR
+//! DUMP
+//! ```
+//!
+//! Keys and values are either bare words or double-quoted strings, the
+//! latter allowing whitespace and the escapes `\"`, `\\`, `\n` and `\t`.
+//! Blank lines and lines starting with `#` are skipped. Every other line gets
+//! exactly one response line on output:
+//!
+//! * `OK` -- INSERT of a new key, CLEAR
+//! * `OK <value>` -- INSERT over an existing key (the old value), LOOKUP,
+//!   REMOVE
+//! * `OK <n>` -- LEN, and DUMP after its entries
+//! * `NOT_FOUND` -- LOOKUP or REMOVE of an absent key
+//! * `ERR <line>:<reason>` -- anything that could not be carried out
+//!
+//! DUMP first prints one `<key> <value>` line per entry, sorted by key.
+//! Keys and values in responses are always quoted, so the output of
+//! different backends can be compared byte for byte.
+use direct_map::DirectMap;
+use std::collections;
+use std::fmt::Write as FmtWrite;
+use std::hash::BuildHasher;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+use HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Insert(String, String),
+    Lookup(String),
+    Remove(String),
+    Len,
+    Clear,
+    Dump,
+}
+
+/// A map the interpreter can drive. Keys arrive as strings; a backend with
+/// a narrower key type rejects those it cannot represent with a reason.
+pub trait Backend {
+    fn insert(&mut self, key: &str, value: String) -> Result<Option<String>, String>;
+
+    fn lookup(&self, key: &str) -> Result<Option<String>, String>;
+
+    fn remove(&mut self, key: &str) -> Result<Option<String>, String>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn clear(&mut self);
+
+    /// Every entry, keys rendered back to strings, in no particular order.
+    fn dump(&self) -> Vec<(String, String)>;
+}
+
+impl<S> Backend for HashMap<String, String, S>
+where
+    S: BuildHasher,
+{
+    fn insert(&mut self, key: &str, value: String) -> Result<Option<String>, String> {
+        Ok(HashMap::insert(self, key.to_string(), value))
+    }
+
+    fn lookup(&self, key: &str) -> Result<Option<String>, String> {
+        Ok(self.get(key).cloned())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<String>, String> {
+        Ok(HashMap::remove(self, key))
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn clear(&mut self) {
+        HashMap::clear(self)
+    }
+
+    fn dump(&self) -> Vec<(String, String)> {
+        self.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+}
+
+impl<S> Backend for collections::HashMap<String, String, S>
+where
+    S: BuildHasher,
+{
+    fn insert(&mut self, key: &str, value: String) -> Result<Option<String>, String> {
+        Ok(collections::HashMap::insert(self, key.to_string(), value))
+    }
+
+    fn lookup(&self, key: &str) -> Result<Option<String>, String> {
+        Ok(self.get(key).cloned())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<String>, String> {
+        Ok(collections::HashMap::remove(self, key))
+    }
+
+    fn len(&self) -> usize {
+        collections::HashMap::len(self)
+    }
+
+    fn clear(&mut self) {
+        collections::HashMap::clear(self)
+    }
+
+    fn dump(&self) -> Vec<(String, String)> {
+        self.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+}
+
+/// Only the spelling `dump` gives a key back is accepted: no sign and no
+/// leading zeroes, or `010` and `10` would be one key to this backend and
+/// two to the others.
+fn parse_u16_key(key: &str) -> Result<u16, String> {
+    match u16::from_str(key) {
+        Ok(k) if k.to_string() == key => Ok(k),
+        _ => Err(format!("key {} is not a u16", quote(key))),
+    }
+}
+
+impl Backend for DirectMap<u16, String> {
+    fn insert(&mut self, key: &str, value: String) -> Result<Option<String>, String> {
+        Ok(DirectMap::insert(self, parse_u16_key(key)?, value))
+    }
+
+    fn lookup(&self, key: &str) -> Result<Option<String>, String> {
+        Ok(self.get(&parse_u16_key(key)?).cloned())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<String>, String> {
+        Ok(DirectMap::remove(self, &parse_u16_key(key)?))
+    }
+
+    fn len(&self) -> usize {
+        DirectMap::len(self)
+    }
+
+    fn clear(&mut self) {
+        DirectMap::clear(self)
+    }
+
+    fn dump(&self) -> Vec<(String, String)> {
+        self.iter().map(|(k, v)| (k.to_string(), v.clone())).collect()
+    }
+}
+
+/// The backends selectable with `--backend`.
+pub const BACKENDS: &[&str] = &["naive", "specialized", "std"];
+
+pub fn new_backend(name: &str) -> Option<Box<dyn Backend>> {
+    match name {
+        "naive" => Some(Box::new(HashMap::<String, String>::new())),
+        "specialized" => Some(Box::new(DirectMap::<u16, String>::new())),
+        "std" => Some(Box::new(collections::HashMap::<String, String>::new())),
+        _ => None,
+    }
+}
+
+/// Picks the backend named by a `--backend <name>` pair among `args`,
+/// falling back to `default` when there is none.
+pub fn backend_from_args<I>(args: I, default: &str) -> Result<Box<dyn Backend>, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut name = default.to_string();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--backend" => match args.next() {
+                Some(val) => name = val,
+                None => return Err("--backend requires a value".to_string()),
+            },
+            _ => return Err(format!("unrecognized argument {}", arg)),
+        }
+    }
+    new_backend(&name).ok_or_else(|| {
+        format!("unknown backend {}, expected one of {}", name, BACKENDS.join("|"))
+    })
+}
+
+/// Splits a line into words, honouring double quotes and their escapes.
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let mut token = String::new();
+        match chars.next() {
+            None => return Ok(tokens),
+            Some('"') => loop {
+                match chars.next() {
+                    None => return Err("unterminated quote".to_string()),
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('"') => token.push('"'),
+                        Some('\\') => token.push('\\'),
+                        Some('n') => token.push('\n'),
+                        Some('t') => token.push('\t'),
+                        Some(c) => return Err(format!("unknown escape \\{}", c)),
+                        None => return Err("unterminated quote".to_string()),
+                    },
+                    Some(c) => token.push(c),
+                }
+            },
+            Some(c) => {
+                token.push(c);
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    if c == '"' {
+                        return Err("quote inside bare word".to_string());
+                    }
+                    token.push(c);
+                    chars.next();
+                }
+            }
+        }
+        if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+            return Err("missing whitespace after quoted word".to_string());
+        }
+        tokens.push(token);
+    }
+}
+
+/// Parses one line. Blank lines and `#` comments parse to `None`.
+pub fn parse_line(line: &str) -> Result<Option<Command>, String> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return Ok(None);
+    }
+    let mut tokens = tokenize(trimmed)?.into_iter();
+    let cmd = tokens.next().expect("non-blank line has a first word");
+    let args: Vec<String> = tokens.collect();
+    let arity = |n: usize| -> Result<(), String> {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(format!("{} takes {} argument(s), got {}", cmd, n, args.len()))
+        }
+    };
+    let command = match cmd.as_str() {
+        "INSERT" => {
+            arity(2)?;
+            let mut args = args.into_iter();
+            Command::Insert(args.next().unwrap(), args.next().unwrap())
+        }
+        "LOOKUP" => {
+            arity(1)?;
+            Command::Lookup(args.into_iter().next().unwrap())
+        }
+        "REMOVE" => {
+            arity(1)?;
+            Command::Remove(args.into_iter().next().unwrap())
+        }
+        "LEN" => {
+            arity(0)?;
+            Command::Len
+        }
+        "CLEAR" => {
+            arity(0)?;
+            Command::Clear
+        }
+        "DUMP" => {
+            arity(0)?;
+            Command::Dump
+        }
+        _ => return Err(format!("unknown command {}", quote(&cmd))),
+    };
+    Ok(Some(command))
+}
+
+/// Renders a key or value as a double-quoted string, escaping as needed.
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn found(val: Option<String>) -> String {
+    match val {
+        Some(val) => format!("OK {}", quote(&val)),
+        None => "NOT_FOUND".to_string(),
+    }
+}
+
+/// Carries out `command`, returning its response without trailing newline.
+pub fn execute<B>(backend: &mut B, lineno: usize, command: Command) -> String
+where
+    B: Backend + ?Sized,
+{
+    let res = match command {
+        Command::Insert(k, v) => backend.insert(&k, v).map(|old| match old {
+            Some(old) => format!("OK {}", quote(&old)),
+            None => "OK".to_string(),
+        }),
+        Command::Lookup(k) => backend.lookup(&k).map(found),
+        Command::Remove(k) => backend.remove(&k).map(found),
+        Command::Len => Ok(format!("OK {}", backend.len())),
+        Command::Clear => {
+            backend.clear();
+            Ok("OK".to_string())
+        }
+        Command::Dump => {
+            let mut entries = backend.dump();
+            entries.sort();
+            let mut out = String::new();
+            for (k, v) in &entries {
+                let _ = writeln!(out, "{} {}", quote(k), quote(v));
+            }
+            let _ = write!(out, "OK {}", entries.len());
+            Ok(out)
+        }
+    };
+    res.unwrap_or_else(|reason| format!("ERR {}:{}", lineno, reason))
+}
+
+/// Reads commands from `input` until it is exhausted, writing a response
+/// for each to `output`. Line numbers in errors count from 1.
+pub fn run<B, R, W>(backend: &mut B, input: R, mut output: W) -> io::Result<()>
+where
+    B: Backend + ?Sized,
+    R: BufRead,
+    W: Write,
+{
+    for (idx, line) in input.lines().enumerate() {
+        let lineno = idx + 1;
+        let response = match line {
+            Ok(line) => match parse_line(&line) {
+                Ok(Some(command)) => execute(backend, lineno, command),
+                Ok(None) => continue,
+                Err(reason) => format!("ERR {}:{}", lineno, reason),
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                format!("ERR {}:line is not valid UTF-8", lineno)
+            }
+            Err(e) => return Err(e),
+        };
+        writeln!(output, "{}", response)?;
+    }
+    output.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn interpret(backend: &str, input: &str) -> String {
+        let mut backend = new_backend(backend).unwrap();
+        let mut output = Vec::new();
+        run(&mut *backend, input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn parses_bare_and_quoted_words() {
+        assert_eq!(
+            Ok(Some(Command::Insert("a key".to_string(), "x\"y\\z\n".to_string()))),
+            parse_line(r#"INSERT "a key" "x\"y\\z\n""#)
+        );
+        assert_eq!(Ok(Some(Command::Lookup("10".to_string()))), parse_line("LOOKUP 10"));
+        assert_eq!(Ok(None), parse_line("   "));
+        assert_eq!(Ok(None), parse_line("# a comment"));
+        assert!(parse_line("LOOKUP \"open").is_err());
+        assert!(parse_line("LOOKUP a\"b").is_err());
+        assert!(parse_line("LOOKUP \"a\"b").is_err());
+        assert!(parse_line("INSERT a").is_err());
+        assert!(parse_line("FROB a").is_err());
+    }
+
+    #[test]
+    fn quote_round_trips() {
+        for s in &["", "plain", "two words", "\"\\\n\t"] {
+            let line = format!("LOOKUP {}", quote(s));
+            assert_eq!(Ok(Some(Command::Lookup(s.to_string()))), parse_line(&line));
+        }
+    }
+
+    #[test]
+    fn responses() {
+        let input = "INSERT 10 ten\n\
+                     INSERT 10 \"ten again\"\n\
+                     LOOKUP 10\n\
+                     LOOKUP 11\n\
+                     \n\
+                     INSERT 2 two\n\
+                     LEN\n\
+                     DUMP\n\
+                     REMOVE 10\n\
+                     REMOVE 10\n\
+                     BOGUS\n\
+                     CLEAR\n\
+                     LEN\n";
+        let expected = "OK\n\
+                        OK \"ten\"\n\
+                        OK \"ten again\"\n\
+                        NOT_FOUND\n\
+                        OK\n\
+                        OK 2\n\
+                        \"10\" \"ten again\"\n\
+                        \"2\" \"two\"\n\
+                        OK 2\n\
+                        OK \"ten again\"\n\
+                        NOT_FOUND\n\
+                        ERR 11:unknown command \"BOGUS\"\n\
+                        OK\n\
+                        OK 0\n";
+        for backend in BACKENDS {
+            assert_eq!(expected, interpret(backend, input), "backend {}", backend);
+        }
+    }
+
+    #[test]
+    fn specialized_rejects_wide_keys() {
+        assert_eq!(
+            "ERR 1:key \"abc\" is not a u16\nERR 2:key \"70000\" is not a u16\n",
+            interpret("specialized", "LOOKUP abc\nINSERT 70000 x\n")
+        );
+    }
+
+    #[test]
+    fn specialized_rejects_other_spellings_of_keys() {
+        assert_eq!(
+            "ERR 1:key \"010\" is not a u16\nERR 2:key \"+10\" is not a u16\nOK\n",
+            interpret("specialized", "INSERT 010 x\nINSERT +10 x\nINSERT 0 x\n")
+        );
+    }
+
+    #[test]
+    fn backend_flag() {
+        let args = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(backend_from_args(args(&[]), "naive").is_ok());
+        assert!(backend_from_args(args(&["--backend", "std"]), "naive").is_ok());
+        assert!(backend_from_args(args(&["--backend", "nope"]), "naive").is_err());
+        assert!(backend_from_args(args(&["--backend"]), "naive").is_err());
+        assert!(backend_from_args(args(&["--frob"]), "naive").is_err());
+    }
+}
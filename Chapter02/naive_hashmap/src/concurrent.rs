@@ -0,0 +1,332 @@
+//! A sharded, lock-protected variant of `HashMap` usable through `&self`.
+//!
+//! Keys are spread over a power-of-two number of shards by the high bits of
+//! their hash, each shard being a `RawTable` behind its own `RwLock`. The low
+//! bits pick the bucket inside a shard, so the two choices stay independent.
+//! Readers of different shards never contend and readers of the same shard
+//! only contend with writers.
+use make_hash;
+use raw_table::RawTable;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::ops::Deref;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+const DEFAULT_SHARDS: usize = 16;
+
+pub struct ConcurrentHashMap<K, V, S = RandomState> {
+    hash_builder: S,
+    shards: Vec<RwLock<RawTable<K, V>>>,
+    shift: u32,
+}
+
+/// A read lock on the shard holding a value, dereferencing to that value.
+/// Writers to the same shard block until the guard is dropped.
+pub struct ReadGuard<'a, K: 'a, V: 'a> {
+    shard: RwLockReadGuard<'a, RawTable<K, V>>,
+    idx: usize,
+}
+
+impl<'a, K, V> Deref for ReadGuard<'a, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.shard.bucket(self.idx).value
+    }
+}
+
+impl<K, V> ConcurrentHashMap<K, V, RandomState>
+where
+    K: Eq + Hash,
+{
+    pub fn new() -> ConcurrentHashMap<K, V> {
+        ConcurrentHashMap::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Creates a map with `shards` segments, rounded up to a power of two.
+    pub fn with_shards(shards: usize) -> ConcurrentHashMap<K, V> {
+        ConcurrentHashMap::with_shards_and_hasher(shards, RandomState::new())
+    }
+}
+
+impl<K, V> Default for ConcurrentHashMap<K, V, RandomState>
+where
+    K: Eq + Hash,
+{
+    fn default() -> ConcurrentHashMap<K, V> {
+        ConcurrentHashMap::new()
+    }
+}
+
+impl<K, V, S> ConcurrentHashMap<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    pub fn with_hasher(hash_builder: S) -> ConcurrentHashMap<K, V, S> {
+        ConcurrentHashMap::with_shards_and_hasher(DEFAULT_SHARDS, hash_builder)
+    }
+
+    /// Creates a map with `shards` segments, rounded up to a power of two.
+    pub fn with_shards_and_hasher(shards: usize, hash_builder: S) -> ConcurrentHashMap<K, V, S> {
+        let shards = shards.max(1).next_power_of_two();
+        ConcurrentHashMap {
+            hash_builder,
+            shift: 64 - shards.trailing_zeros(),
+            shards: (0..shards).map(|_| RwLock::new(RawTable::new())).collect(),
+        }
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard_index(&self, hash: u64) -> usize {
+        // A single shard means a shift of 64, which checked_shr refuses.
+        hash.checked_shr(self.shift).unwrap_or(0) as usize
+    }
+
+    fn read_shard(&self, hash: u64) -> RwLockReadGuard<'_, RawTable<K, V>> {
+        self.shards[self.shard_index(hash)].read().unwrap()
+    }
+
+    fn write_shard(&self, hash: u64) -> RwLockWriteGuard<'_, RawTable<K, V>> {
+        self.shards[self.shard_index(hash)].write().unwrap()
+    }
+
+    pub fn insert(&self, k: K, v: V) -> Option<V> {
+        let hash = make_hash(&self.hash_builder, &k);
+        let mut shard = self.write_shard(hash);
+        match shard.find(hash, &k) {
+            Some(idx) => Some(::std::mem::replace(&mut shard.bucket_mut(idx).value, v)),
+            None => {
+                shard.insert_new(hash, k, v);
+                None
+            }
+        }
+    }
+
+    /// Returns a guard on the value for `k`. The guard holds its shard's
+    /// read lock, so keep it short-lived.
+    pub fn get<Q>(&self, k: &Q) -> Option<ReadGuard<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = make_hash(&self.hash_builder, k);
+        let shard = self.read_shard(hash);
+        shard.find(hash, k).map(|idx| ReadGuard { shard, idx })
+    }
+
+    /// Returns a clone of the value for `k`, holding no lock afterwards.
+    pub fn get_cloned<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.get(k).map(|guard| (*guard).clone())
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = make_hash(&self.hash_builder, k);
+        self.read_shard(hash).find(hash, k).is_some()
+    }
+
+    pub fn remove<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = make_hash(&self.hash_builder, k);
+        let mut shard = self.write_shard(hash);
+        shard.find(hash, k).map(|idx| shard.remove_at(idx).1)
+    }
+
+    /// Applies `f` to the value for `k` under its shard's write lock,
+    /// returning what `f` returns, or `None` if `k` is absent.
+    pub fn update<Q, F, R>(&self, k: &Q, f: F) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        F: FnOnce(&mut V) -> R,
+    {
+        let hash = make_hash(&self.hash_builder, k);
+        let mut shard = self.write_shard(hash);
+        shard.find(hash, k).map(|idx| f(&mut shard.bucket_mut(idx).value))
+    }
+
+    /// Atomically applies `f` to the value for `k`, inserting `default`
+    /// first if `k` is absent.
+    pub fn upsert<F>(&self, k: K, default: V, f: F)
+    where
+        F: FnOnce(&mut V),
+    {
+        let hash = make_hash(&self.hash_builder, &k);
+        let mut shard = self.write_shard(hash);
+        let idx = match shard.find(hash, &k) {
+            Some(idx) => idx,
+            None => shard.insert_new(hash, k, default),
+        };
+        f(&mut shard.bucket_mut(idx).value)
+    }
+
+    /// Total number of elements. Shards are counted one after another, so
+    /// under concurrent modification this is only a snapshot-ish figure.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.read().unwrap().len == 0)
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.write().unwrap().clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use super::*;
+    use quickcheck::{Arbitrary, Gen, QuickCheck, TestResult};
+    use std::collections;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[derive(Clone, Debug)]
+    enum Action {
+        Insert(u8, u16),
+        Lookup(u8),
+        Remove(u8),
+        Update(u8, u16),
+    }
+
+    impl Arbitrary for Action {
+        fn arbitrary<G>(g: &mut G) -> Action
+        where
+            G: Gen,
+        {
+            let i: usize = g.gen_range(0, 100);
+            match i {
+                0..=40 => Action::Insert(u8::arbitrary(g), u16::arbitrary(g)),
+                41..=70 => Action::Lookup(u8::arbitrary(g)),
+                71..=85 => Action::Remove(u8::arbitrary(g)),
+                _ => Action::Update(u8::arbitrary(g), u16::arbitrary(g)),
+            }
+        }
+    }
+
+    /// Every thread works a disjoint slice of the key space -- keys are
+    /// `(thread, key)` pairs -- against one shared map, so each key's history
+    /// is sequential and every result must match the model exactly. The
+    /// threads still contend for the same shards and locks.
+    #[test]
+    fn sut_vs_mutex_model() {
+        fn property(per_thread: Vec<Vec<Action>>, shards: u8) -> TestResult {
+            let system_under_test = Arc::new(ConcurrentHashMap::with_shards(shards as usize));
+            let model = Arc::new(Mutex::new(collections::HashMap::new()));
+
+            let joins: Vec<_> = per_thread
+                .into_iter()
+                .enumerate()
+                .map(|(thread_id, actions)| {
+                    let system_under_test = Arc::clone(&system_under_test);
+                    let model = Arc::clone(&model);
+                    thread::spawn(move || {
+                        for action in actions {
+                            match action {
+                                Action::Insert(k, v) => {
+                                    let k = (thread_id, k);
+                                    let expected = model.lock().unwrap().insert(k, v);
+                                    assert_eq!(expected, system_under_test.insert(k, v));
+                                }
+                                Action::Lookup(k) => {
+                                    let k = (thread_id, k);
+                                    let expected = model.lock().unwrap().get(&k).cloned();
+                                    assert_eq!(expected, system_under_test.get_cloned(&k));
+                                }
+                                Action::Remove(k) => {
+                                    let k = (thread_id, k);
+                                    let expected = model.lock().unwrap().remove(&k);
+                                    assert_eq!(expected, system_under_test.remove(&k));
+                                }
+                                Action::Update(k, v) => {
+                                    let k = (thread_id, k);
+                                    let expected = model.lock().unwrap().get_mut(&k).map(|old| {
+                                        *old = v;
+                                    });
+                                    let actual = system_under_test.update(&k, |old| *old = v);
+                                    assert_eq!(expected, actual);
+                                }
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for jh in joins {
+                if jh.join().is_err() {
+                    return TestResult::failed();
+                }
+            }
+
+            let model = model.lock().unwrap();
+            assert_eq!(model.len(), system_under_test.len());
+            for (k, v) in model.iter() {
+                assert_eq!(Some(*v), system_under_test.get_cloned(k));
+            }
+            TestResult::passed()
+        }
+        QuickCheck::new()
+            .tests(50)
+            .quickcheck(property as fn(Vec<Vec<Action>>, u8) -> TestResult);
+    }
+
+    /// Threads hammer a small set of shared keys with increments. Were any
+    /// upsert lost, the totals would fall short of the model's.
+    #[test]
+    fn concurrent_upserts_are_not_lost() {
+        fn property(per_thread: Vec<Vec<u8>>) -> TestResult {
+            let system_under_test = Arc::new(ConcurrentHashMap::with_shards(4));
+            let mut model = collections::HashMap::new();
+            for keys in &per_thread {
+                for k in keys {
+                    *model.entry(*k % 8).or_insert(0u64) += 1;
+                }
+            }
+
+            let joins: Vec<_> = per_thread
+                .into_iter()
+                .map(|keys| {
+                    let system_under_test = Arc::clone(&system_under_test);
+                    thread::spawn(move || {
+                        for k in keys {
+                            system_under_test.upsert(k % 8, 0u64, |count| *count += 1);
+                        }
+                    })
+                })
+                .collect();
+            for jh in joins {
+                jh.join().unwrap();
+            }
+
+            assert_eq!(model.len(), system_under_test.len());
+            for (k, v) in model.iter() {
+                assert_eq!(Some(*v), system_under_test.get_cloned(k));
+            }
+            TestResult::passed()
+        }
+        QuickCheck::new()
+            .tests(50)
+            .quickcheck(property as fn(Vec<Vec<u8>>) -> TestResult);
+    }
+}
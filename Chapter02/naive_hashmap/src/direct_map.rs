@@ -0,0 +1,475 @@
+//! A direct-address map for keys drawn from a small, dense domain.
+//!
+//! Every possible key owns a slot, so there is no hashing and no probing:
+//! a key converts straight to its slot index. Which slots hold a value is
+//! tracked in a bitmap, one bit per slot, rather than by wrapping each value
+//! in an `Option`, keeping the per-slot overhead at a single bit. Storage is
+//! always on the heap, which matters for `u16` and `i16` with their 65,536
+//! slots.
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+
+/// A key type with few enough values that a slot can be set aside for each.
+///
+/// `to_index` must map every value to a distinct index below `SLOTS`, and
+/// `from_index` must invert it.
+pub trait SmallKey: Copy {
+    const SLOTS: usize;
+
+    fn to_index(self) -> usize;
+
+    fn from_index(idx: usize) -> Self;
+}
+
+impl SmallKey for u8 {
+    const SLOTS: usize = 1 << 8;
+
+    fn to_index(self) -> usize {
+        self as usize
+    }
+
+    fn from_index(idx: usize) -> u8 {
+        idx as u8
+    }
+}
+
+impl SmallKey for i8 {
+    const SLOTS: usize = 1 << 8;
+
+    fn to_index(self) -> usize {
+        (i16::from(self) - i16::from(i8::MIN)) as usize
+    }
+
+    fn from_index(idx: usize) -> i8 {
+        (idx as i16 + i16::from(i8::MIN)) as i8
+    }
+}
+
+impl SmallKey for u16 {
+    const SLOTS: usize = 1 << 16;
+
+    fn to_index(self) -> usize {
+        self as usize
+    }
+
+    fn from_index(idx: usize) -> u16 {
+        idx as u16
+    }
+}
+
+impl SmallKey for i16 {
+    const SLOTS: usize = 1 << 16;
+
+    fn to_index(self) -> usize {
+        (i32::from(self) - i32::from(i16::MIN)) as usize
+    }
+
+    fn from_index(idx: usize) -> i16 {
+        (idx as i32 + i32::from(i16::MIN)) as i16
+    }
+}
+
+impl SmallKey for bool {
+    const SLOTS: usize = 2;
+
+    fn to_index(self) -> usize {
+        self as usize
+    }
+
+    fn from_index(idx: usize) -> bool {
+        idx != 0
+    }
+}
+
+/// Implements `SmallKey` for a fieldless enum. The variants must be listed
+/// in declaration order and use the default discriminants, `0` up.
+///
+/// ```
+/// #[macro_use]
+/// extern crate naive_hashmap;
+///
+/// #[derive(Clone, Copy, Debug, PartialEq)]
+/// enum Suit {
+///     Clubs,
+///     Diamonds,
+///     Hearts,
+///     Spades,
+/// }
+/// small_key_enum!(Suit { Clubs, Diamonds, Hearts, Spades });
+///
+/// fn main() {
+///     let mut map = naive_hashmap::DirectMap::new();
+///     map.insert(Suit::Hearts, "red");
+///     assert_eq!(Some(&"red"), map.get(&Suit::Hearts));
+/// }
+/// ```
+#[macro_export]
+macro_rules! small_key_enum {
+    ($name:ident { $($variant:ident),+ $(,)* }) => {
+        impl $crate::SmallKey for $name {
+            const SLOTS: usize = [$($name::$variant),+].len();
+
+            fn to_index(self) -> usize {
+                self as usize
+            }
+
+            fn from_index(idx: usize) -> $name {
+                [$($name::$variant),+][idx]
+            }
+        }
+    };
+}
+
+const WORD_BITS: usize = 64;
+
+pub struct DirectMap<K, V>
+where
+    K: SmallKey,
+{
+    present: Box<[u64]>,
+    values: Box<[MaybeUninit<V>]>,
+    len: usize,
+    key: PhantomData<K>,
+}
+
+impl<K, V> DirectMap<K, V>
+where
+    K: SmallKey,
+{
+    pub fn new() -> DirectMap<K, V> {
+        let words = K::SLOTS.div_ceil(WORD_BITS);
+        DirectMap {
+            present: vec![0; words].into_boxed_slice(),
+            values: Box::new_uninit_slice(K::SLOTS),
+            len: 0,
+            key: PhantomData,
+        }
+    }
+
+    fn is_present(&self, idx: usize) -> bool {
+        self.present[idx / WORD_BITS] & (1 << (idx % WORD_BITS)) != 0
+    }
+
+    fn set_present(&mut self, idx: usize) {
+        self.present[idx / WORD_BITS] |= 1 << (idx % WORD_BITS);
+    }
+
+    fn clear_present(&mut self, idx: usize) {
+        self.present[idx / WORD_BITS] &= !(1 << (idx % WORD_BITS));
+    }
+
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        let idx = k.to_index();
+        if self.is_present(idx) {
+            // Safety: the presence bit says this slot is initialized.
+            Some(mem::replace(unsafe { self.values[idx].assume_init_mut() }, v))
+        } else {
+            self.values[idx].write(v);
+            self.set_present(idx);
+            self.len += 1;
+            None
+        }
+    }
+
+    pub fn get(&self, k: &K) -> Option<&V> {
+        let idx = k.to_index();
+        if self.is_present(idx) {
+            // Safety: the presence bit says this slot is initialized.
+            Some(unsafe { self.values[idx].assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self, k: &K) -> Option<&mut V> {
+        let idx = k.to_index();
+        if self.is_present(idx) {
+            // Safety: the presence bit says this slot is initialized.
+            Some(unsafe { self.values[idx].assume_init_mut() })
+        } else {
+            None
+        }
+    }
+
+    pub fn contains_key(&self, k: &K) -> bool {
+        self.is_present(k.to_index())
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        let idx = k.to_index();
+        if self.is_present(idx) {
+            self.clear_present(idx);
+            self.len -= 1;
+            // Safety: the slot was initialized and, its presence bit now
+            // cleared, will not be read again until rewritten.
+            Some(unsafe { self.values[idx].assume_init_read() })
+        } else {
+            None
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        for word_idx in 0..self.present.len() {
+            let mut word = mem::replace(&mut self.present[word_idx], 0);
+            while word != 0 {
+                let idx = word_idx * WORD_BITS + word.trailing_zeros() as usize;
+                word &= word - 1;
+                // Safety: the presence bit was set, and is now cleared.
+                unsafe { self.values[idx].assume_init_drop() };
+            }
+        }
+        self.len = 0;
+    }
+
+    /// Iterates in slot order, which for the integer keys is ascending key
+    /// order.
+    pub fn iter(&self) -> DirectIter<'_, K, V> {
+        DirectIter {
+            map: self,
+            word_idx: 0,
+            word: self.present.first().cloned().unwrap_or(0),
+            remaining: self.len,
+        }
+    }
+}
+
+impl<K, V> Default for DirectMap<K, V>
+where
+    K: SmallKey,
+{
+    fn default() -> DirectMap<K, V> {
+        DirectMap::new()
+    }
+}
+
+impl<K, V> Drop for DirectMap<K, V>
+where
+    K: SmallKey,
+{
+    fn drop(&mut self) {
+        if mem::needs_drop::<V>() {
+            self.clear();
+        }
+    }
+}
+
+impl<K, V> Clone for DirectMap<K, V>
+where
+    K: SmallKey,
+    V: Clone,
+{
+    fn clone(&self) -> DirectMap<K, V> {
+        let mut map = DirectMap::new();
+        for (k, v) in self.iter() {
+            map.insert(k, v.clone());
+        }
+        map
+    }
+}
+
+impl<K, V> fmt::Debug for DirectMap<K, V>
+where
+    K: SmallKey + fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// Walks the presence bitmap a word at a time, jumping straight to each set
+/// bit, so sparse maps do not pay for their empty slots one by one.
+pub struct DirectIter<'a, K: 'a, V: 'a>
+where
+    K: SmallKey,
+{
+    map: &'a DirectMap<K, V>,
+    word_idx: usize,
+    word: u64,
+    remaining: usize,
+}
+
+impl<'a, K, V> Iterator for DirectIter<'a, K, V>
+where
+    K: SmallKey,
+{
+    type Item = (K, &'a V);
+
+    fn next(&mut self) -> Option<(K, &'a V)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        while self.word == 0 {
+            self.word_idx += 1;
+            self.word = self.map.present[self.word_idx];
+        }
+        let idx = self.word_idx * WORD_BITS + self.word.trailing_zeros() as usize;
+        self.word &= self.word - 1;
+        self.remaining -= 1;
+        // Safety: the presence bit says this slot is initialized.
+        Some((K::from_index(idx), unsafe { self.map.values[idx].assume_init_ref() }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for DirectIter<'a, K, V> where K: SmallKey {}
+
+impl<'a, K, V> IntoIterator for &'a DirectMap<K, V>
+where
+    K: SmallKey,
+{
+    type Item = (K, &'a V);
+    type IntoIter = DirectIter<'a, K, V>;
+
+    fn into_iter(self) -> DirectIter<'a, K, V> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use super::*;
+    use quickcheck::{Arbitrary, Gen, QuickCheck, TestResult};
+    use std::collections;
+    use std::hash::Hash;
+    use std::rc::Rc;
+
+    #[derive(Clone, Debug)]
+    enum Action<T>
+    where
+        T: Arbitrary,
+    {
+        Insert(T, u16),
+        Lookup(T),
+        Remove(T),
+        Clear,
+    }
+
+    impl<T> Arbitrary for Action<T>
+    where
+        T: Arbitrary,
+    {
+        fn arbitrary<G>(g: &mut G) -> Action<T>
+        where
+            G: Gen,
+        {
+            let i: usize = g.gen_range(0, 100);
+            match i {
+                0..=50 => Action::Insert(Arbitrary::arbitrary(g), u16::arbitrary(g)),
+                51..=80 => Action::Lookup(Arbitrary::arbitrary(g)),
+                81..=98 => Action::Remove(Arbitrary::arbitrary(g)),
+                _ => Action::Clear,
+            }
+        }
+    }
+
+    #[test]
+    fn sut_vs_genuine_article() {
+        fn property<T>(actions: Vec<Action<T>>) -> TestResult
+        where
+            T: Arbitrary + SmallKey + Eq + Hash + Ord + ::std::fmt::Debug,
+        {
+            let mut model = collections::BTreeMap::new();
+            let mut system_under_test = DirectMap::new();
+
+            for action in actions.into_iter() {
+                match action {
+                    Action::Insert(k, v) => {
+                        assert_eq!(model.insert(k, v), system_under_test.insert(k, v));
+                    }
+                    Action::Lookup(k) => {
+                        assert_eq!(model.get(&k), system_under_test.get(&k));
+                        assert_eq!(model.contains_key(&k), system_under_test.contains_key(&k));
+                    }
+                    Action::Remove(k) => {
+                        assert_eq!(model.remove(&k), system_under_test.remove(&k));
+                    }
+                    Action::Clear => {
+                        model.clear();
+                        system_under_test.clear();
+                    }
+                }
+                assert_eq!(model.len(), system_under_test.len());
+            }
+            // Slot order is key order for every integer key, so the two
+            // iterators must agree element for element.
+            let expected: Vec<_> = model.iter().map(|(k, v)| (*k, v)).collect();
+            let actual: Vec<_> = system_under_test.iter().collect();
+            assert_eq!(expected, actual);
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<u8>>) -> TestResult);
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<i8>>) -> TestResult);
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<u16>>) -> TestResult);
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<i16>>) -> TestResult);
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<bool>>) -> TestResult);
+    }
+
+    #[test]
+    fn index_round_trips() {
+        for idx in 0..u16::SLOTS {
+            assert_eq!(idx, i16::from_index(idx).to_index());
+        }
+        for idx in 0..i8::SLOTS {
+            assert_eq!(idx, i8::from_index(idx).to_index());
+        }
+        assert_eq!(0, i16::MIN.to_index());
+        assert_eq!(i16::SLOTS - 1, i16::MAX.to_index());
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Direction {
+        North,
+        East,
+        South,
+        West,
+    }
+    small_key_enum!(Direction { North, East, South, West });
+
+    #[test]
+    fn fieldless_enum_keys() {
+        let mut system_under_test = DirectMap::new();
+        assert_eq!(4, Direction::SLOTS);
+        assert_eq!(None, system_under_test.insert(Direction::South, 1));
+        assert_eq!(None, system_under_test.insert(Direction::North, 2));
+        assert_eq!(Some(1), system_under_test.insert(Direction::South, 3));
+        let contents: Vec<_> = system_under_test.iter().collect();
+        assert_eq!(vec![(Direction::North, &2), (Direction::South, &3)], contents);
+        assert_eq!(Some(2), system_under_test.remove(&Direction::North));
+        assert_eq!(1, system_under_test.len());
+    }
+
+    #[test]
+    fn values_are_dropped_exactly_once() {
+        let value = Rc::new(());
+        {
+            let mut system_under_test = DirectMap::new();
+            for k in 0..200u8 {
+                system_under_test.insert(k, Rc::clone(&value));
+            }
+            system_under_test.insert(3, Rc::clone(&value));
+            drop(system_under_test.remove(&4));
+            let cloned = system_under_test.clone();
+            assert_eq!(1 + 2 * 199, Rc::strong_count(&value));
+            drop(cloned);
+            system_under_test.clear();
+            assert_eq!(1, Rc::strong_count(&value));
+            system_under_test.insert(7, Rc::clone(&value));
+        }
+        assert_eq!(1, Rc::strong_count(&value));
+    }
+}
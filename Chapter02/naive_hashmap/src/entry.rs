@@ -0,0 +1,115 @@
+//! The `Entry` API, mirroring `std::collections::hash_map::Entry`.
+use raw_table::RawTable;
+use std::mem;
+
+/// A view into a single slot of a `HashMap`, which may or may not be
+/// occupied. Constructed by `HashMap::entry`.
+pub enum Entry<'a, K: 'a, V: 'a> {
+    Occupied(OccupiedEntry<'a, K, V>),
+    Vacant(VacantEntry<'a, K, V>),
+}
+
+pub struct OccupiedEntry<'a, K: 'a, V: 'a> {
+    pub(crate) table: &'a mut RawTable<K, V>,
+    pub(crate) idx: usize,
+}
+
+/// The key is held here, hash already computed, until a value is supplied.
+pub struct VacantEntry<'a, K: 'a, V: 'a> {
+    pub(crate) table: &'a mut RawTable<K, V>,
+    pub(crate) hash: u64,
+    pub(crate) key: K,
+}
+
+impl<'a, K, V> Entry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key(),
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    pub fn or_insert_with<F>(self, default: F) -> &'a mut V
+    where
+        F: FnOnce() -> V,
+    {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    pub fn and_modify<F>(self, f: F) -> Entry<'a, K, V>
+    where
+        F: FnOnce(&mut V),
+    {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
+            }
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+        }
+    }
+}
+
+impl<'a, K, V> Entry<'a, K, V>
+where
+    V: Default,
+{
+    pub fn or_default(self) -> &'a mut V {
+        self.or_insert_with(V::default)
+    }
+}
+
+impl<'a, K, V> OccupiedEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        &self.table.bucket(self.idx).key
+    }
+
+    pub fn get(&self) -> &V {
+        &self.table.bucket(self.idx).value
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.table.bucket_mut(self.idx).value
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.table.bucket_mut(self.idx).value
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    pub fn remove_entry(self) -> (K, V) {
+        self.table.remove_at(self.idx)
+    }
+}
+
+impl<'a, K, V> VacantEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    pub fn insert(self, value: V) -> &'a mut V {
+        let idx = self.table.insert_new(self.hash, self.key, value);
+        &mut self.table.bucket_mut(idx).value
+    }
+}
@@ -0,0 +1,284 @@
+//! Differential testing of the maps in this crate against the standard
+//! library's `HashMap`.
+//!
+//! Fuzzer input is an arbitrary byte string, decoded into a sequence of map
+//! operations. Each operation is an opcode byte, its range picking the kind
+//! of operation, followed by that operation's arguments:
+//!
+//! | opcode       | operation | argument bytes        |
+//! |--------------|-----------|-----------------------|
+//! | `0..=95`     | insert    | key, value hi, val lo |
+//! | `96..=159`   | lookup    | key                   |
+//! | `160..=207`  | remove    | key                   |
+//! | `208..=231`  | len       |                       |
+//! | `232..=253`  | dump      |                       |
+//! | `254..=255`  | clear     |                       |
+//!
+//! A trailing operation missing some of its argument bytes is dropped, so
+//! every byte string decodes to something and the fuzzer wastes no effort on
+//! rejected inputs. Keys are single bytes so that `HashMapU8` can take part.
+use std::collections;
+use std::fmt;
+use {HashMap, HashMapU8};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Insert(u8, u16),
+    Lookup(u8),
+    Remove(u8),
+    Len,
+    Dump,
+    Clear,
+}
+
+impl fmt::Display for Op {
+    /// Formats as a line of the interpreters' command language, so a trace
+    /// can be replayed with `naive_interpreter --backend <name>`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Op::Insert(k, v) => write!(f, "INSERT {} {}", k, v),
+            Op::Lookup(k) => write!(f, "LOOKUP {}", k),
+            Op::Remove(k) => write!(f, "REMOVE {}", k),
+            Op::Len => write!(f, "LEN"),
+            Op::Dump => write!(f, "DUMP"),
+            Op::Clear => write!(f, "CLEAR"),
+        }
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Vec<Op> {
+    let mut ops = Vec::new();
+    let mut bytes = bytes.iter().cloned();
+    while let Some(opcode) = bytes.next() {
+        let op = match opcode {
+            0..=95 => match (bytes.next(), bytes.next(), bytes.next()) {
+                (Some(k), Some(hi), Some(lo)) => Op::Insert(k, u16::from(hi) << 8 | u16::from(lo)),
+                _ => break,
+            },
+            96..=159 => match bytes.next() {
+                Some(k) => Op::Lookup(k),
+                None => break,
+            },
+            160..=207 => match bytes.next() {
+                Some(k) => Op::Remove(k),
+                None => break,
+            },
+            208..=231 => Op::Len,
+            232..=253 => Op::Dump,
+            _ => Op::Clear,
+        };
+        ops.push(op);
+    }
+    ops
+}
+
+/// The inverse of `decode`, for writing out a minimized input.
+pub fn encode(ops: &[Op]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for op in ops {
+        match *op {
+            Op::Insert(k, v) => bytes.extend_from_slice(&[0, k, (v >> 8) as u8, v as u8]),
+            Op::Lookup(k) => bytes.extend_from_slice(&[96, k]),
+            Op::Remove(k) => bytes.extend_from_slice(&[160, k]),
+            Op::Len => bytes.push(208),
+            Op::Dump => bytes.push(232),
+            Op::Clear => bytes.push(254),
+        }
+    }
+    bytes
+}
+
+/// What one backend reported for one operation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Observation {
+    Value(Option<u16>),
+    Len(usize),
+    Contents(Vec<(u8, u16)>),
+    Unit,
+}
+
+/// The three maps under comparison, driven in lockstep.
+#[derive(Default)]
+pub struct Backends {
+    naive: HashMap<u8, u16>,
+    specialized: HashMapU8<u16>,
+    standard: collections::HashMap<u8, u16>,
+}
+
+pub const BACKEND_NAMES: [&str; 3] = ["naive", "specialized", "std"];
+
+impl Backends {
+    pub fn new() -> Backends {
+        Backends::default()
+    }
+
+    /// Applies `op` to every backend, returning what each observed in
+    /// `BACKEND_NAMES` order.
+    pub fn apply(&mut self, op: Op) -> [Observation; 3] {
+        match op {
+            Op::Insert(k, v) => [
+                Observation::Value(self.naive.insert(k, v)),
+                Observation::Value(self.specialized.insert(k, v)),
+                Observation::Value(self.standard.insert(k, v)),
+            ],
+            Op::Lookup(k) => [
+                Observation::Value(self.naive.get(&k).cloned()),
+                Observation::Value(self.specialized.get(&k).cloned()),
+                Observation::Value(self.standard.get(&k).cloned()),
+            ],
+            Op::Remove(k) => [
+                Observation::Value(self.naive.remove(&k)),
+                Observation::Value(self.specialized.remove(&k)),
+                Observation::Value(self.standard.remove(&k)),
+            ],
+            Op::Len => [
+                Observation::Len(self.naive.len()),
+                Observation::Len(self.specialized.len()),
+                Observation::Len(self.standard.len()),
+            ],
+            Op::Dump => {
+                let sorted = |mut v: Vec<(u8, u16)>| {
+                    v.sort();
+                    Observation::Contents(v)
+                };
+                [
+                    sorted(self.naive.iter().map(|(k, v)| (*k, *v)).collect()),
+                    sorted(self.specialized.iter().map(|(k, v)| (k, *v)).collect()),
+                    sorted(self.standard.iter().map(|(k, v)| (*k, *v)).collect()),
+                ]
+            }
+            Op::Clear => {
+                self.naive.clear();
+                self.specialized.clear();
+                self.standard.clear();
+                [Observation::Unit, Observation::Unit, Observation::Unit]
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// Position of the diverging operation in the trace.
+    pub index: usize,
+    pub op: Op,
+    pub observations: [Observation; 3],
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "divergence at op {}: {}", self.index, self.op)?;
+        for (name, obs) in BACKEND_NAMES.iter().zip(self.observations.iter()) {
+            writeln!(f, "  {:<12} {:?}", name, obs)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs `ops` against fresh backends, stopping at the first operation on
+/// which they disagree.
+pub fn first_divergence(ops: &[Op]) -> Option<Divergence> {
+    let mut backends = Backends::new();
+    for (index, op) in ops.iter().enumerate() {
+        let observations = backends.apply(*op);
+        if observations[1..].iter().any(|obs| *obs != observations[0]) {
+            return Some(Divergence {
+                index,
+                op: *op,
+                observations,
+            });
+        }
+    }
+    None
+}
+
+/// Shrinks a failing trace, keeping it failing according to `fails`.
+///
+/// Chunks of ops are removed, halving the chunk size down to single ops,
+/// until no removal keeps the trace failing.
+pub fn minimize<F>(ops: &[Op], fails: F) -> Vec<Op>
+where
+    F: Fn(&[Op]) -> bool,
+{
+    let mut ops = ops.to_vec();
+    debug_assert!(fails(&ops));
+    let mut chunk = ops.len().div_ceil(2).max(1);
+    loop {
+        let mut start = 0;
+        let mut shrunk = false;
+        while start < ops.len() {
+            let end = (start + chunk).min(ops.len());
+            let mut candidate = ops[..start].to_vec();
+            candidate.extend_from_slice(&ops[end..]);
+            if fails(&candidate) {
+                ops = candidate;
+                shrunk = true;
+            } else {
+                start = end;
+            }
+        }
+        if chunk == 1 && !shrunk {
+            return ops;
+        }
+        if !shrunk {
+            chunk = chunk.div_ceil(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use super::*;
+    use quickcheck::{QuickCheck, TestResult};
+
+    #[test]
+    fn encode_decode_round_trip() {
+        fn property(bytes: Vec<u8>) -> TestResult {
+            let ops = decode(&bytes);
+            assert_eq!(ops, decode(&encode(&ops)));
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<u8>) -> TestResult);
+    }
+
+    #[test]
+    fn truncated_trailing_op_is_dropped() {
+        assert_eq!(vec![Op::Lookup(7)], decode(&[96, 7, 0, 1, 2]));
+        assert_eq!(vec![Op::Len], decode(&[208, 160]));
+    }
+
+    #[test]
+    fn backends_never_diverge() {
+        fn property(bytes: Vec<u8>) -> TestResult {
+            match first_divergence(&decode(&bytes)) {
+                None => TestResult::passed(),
+                Some(divergence) => TestResult::error(divergence.to_string()),
+            }
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<u8>) -> TestResult);
+    }
+
+    #[test]
+    fn minimize_finds_smallest_failing_trace() {
+        // Stand-in for a bug: lookups of 3 fail once 3 has been inserted.
+        let fails = |ops: &[Op]| {
+            let inserted = ops.iter().position(|op| matches!(*op, Op::Insert(3, _)));
+            match inserted {
+                Some(idx) => ops[idx..].contains(&Op::Lookup(3)),
+                None => false,
+            }
+        };
+        let ops = vec![
+            Op::Insert(1, 1),
+            Op::Lookup(3),
+            Op::Insert(3, 9),
+            Op::Len,
+            Op::Remove(1),
+            Op::Lookup(3),
+            Op::Clear,
+        ];
+        assert_eq!(vec![Op::Insert(3, 9), Op::Lookup(3)], minimize(&ops, fails));
+    }
+}
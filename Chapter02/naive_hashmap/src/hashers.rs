@@ -0,0 +1,288 @@
+//! Hash functions to plug into `HashMap` in place of the default SipHash
+//! `RandomState`.
+//!
+//! None of these are keyed, so none resist HashDoS: an adversary who can pick
+//! keys can make them all collide. What they buy is speed. Benchmark with
+//! `cargo bench --bench hashers` before reaching for one.
+use std::hash::{BuildHasherDefault, Hasher};
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// 64-bit FNV-1a. Byte at a time, so quick for short keys and slow for long
+/// ones.
+#[derive(Clone, Copy)]
+pub struct FnvHasher {
+    state: u64,
+}
+
+impl Default for FnvHasher {
+    fn default() -> FnvHasher {
+        FnvHasher {
+            state: FNV_OFFSET_BASIS,
+        }
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= u64::from(*byte);
+            self.state = self.state.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.state
+    }
+}
+
+pub type FnvBuildHasher = BuildHasherDefault<FnvHasher>;
+
+const SEA_PRIME: u64 = 0x6eed_0e9d_a4d9_4a4f;
+const SEA_SEEDS: [u64; 4] = [
+    0x16f1_1fe8_9b0d_677c,
+    0xb480_a793_d8e6_c86c,
+    0x6fe2_e5aa_f078_ebc9,
+    0x14f9_94a4_c525_9381,
+];
+
+/// SeaHash's diffusion function: a bijection that spreads every input bit
+/// over the whole word.
+fn diffuse(mut x: u64) -> u64 {
+    x = x.wrapping_mul(SEA_PRIME);
+    let a = x >> 32;
+    let b = x >> 60;
+    x ^= a >> b;
+    x.wrapping_mul(SEA_PRIME)
+}
+
+/// A hasher built the way SeaHash is -- four lanes fed eight bytes at a
+/// time, each mixed with `diffuse` -- without promising SeaHash's exact
+/// output. Word at a time, so it pulls ahead of FNV as keys grow.
+#[derive(Clone, Copy)]
+pub struct SeaHasher {
+    lanes: [u64; 4],
+    lane: usize,
+    tail: u64,
+    tail_len: usize,
+    written: u64,
+}
+
+impl Default for SeaHasher {
+    fn default() -> SeaHasher {
+        SeaHasher {
+            lanes: SEA_SEEDS,
+            lane: 0,
+            tail: 0,
+            tail_len: 0,
+            written: 0,
+        }
+    }
+}
+
+impl SeaHasher {
+    fn push_word(&mut self, word: u64) {
+        self.lanes[self.lane] = diffuse(self.lanes[self.lane] ^ word);
+        self.lane = (self.lane + 1) % 4;
+    }
+}
+
+impl Hasher for SeaHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.written += bytes.len() as u64;
+        for byte in bytes {
+            self.tail |= u64::from(*byte) << (8 * self.tail_len);
+            self.tail_len += 1;
+            if self.tail_len == 8 {
+                let word = self.tail;
+                self.push_word(word);
+                self.tail = 0;
+                self.tail_len = 0;
+            }
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        if self.tail_len == 0 {
+            self.written += 8;
+            self.push_word(i);
+        } else {
+            self.write(&i.to_le_bytes());
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        let mut lanes = self.lanes;
+        if self.tail_len > 0 {
+            lanes[self.lane] = diffuse(lanes[self.lane] ^ self.tail);
+        }
+        diffuse(lanes[0] ^ lanes[1] ^ lanes[2] ^ lanes[3] ^ self.written)
+    }
+}
+
+pub type SeaBuildHasher = BuildHasherDefault<SeaHasher>;
+
+/// Hashes an integer key to itself. Fastest of all when keys are already
+/// well spread in their low bits -- sequential ids, say -- and pathological
+/// when they are not: keys sharing their low bits all land in one probe
+/// chain. Every write is folded into the state by rotating it the width of
+/// the write and xoring the write in, so a lone integer hashes to itself
+/// while strings and tuples still hash from all of their parts. There is
+/// little reason to use it for them, though.
+#[derive(Clone, Copy, Default)]
+pub struct IdentityHasher {
+    state: u64,
+}
+
+impl IdentityHasher {
+    fn fold(&mut self, i: u64, bits: u32) {
+        self.state = self.state.rotate_left(bits) ^ i;
+    }
+}
+
+impl Hasher for IdentityHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.fold(u64::from(*byte), 8);
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.fold(u64::from(i), 8);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.fold(u64::from(i), 16);
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.fold(u64::from(i), 32);
+    }
+
+    // A whole word's turn would leave the state where it was, so word-sized
+    // writes turn it by half a word.
+    fn write_u64(&mut self, i: u64) {
+        self.fold(i, 32);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.fold(i as u64, 32);
+    }
+
+    fn finish(&self) -> u64 {
+        self.state
+    }
+}
+
+pub type IdentityBuildHasher = BuildHasherDefault<IdentityHasher>;
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use super::*;
+    use quickcheck::{QuickCheck, TestResult};
+    use std::collections::HashSet;
+    use std::hash::{BuildHasher, Hash};
+    use HashMap;
+
+    fn hash_of<B, T>(t: &T) -> u64
+    where
+        B: BuildHasher + Default,
+        T: Hash,
+    {
+        B::default().hash_one(t)
+    }
+
+    #[test]
+    fn fnv_known_answers() {
+        let fnv = |bytes: &[u8]| {
+            let mut hasher = FnvHasher::default();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_eq!(0xcbf2_9ce4_8422_2325, fnv(b""));
+        assert_eq!(0xaf63_dc4c_8601_ec8c, fnv(b"a"));
+        assert_eq!(0x8594_4171_f739_67e8, fnv(b"foobar"));
+    }
+
+    #[test]
+    fn identity_is_identity_for_integers() {
+        assert_eq!(42, hash_of::<IdentityBuildHasher, _>(&42u8));
+        assert_eq!(42, hash_of::<IdentityBuildHasher, _>(&42u32));
+        assert_eq!(1 << 40, hash_of::<IdentityBuildHasher, _>(&(1u64 << 40)));
+    }
+
+    #[test]
+    fn identity_hashes_strings_and_tuples_from_every_part() {
+        fn distinct<T: Hash>(keys: &[T]) -> usize {
+            keys.iter()
+                .map(hash_of::<IdentityBuildHasher, T>)
+                .collect::<HashSet<u64>>()
+                .len()
+        }
+        let strings = ["", "a", "b", "ab", "ba", "abc", "telemetry"];
+        assert_eq!(strings.len(), distinct(&strings));
+        let pairs = [("a", "b"), ("b", "a"), ("ab", ""), ("", "ab")];
+        assert_eq!(pairs.len(), distinct(&pairs));
+        let halves = [(1u32, 2u32), (2, 1), (1, 1), (2, 2), (0, 3)];
+        assert_eq!(halves.len(), distinct(&halves));
+        let words = [(1u64, 2u64), (2, 1), (1, 1), (2, 2), (0, 3)];
+        assert_eq!(words.len(), distinct(&words));
+    }
+
+    #[test]
+    fn sea_splits_words_consistently() {
+        // Feeding the same bytes in different-sized writes must not matter.
+        fn property(bytes: Vec<u8>, split: usize) -> TestResult {
+            let split = if bytes.is_empty() { 0 } else { split % bytes.len() };
+            let mut whole = SeaHasher::default();
+            whole.write(&bytes);
+            let mut parts = SeaHasher::default();
+            parts.write(&bytes[..split]);
+            parts.write(&bytes[split..]);
+            assert_eq!(whole.finish(), parts.finish());
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<u8>, usize) -> TestResult);
+    }
+
+    #[test]
+    fn sea_write_u64_matches_bytes() {
+        fn property(prefix: Vec<u8>, i: u64) -> TestResult {
+            let mut words = SeaHasher::default();
+            words.write(&prefix);
+            words.write_u64(i);
+            let mut bytes = SeaHasher::default();
+            bytes.write(&prefix);
+            bytes.write(&i.to_le_bytes());
+            assert_eq!(words.finish(), bytes.finish());
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<u8>, u64) -> TestResult);
+    }
+
+    #[test]
+    fn every_hasher_backs_a_working_map() {
+        fn check<B>(keys: &[u64])
+        where
+            B: BuildHasher + Default,
+        {
+            let mut map = HashMap::with_hasher(B::default());
+            for (i, k) in keys.iter().enumerate() {
+                map.insert(*k, i);
+            }
+            for k in keys {
+                assert!(map.contains_key(k));
+            }
+        }
+        fn property(keys: Vec<u64>) -> TestResult {
+            check::<FnvBuildHasher>(&keys);
+            check::<SeaBuildHasher>(&keys);
+            check::<IdentityBuildHasher>(&keys);
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<u64>) -> TestResult);
+    }
+}
@@ -0,0 +1,184 @@
+//! Iterators over `HashMap`. Each walks the bucket array in slot order,
+//! skipping empty buckets, and tracks how many elements remain so
+//! `size_hint` is exact.
+use raw_table::{Bucket, RawTable};
+use std::{mem, slice, vec};
+
+pub struct Iter<'a, K: 'a, V: 'a> {
+    pub(crate) inner: slice::Iter<'a, Option<Bucket<K, V>>>,
+    pub(crate) remaining: usize,
+}
+
+impl<'a, K, V> Clone for Iter<'a, K, V> {
+    fn clone(&self) -> Iter<'a, K, V> {
+        Iter {
+            inner: self.inner.clone(),
+            remaining: self.remaining,
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        for slot in self.inner.by_ref() {
+            if let Some(ref bucket) = *slot {
+                self.remaining -= 1;
+                return Some((&bucket.key, &bucket.value));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {}
+
+pub struct IterMut<'a, K: 'a, V: 'a> {
+    pub(crate) inner: slice::IterMut<'a, Option<Bucket<K, V>>>,
+    pub(crate) remaining: usize,
+}
+
+impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        for slot in self.inner.by_ref() {
+            if let Some(ref mut bucket) = *slot {
+                self.remaining -= 1;
+                return Some((&bucket.key, &mut bucket.value));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for IterMut<'a, K, V> {}
+
+pub struct IntoIter<K, V> {
+    pub(crate) inner: vec::IntoIter<Option<Bucket<K, V>>>,
+    pub(crate) remaining: usize,
+}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        match self.inner.by_ref().flatten().next() {
+            Some(bucket) => {
+                self.remaining -= 1;
+                Some((bucket.key, bucket.value))
+            }
+            None => None,
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoIter<K, V> {}
+
+/// Empties the map as it is iterated, keeping the bucket allocation. Any
+/// elements not yielded are dropped along with the `Drain`.
+pub struct Drain<'a, K: 'a, V: 'a> {
+    /// Gets `buckets` back, emptied, once the drain is dropped.
+    pub(crate) table: &'a mut RawTable<K, V>,
+    pub(crate) buckets: Vec<Option<Bucket<K, V>>>,
+    pub(crate) next: usize,
+    pub(crate) remaining: usize,
+}
+
+impl<'a, K, V> Iterator for Drain<'a, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        while self.next < self.buckets.len() {
+            let slot = self.buckets[self.next].take();
+            self.next += 1;
+            if let Some(bucket) = slot {
+                self.remaining -= 1;
+                return Some((bucket.key, bucket.value));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Drain<'a, K, V> {}
+
+impl<'a, K, V> Drop for Drain<'a, K, V> {
+    fn drop(&mut self) {
+        for slot in &mut self.buckets[self.next..] {
+            *slot = None;
+        }
+        self.table.buckets = mem::take(&mut self.buckets);
+    }
+}
+
+pub struct Keys<'a, K: 'a, V: 'a> {
+    pub(crate) inner: Iter<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.inner.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Keys<'a, K, V> {}
+
+pub struct Values<'a, K: 'a, V: 'a> {
+    pub(crate) inner: Iter<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Values<'a, K, V> {}
+
+pub struct ValuesMut<'a, K: 'a, V: 'a> {
+    pub(crate) inner: IterMut<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for ValuesMut<'a, K, V> {
+    type Item = &'a mut V;
+
+    fn next(&mut self) -> Option<&'a mut V> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for ValuesMut<'a, K, V> {}
@@ -1,136 +1,404 @@
 // start snippet lib-preamble
 #[cfg(test)]
 extern crate quickcheck;
+extern crate rand;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 
-use std::hash::{BuildHasher, Hash, Hasher};
+use std::hash::{BuildHasher, Hash};
 use std::borrow::Borrow;
 use std::collections::hash_map::RandomState;
-use std::{cmp, mem, ptr};
-// end snippet lib-preamble
-
-// start snippet lib-hashmapu8
-pub struct HashMapU8<V>
-where
-    V: ::std::fmt::Debug,
-{
-    data: [Option<V>; 256],
-}
+use std::{fmt, mem};
+use std::iter::FromIterator;
+use std::ops::Index;
 
-impl<V> HashMapU8<V>
-where
-    V: ::std::fmt::Debug,
-{
-    pub fn new() -> HashMapU8<V> {
-        let data = unsafe {
-            let mut data: [Option<V>; 256] = mem::uninitialized();
-            for element in data.iter_mut() {
-                ptr::write(element, None)
-            }
-            data
-        };
-        HashMapU8 { data: data }
-    }
+pub mod command;
+mod concurrent;
+#[macro_use]
+mod direct_map;
+mod entry;
+pub mod fuzz;
+pub mod hashers;
+mod iter;
+mod persistent;
+mod raw_table;
+#[cfg(feature = "serde")]
+mod serde_impls;
+pub mod snapshot;
+pub mod workload;
 
-    pub fn insert(&mut self, k: u8, v: V) -> Option<V> {
-        mem::replace(&mut self.data[(k as usize)], Some(v))
-    }
+pub use concurrent::*;
+pub use direct_map::*;
+pub use entry::*;
+pub use iter::*;
+pub use persistent::PersistentHashMap;
+pub use raw_table::MemoryUsage;
+use raw_table::RawTable;
+// end snippet lib-preamble
 
-    pub fn get(&mut self, k: &u8) -> Option<&V> {
-        let val = unsafe { self.data.get_unchecked((*k as usize)) };
-        val.as_ref()
-    }
-}
+// start snippet lib-hashmapu8
+/// The specialized map for `u8` keys, now one instance of the general
+/// direct-address `DirectMap`.
+pub type HashMapU8<V> = DirectMap<u8, V>;
 // end snippet lib-hashmapu8
 
 // start snippet lib-hashmap-struct
-#[derive(Default)]
-pub struct HashMap<K, V, S = RandomState>
-where
-    K: Eq,
-    V: ::std::fmt::Debug,
-{
+pub struct HashMap<K, V, S = RandomState> {
     hash_builder: S,
-    data: Vec<(u64, K, V)>,
+    table: RawTable<K, V>,
 }
 // end snippet lib-hashmap-struct
 
 // start snippet lib-hashmap-to-with_hasher
-impl<K: Eq, V> HashMap<K, V, RandomState>
+impl<K, V> HashMap<K, V, RandomState>
 where
     K: Eq + Hash,
-    V: ::std::fmt::Debug,
 {
     pub fn new() -> HashMap<K, V> {
         HashMap {
             hash_builder: RandomState::new(),
-            data: Vec::new(),
+            table: RawTable::new(),
         }
     }
+
+    /// Creates a map that holds at least `capacity` elements before its
+    /// first reallocation.
+    pub fn with_capacity(capacity: usize) -> HashMap<K, V> {
+        HashMap::with_capacity_and_hasher(capacity, RandomState::new())
+    }
 }
 
-fn make_hash<T: ?Sized, S>(hash_builder: &S, t: &T) -> u64
+fn make_hash<T, S>(hash_builder: &S, t: &T) -> u64
 where
-    T: Hash,
+    T: Hash + ?Sized,
     S: BuildHasher,
 {
-    let mut state = hash_builder.build_hasher();
-    t.hash(&mut state);
-    state.finish()
+    hash_builder.hash_one(t)
 }
 
 impl<K, V, S> HashMap<K, V, S>
 where
     K: Eq + Hash,
     S: BuildHasher,
-    V: ::std::fmt::Debug,
 {
     pub fn with_hasher(hash_builder: S) -> HashMap<K, V, S> {
         HashMap {
-            hash_builder: hash_builder,
-            data: Vec::new(),
+            hash_builder,
+            table: RawTable::new(),
         }
     }
     // end snippet lib-hashmap-to-with_hasher
 
+    pub fn with_capacity_and_hasher(capacity: usize, hash_builder: S) -> HashMap<K, V, S> {
+        HashMap {
+            hash_builder,
+            table: RawTable::with_capacity(capacity),
+        }
+    }
+
+    /// Makes room for at least `additional` more elements without
+    /// reallocating. Does nothing if there is room already.
+    pub fn reserve(&mut self, additional: usize) {
+        self.table.reserve(additional);
+    }
+
+    /// Shrinks the bucket array as far as the current elements allow,
+    /// freeing it entirely if the map is empty.
+    pub fn shrink_to_fit(&mut self) {
+        self.table.shrink_to_fit();
+    }
+
     // start snippet lib-hashmap-insertion
     pub fn insert(&mut self, k: K, v: V) -> Option<V> {
-        let hash = make_hash(&self.hash_builder, &k);
-
-        let end = self.data.len();
-        for idx in 0..end {
-            match self.data[idx].0.cmp(&hash) {
-                cmp::Ordering::Greater => {
-                    self.data.insert(idx, (hash, k, v));
-                    return None;
-                }
-                cmp::Ordering::Less => continue,
-                cmp::Ordering::Equal => {
-                    let old = mem::replace(&mut self.data[idx].2, v);
-                    return Some(old);
-                }
+        match self.entry(k) {
+            Entry::Occupied(mut entry) => Some(entry.insert(v)),
+            Entry::Vacant(entry) => {
+                entry.insert(v);
+                None
             }
         }
-        self.data.push((hash, k, v));
-        None
     }
     // end snippet lib-hashmap-insertion
 
-    // start snippet lib-hashmap-get
-    pub fn get<Q: ?Sized>(&mut self, k: &Q) -> Option<&V>
+    pub fn entry(&mut self, k: K) -> Entry<'_, K, V> {
+        let hash = make_hash(&self.hash_builder, &k);
+
+        match self.table.find(hash, &k) {
+            Some(idx) => Entry::Occupied(OccupiedEntry {
+                table: &mut self.table,
+                idx,
+            }),
+            None => Entry::Vacant(VacantEntry {
+                table: &mut self.table,
+                hash,
+                key: k,
+            }),
+        }
+    }
+
+    fn find<Q>(&self, k: &Q) -> Option<usize>
     where
-        K: Borrow<Q> + ::std::fmt::Debug,
-        Q: Hash + Eq + ::std::fmt::Debug,
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
     {
         let hash = make_hash(&self.hash_builder, k);
+        self.table.find(hash, k)
+    }
 
-        for &(bucket_hash, _, ref v) in &self.data {
-            if hash == bucket_hash {
-                return Some(v);
-            }
-        }
-        None
+    // start snippet lib-hashmap-get
+    pub fn get<Q>(&self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find(k).map(|idx| &self.table.bucket(idx).value)
     }
     // end snippet lib-hashmap-get
+
+    pub fn get_mut<Q>(&mut self, k: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.find(k) {
+            Some(idx) => Some(&mut self.table.bucket_mut(idx).value),
+            None => None,
+        }
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find(k).is_some()
+    }
+
+    // start snippet lib-hashmap-remove
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.remove_entry(k).map(|(_, v)| v)
+    }
+    // end snippet lib-hashmap-remove
+
+    pub fn remove_entry<Q>(&mut self, k: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.find(k) {
+            Some(idx) => Some(self.table.remove_at(idx)),
+            None => None,
+        }
+    }
+}
+
+impl<K, V, S> HashMap<K, V, S> {
+    pub fn hasher(&self) -> &S {
+        &self.hash_builder
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.len
+    }
+
+    /// How many elements the map holds before it next reallocates.
+    pub fn capacity(&self) -> usize {
+        self.table.capacity()
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.table.memory_usage()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.len == 0
+    }
+
+    /// Removes every element, keeping the allocated buckets for reuse.
+    pub fn clear(&mut self) {
+        self.table.clear();
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            inner: self.table.buckets.iter(),
+            remaining: self.table.len,
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut {
+            inner: self.table.buckets.iter_mut(),
+            remaining: self.table.len,
+        }
+    }
+
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys { inner: self.iter() }
+    }
+
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values { inner: self.iter() }
+    }
+
+    pub fn values_mut(&mut self) -> ValuesMut<'_, K, V> {
+        ValuesMut {
+            inner: self.iter_mut(),
+        }
+    }
+
+    /// Removes every element, yielding them as owned pairs. The allocated
+    /// buckets are kept for reuse.
+    ///
+    /// The map is emptied up front, so one whose `Drain` is leaked is left
+    /// empty, if without its buckets.
+    pub fn drain(&mut self) -> Drain<'_, K, V> {
+        let buckets = mem::take(&mut self.table.buckets);
+        let remaining = mem::replace(&mut self.table.len, 0);
+        Drain {
+            table: &mut self.table,
+            buckets,
+            next: 0,
+            remaining,
+        }
+    }
+}
+
+impl<K, V, S> Default for HashMap<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher + Default,
+{
+    fn default() -> HashMap<K, V, S> {
+        HashMap::with_hasher(S::default())
+    }
+}
+
+impl<K, V, S> Clone for HashMap<K, V, S>
+where
+    K: Clone,
+    V: Clone,
+    S: Clone,
+{
+    fn clone(&self) -> HashMap<K, V, S> {
+        HashMap {
+            hash_builder: self.hash_builder.clone(),
+            table: self.table.clone(),
+        }
+    }
+}
+
+impl<K, V, S> fmt::Debug for HashMap<K, V, S>
+where
+    K: fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K, V, S> PartialEq for HashMap<K, V, S>
+where
+    K: Eq + Hash,
+    V: PartialEq,
+    S: BuildHasher,
+{
+    fn eq(&self, other: &HashMap<K, V, S>) -> bool {
+        self.len() == other.len() && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<K, V, S> Eq for HashMap<K, V, S>
+where
+    K: Eq + Hash,
+    V: Eq,
+    S: BuildHasher,
+{
+}
+
+impl<K, Q, V, S> Index<&Q> for HashMap<K, V, S>
+where
+    K: Eq + Hash + Borrow<Q>,
+    Q: Eq + Hash + ?Sized,
+    S: BuildHasher,
+{
+    type Output = V;
+
+    fn index(&self, k: &Q) -> &V {
+        self.get(k).expect("no entry found for key")
+    }
+}
+
+impl<K, V, S> FromIterator<(K, V)> for HashMap<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher + Default,
+{
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> HashMap<K, V, S> {
+        let mut map = HashMap::with_hasher(S::default());
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K, V, S> Extend<(K, V)> for HashMap<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    fn extend<T: IntoIterator<Item = (K, V)>>(&mut self, iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<'a, K, V, S> Extend<(&'a K, &'a V)> for HashMap<K, V, S>
+where
+    K: Eq + Hash + Copy,
+    V: Copy,
+    S: BuildHasher,
+{
+    fn extend<T: IntoIterator<Item = (&'a K, &'a V)>>(&mut self, iter: T) {
+        self.extend(iter.into_iter().map(|(&k, &v)| (k, v)));
+    }
+}
+
+impl<K, V, S> IntoIterator for HashMap<K, V, S> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(self) -> IntoIter<K, V> {
+        IntoIter {
+            remaining: self.table.len,
+            inner: self.table.buckets.into_iter(),
+        }
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a HashMap<K, V, S> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a mut HashMap<K, V, S> {
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> IterMut<'a, K, V> {
+        self.iter_mut()
+    }
 }
 
 // start snippet lib-hashmap-test-preamble
@@ -140,6 +408,8 @@ mod test {
 
     use super::*;
     use quickcheck::{Arbitrary, Gen, QuickCheck, TestResult};
+    use std::hash::{BuildHasherDefault, Hasher};
+    use std::mem;
     // end snippet lib-hashmap-test-preamble
 
     // start snippet lib-hashmap-test-gwyg
@@ -165,6 +435,24 @@ mod test {
     {
         Insert(T, u16),
         Lookup(T),
+        LookupMut(T, u16),
+        ContainsKey(T),
+        Remove(T),
+        RemoveEntry(T),
+        EntryOrInsert(T, u16),
+        EntryAndModify(T, u16),
+        EntryRemove(T),
+        Index(T),
+        Len,
+        Clear,
+        Iter,
+        IterMut(u16),
+        Keys,
+        Values,
+        Drain,
+        ExtendFrom(Vec<(T, u16)>),
+        Reserve(u8),
+        ShrinkToFit,
     }
     // end snippet lib-hashmap-test-action
 
@@ -179,36 +467,479 @@ mod test {
         {
             let i: usize = g.gen_range(0, 100);
             match i {
-                0...50 => Action::Insert(Arbitrary::arbitrary(g), u16::arbitrary(g)),
-                _ => Action::Lookup(Arbitrary::arbitrary(g)),
+                0..=35 => Action::Insert(Arbitrary::arbitrary(g), u16::arbitrary(g)),
+                36..=50 => Action::Lookup(Arbitrary::arbitrary(g)),
+                51..=55 => Action::LookupMut(Arbitrary::arbitrary(g), u16::arbitrary(g)),
+                56..=60 => Action::ContainsKey(Arbitrary::arbitrary(g)),
+                61..=68 => Action::Remove(Arbitrary::arbitrary(g)),
+                69..=70 => Action::RemoveEntry(Arbitrary::arbitrary(g)),
+                71..=75 => Action::EntryOrInsert(Arbitrary::arbitrary(g), u16::arbitrary(g)),
+                76..=79 => Action::EntryAndModify(Arbitrary::arbitrary(g), u16::arbitrary(g)),
+                80..=82 => Action::EntryRemove(Arbitrary::arbitrary(g)),
+                83..=85 => Action::Index(Arbitrary::arbitrary(g)),
+                86..=87 => Action::Len,
+                88 => Action::Clear,
+                89..=90 => Action::Iter,
+                91..=92 => Action::IterMut(u16::arbitrary(g)),
+                93..=94 => Action::Keys,
+                95..=96 => Action::Values,
+                97 => Action::Drain,
+                98 => Action::ExtendFrom(Arbitrary::arbitrary(g)),
+                _ => {
+                    if bool::arbitrary(g) {
+                        Action::Reserve(u8::arbitrary(g))
+                    } else {
+                        Action::ShrinkToFit
+                    }
+                }
             }
         }
     }
     // end snippet lib-hashmap-test-action-arbitrary
 
+    // start snippet lib-hashmap-test-action-model
+    fn sorted<I, T>(iter: I) -> Vec<T>
+    where
+        I: Iterator<Item = T>,
+        T: Ord,
+    {
+        let mut v: Vec<T> = iter.collect();
+        v.sort();
+        v
+    }
+
+    fn run_against_model<T, S>(
+        actions: Vec<Action<T>>,
+        mut system_under_test: HashMap<T, u16, S>,
+    ) -> TestResult
+    where
+        T: Arbitrary + Eq + Hash + Ord + ::std::fmt::Debug,
+        S: BuildHasher,
+    {
+        use std::collections::hash_map::Entry as ModelEntry;
+
+        let mut model = ::std::collections::HashMap::new();
+
+        for action in actions.into_iter() {
+            match action {
+                Action::Insert(k, v) => {
+                    assert_eq!(model.insert(k.clone(), v), system_under_test.insert(k, v));
+                }
+                Action::Lookup(k) => {
+                    assert_eq!(model.get(&k), system_under_test.get(&k));
+                }
+                Action::LookupMut(k, v) => {
+                    let expected = model.get_mut(&k).map(|old| {
+                        *old = v;
+                        *old
+                    });
+                    let actual = system_under_test.get_mut(&k).map(|old| {
+                        *old = v;
+                        *old
+                    });
+                    assert_eq!(expected, actual);
+                }
+                Action::ContainsKey(k) => {
+                    assert_eq!(model.contains_key(&k), system_under_test.contains_key(&k));
+                }
+                Action::Remove(k) => {
+                    assert_eq!(model.remove(&k), system_under_test.remove(&k));
+                }
+                Action::RemoveEntry(k) => {
+                    assert_eq!(model.remove_entry(&k), system_under_test.remove_entry(&k));
+                }
+                Action::EntryOrInsert(k, v) => {
+                    assert_eq!(
+                        *model.entry(k.clone()).or_insert(v),
+                        *system_under_test.entry(k).or_insert(v)
+                    );
+                }
+                Action::EntryAndModify(k, v) => {
+                    let expected = *model
+                        .entry(k.clone())
+                        .and_modify(|old| *old = old.wrapping_add(v))
+                        .or_default();
+                    let actual = *system_under_test
+                        .entry(k)
+                        .and_modify(|old| *old = old.wrapping_add(v))
+                        .or_default();
+                    assert_eq!(expected, actual);
+                }
+                Action::EntryRemove(k) => {
+                    let expected = match model.entry(k.clone()) {
+                        ModelEntry::Occupied(entry) => Some(entry.remove_entry()),
+                        ModelEntry::Vacant(_) => None,
+                    };
+                    let actual = match system_under_test.entry(k) {
+                        Entry::Occupied(entry) => Some(entry.remove_entry()),
+                        Entry::Vacant(_) => None,
+                    };
+                    assert_eq!(expected, actual);
+                }
+                Action::Index(k) => {
+                    if model.contains_key(&k) {
+                        assert_eq!(model[&k], system_under_test[&k]);
+                    }
+                }
+                Action::Len => {
+                    assert_eq!(model.len(), system_under_test.len());
+                    assert_eq!(model.is_empty(), system_under_test.is_empty());
+                }
+                Action::Clear => {
+                    model.clear();
+                    system_under_test.clear();
+                }
+                Action::Iter => {
+                    assert_eq!(model.len(), system_under_test.iter().len());
+                    assert_eq!(sorted(model.iter()), sorted(system_under_test.iter()));
+                }
+                Action::IterMut(v) => {
+                    for (_, old) in model.iter_mut() {
+                        *old = old.wrapping_add(v);
+                    }
+                    for (_, old) in system_under_test.iter_mut() {
+                        *old = old.wrapping_add(v);
+                    }
+                }
+                Action::Keys => {
+                    assert_eq!(sorted(model.keys()), sorted(system_under_test.keys()));
+                }
+                Action::Values => {
+                    assert_eq!(sorted(model.values()), sorted(system_under_test.values()));
+                }
+                Action::Drain => {
+                    assert_eq!(sorted(model.drain()), sorted(system_under_test.drain()));
+                }
+                Action::ExtendFrom(pairs) => {
+                    model.extend(pairs.clone());
+                    system_under_test.extend(pairs);
+                }
+                Action::Reserve(additional) => {
+                    let additional = additional as usize;
+                    model.reserve(additional);
+                    system_under_test.reserve(additional);
+                    let capacity = system_under_test.capacity();
+                    assert!(capacity >= system_under_test.len() + additional);
+                    for k in model.keys() {
+                        system_under_test.insert(k.clone(), model[k]);
+                    }
+                    assert_eq!(capacity, system_under_test.capacity());
+                }
+                Action::ShrinkToFit => {
+                    model.shrink_to_fit();
+                    system_under_test.shrink_to_fit();
+                }
+            }
+            assert_eq!(model.len(), system_under_test.len());
+            assert!(system_under_test.capacity() >= system_under_test.len());
+        }
+        let expected: Vec<(T, u16)> = sorted(model.into_iter());
+        let actual: Vec<(T, u16)> = sorted(system_under_test.into_iter());
+        assert_eq!(expected, actual);
+        TestResult::passed()
+    }
+    // end snippet lib-hashmap-test-action-model
+
     // start snippet lib-hashmap-test-action-sut
     #[test]
     fn sut_vs_genuine_article() {
         fn property<T>(actions: Vec<Action<T>>) -> TestResult
         where
-            T: Arbitrary + Eq + Hash + ::std::fmt::Debug,
+            T: Arbitrary + Eq + Hash + Ord + ::std::fmt::Debug,
         {
-            let mut model = ::std::collections::HashMap::new();
-            let mut system_under_test = HashMap::new();
+            run_against_model(actions, HashMap::new())
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<u8>>) -> TestResult);
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<u16>>) -> TestResult);
+    }
+    // end snippet lib-hashmap-test-action-sut
+
+    // start snippet lib-hashmap-test-total-collision
+    /// A hasher that maps every key to the same value, so every insertion
+    /// collides with every other and only key comparison tells them apart.
+    #[derive(Default)]
+    struct ConstantHasher;
+
+    impl Hasher for ConstantHasher {
+        fn finish(&self) -> u64 {
+            0
+        }
+
+        fn write(&mut self, _bytes: &[u8]) {}
+    }
+
+    #[test]
+    fn sut_vs_genuine_article_under_total_collision() {
+        fn property<T>(actions: Vec<Action<T>>) -> TestResult
+        where
+            T: Arbitrary + Eq + Hash + Ord + ::std::fmt::Debug,
+        {
+            let hash_builder = BuildHasherDefault::<ConstantHasher>::default();
+            run_against_model(actions, HashMap::with_hasher(hash_builder))
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<u8>>) -> TestResult);
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<String>>) -> TestResult);
+    }
+
+    #[test]
+    fn colliding_keys_do_not_alias() {
+        let hash_builder = BuildHasherDefault::<ConstantHasher>::default();
+        let mut system_under_test = HashMap::with_hasher(hash_builder);
+
+        assert_eq!(None, system_under_test.insert(1u8, 10u16));
+        assert_eq!(None, system_under_test.insert(2u8, 20u16));
+        assert_eq!(Some(&10), system_under_test.get(&1));
+        assert_eq!(Some(&20), system_under_test.get(&2));
+        assert_eq!(None, system_under_test.get(&3));
+
+        assert_eq!(Some(10), system_under_test.remove(&1));
+        assert_eq!(None, system_under_test.get(&1));
+        assert_eq!(Some(&20), system_under_test.get(&2));
+    }
+    // end snippet lib-hashmap-test-total-collision
 
-            for action in actions.into_iter() {
-                match action {
-                    Action::Insert(k, v) => {
-                        assert_eq!(model.insert(k.clone(), v), system_under_test.insert(k, v));
+    /// Runs the same model against a `PersistentHashMap`, each action
+    /// replacing the map with its updated version. Actions that mutate in
+    /// place have no persistent counterpart and are carried out as lookups
+    /// followed by inserts. Every version is kept alongside a copy of the
+    /// model taken with it, so an update that disturbs an older version is
+    /// caught.
+    fn run_persistent_against_model<T, S>(
+        actions: Vec<Action<T>>,
+        mut system_under_test: PersistentHashMap<T, u16, S>,
+    ) -> TestResult
+    where
+        T: Arbitrary + Eq + Hash + Ord + ::std::fmt::Debug,
+        S: BuildHasher + Default,
+    {
+        let mut model = ::std::collections::HashMap::new();
+        let mut versions = Vec::new();
+
+        for action in actions.into_iter() {
+            versions.push((system_under_test.clone(), model.clone()));
+            match action {
+                Action::Insert(k, v) => {
+                    assert_eq!(model.get(&k), system_under_test.get(&k));
+                    model.insert(k.clone(), v);
+                    system_under_test = system_under_test.insert(k, v);
+                }
+                Action::Lookup(k) | Action::Index(k) => {
+                    assert_eq!(model.get(&k), system_under_test.get(&k));
+                }
+                Action::LookupMut(k, v) => {
+                    if let Some(old) = model.get_mut(&k) {
+                        *old = v;
                     }
-                    Action::Lookup(k) => {
-                        assert_eq!(model.get(&k), system_under_test.get(&k));
+                    if system_under_test.contains_key(&k) {
+                        system_under_test = system_under_test.insert(k, v);
                     }
                 }
+                Action::ContainsKey(k) => {
+                    assert_eq!(model.contains_key(&k), system_under_test.contains_key(&k));
+                }
+                Action::Remove(k) | Action::RemoveEntry(k) | Action::EntryRemove(k) => {
+                    assert_eq!(model.get(&k), system_under_test.get(&k));
+                    model.remove(&k);
+                    system_under_test = system_under_test.remove(&k);
+                }
+                Action::EntryOrInsert(k, v) => {
+                    let expected = *model.entry(k.clone()).or_insert(v);
+                    if !system_under_test.contains_key(&k) {
+                        system_under_test = system_under_test.insert(k.clone(), v);
+                    }
+                    assert_eq!(Some(&expected), system_under_test.get(&k));
+                }
+                Action::EntryAndModify(k, v) => {
+                    let expected = *model
+                        .entry(k.clone())
+                        .and_modify(|old| *old = old.wrapping_add(v))
+                        .or_default();
+                    let actual = system_under_test.get(&k).map_or(0, |old| old.wrapping_add(v));
+                    system_under_test = system_under_test.insert(k, actual);
+                    assert_eq!(expected, actual);
+                }
+                Action::Len => {
+                    assert_eq!(model.len(), system_under_test.len());
+                    assert_eq!(model.is_empty(), system_under_test.is_empty());
+                }
+                Action::Clear => {
+                    model.clear();
+                    system_under_test = PersistentHashMap::with_hasher(S::default());
+                }
+                Action::Iter => {
+                    assert_eq!(model.len(), system_under_test.iter().len());
+                    assert_eq!(sorted(model.iter()), sorted(system_under_test.iter()));
+                }
+                Action::IterMut(v) => {
+                    for (_, old) in model.iter_mut() {
+                        *old = old.wrapping_add(v);
+                    }
+                    system_under_test = system_under_test
+                        .iter()
+                        .map(|(k, old)| (k.clone(), old.wrapping_add(v)))
+                        .collect();
+                }
+                Action::Keys => {
+                    assert_eq!(sorted(model.keys()), sorted(system_under_test.keys()));
+                }
+                Action::Values => {
+                    assert_eq!(sorted(model.values()), sorted(system_under_test.values()));
+                }
+                Action::Drain => {
+                    let actual = system_under_test.iter().map(|(k, v)| (k.clone(), *v));
+                    assert_eq!(sorted(model.drain()), sorted(actual));
+                    system_under_test = PersistentHashMap::with_hasher(S::default());
+                }
+                Action::ExtendFrom(pairs) => {
+                    model.extend(pairs.clone());
+                    system_under_test.extend(pairs);
+                }
+                Action::Reserve(_) | Action::ShrinkToFit => {}
             }
-            TestResult::passed()
+            assert_eq!(model.len(), system_under_test.len());
+        }
+        for (version, expected) in versions {
+            assert_eq!(expected.len(), version.len());
+            let actual = version.iter().map(|(k, v)| (k.clone(), *v));
+            assert_eq!(sorted(expected.into_iter()), sorted(actual));
+        }
+        TestResult::passed()
+    }
+
+    #[test]
+    fn persistent_vs_genuine_article() {
+        fn property<T>(actions: Vec<Action<T>>) -> TestResult
+        where
+            T: Arbitrary + Eq + Hash + Ord + ::std::fmt::Debug,
+        {
+            run_persistent_against_model(actions, PersistentHashMap::new())
         }
         QuickCheck::new().quickcheck(property as fn(Vec<Action<u8>>) -> TestResult);
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<u16>>) -> TestResult);
+    }
+
+    #[test]
+    fn persistent_vs_genuine_article_under_total_collision() {
+        fn property<T>(actions: Vec<Action<T>>) -> TestResult
+        where
+            T: Arbitrary + Eq + Hash + Ord + ::std::fmt::Debug,
+        {
+            let hash_builder = BuildHasherDefault::<ConstantHasher>::default();
+            run_persistent_against_model(actions, PersistentHashMap::with_hasher(hash_builder))
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<u8>>) -> TestResult);
+        QuickCheck::new().quickcheck(property as fn(Vec<Action<String>>) -> TestResult);
+    }
+
+    #[test]
+    fn clone_and_collect_are_equal() {
+        fn property(pairs: Vec<(u16, u16)>) -> TestResult {
+            let system_under_test: HashMap<u16, u16> = pairs.iter().cloned().collect();
+            let model: ::std::collections::HashMap<u16, u16> = pairs.into_iter().collect();
+
+            assert_eq!(system_under_test, system_under_test.clone());
+            assert_eq!(system_under_test, model.iter().map(|(&k, &v)| (k, v)).collect());
+            assert_eq!(model.len(), system_under_test.len());
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<(u16, u16)>) -> TestResult);
+    }
+
+    #[test]
+    fn debug_formats_as_map() {
+        let mut system_under_test = HashMap::new();
+        system_under_test.insert("key", 1);
+        assert_eq!("{\"key\": 1}", format!("{:?}", system_under_test));
+    }
+
+    #[test]
+    fn leaked_drain_leaves_map_empty() {
+        let mut system_under_test: HashMap<u16, u16> = (0..100).map(|i| (i, i)).collect();
+        {
+            let mut drain = system_under_test.drain();
+            assert!(drain.next().is_some());
+            mem::forget(drain);
+        }
+        assert!(system_under_test.is_empty());
+        assert_eq!(None, system_under_test.get(&1));
+        assert_eq!(0, system_under_test.iter().count());
+        for i in 0..100 {
+            system_under_test.insert(i, i);
+        }
+        assert_eq!(100, system_under_test.len());
+        assert_eq!(Some(&42), system_under_test.get(&42));
+    }
+
+    #[test]
+    fn dropped_drain_empties_map() {
+        let mut system_under_test: HashMap<u16, u16> = (0..100).map(|i| (i, i)).collect();
+        assert_eq!(1, system_under_test.drain().take(1).count());
+        assert!(system_under_test.is_empty());
+        assert_eq!(None, system_under_test.get(&1));
+        assert_eq!(0, system_under_test.iter().count());
+    }
+
+    #[test]
+    fn capacity_is_honoured() {
+        fn property(capacity: u16) -> TestResult {
+            let capacity = capacity as usize;
+            let mut system_under_test = HashMap::with_capacity(capacity);
+            let initial = system_under_test.capacity();
+            assert!(initial >= capacity);
+            for k in 0..capacity {
+                system_under_test.insert(k, k);
+            }
+            assert_eq!(initial, system_under_test.capacity());
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(u16) -> TestResult);
+    }
+
+    #[test]
+    fn shrink_to_fit_releases_memory() {
+        let mut system_under_test: HashMap<u64, u64> = (0..1_000).map(|i| (i, i)).collect();
+        let full = system_under_test.memory_usage();
+        for i in 10..1_000 {
+            system_under_test.remove(&i);
+        }
+        assert_eq!(full.buckets, system_under_test.memory_usage().buckets);
+        system_under_test.shrink_to_fit();
+        let shrunk = system_under_test.memory_usage();
+        assert!(shrunk.buckets < full.buckets);
+        assert!(system_under_test.capacity() >= 10);
+        for i in 0..10 {
+            assert_eq!(Some(&i), system_under_test.get(&i));
+        }
+        system_under_test.clear();
+        system_under_test.shrink_to_fit();
+        assert_eq!(0, system_under_test.capacity());
+        assert_eq!(0, system_under_test.memory_usage().buckets);
+    }
+
+    #[test]
+    fn memory_usage_accounts_for_every_byte() {
+        let mut system_under_test: HashMap<u32, u64> = HashMap::new();
+        assert_eq!(0, system_under_test.memory_usage().buckets);
+        for i in 0..100 {
+            system_under_test.insert(i, u64::from(i));
+        }
+        let usage = system_under_test.memory_usage();
+        // 100 elements at a load of at most 9/10 need 112 slots, rounded up
+        // to a power of two. A slot is a stored hash, key and value behind
+        // an `Option` tag.
+        let slots = 128;
+        let slot = mem::size_of::<Option<(u64, u32, u64)>>();
+        assert_eq!(slots * slot, usage.buckets);
+        assert_eq!(100 * 4, usage.keys);
+        assert_eq!(100 * 8, usage.values);
+        assert_eq!(100 * (slot - 4 - 8), usage.metadata);
+        assert_eq!((slots - 100) * slot, usage.unused());
+    }
+
+    #[test]
+    #[should_panic]
+    fn index_missing_key_panics() {
+        let system_under_test: HashMap<u16, u16> = HashMap::new();
+        let _ = system_under_test[&1];
     }
-    // end snippet lib-hashmap-test-action-sut
 }
@@ -0,0 +1,600 @@
+//! An immutable map whose updates return a new map, sharing all unchanged
+//! structure with the old one.
+//!
+//! The map is a hash array mapped trie. Each branch consumes five bits of a
+//! key's hash and holds up to 32 children, stored densely: a bitmap records
+//! which of the 32 are present and a child's position is the number of set
+//! bits below its own. Leaves hold the entries whose hashes are equal in
+//! full, almost always just one. Children are reference counted, so an
+//! update copies only the branches on the path to the changed leaf -- at
+//! most one per five bits of hash -- and every map, old or new, is an
+//! independent value that can be sent to other threads and read there
+//! without locks.
+use make_hash;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::iter::FromIterator;
+use std::sync::Arc;
+
+const BITS: u32 = 5;
+const MASK: u64 = (1 << BITS) - 1;
+
+enum Node<K, V> {
+    Branch {
+        bitmap: u32,
+        children: Vec<Arc<Node<K, V>>>,
+    },
+    Leaf {
+        hash: u64,
+        entries: Vec<(K, V)>,
+    },
+}
+
+/// Where among a branch's 32 slots `hash` falls at depth `shift`. Past the
+/// last whole chunk the remaining bits are zero-padded, and past the end of
+/// the hash every key falls into slot 0; keys that get that far have equal
+/// hashes and share a leaf long before.
+fn slot_of(hash: u64, shift: u32) -> u32 {
+    (hash.checked_shr(shift).unwrap_or(0) & MASK) as u32
+}
+
+/// Position in a branch's dense `children` of the child in `slot`.
+fn position(bitmap: u32, slot: u32) -> usize {
+    (bitmap & ((1 << slot) - 1)).count_ones() as usize
+}
+
+impl<K, V> Node<K, V> {
+    fn empty() -> Node<K, V> {
+        Node::Branch {
+            bitmap: 0,
+            children: Vec::new(),
+        }
+    }
+
+    fn get<Q>(&self, hash: u64, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        let mut node = self;
+        let mut shift = 0;
+        loop {
+            match *node {
+                Node::Branch {
+                    bitmap,
+                    ref children,
+                } => {
+                    let slot = slot_of(hash, shift);
+                    if bitmap & (1 << slot) == 0 {
+                        return None;
+                    }
+                    node = &children[position(bitmap, slot)];
+                    shift += BITS;
+                }
+                Node::Leaf {
+                    hash: leaf_hash,
+                    ref entries,
+                } => {
+                    if leaf_hash != hash {
+                        return None;
+                    }
+                    return entries
+                        .iter()
+                        .find(|entry| entry.0.borrow() == k)
+                        .map(|entry| &entry.1);
+                }
+            }
+        }
+    }
+}
+
+impl<K, V> Node<K, V>
+where
+    K: Eq + Clone,
+    V: Clone,
+{
+    /// Returns the node with `k` bound to `v`, and whether `k` is new.
+    fn insert(&self, shift: u32, hash: u64, k: K, v: V) -> (Node<K, V>, bool) {
+        match *self {
+            Node::Branch {
+                bitmap,
+                ref children,
+            } => {
+                let slot = slot_of(hash, shift);
+                let pos = position(bitmap, slot);
+                let mut children = children.clone();
+                if bitmap & (1 << slot) == 0 {
+                    let leaf = Node::Leaf {
+                        hash,
+                        entries: vec![(k, v)],
+                    };
+                    children.insert(pos, Arc::new(leaf));
+                    let branch = Node::Branch {
+                        bitmap: bitmap | (1 << slot),
+                        children,
+                    };
+                    return (branch, true);
+                }
+                let child = children[pos].clone();
+                let added = match *child {
+                    Node::Leaf { hash: leaf_hash, .. } if leaf_hash != hash => {
+                        // Split the leaf: push it down into a branch of its
+                        // own and insert there.
+                        let old_slot = slot_of(leaf_hash, shift + BITS);
+                        let below = Node::Branch {
+                            bitmap: 1 << old_slot,
+                            children: vec![child.clone()],
+                        };
+                        let (below, _) = below.insert(shift + BITS, hash, k, v);
+                        children[pos] = Arc::new(below);
+                        true
+                    }
+                    ref child => {
+                        let (child, added) = child.insert(shift + BITS, hash, k, v);
+                        children[pos] = Arc::new(child);
+                        added
+                    }
+                };
+                (Node::Branch { bitmap, children }, added)
+            }
+            Node::Leaf { ref entries, .. } => {
+                let mut entries = entries.clone();
+                let added = match entries.iter().position(|entry| entry.0 == k) {
+                    Some(idx) => {
+                        entries[idx].1 = v;
+                        false
+                    }
+                    None => {
+                        entries.push((k, v));
+                        true
+                    }
+                };
+                (Node::Leaf { hash, entries }, added)
+            }
+        }
+    }
+
+    /// Returns the node without `k`, `None` for a node left empty, or
+    /// `Err(())` if `k` was not there to remove. Branches left holding a
+    /// lone leaf give way to it, so a trie is the same shape however it was
+    /// built.
+    fn remove<Q>(&self, shift: u32, hash: u64, k: &Q) -> Result<Option<Arc<Node<K, V>>>, ()>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        match *self {
+            Node::Branch {
+                bitmap,
+                ref children,
+            } => {
+                let slot = slot_of(hash, shift);
+                if bitmap & (1 << slot) == 0 {
+                    return Err(());
+                }
+                let pos = position(bitmap, slot);
+                let removed = children[pos].remove(shift + BITS, hash, k)?;
+                let mut children = children.clone();
+                let mut bitmap = bitmap;
+                match removed {
+                    Some(child) => children[pos] = child,
+                    None => {
+                        children.remove(pos);
+                        bitmap &= !(1 << slot);
+                    }
+                }
+                if children.is_empty() {
+                    return Ok(None);
+                }
+                if children.len() == 1 && shift > 0 {
+                    if let Node::Leaf { .. } = *children[0] {
+                        return Ok(children.pop());
+                    }
+                }
+                Ok(Some(Arc::new(Node::Branch { bitmap, children })))
+            }
+            Node::Leaf {
+                hash: leaf_hash,
+                ref entries,
+            } => {
+                if leaf_hash != hash {
+                    return Err(());
+                }
+                let idx = entries
+                    .iter()
+                    .position(|entry| entry.0.borrow() == k)
+                    .ok_or(())?;
+                if entries.len() == 1 {
+                    return Ok(None);
+                }
+                let mut entries = entries.clone();
+                entries.remove(idx);
+                Ok(Some(Arc::new(Node::Leaf { hash, entries })))
+            }
+        }
+    }
+}
+
+pub struct PersistentHashMap<K, V, S = RandomState> {
+    hash_builder: Arc<S>,
+    root: Arc<Node<K, V>>,
+    len: usize,
+}
+
+impl<K, V> PersistentHashMap<K, V, RandomState>
+where
+    K: Eq + Hash,
+{
+    pub fn new() -> PersistentHashMap<K, V> {
+        PersistentHashMap::with_hasher(RandomState::new())
+    }
+}
+
+impl<K, V, S> PersistentHashMap<K, V, S> {
+    pub fn with_hasher(hash_builder: S) -> PersistentHashMap<K, V, S> {
+        PersistentHashMap {
+            hash_builder: Arc::new(hash_builder),
+            root: Arc::new(Node::empty()),
+            len: 0,
+        }
+    }
+
+    pub fn hasher(&self) -> &S {
+        &self.hash_builder
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            stack: vec![(&*self.root, 0)],
+            remaining: self.len,
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, v)| v)
+    }
+
+    /// Whether `self` and `other` are versions of one map that share their
+    /// whole structure, which makes them equal without comparing entries.
+    pub fn ptr_eq(&self, other: &PersistentHashMap<K, V, S>) -> bool {
+        Arc::ptr_eq(&self.root, &other.root)
+    }
+}
+
+impl<K, V, S> PersistentHashMap<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    pub fn get<Q>(&self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = make_hash(&*self.hash_builder, k);
+        self.root.get(hash, k)
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get(k).is_some()
+    }
+}
+
+impl<K, V, S> PersistentHashMap<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    S: BuildHasher,
+{
+    /// Returns a map with `k` bound to `v`, leaving `self` as it was.
+    pub fn insert(&self, k: K, v: V) -> PersistentHashMap<K, V, S> {
+        let hash = make_hash(&*self.hash_builder, &k);
+        let (root, added) = self.root.insert(0, hash, k, v);
+        PersistentHashMap {
+            hash_builder: self.hash_builder.clone(),
+            root: Arc::new(root),
+            len: if added { self.len + 1 } else { self.len },
+        }
+    }
+
+    /// Returns a map without `k`, leaving `self` as it was. Removing an
+    /// absent key returns a map sharing everything with `self`.
+    pub fn remove<Q>(&self, k: &Q) -> PersistentHashMap<K, V, S>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = make_hash(&*self.hash_builder, k);
+        match self.root.remove(0, hash, k) {
+            Err(()) => self.clone(),
+            Ok(root) => PersistentHashMap {
+                hash_builder: self.hash_builder.clone(),
+                root: root.unwrap_or_else(|| Arc::new(Node::empty())),
+                len: self.len - 1,
+            },
+        }
+    }
+}
+
+impl<K, V, S> Clone for PersistentHashMap<K, V, S> {
+    /// Clones in constant time: the clone shares everything with `self`.
+    fn clone(&self) -> PersistentHashMap<K, V, S> {
+        PersistentHashMap {
+            hash_builder: self.hash_builder.clone(),
+            root: self.root.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<K, V, S> Default for PersistentHashMap<K, V, S>
+where
+    S: Default,
+{
+    fn default() -> PersistentHashMap<K, V, S> {
+        PersistentHashMap::with_hasher(S::default())
+    }
+}
+
+impl<K, V, S> fmt::Debug for PersistentHashMap<K, V, S>
+where
+    K: fmt::Debug,
+    V: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K, V, S> PartialEq for PersistentHashMap<K, V, S>
+where
+    K: Eq + Hash,
+    V: PartialEq,
+    S: BuildHasher,
+{
+    fn eq(&self, other: &PersistentHashMap<K, V, S>) -> bool {
+        if self.ptr_eq(other) {
+            return true;
+        }
+        self.len() == other.len()
+            && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<K, V, S> Eq for PersistentHashMap<K, V, S>
+where
+    K: Eq + Hash,
+    V: Eq,
+    S: BuildHasher,
+{
+}
+
+impl<K, V, S> FromIterator<(K, V)> for PersistentHashMap<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    S: BuildHasher + Default,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> PersistentHashMap<K, V, S> {
+        let mut map = PersistentHashMap::default();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K, V, S> Extend<(K, V)> for PersistentHashMap<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    S: BuildHasher,
+{
+    /// Replaces `self` with a map holding the new entries too. Other
+    /// versions of the map are unaffected.
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (k, v) in iter {
+            *self = self.insert(k, v);
+        }
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a PersistentHashMap<K, V, S> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+/// Depth-first iterator over the entries of a `PersistentHashMap`, in no
+/// particular order.
+pub struct Iter<'a, K: 'a, V: 'a> {
+    /// Nodes being walked, each with the index of its next child or entry.
+    stack: Vec<(&'a Node<K, V>, usize)>,
+    remaining: usize,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            let (node, idx) = match self.stack.last_mut() {
+                Some(&mut (node, ref mut idx)) => {
+                    *idx += 1;
+                    (node, *idx - 1)
+                }
+                None => return None,
+            };
+            match *node {
+                Node::Branch { ref children, .. } => match children.get(idx) {
+                    Some(child) => self.stack.push((child, 0)),
+                    None => {
+                        self.stack.pop();
+                    }
+                },
+                Node::Leaf { ref entries, .. } => match entries.get(idx) {
+                    Some(entry) => {
+                        self.remaining -= 1;
+                        return Some((&entry.0, &entry.1));
+                    }
+                    None => {
+                        self.stack.pop();
+                    }
+                },
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use super::*;
+    use hashers::IdentityBuildHasher;
+    use quickcheck::{QuickCheck, TestResult};
+    use std::hash::{BuildHasherDefault, Hasher};
+    use std::thread;
+
+    /// Keys hash to themselves, so tests can place them in the trie.
+    type Placed = PersistentHashMap<u64, u64, IdentityBuildHasher>;
+
+    fn depth<K, V>(node: &Node<K, V>) -> usize {
+        match *node {
+            Node::Branch { ref children, .. } => {
+                1 + children.iter().map(|child| depth(child)).max().unwrap_or(0)
+            }
+            Node::Leaf { .. } => 0,
+        }
+    }
+
+    #[test]
+    fn old_versions_are_unchanged() {
+        fn property(pairs: Vec<(u16, u16)>, removals: Vec<u16>) -> TestResult {
+            let mut versions = vec![PersistentHashMap::new()];
+            let mut models = vec![::std::collections::HashMap::new()];
+            for &(k, v) in &pairs {
+                let next = versions.last().unwrap().insert(k, v);
+                let mut model = models.last().unwrap().clone();
+                model.insert(k, v);
+                versions.push(next);
+                models.push(model);
+            }
+            for k in &removals {
+                let next = versions.last().unwrap().remove(k);
+                let mut model = models.last().unwrap().clone();
+                model.remove(k);
+                versions.push(next);
+                models.push(model);
+            }
+            for (version, model) in versions.iter().zip(models.iter()) {
+                assert_eq!(model.len(), version.len());
+                for (k, v) in model {
+                    assert_eq!(Some(v), version.get(k));
+                }
+            }
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<(u16, u16)>, Vec<u16>) -> TestResult);
+    }
+
+    #[test]
+    fn updates_share_untouched_structure() {
+        let map: Placed = (0..1024).map(|k| (k, k)).collect();
+        let updated = map.insert(0, 1);
+        let children = |map: &Placed| match *map.root {
+            Node::Branch { ref children, .. } => children.clone(),
+            Node::Leaf { .. } => panic!("root is always a branch"),
+        };
+        let (old_children, new_children) = (children(&map), children(&updated));
+        assert_eq!(old_children.len(), new_children.len());
+        // Only the child on the path to key 0 is copied.
+        let shared = old_children
+            .iter()
+            .zip(new_children.iter())
+            .filter(|&(old, new)| Arc::ptr_eq(old, new))
+            .count();
+        assert_eq!(old_children.len() - 1, shared);
+        assert!(map.remove(&4096).ptr_eq(&map));
+    }
+
+    #[test]
+    fn removal_collapses_branches() {
+        // 0 and 1 << 40 agree in their low 40 bits, so they sit eight
+        // branches deep.
+        let map = Placed::default().insert(0, 0).insert(1 << 40, 1);
+        assert_eq!(9, depth(&map.root));
+        let map = map.remove(&(1 << 40));
+        assert_eq!(1, depth(&map.root));
+        assert_eq!(Some(&0), map.get(&0));
+        assert_eq!(1, depth(&map.remove(&0).root));
+    }
+
+    #[test]
+    fn full_hash_collisions_share_a_leaf() {
+        #[derive(Default)]
+        struct ConstantHasher;
+
+        impl Hasher for ConstantHasher {
+            fn finish(&self) -> u64 {
+                0
+            }
+
+            fn write(&mut self, _bytes: &[u8]) {}
+        }
+
+        let map: PersistentHashMap<u8, u8, BuildHasherDefault<ConstantHasher>> =
+            (0..10).map(|k| (k, k)).collect();
+        assert_eq!(10, map.len());
+        assert_eq!(1, depth(&map.root));
+        for k in 0..10 {
+            assert_eq!(Some(&k), map.get(&k));
+        }
+        let map = map.remove(&3);
+        assert_eq!(None, map.get(&3));
+        assert_eq!(9, map.iter().count());
+    }
+
+    #[test]
+    fn snapshots_are_readable_across_threads() {
+        let mut map = PersistentHashMap::new();
+        let mut readers = Vec::new();
+        for round in 0..8u32 {
+            for k in 0..100u32 {
+                map = map.insert(k, round);
+            }
+            let snapshot = map.clone();
+            readers.push(thread::spawn(move || {
+                (0..100u32).all(|k| snapshot.get(&k) == Some(&round))
+            }));
+        }
+        for reader in readers {
+            assert!(reader.join().unwrap());
+        }
+    }
+}
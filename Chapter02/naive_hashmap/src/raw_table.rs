@@ -0,0 +1,247 @@
+//! The Robin Hood open-addressing table underneath `HashMap`.
+//!
+//! `RawTable` knows nothing of hashing: callers hand it pre-computed hashes
+//! and it takes care of probing, displacement, growth and backward-shift
+//! deletion. Keeping the hasher out lets entries borrow the table alone.
+use std::borrow::Borrow;
+use std::{cmp, mem};
+
+/// The table grows once it would become more than MAX_LOAD_NUMERATOR /
+/// MAX_LOAD_DENOMINATOR full. Robin Hood probing keeps the variance of probe
+/// lengths low enough that a high load factor is tolerable.
+const MAX_LOAD_NUMERATOR: usize = 9;
+const MAX_LOAD_DENOMINATOR: usize = 10;
+const MIN_CAPACITY: usize = 8;
+
+#[derive(Clone)]
+pub(crate) struct Bucket<K, V> {
+    pub(crate) hash: u64,
+    pub(crate) key: K,
+    pub(crate) value: V,
+}
+
+#[derive(Clone)]
+pub(crate) struct RawTable<K, V> {
+    pub(crate) buckets: Vec<Option<Bucket<K, V>>>,
+    pub(crate) len: usize,
+}
+
+/// A breakdown of the memory a map holds directly, in bytes.
+///
+/// Only the bucket array is counted: memory keys and values own
+/// themselves, such as a `String`'s heap buffer, is not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The whole bucket array, occupied and empty slots alike.
+    pub buckets: usize,
+    /// Keys of the elements present.
+    pub keys: usize,
+    /// Values of the elements present.
+    pub values: usize,
+    /// The rest of the occupied slots: stored hashes, `Option` tags and
+    /// padding.
+    pub metadata: usize,
+}
+
+impl MemoryUsage {
+    /// Bytes in empty slots, allocated against future growth.
+    pub fn unused(&self) -> usize {
+        self.buckets - self.keys - self.values - self.metadata
+    }
+}
+
+/// The number of buckets needed to hold `capacity` elements without
+/// exceeding the maximum load factor.
+fn buckets_for(capacity: usize) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+    let min_buckets = (capacity * MAX_LOAD_DENOMINATOR).div_ceil(MAX_LOAD_NUMERATOR);
+    cmp::max(min_buckets.next_power_of_two(), MIN_CAPACITY)
+}
+
+impl<K, V> RawTable<K, V> {
+    pub(crate) fn new() -> RawTable<K, V> {
+        RawTable {
+            buckets: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn with_capacity(capacity: usize) -> RawTable<K, V> {
+        let mut table = RawTable::new();
+        table.resize(buckets_for(capacity));
+        table
+    }
+
+    /// How many elements fit before the table next grows.
+    pub(crate) fn capacity(&self) -> usize {
+        self.buckets.len() * MAX_LOAD_NUMERATOR / MAX_LOAD_DENOMINATOR
+    }
+
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        let needed = self.len.checked_add(additional).expect("capacity overflow");
+        if needed > self.capacity() {
+            self.resize(buckets_for(needed));
+        }
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        let buckets = buckets_for(self.len);
+        if buckets < self.buckets.len() {
+            self.resize(buckets);
+        }
+    }
+
+    pub(crate) fn memory_usage(&self) -> MemoryUsage {
+        let keys = self.len * mem::size_of::<K>();
+        let values = self.len * mem::size_of::<V>();
+        let slot = mem::size_of::<Option<Bucket<K, V>>>();
+        MemoryUsage {
+            buckets: self.buckets.capacity() * slot,
+            keys,
+            values,
+            metadata: self.len * slot - keys - values,
+        }
+    }
+
+    /// Bucket index a hash would occupy if there were no collisions.
+    fn ideal_index(&self, hash: u64) -> usize {
+        (hash as usize) & (self.buckets.len() - 1)
+    }
+
+    /// How far the bucket at `idx` sits from its ideal index.
+    fn probe_distance(&self, hash: u64, idx: usize) -> usize {
+        idx.wrapping_sub(self.ideal_index(hash)) & (self.buckets.len() - 1)
+    }
+
+    /// Returns the index of the bucket holding `k`, if any.
+    ///
+    /// The search stops early as soon as it meets a bucket that is closer to
+    /// its ideal index than we are to ours: under the Robin Hood invariant
+    /// our key would have displaced that bucket had it been inserted.
+    pub(crate) fn find<Q>(&self, hash: u64, k: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        if self.len == 0 {
+            return None;
+        }
+        let mut idx = self.ideal_index(hash);
+        let mut dist = 0;
+        loop {
+            match self.buckets[idx] {
+                None => return None,
+                Some(ref bucket) => {
+                    if self.probe_distance(bucket.hash, idx) < dist {
+                        return None;
+                    }
+                    if bucket.hash == hash && bucket.key.borrow() == k {
+                        return Some(idx);
+                    }
+                }
+            }
+            idx = (idx + 1) & (self.buckets.len() - 1);
+            dist += 1;
+        }
+    }
+
+    /// Inserts a key known not to be in the table, growing it first if
+    /// need be. Returns the index the new bucket landed at.
+    pub(crate) fn insert_new(&mut self, hash: u64, key: K, value: V) -> usize {
+        self.grow_if_needed();
+        let idx = self.insert_bucket(Bucket { hash, key, value });
+        self.len += 1;
+        idx
+    }
+
+    /// Places a bucket known not to be in the table, stealing the slot of
+    /// any richer resident -- one closer to its ideal index -- and carrying
+    /// the displaced bucket onward. Returns the index the original bucket
+    /// landed at; displaced residents never move it again.
+    fn insert_bucket(&mut self, mut bucket: Bucket<K, V>) -> usize {
+        let mut idx = self.ideal_index(bucket.hash);
+        let mut dist = 0;
+        let mut landed = None;
+        loop {
+            let resident_dist = match self.buckets[idx] {
+                None => {
+                    self.buckets[idx] = Some(bucket);
+                    return landed.unwrap_or(idx);
+                }
+                Some(ref resident) => self.probe_distance(resident.hash, idx),
+            };
+            if resident_dist < dist {
+                if let Some(ref mut resident) = self.buckets[idx] {
+                    mem::swap(resident, &mut bucket);
+                }
+                landed = landed.or(Some(idx));
+                dist = resident_dist;
+            }
+            idx = (idx + 1) & (self.buckets.len() - 1);
+            dist += 1;
+        }
+    }
+
+    /// Removes the bucket at `idx`, which must be occupied.
+    ///
+    /// Backward-shift deletion: every following displaced bucket is pulled
+    /// one slot towards its ideal index, so no tombstone is needed.
+    pub(crate) fn remove_at(&mut self, mut idx: usize) -> (K, V) {
+        let removed = self.buckets[idx].take().expect("remove_at on empty bucket");
+        self.len -= 1;
+
+        let mask = self.buckets.len() - 1;
+        loop {
+            let next = (idx + 1) & mask;
+            let displaced = match self.buckets[next] {
+                Some(ref bucket) => self.probe_distance(bucket.hash, next) > 0,
+                None => false,
+            };
+            if !displaced {
+                break;
+            }
+            self.buckets[idx] = self.buckets[next].take();
+            idx = next;
+        }
+        (removed.key, removed.value)
+    }
+
+    pub(crate) fn bucket(&self, idx: usize) -> &Bucket<K, V> {
+        self.buckets[idx].as_ref().expect("bucket index not occupied")
+    }
+
+    pub(crate) fn bucket_mut(&mut self, idx: usize) -> &mut Bucket<K, V> {
+        self.buckets[idx].as_mut().expect("bucket index not occupied")
+    }
+
+    pub(crate) fn clear(&mut self) {
+        for slot in &mut self.buckets {
+            *slot = None;
+        }
+        self.len = 0;
+    }
+
+    /// Grows the table if one more element would push it past the maximum
+    /// load factor.
+    fn grow_if_needed(&mut self) {
+        let capacity = self.buckets.len();
+        if (self.len + 1) * MAX_LOAD_DENOMINATOR > capacity * MAX_LOAD_NUMERATOR {
+            self.resize(cmp::max(capacity * 2, MIN_CAPACITY));
+        }
+    }
+
+    /// Rehashes every element into a fresh array of `capacity` buckets, a
+    /// power of two or zero. This is the table's only allocation.
+    fn resize(&mut self, capacity: usize) {
+        debug_assert!(capacity == 0 || capacity.is_power_of_two());
+        debug_assert!(self.len <= capacity);
+        let mut buckets = Vec::with_capacity(capacity);
+        buckets.resize_with(capacity, || None);
+        let old = mem::replace(&mut self.buckets, buckets);
+        for bucket in old.into_iter().flatten() {
+            self.insert_bucket(bucket);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! `Serialize` and `Deserialize` for the maps, behind the `serde` feature.
+//! Both serialize as plain maps, so any serde format sees them as it would a
+//! `std::collections::HashMap`.
+use direct_map::{DirectMap, SmallKey};
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use HashMap;
+
+/// Never pre-allocate for more than this many entries on the strength of a
+/// deserializer's size hint.
+const MAX_PREALLOCATE: usize = 1 << 16;
+
+impl<K, V, S> Serialize for HashMap<K, V, S>
+where
+    K: Serialize,
+    V: Serialize,
+{
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+impl<K, V> Serialize for DirectMap<K, V>
+where
+    K: SmallKey + Serialize,
+    V: Serialize,
+{
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+struct HashMapVisitor<K, V, S> {
+    marker: PhantomData<HashMap<K, V, S>>,
+}
+
+impl<'de, K, V, S> Visitor<'de> for HashMapVisitor<K, V, S>
+where
+    K: Deserialize<'de> + Eq + Hash,
+    V: Deserialize<'de>,
+    S: BuildHasher + Default,
+{
+    type Value = HashMap<K, V, S>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let capacity = access.size_hint().unwrap_or(0).min(MAX_PREALLOCATE);
+        let mut map = HashMap::with_capacity_and_hasher(capacity, S::default());
+        while let Some((k, v)) = access.next_entry()? {
+            map.insert(k, v);
+        }
+        Ok(map)
+    }
+}
+
+impl<'de, K, V, S> Deserialize<'de> for HashMap<K, V, S>
+where
+    K: Deserialize<'de> + Eq + Hash,
+    V: Deserialize<'de>,
+    S: BuildHasher + Default,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(HashMapVisitor {
+            marker: PhantomData,
+        })
+    }
+}
+
+struct DirectMapVisitor<K, V> {
+    marker: PhantomData<(K, V)>,
+}
+
+impl<'de, K, V> Visitor<'de> for DirectMapVisitor<K, V>
+where
+    K: Deserialize<'de> + SmallKey,
+    V: Deserialize<'de>,
+{
+    type Value = DirectMap<K, V>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let mut map = DirectMap::new();
+        while let Some((k, v)) = access.next_entry()? {
+            map.insert(k, v);
+        }
+        Ok(map)
+    }
+}
+
+impl<'de, K, V> Deserialize<'de> for DirectMap<K, V>
+where
+    K: Deserialize<'de> + SmallKey,
+    V: Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(DirectMapVisitor {
+            marker: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use super::*;
+    use quickcheck::{QuickCheck, TestResult};
+    use serde_json;
+    use HashMapU8;
+
+    #[test]
+    fn hash_map_round_trip() {
+        fn property(pairs: Vec<(String, u32)>) -> TestResult {
+            let map: HashMap<String, u32> = pairs.into_iter().collect();
+            let json = serde_json::to_string(&map).unwrap();
+            let loaded: HashMap<String, u32> = serde_json::from_str(&json).unwrap();
+            assert_eq!(map, loaded);
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<(String, u32)>) -> TestResult);
+    }
+
+    #[test]
+    fn direct_map_reads_like_std() {
+        let mut map = HashMapU8::new();
+        map.insert(7, "seven".to_string());
+        map.insert(1, "one".to_string());
+        let json = serde_json::to_string(&map).unwrap();
+        assert_eq!(r#"{"1":"one","7":"seven"}"#, json);
+
+        let std_map: ::std::collections::HashMap<u8, String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(2, std_map.len());
+        let loaded: HashMapU8<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(Some(&"seven".to_string()), loaded.get(&7));
+    }
+}
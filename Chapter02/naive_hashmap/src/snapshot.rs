@@ -0,0 +1,476 @@
+//! A compact, versioned binary snapshot format for the maps in this crate.
+//!
+//! A snapshot is laid out as
+//!
+//! ```text
+//! magic    4 bytes   b"NHMS"
+//! version  1 byte    currently 1
+//! count    varint    number of entries
+//! entries  count * (key, value), each as its `Persist` encoding
+//! checksum 8 bytes   FNV-1a of every preceding byte, little-endian
+//! ```
+//!
+//! Unsigned integers are LEB128 varints, signed integers zigzag-encoded
+//! varints, and strings and byte vectors a varint length followed by the
+//! bytes. Loading checks the magic, the version and the checksum, so a
+//! truncated or corrupted file is reported as such rather than producing a
+//! partial map.
+use direct_map::{DirectMap, SmallKey};
+use hashers::FnvHasher;
+use std::error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, Read, Write};
+use HashMap;
+
+const MAGIC: &[u8; 4] = b"NHMS";
+const VERSION: u8 = 1;
+/// Never pre-allocate for more than this many entries on the strength of
+/// the count in a snapshot, which may be corrupt.
+const MAX_PREALLOCATE: u64 = 1 << 16;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadChecksum { expected: u64, found: u64 },
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "snapshot i/o error: {}", e),
+            Error::BadMagic => write!(f, "not a snapshot: bad magic number"),
+            Error::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            Error::BadChecksum { expected, found } => write!(
+                f,
+                "snapshot checksum mismatch: expected {:016x}, found {:016x}",
+                expected, found
+            ),
+            Error::Corrupt(reason) => write!(f, "corrupt snapshot: {}", reason),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// A type with a snapshot encoding.
+pub trait Persist: Sized {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()>;
+
+    fn read_from<R: Read>(r: &mut R) -> Result<Self, Error>;
+}
+
+fn write_varint<W: Write>(w: &mut W, mut n: u64) -> io::Result<()> {
+    let mut buf = [0; 10];
+    let mut len = 0;
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    w.write_all(&buf[..len])
+}
+
+fn read_varint<R: Read>(r: &mut R) -> Result<u64, Error> {
+    let mut n: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        r.read_exact(&mut byte)?;
+        let bits = u64::from(byte[0] & 0x7f);
+        if shift == 63 && bits > 1 {
+            return Err(Error::Corrupt("varint overflows 64 bits"));
+        }
+        n |= bits << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(Error::Corrupt("varint overflows 64 bits"))
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> Result<Vec<u8>, Error> {
+    let len = read_varint(r)?;
+    let mut bytes = Vec::new();
+    // Read through `take` rather than trusting `len` for an allocation.
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(bytes)
+}
+
+impl Persist for u8 {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&[*self])
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> Result<u8, Error> {
+        let mut byte = [0];
+        r.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+}
+
+impl Persist for bool {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&[*self as u8])
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> Result<bool, Error> {
+        match u8::read_from(r)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::Corrupt("bool out of range")),
+        }
+    }
+}
+
+macro_rules! persist_unsigned {
+    ($($t:ty),*) => {
+        $(
+            impl Persist for $t {
+                fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+                    write_varint(w, *self as u64)
+                }
+
+                fn read_from<R: Read>(r: &mut R) -> Result<$t, Error> {
+                    let n = read_varint(r)?;
+                    if n > <$t>::MAX as u64 {
+                        return Err(Error::Corrupt(concat!(stringify!($t), " out of range")));
+                    }
+                    Ok(n as $t)
+                }
+            }
+        )*
+    };
+}
+
+persist_unsigned!(u16, u32, u64, usize);
+
+macro_rules! persist_signed {
+    ($($t:ty),*) => {
+        $(
+            impl Persist for $t {
+                fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+                    let n = *self as i64;
+                    write_varint(w, ((n << 1) ^ (n >> 63)) as u64)
+                }
+
+                fn read_from<R: Read>(r: &mut R) -> Result<$t, Error> {
+                    let n = read_varint(r)?;
+                    let n = ((n >> 1) as i64) ^ -((n & 1) as i64);
+                    if n < <$t>::MIN as i64 || n > <$t>::MAX as i64 {
+                        return Err(Error::Corrupt(concat!(stringify!($t), " out of range")));
+                    }
+                    Ok(n as $t)
+                }
+            }
+        )*
+    };
+}
+
+persist_signed!(i8, i16, i32, i64);
+
+impl Persist for Vec<u8> {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_varint(w, self.len() as u64)?;
+        w.write_all(self)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> Result<Vec<u8>, Error> {
+        read_bytes(r)
+    }
+}
+
+impl Persist for String {
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_varint(w, self.len() as u64)?;
+        w.write_all(self.as_bytes())
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> Result<String, Error> {
+        String::from_utf8(read_bytes(r)?).map_err(|_| Error::Corrupt("string is not UTF-8"))
+    }
+}
+
+/// Passes bytes through to `inner`, checksumming them on the way.
+struct ChecksumWriter<W> {
+    inner: W,
+    hasher: FnvHasher,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.write(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Passes bytes through from `inner`, checksumming them on the way.
+struct ChecksumReader<R> {
+    inner: R,
+    hasher: FnvHasher,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.write(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Writes a snapshot of `len` entries, the entries themselves written by
+/// `write_entries`.
+fn save_entries<W, F>(w: W, len: usize, write_entries: F) -> Result<(), Error>
+where
+    W: Write,
+    F: FnOnce(&mut ChecksumWriter<W>) -> io::Result<()>,
+{
+    let mut w = ChecksumWriter {
+        inner: w,
+        hasher: FnvHasher::default(),
+    };
+    w.write_all(MAGIC)?;
+    w.write_all(&[VERSION])?;
+    write_varint(&mut w, len as u64)?;
+    write_entries(&mut w)?;
+    let checksum = w.hasher.finish();
+    w.inner.write_all(&checksum.to_le_bytes())?;
+    w.inner.flush()?;
+    Ok(())
+}
+
+fn load_entries<R, K, V, F>(r: R, mut insert: F) -> Result<(), Error>
+where
+    R: Read,
+    K: Persist,
+    V: Persist,
+    F: FnMut(u64, K, V),
+{
+    let mut r = ChecksumReader {
+        inner: r,
+        hasher: FnvHasher::default(),
+    };
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::BadMagic);
+    }
+    match u8::read_from(&mut r)? {
+        VERSION => {}
+        version => return Err(Error::UnsupportedVersion(version)),
+    }
+    let count = read_varint(&mut r)?;
+    for _ in 0..count {
+        let k = K::read_from(&mut r)?;
+        let v = V::read_from(&mut r)?;
+        insert(count, k, v);
+    }
+    let expected = r.hasher.finish();
+    let mut found = [0; 8];
+    r.inner.read_exact(&mut found)?;
+    let found = u64::from_le_bytes(found);
+    if expected != found {
+        return Err(Error::BadChecksum { expected, found });
+    }
+    Ok(())
+}
+
+impl<K, V, S> HashMap<K, V, S>
+where
+    K: Eq + Hash + Persist,
+    V: Persist,
+    S: BuildHasher,
+{
+    /// Writes the map to `w` as a snapshot. The hasher is not saved.
+    pub fn save_to<W: Write>(&self, w: W) -> Result<(), Error> {
+        save_entries(w, self.len(), |w| {
+            for (k, v) in self.iter() {
+                k.write_to(w)?;
+                v.write_to(w)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Reads a map from a snapshot written by `save_to`, building it with a
+    /// default hasher.
+    pub fn load_from<R: Read>(r: R) -> Result<HashMap<K, V, S>, Error>
+    where
+        S: Default,
+    {
+        let mut map = HashMap::with_hasher(S::default());
+        let mut reserved = false;
+        load_entries(r, |count, k, v| {
+            if !reserved {
+                map.reserve(count.min(MAX_PREALLOCATE) as usize);
+                reserved = true;
+            }
+            map.insert(k, v);
+        })?;
+        Ok(map)
+    }
+}
+
+impl<K, V> DirectMap<K, V>
+where
+    K: SmallKey + Persist,
+    V: Persist,
+{
+    /// Writes the map to `w` as a snapshot, readable as a `HashMap` too.
+    pub fn save_to<W: Write>(&self, w: W) -> Result<(), Error> {
+        save_entries(w, self.len(), |w| {
+            for (k, v) in self.iter() {
+                k.write_to(w)?;
+                v.write_to(w)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Reads a map from a snapshot written by `save_to`, of this map or of a
+    /// `HashMap` with the same key and value types.
+    pub fn load_from<R: Read>(r: R) -> Result<DirectMap<K, V>, Error> {
+        let mut map = DirectMap::new();
+        load_entries(r, |_, k, v| {
+            map.insert(k, v);
+        })?;
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use super::*;
+    use quickcheck::{QuickCheck, TestResult};
+    use HashMapU8;
+
+    #[test]
+    fn round_trip() {
+        fn property(pairs: Vec<(String, i64)>) -> TestResult {
+            let map: HashMap<String, i64> = pairs.into_iter().collect();
+            let mut bytes = Vec::new();
+            map.save_to(&mut bytes).unwrap();
+            let loaded: HashMap<String, i64> = HashMap::load_from(&bytes[..]).unwrap();
+            assert_eq!(map, loaded);
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<(String, i64)>) -> TestResult);
+    }
+
+    #[test]
+    fn round_trip_direct_map() {
+        fn property(pairs: Vec<(u8, u32)>) -> TestResult {
+            let mut map = HashMapU8::new();
+            for (k, v) in pairs {
+                map.insert(k, v);
+            }
+            let mut bytes = Vec::new();
+            map.save_to(&mut bytes).unwrap();
+            let loaded: HashMapU8<u32> = HashMapU8::load_from(&bytes[..]).unwrap();
+            assert_eq!(
+                map.iter().collect::<Vec<_>>(),
+                loaded.iter().collect::<Vec<_>>()
+            );
+            // The formats are one and the same.
+            let as_hash_map: HashMap<u8, u32> = HashMap::load_from(&bytes[..]).unwrap();
+            assert_eq!(map.len(), as_hash_map.len());
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<(u8, u32)>) -> TestResult);
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        fn property(pairs: Vec<(u16, String)>, idx: usize, flip: u8) -> TestResult {
+            if flip == 0 {
+                return TestResult::discard();
+            }
+            let map: HashMap<u16, String> = pairs.into_iter().collect();
+            let mut bytes = Vec::new();
+            map.save_to(&mut bytes).unwrap();
+            let idx = idx % bytes.len();
+            bytes[idx] ^= flip;
+            let loaded: Result<HashMap<u16, String>, Error> = HashMap::load_from(&bytes[..]);
+            TestResult::from_bool(loaded.is_err())
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<(u16, String)>, usize, u8) -> TestResult);
+    }
+
+    #[test]
+    fn truncation_is_detected() {
+        let map: HashMap<u32, u32> = (0..100).map(|i| (i, i * i)).collect();
+        let mut bytes = Vec::new();
+        map.save_to(&mut bytes).unwrap();
+        for len in 0..bytes.len() {
+            let loaded: Result<HashMap<u32, u32>, Error> = HashMap::load_from(&bytes[..len]);
+            assert!(loaded.is_err(), "truncated to {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn header_is_checked() {
+        let map: HashMap<u32, u32> = HashMap::new();
+        let mut bytes = Vec::new();
+        map.save_to(&mut bytes).unwrap();
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        match HashMap::<u32, u32>::load_from(&bad_magic[..]) {
+            Err(Error::BadMagic) => {}
+            other => panic!("expected BadMagic, got {:?}", other.map(|m| m.len())),
+        }
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = 2;
+        match HashMap::<u32, u32>::load_from(&bad_version[..]) {
+            Err(Error::UnsupportedVersion(2)) => {}
+            other => panic!("expected UnsupportedVersion, got {:?}", other.map(|m| m.len())),
+        }
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        fn property(n: u64) -> TestResult {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, n).unwrap();
+            assert_eq!(n, read_varint(&mut &bytes[..]).unwrap());
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(u64) -> TestResult);
+        for n in &[0, 127, 128, u64::MAX] {
+            property(*n);
+        }
+        assert!(read_varint(&mut &[0xff; 11][..]).is_err());
+    }
+}
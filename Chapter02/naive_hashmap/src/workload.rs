@@ -0,0 +1,646 @@
+//! Synthetic workloads for benchmarking the maps in this crate.
+//!
+//! A `Spec` describes a workload -- how many operations, the mix of lookups,
+//! inserts and removes, how keys are skewed and how wide they are -- and
+//! generates a `Trace` from it, deterministically for a given seed. Traces
+//! are saved as lines of the interpreters' command language under a header
+//! naming the key size:
+//!
+//! ```text
+//! # naive_hashmap trace v1 key-size=u16
+//! INSERT 4711 90210
+//! LOOKUP 4711
+//! REMOVE 12
+//! ```
+//!
+//! so one can be replayed by `workload replay` against every map at once, or
+//! piped into `naive_interpreter` to look at the map's responses.
+use command::{self, Command};
+use concurrent::ConcurrentHashMap;
+use direct_map::{DirectMap, SmallKey};
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::collections;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use HashMap;
+
+const HEADER: &str = "# naive_hashmap trace v1 key-size=";
+/// The seed the book's benchmarks have always used.
+pub const DEFAULT_SEED: [u32; 4] = [1981, 1986, 2003, 2011];
+/// Zipfian ranks are drawn from a table of cumulative weights, one per
+/// distinct key, so their number is bounded.
+pub const MAX_ZIPF_KEYS: u64 = 1 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeySize {
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl KeySize {
+    pub fn bits(self) -> u32 {
+        match self {
+            KeySize::U8 => 8,
+            KeySize::U16 => 16,
+            KeySize::U32 => 32,
+            KeySize::U64 => 64,
+        }
+    }
+
+    /// The largest key of this size.
+    pub fn max(self) -> u64 {
+        u64::MAX >> (64 - self.bits())
+    }
+}
+
+impl fmt::Display for KeySize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "u{}", self.bits())
+    }
+}
+
+impl FromStr for KeySize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<KeySize, String> {
+        match s {
+            "u8" => Ok(KeySize::U8),
+            "u16" => Ok(KeySize::U16),
+            "u32" => Ok(KeySize::U32),
+            "u64" => Ok(KeySize::U64),
+            _ => Err(format!("unknown key size {}, expected u8|u16|u32|u64", s)),
+        }
+    }
+}
+
+/// How often each distinct key is picked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Skew {
+    Uniform,
+    /// The key of rank `r` is picked with probability proportional to
+    /// `1 / r^s` for exponent `s`. Around 1 is typical of real workloads.
+    Zipf(f64),
+}
+
+impl fmt::Display for Skew {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Skew::Uniform => write!(f, "uniform"),
+            Skew::Zipf(s) => write!(f, "zipf:{}", s),
+        }
+    }
+}
+
+impl FromStr for Skew {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Skew, String> {
+        if s == "uniform" {
+            return Ok(Skew::Uniform);
+        }
+        let exponent = if s == "zipf" {
+            Some(1.0)
+        } else {
+            s.strip_prefix("zipf:").and_then(|e| e.parse::<f64>().ok())
+        };
+        match exponent {
+            Some(e) if e.is_finite() && e >= 0.0 => Ok(Skew::Zipf(e)),
+            _ => Err(format!("bad skew {}, expected uniform|zipf|zipf:<exponent>", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Insert(u64, u32),
+    Lookup(u64),
+    Remove(u64),
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Op::Insert(k, v) => write!(f, "INSERT {} {}", k, v),
+            Op::Lookup(k) => write!(f, "LOOKUP {}", k),
+            Op::Remove(k) => write!(f, "REMOVE {}", k),
+        }
+    }
+}
+
+/// A workload description. The mix is given as relative weights, so
+/// `lookups: 1, inserts: 1, removes: 0` is the book's 50/50 split.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spec {
+    pub ops: usize,
+    pub lookups: u32,
+    pub inserts: u32,
+    pub removes: u32,
+    pub skew: Skew,
+    pub key_size: KeySize,
+    /// Number of distinct keys drawn from, at most one more than
+    /// `key_size.max()`.
+    pub keys: u64,
+    pub seed: [u32; 4],
+}
+
+impl Default for Spec {
+    fn default() -> Spec {
+        Spec {
+            ops: 100_000,
+            lookups: 1,
+            inserts: 1,
+            removes: 0,
+            skew: Skew::Uniform,
+            key_size: KeySize::U8,
+            keys: 256,
+            seed: DEFAULT_SEED,
+        }
+    }
+}
+
+impl Spec {
+    pub fn validate(&self) -> Result<(), String> {
+        let weight =
+            u64::from(self.lookups) + u64::from(self.inserts) + u64::from(self.removes);
+        if weight == 0 {
+            return Err("operation mix has no weight".to_string());
+        }
+        if weight > u64::from(u32::MAX) {
+            return Err(format!("operation mix weighs more than {}", u32::MAX));
+        }
+        if self.keys == 0 {
+            return Err("need at least one key".to_string());
+        }
+        if self.keys - 1 > self.key_size.max() {
+            return Err(format!("{} keys do not fit in {}", self.keys, self.key_size));
+        }
+        if let Skew::Zipf(_) = self.skew {
+            if self.keys > MAX_ZIPF_KEYS {
+                return Err(format!("zipf skew supports at most {} keys", MAX_ZIPF_KEYS));
+            }
+        }
+        if self.seed == [0; 4] {
+            return Err("seed must not be all zeroes".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn generate(&self) -> Result<Trace, String> {
+        self.validate()?;
+        let mut rng: XorShiftRng = SeedableRng::from_seed(self.seed);
+        let ranks = Ranks::new(self.skew, self.keys);
+        let total = self.lookups + self.inserts + self.removes;
+        let mut ops = Vec::with_capacity(self.ops);
+        for _ in 0..self.ops {
+            let key = self.key_of(ranks.sample(&mut rng));
+            let pick = rng.gen_range(0, total);
+            let op = if pick < self.lookups {
+                Op::Lookup(key)
+            } else if pick < self.lookups + self.inserts {
+                Op::Insert(key, rng.gen::<u32>())
+            } else {
+                Op::Remove(key)
+            };
+            ops.push(op);
+        }
+        Ok(Trace {
+            key_size: self.key_size,
+            ops,
+        })
+    }
+
+    /// Scatters ranks over the key space, so the hottest keys are not also
+    /// the smallest. Multiplying by an odd constant is a bijection modulo
+    /// any power of two, so distinct ranks stay distinct keys.
+    fn key_of(&self, rank: u64) -> u64 {
+        rank.wrapping_mul(0x9e37_79b9_7f4a_7c15) & self.key_size.max()
+    }
+}
+
+/// Draws key ranks in `0..keys`, rank 0 the most popular under Zipf.
+enum Ranks {
+    Uniform(u64),
+    Zipf(Vec<f64>),
+}
+
+impl Ranks {
+    fn new(skew: Skew, keys: u64) -> Ranks {
+        match skew {
+            Skew::Uniform => Ranks::Uniform(keys),
+            Skew::Zipf(s) => {
+                let mut cumulative = Vec::with_capacity(keys as usize);
+                let mut sum = 0.0;
+                for rank in 1..=keys {
+                    sum += 1.0 / (rank as f64).powf(s);
+                    cumulative.push(sum);
+                }
+                Ranks::Zipf(cumulative)
+            }
+        }
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+        match *self {
+            Ranks::Uniform(keys) => rng.gen_range(0, keys),
+            Ranks::Zipf(ref cumulative) => {
+                let target = rng.gen::<f64>() * cumulative[cumulative.len() - 1];
+                let rank = match cumulative.binary_search_by(|w| w.partial_cmp(&target).unwrap()) {
+                    Ok(idx) | Err(idx) => idx,
+                };
+                rank.min(cumulative.len() - 1) as u64
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    pub key_size: KeySize,
+    pub ops: Vec<Op>,
+}
+
+impl Trace {
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "{}{}", HEADER, self.key_size)?;
+        for op in &self.ops {
+            writeln!(w, "{}", op)?;
+        }
+        w.flush()
+    }
+
+    pub fn read_from<R: BufRead>(r: R) -> Result<Trace, String> {
+        let mut lines = r.lines();
+        let header = match lines.next() {
+            Some(line) => line.map_err(|e| e.to_string())?,
+            None => return Err("empty trace".to_string()),
+        };
+        let key_size: KeySize = match header.strip_prefix(HEADER) {
+            Some(key_size) => key_size.trim().parse()?,
+            None => return Err("not a trace: missing header".to_string()),
+        };
+
+        let key = |lineno: usize, s: &str| -> Result<u64, String> {
+            match s.parse::<u64>() {
+                Ok(k) if k <= key_size.max() => Ok(k),
+                _ => Err(format!("{}:key {} is not a {}", lineno, command::quote(s), key_size)),
+            }
+        };
+        let mut ops = Vec::new();
+        for (idx, line) in lines.enumerate() {
+            let lineno = idx + 2;
+            let line = line.map_err(|e| e.to_string())?;
+            let op = match command::parse_line(&line).map_err(|e| format!("{}:{}", lineno, e))? {
+                None => continue,
+                Some(Command::Insert(k, v)) => {
+                    let v = v
+                        .parse::<u32>()
+                        .map_err(|_| format!("{}:value {} is not a u32", lineno, command::quote(&v)))?;
+                    Op::Insert(key(lineno, &k)?, v)
+                }
+                Some(Command::Lookup(k)) => Op::Lookup(key(lineno, &k)?),
+                Some(Command::Remove(k)) => Op::Remove(key(lineno, &k)?),
+                Some(other) => {
+                    return Err(format!("{}:{:?} has no place in a trace", lineno, other))
+                }
+            };
+            ops.push(op);
+        }
+        Ok(Trace { key_size, ops })
+    }
+}
+
+/// Integer keys a trace's `u64` keys narrow to. A trace only holds keys
+/// that fit its key size, so the narrowing loses nothing.
+pub trait Key: Copy + Eq + Hash {
+    fn from_u64(n: u64) -> Self;
+}
+
+macro_rules! key {
+    ($($t:ty),*) => {
+        $(
+            impl Key for $t {
+                #[inline]
+                fn from_u64(n: u64) -> $t {
+                    n as $t
+                }
+            }
+        )*
+    };
+}
+
+key!(u8, u16, u32, u64);
+
+/// A map a trace can be replayed against.
+pub trait Replay {
+    /// Carries out `op`, returning whether its key was present.
+    fn apply(&mut self, op: Op) -> bool;
+}
+
+impl<K, S> Replay for HashMap<K, u32, S>
+where
+    K: Key,
+    S: BuildHasher,
+{
+    fn apply(&mut self, op: Op) -> bool {
+        match op {
+            Op::Insert(k, v) => self.insert(K::from_u64(k), v).is_some(),
+            Op::Lookup(k) => self.get(&K::from_u64(k)).is_some(),
+            Op::Remove(k) => self.remove(&K::from_u64(k)).is_some(),
+        }
+    }
+}
+
+impl<K> Replay for DirectMap<K, u32>
+where
+    K: Key + SmallKey,
+{
+    fn apply(&mut self, op: Op) -> bool {
+        match op {
+            Op::Insert(k, v) => self.insert(K::from_u64(k), v).is_some(),
+            Op::Lookup(k) => self.get(&K::from_u64(k)).is_some(),
+            Op::Remove(k) => self.remove(&K::from_u64(k)).is_some(),
+        }
+    }
+}
+
+impl<K, S> Replay for ConcurrentHashMap<K, u32, S>
+where
+    K: Key,
+    S: BuildHasher,
+{
+    fn apply(&mut self, op: Op) -> bool {
+        match op {
+            Op::Insert(k, v) => self.insert(K::from_u64(k), v).is_some(),
+            Op::Lookup(k) => self.get(&K::from_u64(k)).is_some(),
+            Op::Remove(k) => self.remove(&K::from_u64(k)).is_some(),
+        }
+    }
+}
+
+impl<K, S> Replay for collections::HashMap<K, u32, S>
+where
+    K: Key,
+    S: BuildHasher,
+{
+    fn apply(&mut self, op: Op) -> bool {
+        match op {
+            Op::Insert(k, v) => self.insert(K::from_u64(k), v).is_some(),
+            Op::Lookup(k) => self.get(&K::from_u64(k)).is_some(),
+            Op::Remove(k) => self.remove(&K::from_u64(k)).is_some(),
+        }
+    }
+}
+
+/// The outcome of one replay.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub elapsed: Duration,
+    /// How many operations found their key present. Every map replaying the
+    /// same trace must agree on this.
+    pub hits: usize,
+    /// Per-operation latencies in nanoseconds, sorted.
+    latencies: Vec<u64>,
+}
+
+impl Report {
+    pub fn ops(&self) -> usize {
+        self.latencies.len()
+    }
+
+    /// Operations per second over the whole replay.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 + f64::from(self.elapsed.subsec_nanos()) / 1e9;
+        if secs == 0.0 {
+            0.0
+        } else {
+            self.ops() as f64 / secs
+        }
+    }
+
+    /// The latency in nanoseconds at quantile `q` in `[0, 1]`, by the
+    /// nearest-rank method. Zero for an empty replay.
+    pub fn percentile(&self, q: f64) -> u64 {
+        if self.latencies.is_empty() {
+            return 0;
+        }
+        let rank = (q * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.max(1).min(self.latencies.len()) - 1]
+    }
+}
+
+/// Replays `ops` against `map`, timing every operation. Each timing includes
+/// the cost of reading the clock, a few tens of nanoseconds, which puts a
+/// floor under the lower percentiles; throughput is measured over the whole
+/// run and so is less affected.
+pub fn replay<M: Replay>(map: &mut M, ops: &[Op]) -> Report {
+    let mut latencies = Vec::with_capacity(ops.len());
+    let mut hits = 0;
+    let start = Instant::now();
+    for op in ops {
+        let before = Instant::now();
+        if map.apply(*op) {
+            hits += 1;
+        }
+        let took = before.elapsed();
+        latencies.push(took.as_secs() * 1_000_000_000 + u64::from(took.subsec_nanos()));
+    }
+    let elapsed = start.elapsed();
+    latencies.sort_unstable();
+    Report {
+        elapsed,
+        hits,
+        latencies,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+
+    use super::*;
+    use quickcheck::{Arbitrary, Gen, QuickCheck, TestResult};
+
+    impl Arbitrary for KeySize {
+        fn arbitrary<G: Gen>(g: &mut G) -> KeySize {
+            *g.choose(&[KeySize::U8, KeySize::U16, KeySize::U32, KeySize::U64])
+                .unwrap()
+        }
+    }
+
+    fn spec(ops: u16, mix: (u8, u8, u8), key_size: KeySize, keys: u64, zipf: bool) -> Spec {
+        Spec {
+            ops: ops as usize,
+            lookups: u32::from(mix.0),
+            inserts: u32::from(mix.1),
+            removes: u32::from(mix.2),
+            skew: if zipf { Skew::Zipf(1.0) } else { Skew::Uniform },
+            key_size,
+            keys: keys % 1024 + 1,
+            seed: DEFAULT_SEED,
+        }
+    }
+
+    #[test]
+    fn generation_is_deterministic() {
+        let spec = Spec::default();
+        assert_eq!(spec.generate(), spec.generate());
+        let reseeded = Spec {
+            seed: [1, 2, 3, 4],
+            ..Spec::default()
+        };
+        assert!(spec.generate() != reseeded.generate());
+    }
+
+    #[test]
+    fn mix_is_honoured() {
+        let spec = Spec {
+            ops: 100_000,
+            lookups: 6,
+            inserts: 3,
+            removes: 1,
+            ..Spec::default()
+        };
+        let trace = spec.generate().unwrap();
+        let count = |f: fn(&Op) -> bool| trace.ops.iter().filter(|op| f(op)).count();
+        let lookups = count(|op| matches!(*op, Op::Lookup(_)));
+        let inserts = count(|op| matches!(*op, Op::Insert(..)));
+        let removes = count(|op| matches!(*op, Op::Remove(_)));
+        assert!((59_000..61_000).contains(&lookups), "{} lookups", lookups);
+        assert!((29_000..31_000).contains(&inserts), "{} inserts", inserts);
+        assert!((9_000..11_000).contains(&removes), "{} removes", removes);
+    }
+
+    #[test]
+    fn mix_weight_fits_a_draw() {
+        let spec = Spec {
+            lookups: u32::MAX,
+            inserts: 1,
+            ..Spec::default()
+        };
+        assert!(spec.generate().is_err());
+        let spec = Spec {
+            lookups: u32::MAX - 1,
+            inserts: 1,
+            ops: 10,
+            ..Spec::default()
+        };
+        assert!(spec.generate().is_ok());
+    }
+
+    #[test]
+    fn zipf_favours_the_hottest_key() {
+        let spec = Spec {
+            ops: 100_000,
+            skew: Skew::Zipf(1.0),
+            key_size: KeySize::U32,
+            keys: 1000,
+            ..Spec::default()
+        };
+        let trace = spec.generate().unwrap();
+        let mut counts = collections::HashMap::new();
+        for op in &trace.ops {
+            let key = match *op {
+                Op::Insert(k, _) | Op::Lookup(k) | Op::Remove(k) => k,
+            };
+            *counts.entry(key).or_insert(0) += 1;
+        }
+        let mut counts: Vec<usize> = counts.values().cloned().collect();
+        counts.sort_unstable_by(|a, b| b.cmp(a));
+        // Rank 1 carries 1/H(1000), about 13%, of the weight; rank 2 half
+        // of that.
+        assert!(counts[0] > 12_000 && counts[0] < 14_500, "{:?}", &counts[..3]);
+        assert!(counts[0] > counts[1] * 3 / 2);
+    }
+
+    #[test]
+    fn keys_fit_their_size_and_count() {
+        fn property(ops: u16, key_size: KeySize, keys: u64, zipf: bool) -> TestResult {
+            let spec = spec(ops, (1, 1, 1), key_size, keys, zipf);
+            if spec.keys - 1 > key_size.max() {
+                return TestResult::from_bool(spec.generate().is_err());
+            }
+            let trace = spec.generate().unwrap();
+            let mut distinct = collections::HashSet::new();
+            for op in &trace.ops {
+                let key = match *op {
+                    Op::Insert(k, _) | Op::Lookup(k) | Op::Remove(k) => k,
+                };
+                assert!(key <= key_size.max());
+                distinct.insert(key);
+            }
+            TestResult::from_bool(distinct.len() as u64 <= spec.keys)
+        }
+        QuickCheck::new().quickcheck(property as fn(u16, KeySize, u64, bool) -> TestResult);
+    }
+
+    #[test]
+    fn trace_round_trip() {
+        fn property(ops: u16, mix: (u8, u8, u8), key_size: KeySize, keys: u64) -> TestResult {
+            let spec = spec(ops, mix, key_size, keys, false);
+            let trace = match spec.generate() {
+                Ok(trace) => trace,
+                Err(_) => return TestResult::discard(),
+            };
+            let mut bytes = Vec::new();
+            trace.write_to(&mut bytes).unwrap();
+            assert_eq!(trace, Trace::read_from(&bytes[..]).unwrap());
+            TestResult::passed()
+        }
+        QuickCheck::new()
+            .quickcheck(property as fn(u16, (u8, u8, u8), KeySize, u64) -> TestResult);
+    }
+
+    #[test]
+    fn bad_traces_are_rejected() {
+        let read = |s: &str| Trace::read_from(s.as_bytes());
+        assert!(read("").is_err());
+        assert!(read("INSERT 1 2\n").is_err());
+        assert!(read("# naive_hashmap trace v1 key-size=u7\n").is_err());
+        let u8_trace = "# naive_hashmap trace v1 key-size=u8\n";
+        assert!(read(&format!("{}INSERT 256 1\n", u8_trace)).is_err());
+        assert!(read(&format!("{}INSERT 1 -1\n", u8_trace)).is_err());
+        assert!(read(&format!("{}LEN\n", u8_trace)).is_err());
+        assert_eq!(
+            Ok(vec![Op::Lookup(255)]),
+            read(&format!("{}# comment\n\nLOOKUP 255\n", u8_trace)).map(|t| t.ops)
+        );
+    }
+
+    #[test]
+    fn every_map_sees_the_same_hits() {
+        fn property(ops: u16, mix: (u8, u8, u8), keys: u64) -> TestResult {
+            let trace = match spec(ops, mix, KeySize::U16, keys, true).generate() {
+                Ok(trace) => trace,
+                Err(_) => return TestResult::discard(),
+            };
+            let expected = replay(&mut collections::HashMap::<u16, u32>::new(), &trace.ops).hits;
+            assert_eq!(expected, replay(&mut HashMap::<u16, u32>::new(), &trace.ops).hits);
+            assert_eq!(expected, replay(&mut DirectMap::<u16, u32>::new(), &trace.ops).hits);
+            assert_eq!(
+                expected,
+                replay(&mut ConcurrentHashMap::<u16, u32>::new(), &trace.ops).hits
+            );
+            TestResult::passed()
+        }
+        QuickCheck::new().quickcheck(property as fn(u16, (u8, u8, u8), u64) -> TestResult);
+    }
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let report = Report {
+            elapsed: Duration::from_secs(2),
+            hits: 0,
+            latencies: (1..=100).collect(),
+        };
+        assert_eq!(50.0, report.throughput());
+        assert_eq!(1, report.percentile(0.0));
+        assert_eq!(50, report.percentile(0.5));
+        assert_eq!(99, report.percentile(0.99));
+        assert_eq!(100, report.percentile(1.0));
+    }
+}
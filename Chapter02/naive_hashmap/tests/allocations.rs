@@ -0,0 +1,91 @@
+//! Counts the allocations `HashMap` makes. Lives in its own test binary so
+//! the counting global allocator sees nothing but these tests.
+extern crate naive_hashmap;
+
+use naive_hashmap::HashMap;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAllocator;
+
+thread_local! {
+    // Counted per thread, so the test harness allocating on its own threads
+    // does not disturb the count.
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations_during<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.with(|count| count.get());
+    f();
+    ALLOCATIONS.with(|count| count.get()) - before
+}
+
+const BULK: u64 = 100_000;
+
+/// The number of times the bucket array grows while `n` elements are
+/// inserted from empty: it starts at 8 buckets and doubles whenever one more
+/// element would fill it past 9/10.
+fn growths(n: u64) -> usize {
+    let (mut buckets, mut growths) = (0, 0);
+    for len in 1..=n {
+        if len * 10 > buckets * 9 {
+            buckets = if buckets == 0 { 8 } else { buckets * 2 };
+            growths += 1;
+        }
+    }
+    growths
+}
+
+/// Each growth allocates one new bucket array, 15 of them for 100,000
+/// elements, and nothing else allocates for plain keys.
+#[test]
+fn bulk_insert_allocates_once_per_growth() {
+    assert_eq!(15, growths(BULK));
+    let mut hash_map = HashMap::new();
+    let allocations = allocations_during(|| {
+        for i in 0..BULK {
+            hash_map.insert(i, i);
+        }
+    });
+    assert_eq!(growths(BULK), allocations);
+}
+
+/// A map sized up front never allocates while being filled to that size.
+#[test]
+fn presized_bulk_insert_allocates_once() {
+    let allocations = allocations_during(|| {
+        let mut hash_map = HashMap::with_capacity(BULK as usize);
+        for i in 0..BULK {
+            hash_map.insert(i, i);
+        }
+    });
+    assert_eq!(1, allocations);
+
+    let mut hash_map = HashMap::new();
+    hash_map.reserve(BULK as usize);
+    let allocations = allocations_during(|| {
+        for i in 0..BULK {
+            hash_map.insert(i, i);
+        }
+    });
+    assert_eq!(0, allocations);
+}
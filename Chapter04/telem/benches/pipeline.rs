@@ -0,0 +1,82 @@
+//! Packets per second through ingest, fanned out to a low and a high
+//! filter, and on to one egress that takes what both pass.
+#[macro_use]
+extern crate criterion;
+extern crate telem;
+
+use criterion::{Criterion, Throughput};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use telem::channel::{self, Policy};
+use telem::egress::Egress;
+use telem::event::{Event, Telemetry};
+use telem::filter::{Filter, HighFilter, LowFilter};
+use telem::parser::StatsdParser;
+use telem::Ingester;
+
+const CAPACITY: usize = 1_024;
+
+/// A packet as a busy StatsD client sends it: several lines of a handful
+/// of metrics, one sampled and one tagged.
+const PACKET: &[u8] = b"api.requests:1|c|@0.5\n\
+                        api.latency:12.5|ms\n\
+                        api.latency:40|ms|#route:/users\n\
+                        queue.depth:7|g\n\
+                        sessions:1843|s";
+
+/// Counts what reaches it, so that the measurement is of the pipeline and
+/// not of a sink.
+struct Counting(usize);
+
+impl Egress for Counting {
+    fn deliver(&mut self, _: Arc<Telemetry>) {
+        self.0 += 1;
+    }
+
+    fn report(&mut self) {}
+}
+
+/// Runs `packets` packets through a pipeline built for the purpose,
+/// timing from the first packet until egress has seen the last.
+fn pipeline(packets: u64) -> Duration {
+    let (low_snd, low_recv) = channel::bounded("low", CAPACITY, Policy::Block);
+    let (high_snd, high_recv) = channel::bounded("high", CAPACITY, Policy::Block);
+    let (egress_snd, egress_recv) =
+        channel::bounded("egress", CAPACITY, Policy::Block);
+    egress_recv.expect_upstreams(2);
+
+    let low_out = egress_snd.clone();
+    let low =
+        thread::spawn(move || LowFilter::new(100.0).run(&low_recv, vec![low_out]));
+    let high =
+        thread::spawn(move || HighFilter::new(10.0).run(&high_recv, vec![egress_snd]));
+    let egress = thread::spawn(move || {
+        let mut egress = Counting(0);
+        egress.run(&egress_recv);
+        egress.0
+    });
+
+    let mut ingester = Ingester::new(StatsdParser, vec![low_snd, high_snd]);
+    let start = Instant::now();
+    for _ in 0..packets {
+        assert!(ingester.packet(PACKET));
+    }
+    ingester.send(Event::Shutdown);
+    low.join().unwrap();
+    high.join().unwrap();
+    let delivered = egress.join().unwrap();
+    let elapsed = start.elapsed();
+    assert!(packets == 0 || delivered > 0);
+    elapsed
+}
+
+fn ingest_filter_egress(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pipeline");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("statsd_packets", |b| b.iter_custom(pipeline));
+    group.finish();
+}
+
+criterion_group!(benches, ingest_filter_egress);
+criterion_main!(benches);
@@ -0,0 +1,377 @@
+//! A local interface for asking the running pipeline about itself.
+//!
+//! `Admin` listens on a TCP address and answers requests, one per line,
+//! on the connection they came in on:
+//!
+//! ```text
+//! STATS                   every channel's and ingest's counters
+//! METRICS <glob>          the series egress has reported; all without a glob
+//! QUANTILE <series> <q>   the q quantile of a distribution, 0 <= q <= 1
+//! ```
+//!
+//! An answer is its lines followed by `OK`, or the one line `ERR <why>`.
+//! Series and quantiles are as of each egress's last flush, when it
+//! publishes what it reported to the `Board`.
+use channel::Stats;
+use filter::Pattern;
+use ingest_point::IngestCounters;
+use quantiles::ckms::CKMS;
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::Duration;
+use util;
+
+/// How often the listener looks for a shutdown between connections.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long the listener waits after failing to accept, as when the
+/// process is out of file descriptors.
+const ACCEPT_RETRY: Duration = Duration::from_secs(1);
+/// How long a connection may sit without a request before it is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// The longest request read. A longer one closes the connection.
+const MAX_REQUEST: usize = 1024;
+/// Series listed before the board stops taking new ones, so that a flood
+/// of distinct names cannot grow it without bound.
+const MAX_SERIES: usize = 100_000;
+
+/// Every series egress has reported, and the latest sketch of each
+/// distribution among them.
+#[derive(Default)]
+pub struct Board {
+    published: Mutex<Published>,
+}
+
+#[derive(Default)]
+struct Published {
+    series: BTreeSet<String>,
+    sketches: util::HashMap<String, CKMS<f64>>,
+}
+
+/// The board that `egress::Report::emit` publishes to.
+pub fn board() -> Arc<Board> {
+    static BOARD: OnceLock<Arc<Board>> = OnceLock::new();
+    Arc::clone(BOARD.get_or_init(Default::default))
+}
+
+impl Board {
+    pub fn new() -> Board {
+        Board::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Published> {
+        self.published.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Adds `series` to those known, and makes each of `sketches` the
+    /// latest of its series.
+    pub fn publish<'a, I>(&self, series: I, sketches: Vec<(String, CKMS<f64>)>)
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut published = self.lock();
+        for name in series {
+            let full = published.series.len() >= MAX_SERIES;
+            if !full && !published.series.contains(name) {
+                published.series.insert(name.to_string());
+            }
+        }
+        for (name, sketch) in sketches {
+            if published.series.contains(&name) {
+                published.sketches.insert(name, sketch);
+            }
+        }
+    }
+
+    /// The known series that `pattern` matches, in order.
+    pub fn series(&self, pattern: &Pattern) -> Vec<String> {
+        let published = self.lock();
+        published
+            .series
+            .iter()
+            .filter(|name| pattern.matches(name))
+            .cloned()
+            .collect()
+    }
+
+    /// The `q` quantile of the latest sketch of `series`, if it has one.
+    pub fn quantile(&self, series: &str, q: f64) -> Option<f64> {
+        let published = self.lock();
+        let sketch = published.sketches.get(series)?;
+        sketch.query(q).map(|(_, value)| value)
+    }
+}
+
+/// Answers requests from a board and the pipeline's own counters.
+#[derive(Clone)]
+pub struct Responder {
+    board: Arc<Board>,
+    channels: Vec<Arc<Stats>>,
+    ingest: Arc<IngestCounters>,
+}
+
+impl Responder {
+    pub fn new(
+        board: Arc<Board>,
+        channels: Vec<Arc<Stats>>,
+        ingest: Arc<IngestCounters>,
+    ) -> Responder {
+        Responder {
+            board,
+            channels,
+            ingest,
+        }
+    }
+
+    /// The lines answering `request`, or why there are none.
+    pub fn answer(&self, request: &str) -> Result<Vec<String>, String> {
+        let mut words = request.split_whitespace();
+        let command = words.next().unwrap_or("").to_ascii_uppercase();
+        let args: Vec<&str> = words.collect();
+        match (command.as_str(), args.as_slice()) {
+            ("STATS", []) => Ok(self.stats()),
+            ("METRICS", []) => Ok(self.board.series(&Pattern::glob("*"))),
+            ("METRICS", [glob]) => Ok(self.board.series(&Pattern::glob(glob))),
+            ("QUANTILE", [series, q]) => {
+                let q = match q.parse::<f64>() {
+                    Ok(q) if (0.0..=1.0).contains(&q) => q,
+                    _ => return Err(format!("`{}` is not a quantile from 0 to 1", q)),
+                };
+                match self.board.quantile(series, q) {
+                    Some(value) => Ok(vec![value.to_string()]),
+                    None => Err(format!("no distribution `{}`", series)),
+                }
+            }
+            ("STATS", _) => Err("usage: STATS".to_string()),
+            ("METRICS", _) => Err("usage: METRICS <glob>".to_string()),
+            ("QUANTILE", _) => Err("usage: QUANTILE <series> <q>".to_string()),
+            _ => Err(format!("unknown request `{}`", command)),
+        }
+    }
+
+    fn stats(&self) -> Vec<String> {
+        let channels = self.channels.iter().flat_map(|stats| stats.totals());
+        channels
+            .chain(self.ingest.totals())
+            .map(|(name, value)| format!("{} {}", name, value))
+            .collect()
+    }
+}
+
+/// Serves a `Responder` from a thread of its own, with a thread for each
+/// connection, until dropped.
+pub struct Admin {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    listener: Option<thread::JoinHandle<()>>,
+}
+
+impl Admin {
+    /// Listens on `address` for requests. Port 0 picks a free port; see
+    /// `local_addr`.
+    pub fn bind<A: ToSocketAddrs>(
+        address: A,
+        responder: Responder,
+    ) -> io::Result<Admin> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let handle = thread::Builder::new()
+            .name(format!("admin {}", local_addr))
+            .spawn(move || serve(&listener, &responder, &thread_stop))?;
+        Ok(Admin {
+            local_addr,
+            stop,
+            listener: Some(handle),
+        })
+    }
+
+    /// The address requests are answered on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for Admin {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.listener.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn serve(listener: &TcpListener, responder: &Responder, stop: &AtomicBool) {
+    while !stop.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let responder = responder.clone();
+                // An operator may keep a connection open between requests,
+                // so one connection must not hold up the next.
+                let _ = thread::Builder::new()
+                    .name("admin connection".to_string())
+                    .spawn(move || {
+                        let _ = respond(stream, &responder);
+                    });
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL)
+            }
+            Err(e) => {
+                eprintln!("[ADMIN] accept failed: {}", e);
+                thread::sleep(ACCEPT_RETRY);
+            }
+        }
+    }
+}
+
+/// Answers each request on `stream` until it closes or sits idle.
+fn respond(stream: TcpStream, responder: &Responder) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+    let mut out = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let len = (&mut reader)
+            .take(MAX_REQUEST as u64 + 1)
+            .read_until(b'\n', &mut line)?;
+        if len == 0 {
+            return Ok(());
+        }
+        if line.len() > MAX_REQUEST {
+            return out.write_all(b"ERR request too long\n");
+        }
+        let request = String::from_utf8_lossy(&line);
+        if request.trim().is_empty() {
+            continue;
+        }
+        let mut answer = String::new();
+        match responder.answer(&request) {
+            Ok(lines) => {
+                for line in lines {
+                    answer.push_str(&line);
+                    answer.push('\n');
+                }
+                answer.push_str("OK\n");
+            }
+            Err(why) => answer = format!("ERR {}\n", why),
+        }
+        out.write_all(answer.as_bytes())?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use channel::{self, Policy};
+    use event::{Event, MetricKind, Telemetry};
+
+    fn sketch(values: &[f64]) -> CKMS<f64> {
+        let mut sketch = CKMS::new(0.001);
+        for value in values {
+            sketch.insert(*value);
+        }
+        sketch
+    }
+
+    fn responder() -> (Responder, channel::Sender, channel::Receiver) {
+        let board = Arc::new(Board::new());
+        board.publish(
+            vec!["api.latency", "api.latency", "api.requests", "db.latency"],
+            vec![
+                ("api.latency".to_string(), sketch(&[1.0, 2.0, 3.0, 4.0])),
+                ("db.latency".to_string(), sketch(&[10.0])),
+            ],
+        );
+        let (snd, recv) = channel::bounded("ckms", 4, Policy::Block);
+        let responder = Responder::new(board, vec![snd.stats()], Default::default());
+        (responder, snd, recv)
+    }
+
+    #[test]
+    fn answers_from_the_board() {
+        let (responder, _, _) = responder();
+        assert_eq!(
+            Ok(vec!["api.latency".to_string(), "api.requests".to_string()]),
+            responder.answer("METRICS api.*")
+        );
+        assert_eq!(3, responder.answer("metrics").unwrap().len());
+        assert_eq!(
+            Ok(vec!["4".to_string()]),
+            responder.answer("QUANTILE api.latency 1")
+        );
+        assert_eq!(
+            Err("no distribution `api.requests`".to_string()),
+            responder.answer("QUANTILE api.requests 0.5")
+        );
+        assert_eq!(
+            Err("`1.5` is not a quantile from 0 to 1".to_string()),
+            responder.answer("QUANTILE api.latency 1.5")
+        );
+        assert_eq!(
+            Err("usage: QUANTILE <series> <q>".to_string()),
+            responder.answer("QUANTILE api.latency")
+        );
+        assert_eq!(
+            Err("unknown request `SCRAPE`".to_string()),
+            responder.answer("scrape")
+        );
+    }
+
+    #[test]
+    fn stats_are_totals() {
+        let (responder, snd, recv) = responder();
+        let telem = Telemetry::new("x", 1.0, MetricKind::Gauge);
+        snd.send(Event::Telemetry(Arc::new(telem))).unwrap();
+        snd.send(Event::Flush).unwrap();
+        assert!(recv.try_recv().is_some());
+        // Reporting to the pipeline itself takes nothing from the totals.
+        snd.stats().telemetry();
+        let stats = responder.answer("STATS").unwrap();
+        assert_eq!(
+            vec![
+                "telem.channel.ckms.depth 1",
+                "telem.channel.ckms.spilled 0",
+                "telem.channel.ckms.dropped 0",
+                "telem.channel.ckms.received 1",
+                "telem.channel.ckms.flush_ms 0",
+                "telem.ingest.packets 0",
+                "telem.ingest.lines 0",
+                "telem.ingest.parse_errors 0",
+                "telem.ingest.malformed 0",
+            ],
+            stats
+        );
+    }
+
+    #[test]
+    fn serves_each_request_on_a_connection() {
+        let (responder, _, _) = responder();
+        let admin = Admin::bind("127.0.0.1:0", responder).unwrap();
+        let stream = TcpStream::connect(admin.local_addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut out = stream.try_clone().unwrap();
+        out.write_all(b"METRICS db.*\r\n\nQUANTILE db.latency\n")
+            .unwrap();
+        let lines: Vec<String> = BufReader::new(stream)
+            .lines()
+            .take(3)
+            .map(|line| line.unwrap())
+            .collect();
+        assert_eq!(
+            vec!["db.latency", "OK", "ERR usage: QUANTILE <series> <q>"],
+            lines
+        );
+        drop(admin);
+    }
+}
@@ -1,44 +1,309 @@
+extern crate libc;
+extern crate regex;
 extern crate telem;
 
-use std::{thread, time};
-use std::sync::mpsc;
+use regex::Regex;
+use std::{env, process, thread, time};
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use telem::IngestPoint;
-use telem::egress::{CKMSEgress, CMAEgress, Egress};
+use telem::admin::{self, Admin, Responder};
+use telem::channel;
+use telem::config::{Config, EgressKind, FilterKind, Relabel, Sampling};
+use telem::emitter::Emitter;
+use telem::egress::{
+    CKMSEgress, CMAEgress, Egress, GraphiteEgress, InfluxEgress, PrometheusEgress,
+    SetEgress, ShardedEgress,
+};
 use telem::event::Event;
-use telem::filter::{Filter, HighFilter, LowFilter};
+use telem::filter::{
+    Filter, FilterChain, HighFilter, LowFilter, NameFilter, Pattern, RangeFilter,
+    RateLimitFilter, RelabelFilter, SampleFilter,
+};
+use telem::supervisor::supervise;
+
+/// The pipeline run without `--config`.
+const DEFAULT_CONFIG: &str = include_str!("../../telem.toml");
+
+/// Telemetry handed to an emitter at a time. Batches end at every flush,
+/// so this bounds nothing but how often an emitter is called.
+const EMITTER_BATCH: usize = 64;
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+fn usage() -> ! {
+    eprintln!("usage: telem [--config PATH]");
+    process::exit(2)
+}
+
+fn load_config() -> Config {
+    let mut args = env::args().skip(1);
+    let path = match (args.next(), args.next(), args.next()) {
+        (None, _, _) => None,
+        (Some(ref flag), Some(path), None) if flag == "--config" => Some(path),
+        (Some(ref flag), None, None) if flag.starts_with("--config=") => {
+            Some(flag["--config=".len()..].to_string())
+        }
+        _ => usage(),
+    };
+    let loaded = match path {
+        Some(ref path) => Config::load(path),
+        None => DEFAULT_CONFIG.parse(),
+    };
+    loaded.unwrap_or_else(|e| {
+        let path = path.as_deref().unwrap_or("default configuration");
+        eprintln!("telem: {}: {}", path, e);
+        process::exit(1)
+    })
+}
+
+fn filter(kind: FilterKind) -> Box<dyn Filter + Send> {
+    match kind {
+        FilterKind::Low { limit } => Box::new(LowFilter::new(limit)),
+        FilterKind::High { limit } => Box::new(HighFilter::new(limit)),
+        FilterKind::Range { min, max } => Box::new(RangeFilter::new(min, max)),
+        FilterKind::Name { allow, deny } => {
+            let mut filter = NameFilter::new();
+            for glob in &allow {
+                filter = filter.allow(Pattern::glob(glob));
+            }
+            for glob in &deny {
+                filter = filter.deny(Pattern::glob(glob));
+            }
+            Box::new(filter)
+        }
+        FilterKind::Sample { sampling, seed } => {
+            let filter = match sampling {
+                Sampling::OneIn(n) => SampleFilter::one_in(n),
+                Sampling::Probability(p) => SampleFilter::probability(p),
+            };
+            match seed {
+                Some(seed) => Box::new(filter.with_seed(seed)),
+                None => Box::new(filter),
+            }
+        }
+        FilterKind::RateLimit { limit, period } => {
+            Box::new(RateLimitFilter::new(limit, period))
+        }
+        FilterKind::Relabel { rules } => {
+            Box::new(rules.into_iter().fold(RelabelFilter::new(), relabel))
+        }
+        FilterKind::Chain { filters } => Box::new(
+            filters
+                .into_iter()
+                .fold(FilterChain::new(), |chain, kind| chain.then(filter(kind))),
+        ),
+    }
+}
+
+fn relabel(filter: RelabelFilter, rule: Relabel) -> RelabelFilter {
+    match rule {
+        Relabel::Rename { regex, replacement } => {
+            // The configuration checked the regex when it was read.
+            let regex = Regex::new(&regex).expect("invalid rename regex");
+            filter.rename(regex, &replacement)
+        }
+        Relabel::Prefix(prefix) => filter.prefix(&prefix),
+        Relabel::SetTag { key, value } => filter.set_tag(&key, &value),
+        Relabel::RenameTag { from, to } => filter.rename_tag(&from, &to),
+        Relabel::DropTag(key) => filter.drop_tag(&key),
+    }
+}
+
+/// Builds the egress `kind` describes. A `prometheus` egress serves from
+/// `listener`, bound by `main`.
+fn egress(kind: EgressKind, listener: Option<&TcpListener>) -> Box<dyn Egress> {
+    match kind {
+        EgressKind::Ckms { config, shards: 1 } => {
+            Box::new(CKMSEgress::runnable(config).batched(EMITTER_BATCH))
+        }
+        EgressKind::Ckms { config, shards } => {
+            Box::new(ShardedEgress::new::<CKMSEgress, _>(shards, config))
+        }
+        EgressKind::Cma { config, shards: 1 } => {
+            Box::new(CMAEgress::runnable(config).batched(EMITTER_BATCH))
+        }
+        EgressKind::Cma { config, shards } => {
+            Box::new(ShardedEgress::new::<CMAEgress, _>(shards, config))
+        }
+        EgressKind::Set => Box::new(SetEgress::new()),
+        EgressKind::Graphite { address, error } => {
+            Box::new(GraphiteEgress::new(&address, error))
+        }
+        EgressKind::Influx {
+            address,
+            transport,
+            error,
+        } => Box::new(InfluxEgress::new(&address, transport, error)),
+        // A restarted stage serves from the same socket as the last.
+        EgressKind::Prometheus { address, error } => {
+            let listener = listener.expect("prometheus listener not bound");
+            match listener
+                .try_clone()
+                .and_then(|listener| PrometheusEgress::from_listener(listener, error))
+            {
+                Ok(egress) => Box::new(egress),
+                Err(e) => panic!("cannot serve metrics on {}: {}", address, e),
+            }
+        }
+    }
+}
 
 fn main() {
-    let limit = 100;
-    let (lp_ic_snd, lp_ic_rcv) = mpsc::channel::<Event>();
-    let (hp_ic_snd, hp_ic_rcv) = mpsc::channel::<Event>();
-    let (ckms_snd, ckms_rcv) = mpsc::channel::<Event>();
-    let (cma_snd, cma_rcv) = mpsc::channel::<Event>();
-
-    let filter_sends = vec![lp_ic_snd, hp_ic_snd];
-    let ingest_filter_sends = filter_sends.clone();
-    let _ingest_jh = thread::spawn(move || {
-        IngestPoint::init("127.0.0.1".to_string(), 1990, ingest_filter_sends).run();
-    });
-    let _low_jh = thread::spawn(move || {
-        let mut low_filter = LowFilter::new(limit);
-        low_filter.run(lp_ic_rcv, vec![ckms_snd]);
-    });
-    let _high_jh = thread::spawn(move || {
-        let mut high_filter = HighFilter::new(limit);
-        high_filter.run(hp_ic_rcv, vec![cma_snd]);
-    });
-    let _ckms_egress_jh = thread::spawn(move || {
-        CKMSEgress::new(0.01).run(ckms_rcv);
+    let config = load_config();
+
+    // Storing to an atomic is all the handler does, which is safe to do
+    // from a signal handler.
+    unsafe {
+        let handler = request_shutdown as extern "C" fn(libc::c_int);
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+    }
+
+    // One channel into every filter and egress. A stage fed by several
+    // others waits for all of them to flush or shut down.
+    let mut sends = HashMap::new();
+    let mut recvs = HashMap::new();
+    let mut channel_stats = Vec::new();
+    let queues = config
+        .filters
+        .iter()
+        .map(|f| (&f.name, &f.queue))
+        .chain(config.egresses.iter().map(|e| (&e.name, &e.queue)));
+    for (name, queue) in queues {
+        let (snd, recv) = channel::bounded(name, queue.capacity, queue.policy.clone());
+        recv.expect_upstreams(config.upstreams(name));
+        channel_stats.push(snd.stats());
+        sends.insert(name.as_str(), snd);
+        recvs.insert(name.as_str(), recv);
+    }
+    let senders = |to: &[String]| -> Vec<channel::Sender> {
+        to.iter().map(|name| sends[name.as_str()].clone()).collect()
+    };
+
+    // Sources are bound before any stage starts, so one that cannot be
+    // stops telem at once rather than leaving it running deaf.
+    let mut ingest = IngestPoint::new(Vec::new());
+    for source in &config.sources {
+        ingest
+            .listen_into(
+                source.transport,
+                source.host.clone(),
+                source.port,
+                source.format.parser(),
+                senders(&source.to),
+            )
+            .unwrap_or_else(|e| {
+                let address = format!("{}:{}", source.host, source.port);
+                eprintln!("telem: cannot listen on {}: {}", address, e);
+                process::exit(1)
+            });
+    }
+    let counters = ingest.counters();
+    let _admin = config.admin_address.as_ref().map(|address| {
+        let responder = Responder::new(
+            admin::board(),
+            channel_stats.clone(),
+            Arc::clone(&counters),
+        );
+        Admin::bind(address.as_str(), responder).unwrap_or_else(|e| {
+            eprintln!("telem: cannot answer admin requests on {}: {}", address, e);
+            process::exit(1)
+        })
     });
-    let _cma_egress_jh = thread::spawn(move || {
-        CMAEgress::new().run(cma_rcv);
+    // A listener that cannot be bound would fail its stage at every
+    // restart, so it is bound once, up front.
+    let mut listeners = HashMap::new();
+    for stage in &config.egresses {
+        if let EgressKind::Prometheus { ref address, .. } = stage.kind {
+            let listener = TcpListener::bind(address.as_str()).unwrap_or_else(|e| {
+                eprintln!("telem: cannot serve metrics on {}: {}", address, e);
+                process::exit(1)
+            });
+            listeners.insert(stage.name.as_str(), listener);
+        }
+    }
+    let stop_ingest = ingest.shutdown_handle();
+    let ingest_jh = thread::spawn(move || {
+        ingest.run();
     });
 
-    let one_second = time::Duration::from_millis(1_000);
-    loop {
-        for snd in &filter_sends {
-            snd.send(Event::Flush).unwrap();
+    let mut stages = Vec::new();
+    for stage in &config.filters {
+        let kind = stage.kind.clone();
+        let chans = senders(&stage.to);
+        let recv = recvs.remove(stage.name.as_str()).unwrap();
+        stages.push(supervise(&stage.name, recv, move |recv| {
+            filter(kind.clone()).run(recv, chans.clone());
+        }));
+    }
+    for stage in &config.egresses {
+        let kind = stage.kind.clone();
+        let listener = listeners.remove(stage.name.as_str());
+        let recv = recvs.remove(stage.name.as_str()).unwrap();
+        stages.push(supervise(&stage.name, recv, move |recv| {
+            egress(kind.clone(), listener.as_ref()).run(recv);
+        }));
+    }
+    // Flushes and the shutdown enter where telemetry does, and reach every
+    // other stage through the filters.
+    let root_sends: Vec<channel::Sender> =
+        config.roots().iter().map(|name| sends[name].clone()).collect();
+    drop(sends);
+
+    let tick = time::Duration::from_millis(100);
+    let mut reported_errors = 0;
+    'flushing: loop {
+        let flush_at = time::Instant::now() + config.flush_interval;
+        loop {
+            if SHUTDOWN.load(Ordering::SeqCst) {
+                break 'flushing;
+            }
+            let left = flush_at.saturating_duration_since(time::Instant::now());
+            if left == time::Duration::from_millis(0) {
+                break;
+            }
+            thread::sleep(tick.min(left));
         }
-        thread::sleep(one_second);
+        // The pipeline reports on its ingest and its own channels
+        // alongside what it aggregates.
+        let own = channel_stats.iter().flat_map(|stats| stats.telemetry());
+        for telem in counters.telemetry().into_iter().chain(own) {
+            let telem = Arc::new(telem);
+            for snd in &root_sends {
+                let _ = snd.send(Event::Telemetry(Arc::clone(&telem)));
+            }
+        }
+        for snd in &root_sends {
+            let _ = snd.send(Event::Flush);
+        }
+        let errors = counters.parse_errors.load(Ordering::Relaxed)
+            + counters.malformed.load(Ordering::Relaxed);
+        if errors != reported_errors {
+            println!(
+                "[INGEST] lines {} parse_errors {} malformed {}",
+                counters.lines.load(Ordering::Relaxed),
+                counters.parse_errors.load(Ordering::Relaxed),
+                counters.malformed.load(Ordering::Relaxed)
+            );
+            reported_errors = errors;
+        }
+    }
+
+    // Stop taking in telemetry, then let what was taken in drain through
+    // the filters to a final report from every egress.
+    stop_ingest.store(true, Ordering::SeqCst);
+    ingest_jh.join().expect("ingest thread panicked");
+    for snd in &root_sends {
+        let _ = snd.send(Event::Shutdown);
+    }
+    for stage in stages {
+        stage.join();
     }
 }
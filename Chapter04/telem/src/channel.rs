@@ -0,0 +1,928 @@
+//! Bounded channels between pipeline stages.
+//!
+//! Each channel holds at most `capacity` events in memory. What a sender
+//! does when the channel is full is the channel's `Policy`: wait for room,
+//! drop the event, drop the oldest queued event, or spill to a file on disk
+//! that the receiver drains back in order. Flushes and shutdowns are never
+//! dropped; under the dropping policies they are queued past capacity.
+//!
+//! A channel may instead write everything sent through it to a `Journal`,
+//! which outlives the process. The receiver acknowledges events once it
+//! has handled the flush or shutdown after them, so a pipeline killed
+//! partway through an interval takes up again from the last flush it
+//! finished when it next starts: delivery is at least once, and events
+//! handled after that flush are handled again.
+//!
+//! Every channel keeps `Stats` that the pipeline reports on itself.
+use event::{Event, MetricKind, Telemetry};
+use journal::{Journal, Position};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// What a sender does with an event when the channel is full.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Policy {
+    /// Wait until the receiver makes room, pushing back on the sender.
+    Block,
+    /// Drop the event being sent.
+    DropNewest,
+    /// Drop the oldest telemetry in the queue to make room.
+    DropOldest,
+    /// Append the event to the file at the path. Once anything is spilled,
+    /// everything sent after it is too, until the receiver has caught up.
+    Spill(PathBuf),
+    /// Append every event to the journal in the directory at the path,
+    /// full or not, and read it back from there. Senders never wait.
+    Journal(PathBuf),
+}
+
+/// Counts kept by a channel, readable from either end.
+#[derive(Debug)]
+pub struct Stats {
+    name: String,
+    /// Events queued, in memory or spilled.
+    pub depth: AtomicUsize,
+    /// Events spilled or journaled to disk and not yet received.
+    pub spilled: AtomicUsize,
+    /// Events dropped by the policy, or lost to a failing spill file or
+    /// journal.
+    pub dropped: AtomicUsize,
+    reported_drops: AtomicUsize,
+    /// Telemetry handed to the receiver.
+    pub received: AtomicUsize,
+    reported_received: AtomicUsize,
+    /// How long the receiver took over the last flush it was handed, from
+    /// handing it over until the receiver asked for more.
+    pub flush_micros: AtomicU64,
+}
+
+impl Stats {
+    fn new(name: &str) -> Stats {
+        Stats {
+            name: name.to_string(),
+            depth: AtomicUsize::new(0),
+            spilled: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            reported_drops: AtomicUsize::new(0),
+            received: AtomicUsize::new(0),
+            reported_received: AtomicUsize::new(0),
+            flush_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The channel's stats as telemetry about the pipeline itself: gauges
+    /// `telem.channel.<name>.depth`, `.spilled` and `.flush_ms`, and
+    /// counters `.dropped` and `.received` of what was dropped and received
+    /// since the last call.
+    pub fn telemetry(&self) -> Vec<Telemetry> {
+        let since = |count: &AtomicUsize, reported: &AtomicUsize| {
+            let count = count.load(Ordering::Relaxed);
+            count.saturating_sub(reported.swap(count, Ordering::Relaxed)) as f64
+        };
+        vec![
+            Telemetry::new(self.stat("depth"), load(&self.depth), MetricKind::Gauge),
+            Telemetry::new(
+                self.stat("spilled"),
+                load(&self.spilled),
+                MetricKind::Gauge,
+            ),
+            Telemetry::new(
+                self.stat("dropped"),
+                since(&self.dropped, &self.reported_drops),
+                MetricKind::Counter,
+            ),
+            Telemetry::new(
+                self.stat("received"),
+                since(&self.received, &self.reported_received),
+                MetricKind::Counter,
+            ),
+            Telemetry::new(self.stat("flush_ms"), self.flush_ms(), MetricKind::Gauge),
+        ]
+    }
+
+    /// The stats under the names `telemetry` gives them, with `.dropped`
+    /// and `.received` counted since the channel was made.
+    pub fn totals(&self) -> Vec<(String, f64)> {
+        vec![
+            (self.stat("depth"), load(&self.depth)),
+            (self.stat("spilled"), load(&self.spilled)),
+            (self.stat("dropped"), load(&self.dropped)),
+            (self.stat("received"), load(&self.received)),
+            (self.stat("flush_ms"), self.flush_ms()),
+        ]
+    }
+
+    fn stat(&self, stat: &str) -> String {
+        format!("telem.channel.{}.{}", self.name, stat)
+    }
+
+    fn flush_ms(&self) -> f64 {
+        self.flush_micros.load(Ordering::Relaxed) as f64 / 1000.0
+    }
+}
+
+fn load(count: &AtomicUsize) -> f64 {
+    count.load(Ordering::Relaxed) as f64
+}
+
+/// The receiver has hung up; the event is handed back.
+#[derive(Debug, PartialEq)]
+pub struct SendError(pub Event);
+
+/// Every sender has hung up and nothing is left queued.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecvError;
+
+/// Why `recv_timeout` returned without an event.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecvTimeoutError {
+    /// Nothing arrived in time; senders remain.
+    Timeout,
+    /// Every sender has hung up and nothing is left queued.
+    Disconnected,
+}
+
+/// Events spilled to a file, read back from `read_pos` and appended at
+/// `write_pos`. The file is truncated whenever it is drained.
+struct SpillFile {
+    path: PathBuf,
+    file: Option<File>,
+    read_pos: u64,
+    write_pos: u64,
+    pending: usize,
+    buf: Vec<u8>,
+}
+
+impl SpillFile {
+    fn new(path: PathBuf) -> SpillFile {
+        SpillFile {
+            path,
+            file: None,
+            read_pos: 0,
+            write_pos: 0,
+            pending: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    fn file(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&self.path)?;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    fn push(&mut self, event: &Event) -> io::Result<()> {
+        let mut buf = ::std::mem::take(&mut self.buf);
+        buf.clear();
+        event.encode(&mut buf);
+        let write_pos = self.write_pos;
+        let written = self.file().and_then(|file| {
+            file.seek(SeekFrom::Start(write_pos))?;
+            file.write_all(&buf)
+        });
+        self.buf = buf;
+        written?;
+        self.write_pos += self.buf.len() as u64;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Reads back up to `max` of the oldest spilled events.
+    fn pop(&mut self, max: usize, into: &mut VecDeque<Event>) -> io::Result<()> {
+        let count = max.min(self.pending);
+        let read_pos = self.read_pos;
+        let file = self.file()?;
+        file.seek(SeekFrom::Start(read_pos))?;
+        let mut reader = BufReader::new(file);
+        for _ in 0..count {
+            into.push_back(Event::decode(&mut reader)?);
+        }
+        self.read_pos = reader.stream_position()?;
+        self.pending -= count;
+        if self.pending == 0 {
+            self.clear()?;
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.read_pos = 0;
+        self.write_pos = 0;
+        self.pending = 0;
+        match self.file {
+            Some(ref file) => file.set_len(0),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A channel's journal and how far the receiver has got through it.
+struct Journaled {
+    journal: Journal,
+    /// The position just past each event in the queue, oldest first.
+    positions: VecDeque<Position>,
+    /// Just past the last event taken from the queue.
+    taken: Option<Position>,
+    /// Just past the last flush or shutdown the receiver was handed, to
+    /// be acknowledged once it asks for another event.
+    received: Option<Position>,
+}
+
+impl Journaled {
+    /// Refills `queue` from the journal, up to `room` events.
+    fn read(&mut self, room: usize, queue: &mut VecDeque<Event>) -> io::Result<()> {
+        while queue.len() < room {
+            match self.journal.read()? {
+                Some((event, pos)) => {
+                    queue.push_back(event);
+                    self.positions.push_back(pos);
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+struct State {
+    queue: VecDeque<Event>,
+    spill: Option<SpillFile>,
+    journal: Option<Journaled>,
+    senders: usize,
+    receiver: bool,
+    /// Stages feeding the channel, each sending its own flushes and
+    /// shutdown; see `Receiver::expect_upstreams`.
+    upstreams: usize,
+    flushes: usize,
+    shutdowns: usize,
+    /// When the receiver was handed the flush it is handling, if it is.
+    flushing: Option<Instant>,
+}
+
+impl State {
+    fn depth(&self) -> usize {
+        self.queue.len() + self.on_disk()
+    }
+
+    fn on_disk(&self) -> usize {
+        let spilled = self.spill.as_ref().map_or(0, |spill| spill.pending);
+        spilled + self.journal.as_ref().map_or(0, |j| j.journal.unread())
+    }
+
+    fn spilling(&self) -> bool {
+        self.spill.as_ref().is_some_and(|spill| spill.pending > 0)
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    policy: Policy,
+    stats: Arc<Stats>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // A stage that panics is restarted around its receiver, so the
+        // queue must outlive a poisoning.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn update_stats(&self, state: &State) {
+        self.stats.depth.store(state.depth(), Ordering::Relaxed);
+        self.stats.spilled.store(state.on_disk(), Ordering::Relaxed);
+    }
+
+    fn drop_events(&self, count: usize) {
+        self.stats.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+}
+
+/// Creates a channel named `name` holding up to `capacity` events in
+/// memory, full by `policy`. The name labels its self-telemetry.
+///
+/// # Panics
+///
+/// If `capacity` is zero, or the channel journals and its journal cannot
+/// be opened.
+pub fn bounded(name: &str, capacity: usize, policy: Policy) -> (Sender, Receiver) {
+    assert!(capacity > 0, "channel capacity must be positive");
+    let spill = match policy {
+        Policy::Spill(ref path) => Some(SpillFile::new(path.clone())),
+        _ => None,
+    };
+    let journal = match policy {
+        Policy::Journal(ref dir) => match Journal::open(dir) {
+            Ok(journal) => Some(Journaled {
+                journal,
+                positions: VecDeque::new(),
+                taken: None,
+                received: None,
+            }),
+            Err(e) => panic!("cannot open the journal in {}: {}", dir.display(), e),
+        },
+        _ => None,
+    };
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            // Grown as it fills: a generous capacity costs nothing unused.
+            queue: VecDeque::new(),
+            spill,
+            journal,
+            senders: 1,
+            receiver: true,
+            upstreams: 1,
+            flushes: 0,
+            shutdowns: 0,
+            flushing: None,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        capacity,
+        policy,
+        stats: Arc::new(Stats::new(name)),
+    });
+    shared.update_stats(&shared.lock());
+    (
+        Sender {
+            shared: Arc::clone(&shared),
+        },
+        Receiver { shared },
+    )
+}
+
+pub struct Sender {
+    shared: Arc<Shared>,
+}
+
+impl Sender {
+    /// Sends `event`, or fails if the receiver has hung up. Whether a full
+    /// channel blocks or drops is up to its policy; a drop is not an error.
+    pub fn send(&self, event: Event) -> Result<(), SendError> {
+        let shared = &*self.shared;
+        let mut state = shared.lock();
+        if !state.receiver {
+            return Err(SendError(event));
+        }
+        let control = !matches!(event, Event::Telemetry(_));
+
+        if let Some(ref mut journaled) = state.journal {
+            let journal = &mut journaled.journal;
+            let written = journal.append(&event).and_then(|()| match event {
+                Event::Telemetry(_) => Ok(()),
+                _ => journal.sync(),
+            });
+            if let Err(e) = written {
+                eprintln!("[CHANNEL] {} could not journal: {}", shared.stats.name, e);
+                shared.drop_events(1);
+            }
+        } else if state.spilling() {
+            let spilled = state.spill.as_mut().unwrap().push(&event);
+            if let Err(e) = spilled {
+                eprintln!("[CHANNEL] {} could not spill: {}", shared.stats.name, e);
+                shared.drop_events(1);
+            }
+        } else if state.queue.len() < shared.capacity {
+            state.queue.push_back(event);
+        } else {
+            match shared.policy {
+                Policy::Block => {
+                    while state.receiver && state.queue.len() >= shared.capacity {
+                        state = shared
+                            .not_full
+                            .wait(state)
+                            .unwrap_or_else(|e| e.into_inner());
+                    }
+                    if !state.receiver {
+                        return Err(SendError(event));
+                    }
+                    state.queue.push_back(event);
+                }
+                Policy::DropNewest => {
+                    if control {
+                        state.queue.push_back(event);
+                    } else {
+                        shared.drop_events(1);
+                    }
+                }
+                Policy::DropOldest => {
+                    let oldest = state
+                        .queue
+                        .iter()
+                        .position(|queued| matches!(*queued, Event::Telemetry(_)));
+                    if let Some(pos) = oldest {
+                        state.queue.remove(pos);
+                        shared.drop_events(1);
+                    }
+                    state.queue.push_back(event);
+                }
+                Policy::Journal(_) => {
+                    unreachable!("journaled events are never queued")
+                }
+                Policy::Spill(_) => {
+                    let spilled = state.spill.as_mut().unwrap().push(&event);
+                    if let Err(e) = spilled {
+                        eprintln!(
+                            "[CHANNEL] {} could not spill: {}",
+                            shared.stats.name, e
+                        );
+                        shared.drop_events(1);
+                    }
+                }
+            }
+        }
+        shared.update_stats(&state);
+        shared.not_empty.notify_one();
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        Arc::clone(&self.shared.stats)
+    }
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Sender {
+        self.shared.lock().senders += 1;
+        Sender {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.not_empty.notify_all();
+        }
+    }
+}
+
+pub struct Receiver {
+    shared: Arc<Shared>,
+}
+
+impl Receiver {
+    /// Waits for the next event, failing once every sender has hung up and
+    /// the channel is drained.
+    pub fn recv(&self) -> Result<Event, RecvError> {
+        let shared = &*self.shared;
+        let mut state = shared.lock();
+        loop {
+            if let Some(event) = self.pop(&mut state) {
+                return Ok(event);
+            }
+            if state.senders == 0 {
+                return Err(RecvError);
+            }
+            state = shared
+                .not_empty
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// As `recv`, but gives up once `timeout` has passed.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Event, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let shared = &*self.shared;
+        let mut state = shared.lock();
+        loop {
+            if let Some(event) = self.pop(&mut state) {
+                return Ok(event);
+            }
+            if state.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_millis(0) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            state = shared
+                .not_empty
+                .wait_timeout(state, left)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// The next event, if one is queued.
+    pub fn try_recv(&self) -> Option<Event> {
+        let mut state = self.shared.lock();
+        self.pop(&mut state)
+    }
+
+    /// Blocks for each event in turn until every sender has hung up.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { recv: self }
+    }
+
+    /// The events queued now, without waiting for more.
+    pub fn try_iter(&self) -> TryIter<'_> {
+        TryIter { recv: self }
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        Arc::clone(&self.shared.stats)
+    }
+
+    /// Declares that `upstreams` stages feed this channel, each passing on
+    /// every flush and a final shutdown. The receiver then sees one flush
+    /// for every `upstreams` sent, and the shutdown only once all of them
+    /// have shut down, so a stage fed by several others reports once per
+    /// interval and does not exit while any of them may still send.
+    pub fn expect_upstreams(&self, upstreams: usize) {
+        assert!(upstreams > 0, "a channel needs an upstream");
+        let mut state = self.shared.lock();
+        state.upstreams = upstreams;
+    }
+
+    /// The oldest event, holding back flushes and shutdowns until every
+    /// upstream has sent one.
+    ///
+    /// A receiver that asks for another event has handled the last flush
+    /// or shutdown it was handed, and so all that came before. That ends
+    /// the timing of a flush, and a journal is told as much.
+    fn pop(&self, state: &mut State) -> Option<Event> {
+        let stats = &self.shared.stats;
+        if let Some(at) = state.flushing.take() {
+            let micros = at.elapsed().as_micros() as u64;
+            stats.flush_micros.store(micros, Ordering::Relaxed);
+        }
+        self.acknowledge(state);
+        loop {
+            let event = self.pop_queued(state)?;
+            let seen = match event {
+                Event::Telemetry(_) => {
+                    stats.received.fetch_add(1, Ordering::Relaxed);
+                    return Some(event);
+                }
+                Event::Flush => &mut state.flushes,
+                Event::Shutdown => &mut state.shutdowns,
+            };
+            *seen += 1;
+            if *seen >= state.upstreams {
+                *seen = 0;
+                if event == Event::Flush {
+                    state.flushing = Some(Instant::now());
+                }
+                if let Some(ref mut journaled) = state.journal {
+                    journaled.received = journaled.taken;
+                }
+                return Some(event);
+            }
+        }
+    }
+
+    /// Takes the oldest event, refilling memory from the spill file as room
+    /// allows so that spilled events come back in the order they were sent.
+    fn pop_queued(&self, state: &mut State) -> Option<Event> {
+        let shared = &*self.shared;
+        if state.queue.is_empty() && state.spilling() {
+            self.unspill(state);
+        }
+        if state.queue.is_empty() && state.journal.is_some() {
+            self.unjournal(state);
+        }
+        let event = state.queue.pop_front()?;
+        if let Some(ref mut journaled) = state.journal {
+            journaled.taken = journaled.positions.pop_front();
+        }
+        if state.spilling() {
+            self.unspill(state);
+        }
+        shared.update_stats(state);
+        shared.not_full.notify_one();
+        Some(event)
+    }
+
+    fn unspill(&self, state: &mut State) {
+        let shared = &*self.shared;
+        let room = shared.capacity.saturating_sub(state.queue.len());
+        let spill = state.spill.as_mut().unwrap();
+        if let Err(e) = spill.pop(room, &mut state.queue) {
+            eprintln!("[CHANNEL] {} lost its spill file: {}", shared.stats.name, e);
+            shared.drop_events(spill.pending);
+            let _ = spill.clear();
+        }
+    }
+
+    fn unjournal(&self, state: &mut State) {
+        let shared = &*self.shared;
+        let journaled = state.journal.as_mut().unwrap();
+        if let Err(e) = journaled.read(shared.capacity, &mut state.queue) {
+            let name = &shared.stats.name;
+            eprintln!("[CHANNEL] {} cannot read its journal: {}", name, e);
+            shared.drop_events(journaled.journal.skip_unread());
+        }
+    }
+
+    fn acknowledge(&self, state: &mut State) {
+        let journaled = match state.journal {
+            Some(ref mut journaled) => journaled,
+            None => return,
+        };
+        if let Some(pos) = journaled.received.take() {
+            if let Err(e) = journaled.journal.ack(pos) {
+                let name = &self.shared.stats.name;
+                eprintln!("[CHANNEL] {} could not acknowledge: {}", name, e);
+            }
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        // Stages are dropped once they have handled their shutdown. What
+        // is still journaled is kept for the next run.
+        self.acknowledge(&mut state);
+        state.receiver = false;
+        state.queue.clear();
+        if let Some(spill) = state.spill.take() {
+            if spill.file.is_some() {
+                let _ = fs::remove_file(&spill.path);
+            }
+        }
+        self.shared.update_stats(&state);
+        self.shared.not_full.notify_all();
+    }
+}
+
+pub struct Iter<'a> {
+    recv: &'a Receiver,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.recv.recv().ok()
+    }
+}
+
+pub struct TryIter<'a> {
+    recv: &'a Receiver,
+}
+
+impl<'a> Iterator for TryIter<'a> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.recv.try_recv()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::thread;
+
+    /// Stamped with a fixed time, so that events made apart compare equal.
+    fn telem(value: f64) -> Event {
+        let mut telem = Telemetry::new("x".to_string(), value, MetricKind::Timer);
+        telem.timestamp = 0;
+        Event::Telemetry(Arc::new(telem))
+    }
+
+    fn values(recv: &Receiver) -> Vec<f64> {
+        recv.try_iter()
+            .filter_map(|event| match event {
+                Event::Telemetry(telem) => Some(telem.value),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_first() {
+        let (snd, recv) = bounded("test", 2, Policy::DropNewest);
+        for value in 0..4 {
+            snd.send(telem(f64::from(value))).unwrap();
+        }
+        snd.send(Event::Flush).unwrap();
+        assert_eq!(3, recv.stats().depth.load(Ordering::Relaxed));
+        assert_eq!(2, recv.stats().dropped.load(Ordering::Relaxed));
+        assert_eq!(vec![0.0, 1.0], values(&recv));
+        assert_eq!(0, recv.stats().depth.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_last_and_flushes() {
+        let (snd, recv) = bounded("test", 2, Policy::DropOldest);
+        snd.send(telem(0.0)).unwrap();
+        snd.send(Event::Flush).unwrap();
+        for value in 1..4 {
+            snd.send(telem(f64::from(value))).unwrap();
+        }
+        let events: Vec<Event> = recv.try_iter().collect();
+        // The flush takes up room but is never the one dropped.
+        assert_eq!(vec![Event::Flush, telem(3.0)], events);
+        assert_eq!(3, recv.stats().dropped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let (snd, recv) = bounded("test", 1, Policy::Block);
+        let sender = thread::spawn(move || {
+            for value in 0..100 {
+                snd.send(telem(f64::from(value))).unwrap();
+            }
+        });
+        let mut received = Vec::new();
+        for event in recv.iter() {
+            assert!(recv.stats().depth.load(Ordering::Relaxed) <= 1);
+            if let Event::Telemetry(telem) = event {
+                received.push(telem.value);
+            }
+        }
+        sender.join().unwrap();
+        assert_eq!((0..100).map(f64::from).collect::<Vec<_>>(), received);
+        assert_eq!(0, recv.stats().dropped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn blocked_senders_fail_when_the_receiver_hangs_up() {
+        let (snd, recv) = bounded("test", 1, Policy::Block);
+        snd.send(Event::Flush).unwrap();
+        let sender = thread::spawn(move || snd.send(telem(1.0)));
+        thread::sleep(Duration::from_millis(50));
+        drop(recv);
+        assert_eq!(Err(SendError(telem(1.0))), sender.join().unwrap());
+    }
+
+    #[test]
+    fn spill_preserves_order() {
+        let path = env::temp_dir().join(format!("telem-spill-test-{}", process::id()));
+        let (snd, recv) = bounded("test", 3, Policy::Spill(path.clone()));
+        for value in 0..10 {
+            snd.send(telem(f64::from(value))).unwrap();
+        }
+        assert_eq!(7, snd.stats().spilled.load(Ordering::Relaxed));
+        assert_eq!(10, snd.stats().depth.load(Ordering::Relaxed));
+
+        // Sends while the spill drains go behind what was spilled.
+        let mut received = Vec::new();
+        for value in 10..15 {
+            if let Some(Event::Telemetry(telem)) = recv.try_recv() {
+                received.push(telem.value);
+            }
+            snd.send(telem(f64::from(value))).unwrap();
+        }
+        received.extend(values(&recv));
+        assert_eq!((0..15).map(f64::from).collect::<Vec<_>>(), received);
+        assert_eq!(0, snd.stats().spilled.load(Ordering::Relaxed));
+        assert_eq!(0, snd.stats().dropped.load(Ordering::Relaxed));
+
+        drop(recv);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn journal_keeps_what_was_not_flushed() {
+        let dir =
+            env::temp_dir().join(format!("telem-journal-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (snd, recv) = bounded("test", 2, Policy::Journal(dir.clone()));
+        for value in 0..3 {
+            snd.send(telem(f64::from(value))).unwrap();
+        }
+        snd.send(Event::Flush).unwrap();
+        snd.send(telem(3.0)).unwrap();
+        assert_eq!(5, snd.stats().depth.load(Ordering::Relaxed));
+        let received: Vec<Event> = recv.try_iter().take(4).collect();
+        assert_eq!(Event::Flush, received[3]);
+        // The flush is handled only once the receiver asks again.
+        assert_eq!(Some(telem(3.0)), recv.try_recv());
+        snd.send(telem(4.0)).unwrap();
+        assert_eq!(Some(telem(4.0)), recv.try_recv());
+
+        // Killed before its next flush, the pipeline has yet to handle the
+        // last two.
+        ::std::mem::forget(recv);
+        ::std::mem::forget(snd);
+        let (snd, recv) = bounded("test", 2, Policy::Journal(dir.clone()));
+        assert_eq!(2, snd.stats().spilled.load(Ordering::Relaxed));
+        assert_eq!(vec![3.0, 4.0], values(&recv));
+        snd.send(Event::Shutdown).unwrap();
+        assert_eq!(Some(Event::Shutdown), recv.try_recv());
+        drop(recv);
+        drop(snd);
+
+        // The shutdown was handled, so there is nothing more.
+        let (snd, recv) = bounded("test", 2, Policy::Journal(dir.clone()));
+        assert_eq!(0, snd.stats().depth.load(Ordering::Relaxed));
+        assert_eq!(None, recv.try_recv());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recv_timeout_waits_only_so_long() {
+        let (snd, recv) = bounded("test", 2, Policy::Block);
+        let wait = Duration::from_millis(10);
+        assert_eq!(Err(RecvTimeoutError::Timeout), recv.recv_timeout(wait));
+        snd.send(Event::Flush).unwrap();
+        drop(snd);
+        assert_eq!(Ok(Event::Flush), recv.recv_timeout(wait));
+        assert_eq!(Err(RecvTimeoutError::Disconnected), recv.recv_timeout(wait));
+    }
+
+    #[test]
+    fn receivers_drain_before_disconnecting() {
+        let (snd, recv) = bounded("test", 4, Policy::Block);
+        let other = snd.clone();
+        snd.send(Event::Flush).unwrap();
+        drop(snd);
+        other.send(Event::Shutdown).unwrap();
+        drop(other);
+        assert_eq!(Ok(Event::Flush), recv.recv());
+        assert_eq!(Ok(Event::Shutdown), recv.recv());
+        assert_eq!(Err(RecvError), recv.recv());
+    }
+
+    #[test]
+    fn control_events_wait_for_every_upstream() {
+        let (snd, recv) = bounded("test", 8, Policy::Block);
+        recv.expect_upstreams(2);
+        let other = snd.clone();
+        snd.send(Event::Flush).unwrap();
+        snd.send(telem(1.0)).unwrap();
+        snd.send(Event::Shutdown).unwrap();
+        assert_eq!(vec![telem(1.0)], recv.try_iter().collect::<Vec<_>>());
+        other.send(Event::Flush).unwrap();
+        other.send(Event::Shutdown).unwrap();
+        assert_eq!(
+            vec![Event::Flush, Event::Shutdown],
+            recv.try_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn telemetry_reports_drops_since_last_call() {
+        let (snd, recv) = bounded("ckms", 1, Policy::DropNewest);
+        for value in 0..3 {
+            snd.send(telem(f64::from(value))).unwrap();
+        }
+        snd.send(Event::Flush).unwrap();
+        assert_eq!(Some(telem(0.0)), recv.try_recv());
+        let values = |stats: &Stats| -> Vec<(String, f64)> {
+            stats
+                .telemetry()
+                .into_iter()
+                .map(|telem| (telem.name.to_string(), telem.value))
+                .collect()
+        };
+        let stats = snd.stats();
+        assert_eq!(
+            vec![
+                ("telem.channel.ckms.depth".to_string(), 1.0),
+                ("telem.channel.ckms.spilled".to_string(), 0.0),
+                ("telem.channel.ckms.dropped".to_string(), 2.0),
+                ("telem.channel.ckms.received".to_string(), 1.0),
+                ("telem.channel.ckms.flush_ms".to_string(), 0.0),
+            ],
+            values(&stats)
+        );
+        let again = values(&stats);
+        assert_eq!((0.0, 0.0), (again[2].1, again[3].1));
+        assert_eq!(2.0, stats.totals()[2].1);
+    }
+
+    #[test]
+    fn flushes_are_timed_until_the_receiver_asks_again() {
+        let (snd, recv) = bounded("test", 4, Policy::Block);
+        snd.send(Event::Flush).unwrap();
+        assert_eq!(Ok(Event::Flush), recv.recv());
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(None, recv.try_recv());
+        let micros = recv.stats().flush_micros.load(Ordering::Relaxed);
+        assert!(micros >= 20_000, "timed at {}us", micros);
+
+        // Only the first ask after a flush ends it.
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(None, recv.try_recv());
+        assert_eq!(micros, recv.stats().flush_micros.load(Ordering::Relaxed));
+    }
+}
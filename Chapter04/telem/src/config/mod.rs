@@ -0,0 +1,1405 @@
+//! The pipeline's topology, read from a TOML file.
+//!
+//! Sources listen on sockets and send what they parse on to filters and
+//! egresses; filters send what they pass on to further filters and
+//! egresses. Every stage is named, and a stage's `to` lists the stages it
+//! sends to, so the file describes a graph that must be acyclic, reach
+//! every stage from some source and end in egress:
+//!
+//! ```toml
+//! flush_interval_ms = 1000
+//! admin_address = "127.0.0.1:8126"   # none by default
+//!
+//! [[source]]
+//! name = "statsd"
+//! transport = "udp"        # or "tcp"
+//! host = "127.0.0.1"
+//! port = 8125
+//! format = "statsd"        # or "native", "graphite"
+//! to = ["low"]
+//!
+//! [[filter]]
+//! name = "low"
+//! type = "low"             # or "high"
+//! limit = 100.0
+//! to = ["ckms"]
+//!
+//! [[egress]]
+//! name = "ckms"
+//! type = "ckms"            # or "cma", "set"
+//! error = 0.01
+//! capacity = 10000         # of its input channel, 10000 by default
+//! policy = "drop-oldest"   # or "block" (the default), "drop-newest", "spill",
+//!                          # "journal"
+//! window_ms = 60000        # all time by default
+//! slide_ms = 10000         # the whole window by default
+//! retain = 6               # windows reported, newest first; 1 by default
+//! shards = 4               # threads aggregating, 1 by default
+//! ```
+//!
+//! A `spill` policy also needs a `spill_path`, and a `journal` policy a
+//! `journal_path`: the directory of a journal that keeps what the egress
+//! has not yet flushed across restarts. Only an egress journals.
+//!
+//! Besides `low` and `high`, a filter may be one of:
+//!
+//! ```toml
+//! type = "range"           # values within min..=max
+//! min = 0                  # either bound may be left out
+//! max = 100.0
+//!
+//! type = "name"            # names matching some allow glob and no deny glob
+//! allow = ["api.*"]        # every name by default
+//! deny = ["*.debug"]
+//!
+//! type = "sample"          # given one_in or probability
+//! one_in = 10              # the first of every 10 events
+//! probability = 0.1        # each event at random
+//! seed = 42                # of the random choices, the clock by default
+//!
+//! type = "rate_limit"      # at most limit events of each name per period
+//! limit = 1000
+//! period_ms = 1000
+//!
+//! type = "relabel"         # each rule in turn
+//! rules = [
+//!     { rename = "^api\\.(.*)", replacement = "svc.$1" },
+//!     { prefix = "prod." },
+//!     { set_tag = "dc", value = "ams" },
+//!     { rename_tag = "host", to = "instance" },
+//!     { drop_tag = "pid" },
+//! ]
+//!
+//! type = "chain"           # several filters in one stage, in order
+//! filters = [
+//!     { type = "name", allow = ["api.*"] },
+//!     { type = "sample", one_in = 10 },
+//! ]
+//! ```
+//!
+//! Instead of a window, a `cma` egress may take a `half_life_ms` to decay
+//! its average. A `ckms` or `cma` egress with several `shards` splits
+//! metric names among them by hash.
+//!
+//! Egresses of type `graphite`, `influx` and `prometheus` forward what they
+//! aggregate instead of printing it:
+//!
+//! ```toml
+//! [[egress]]
+//! name = "influx"
+//! type = "influx"          # or "graphite", "prometheus"
+//! address = "127.0.0.1:8089"
+//! transport = "udp"        # or "tcp"; only for influx
+//! error = 0.001            # of quantiles, 0.01 by default
+//! ```
+//!
+//! A `prometheus` egress serves scrapes on its `address` rather than
+//! connecting to it.
+//!
+//! Given an `admin_address`, telem answers requests about itself there;
+//! see `admin`.
+use channel::Policy;
+use egress::{CKMSConfig, CMAConfig, Window};
+use ingest_point::Transport;
+use parser::{GraphiteParser, NativeParser, Parser, StatsdParser};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use toml::{Table, Value};
+
+const DEFAULT_CAPACITY: usize = 10_000;
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_FLUSH_INTERVAL_MS: i64 = 1_000;
+const DEFAULT_SINK_ERROR: f64 = 0.01;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The file is not TOML.
+    Syntax {
+        line: usize,
+        reason: String,
+    },
+    /// The file is TOML but does not describe a valid pipeline.
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Syntax { line, ref reason } => {
+                write!(f, "line {}: {}", line, reason)
+            }
+            Error::Invalid(ref reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+fn invalid<T>(reason: String) -> Result<T, Error> {
+    Err(Error::Invalid(reason))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Native,
+    Statsd,
+    Graphite,
+}
+
+impl Format {
+    pub fn parser(&self) -> Box<dyn Parser> {
+        match *self {
+            Format::Native => Box::new(NativeParser),
+            Format::Statsd => Box::new(StatsdParser),
+            Format::Graphite => Box::new(GraphiteParser),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub name: String,
+    pub transport: Transport,
+    pub host: String,
+    pub port: u16,
+    pub format: Format,
+    pub to: Vec<String>,
+}
+
+/// How a stage's input channel is bounded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Queue {
+    pub capacity: usize,
+    pub policy: Policy,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterKind {
+    Low { limit: f64 },
+    High { limit: f64 },
+    /// Values within `min..=max`, a bound not given being infinite.
+    Range { min: f64, max: f64 },
+    /// Names matching an `allow` glob, or any name if there are none, and
+    /// no `deny` glob.
+    Name { allow: Vec<String>, deny: Vec<String> },
+    /// A sample, its random choices seeded from the clock unless given a
+    /// `seed`.
+    Sample { sampling: Sampling, seed: Option<u64> },
+    /// At most `limit` events of each name per `period`.
+    RateLimit { limit: usize, period: Duration },
+    /// Names and tags rewritten by each rule in turn.
+    Relabel { rules: Vec<Relabel> },
+    /// Several filters run in one stage, each over what the last passed.
+    Chain { filters: Vec<FilterKind> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sampling {
+    OneIn(u64),
+    Probability(f64),
+}
+
+/// A rule of a `relabel` filter; see `filter::RelabelFilter`. The regex of
+/// a rename is checked when the configuration is read.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Relabel {
+    Rename { regex: String, replacement: String },
+    Prefix(String),
+    SetTag { key: String, value: String },
+    RenameTag { from: String, to: String },
+    DropTag(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterStage {
+    pub name: String,
+    pub kind: FilterKind,
+    pub queue: Queue,
+    pub to: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EgressKind {
+    /// Aggregated in `shards` threads; see `egress::ShardedEgress`.
+    Ckms { config: CKMSConfig, shards: usize },
+    Cma { config: CMAConfig, shards: usize },
+    Set,
+    /// Forwarded to Graphite's plaintext protocol over TCP.
+    Graphite { address: String, error: f64 },
+    /// Forwarded as InfluxDB line protocol.
+    Influx {
+        address: String,
+        transport: Transport,
+        error: f64,
+    },
+    /// Served to Prometheus scrapes from `address`.
+    Prometheus { address: String, error: f64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EgressStage {
+    pub name: String,
+    pub kind: EgressKind,
+    pub queue: Queue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub flush_interval: Duration,
+    /// Where to answer admin requests; see `admin`.
+    pub admin_address: Option<String>,
+    pub sources: Vec<Source>,
+    pub filters: Vec<FilterStage>,
+    pub egresses: Vec<EgressStage>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// The stages sources send to directly. These are the ones the
+    /// pipeline's flushes and shutdown are sent to.
+    pub fn roots(&self) -> Vec<&str> {
+        let mut roots: Vec<&str> = Vec::new();
+        for target in self.sources.iter().flat_map(|source| &source.to) {
+            if !roots.contains(&target.as_str()) {
+                roots.push(target);
+            }
+        }
+        roots
+    }
+
+    /// How many upstreams send flushes and shutdowns to the stage `name`:
+    /// each filter sending to it, plus the pipeline itself if any source
+    /// does. See `channel::Receiver::expect_upstreams`.
+    pub fn upstreams(&self, name: &str) -> usize {
+        let sends_to = |to: &Vec<String>| to.iter().any(|target| target == name);
+        let filters = self.filters.iter().filter(|f| sends_to(&f.to)).count();
+        let sourced = self.sources.iter().any(|s| sends_to(&s.to));
+        filters + sourced as usize
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.sources.is_empty() {
+            return invalid("no source is configured".to_string());
+        }
+        if self.egresses.is_empty() {
+            return invalid("no egress is configured".to_string());
+        }
+
+        let mut names = HashSet::new();
+        let all_names = self
+            .sources
+            .iter()
+            .map(|s| &s.name)
+            .chain(self.filters.iter().map(|f| &f.name))
+            .chain(self.egresses.iter().map(|e| &e.name));
+        for name in all_names {
+            if !names.insert(name.as_str()) {
+                return invalid(format!("`{}` is defined twice", name));
+            }
+        }
+
+        let stages: HashSet<&str> = self
+            .filters
+            .iter()
+            .map(|f| f.name.as_str())
+            .chain(self.egresses.iter().map(|e| e.name.as_str()))
+            .collect();
+        let edges = self
+            .sources
+            .iter()
+            .map(|s| ("source", &s.name, &s.to))
+            .chain(self.filters.iter().map(|f| ("filter", &f.name, &f.to)));
+        for (what, name, to) in edges {
+            if to.is_empty() {
+                return invalid(format!("{} `{}` sends to no stage", what, name));
+            }
+            for target in to {
+                if !stages.contains(target.as_str()) {
+                    let reason = if names.contains(target.as_str()) {
+                        "is a source"
+                    } else {
+                        "is not defined"
+                    };
+                    return invalid(format!(
+                        "{} `{}` sends to `{}`, which {}",
+                        what, name, target, reason
+                    ));
+                }
+            }
+        }
+
+        self.check_acyclic()?;
+
+        let mut reached: HashSet<&str> = HashSet::new();
+        let mut pending = self.roots();
+        while let Some(name) = pending.pop() {
+            if reached.insert(name) {
+                if let Some(filter) = self.filters.iter().find(|f| f.name == name) {
+                    pending.extend(filter.to.iter().map(|t| t.as_str()));
+                }
+            }
+        }
+        let names_in_order = self
+            .filters
+            .iter()
+            .map(|f| f.name.as_str())
+            .chain(self.egresses.iter().map(|e| e.name.as_str()));
+        for name in names_in_order {
+            if !reached.contains(name) {
+                return invalid(format!("`{}` is not fed by any source", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks the filters depth first, failing on the first path that leads
+    /// back to a filter already on it.
+    fn check_acyclic(&self) -> Result<(), Error> {
+        let filters: HashMap<&str, &FilterStage> =
+            self.filters.iter().map(|f| (f.name.as_str(), f)).collect();
+        let mut done: HashSet<&str> = HashSet::new();
+        let mut path: Vec<&str> = Vec::new();
+
+        fn visit<'a>(
+            name: &'a str,
+            filters: &HashMap<&'a str, &'a FilterStage>,
+            done: &mut HashSet<&'a str>,
+            path: &mut Vec<&'a str>,
+        ) -> Result<(), Error> {
+            if let Some(start) = path.iter().position(|n| *n == name) {
+                let mut cycle = path[start..].to_vec();
+                cycle.push(name);
+                return invalid(format!(
+                    "filters form a cycle: {}",
+                    cycle.join(" -> ")
+                ));
+            }
+            let filter = match filters.get(name) {
+                Some(filter) if !done.contains(name) => filter,
+                _ => return Ok(()),
+            };
+            path.push(name);
+            for target in &filter.to {
+                visit(target, filters, done, path)?;
+            }
+            path.pop();
+            done.insert(name);
+            Ok(())
+        }
+
+        for filter in &self.filters {
+            visit(&filter.name, &filters, &mut done, &mut path)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Config, Error> {
+        let root = input.parse::<Table>().map_err(|e| {
+            let at = e.span().map_or(0, |span| span.start);
+            Error::Syntax {
+                line: input[..at].matches('\n').count() + 1,
+                reason: e.message().to_string(),
+            }
+        })?;
+        let mut root = Fields::new("the top level".to_string(), root);
+        let flush_ms = root
+            .integer("flush_interval_ms")?
+            .unwrap_or(DEFAULT_FLUSH_INTERVAL_MS);
+        if flush_ms <= 0 {
+            return invalid("flush_interval_ms must be positive".to_string());
+        }
+        let admin_address = root.address("admin_address")?;
+        let sources = root.tables("source")?;
+        let filters = root.tables("filter")?;
+        let egresses = root.tables("egress")?;
+        root.finish()?;
+
+        let config = Config {
+            flush_interval: Duration::from_millis(flush_ms as u64),
+            admin_address,
+            sources: sources.into_iter().map(source).collect::<Result<_, _>>()?,
+            filters: filters.into_iter().map(filter).collect::<Result<_, _>>()?,
+            egresses: egresses.into_iter().map(egress).collect::<Result<_, _>>()?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn source(table: Table) -> Result<Source, Error> {
+    let mut fields = Fields::new("a source".to_string(), table);
+    let name = fields.name()?;
+    let transport = fields.transport()?;
+    let host = fields
+        .string("host")?
+        .unwrap_or_else(|| DEFAULT_HOST.to_string());
+    let port = match fields.integer("port")? {
+        Some(port) if port > 0 && port <= i64::from(u16::MAX) => port as u16,
+        Some(port) => {
+            return invalid(format!("{} has invalid port {}", fields.what, port))
+        }
+        None => return fields.missing("port"),
+    };
+    let format = match fields.required_string("format")?.as_str() {
+        "native" => Format::Native,
+        "statsd" => Format::Statsd,
+        "graphite" => Format::Graphite,
+        other => return fields.unknown("format", other),
+    };
+    let to = fields.strings("to")?;
+    fields.finish()?;
+    Ok(Source {
+        name,
+        transport,
+        host,
+        port,
+        format,
+        to,
+    })
+}
+
+fn filter(table: Table) -> Result<FilterStage, Error> {
+    let mut fields = Fields::new("a filter".to_string(), table);
+    let name = fields.name()?;
+    let kind = fields.filter_kind()?;
+    let queue = fields.queue()?;
+    // A filter is done with an event once it has passed it on, before
+    // anything downstream has flushed it.
+    if let Policy::Journal(_) = queue.policy {
+        return invalid(format!("{} cannot journal; only an egress can", fields.what));
+    }
+    let to = fields.strings("to")?;
+    fields.finish()?;
+    Ok(FilterStage {
+        name,
+        kind,
+        queue,
+        to,
+    })
+}
+
+fn egress(table: Table) -> Result<EgressStage, Error> {
+    let mut fields = Fields::new("an egress".to_string(), table);
+    let name = fields.name()?;
+    let kind = match fields.required_string("type")?.as_str() {
+        "ckms" => {
+            let error = match fields.error()? {
+                Some(error) => error,
+                None => return fields.missing("error"),
+            };
+            let window = fields.window()?;
+            EgressKind::Ckms {
+                config: CKMSConfig { error, window },
+                shards: fields.shards()?,
+            }
+        }
+        "cma" => {
+            let window = fields.window()?;
+            let half_life = fields.millis("half_life_ms")?;
+            if window.is_some() && half_life.is_some() {
+                return invalid(format!(
+                    "{} cannot both window and decay its average",
+                    fields.what
+                ));
+            }
+            EgressKind::Cma {
+                config: CMAConfig { window, half_life },
+                shards: fields.shards()?,
+            }
+        }
+        "set" => EgressKind::Set,
+        "graphite" => EgressKind::Graphite {
+            address: fields.required_address()?,
+            error: fields.error()?.unwrap_or(DEFAULT_SINK_ERROR),
+        },
+        "influx" => EgressKind::Influx {
+            address: fields.required_address()?,
+            transport: fields.transport()?,
+            error: fields.error()?.unwrap_or(DEFAULT_SINK_ERROR),
+        },
+        "prometheus" => EgressKind::Prometheus {
+            address: fields.required_address()?,
+            error: fields.error()?.unwrap_or(DEFAULT_SINK_ERROR),
+        },
+        other => return fields.unknown("type", other),
+    };
+    let queue = fields.queue()?;
+    fields.finish()?;
+    Ok(EgressStage { name, kind, queue })
+}
+
+/// A table whose keys are taken out as they are read, so that whatever is
+/// left over can be reported as unknown.
+struct Fields {
+    what: String,
+    table: Table,
+}
+
+impl Fields {
+    fn new(what: String, table: Table) -> Fields {
+        Fields { what, table }
+    }
+
+    fn wrong_type<T>(
+        &self,
+        key: &str,
+        expected: &str,
+        value: &Value,
+    ) -> Result<T, Error> {
+        invalid(format!(
+            "`{}` of {} must be {}, not {}",
+            key,
+            self.what,
+            expected,
+            value.type_str()
+        ))
+    }
+
+    fn missing<T>(&self, key: &str) -> Result<T, Error> {
+        invalid(format!("{} is missing `{}`", self.what, key))
+    }
+
+    fn unknown<T>(&self, key: &str, value: &str) -> Result<T, Error> {
+        invalid(format!("{} has unknown {} `{}`", self.what, key, value))
+    }
+
+    /// Reads the stage's `name`, which then identifies it in errors.
+    fn name(&mut self) -> Result<String, Error> {
+        let name = self.required_string("name")?;
+        self.what = format!("{} `{}`", self.what.rsplit(' ').next().unwrap(), name);
+        Ok(name)
+    }
+
+    fn string(&mut self, key: &str) -> Result<Option<String>, Error> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(other) => self.wrong_type(key, "a string", &other),
+        }
+    }
+
+    fn required_string(&mut self, key: &str) -> Result<String, Error> {
+        match self.string(key)? {
+            Some(s) => Ok(s),
+            None => self.missing(key),
+        }
+    }
+
+    fn integer(&mut self, key: &str) -> Result<Option<i64>, Error> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::Integer(i)) => Ok(Some(i)),
+            Some(other) => self.wrong_type(key, "an integer", &other),
+        }
+    }
+
+    /// A positive number of milliseconds.
+    fn millis(&mut self, key: &str) -> Result<Option<Duration>, Error> {
+        match self.integer(key)? {
+            None => Ok(None),
+            Some(ms) if ms > 0 => Ok(Some(Duration::from_millis(ms as u64))),
+            Some(_) => invalid(format!("`{}` of {} must be positive", key, self.what)),
+        }
+    }
+
+    /// A window of `window_ms`, sliding by `slide_ms` if given and keeping
+    /// the last `retain` windows. No `window_ms`, no window.
+    fn window(&mut self) -> Result<Option<Window>, Error> {
+        let width = self.millis("window_ms")?;
+        let slide = self.millis("slide_ms")?;
+        let retain = self.integer("retain")?;
+        let width = match width {
+            Some(width) => width,
+            None if slide.is_none() && retain.is_none() => return Ok(None),
+            None => return self.missing("window_ms"),
+        };
+        let slide = slide.unwrap_or(width);
+        if slide > width || width.as_millis() % slide.as_millis() != 0 {
+            return invalid(format!(
+                "{} needs a window_ms that is a multiple of its slide_ms",
+                self.what
+            ));
+        }
+        let window = Window::sliding(width, slide);
+        match retain {
+            None => Ok(Some(window)),
+            Some(retain) if retain > 0 => Ok(Some(window.retaining(retain as usize))),
+            Some(_) => invalid(format!("{} must retain at least one window", self.what)),
+        }
+    }
+
+    /// How many threads aggregate, 1 unless given.
+    fn shards(&mut self) -> Result<usize, Error> {
+        match self.integer("shards")? {
+            None => Ok(1),
+            Some(shards) if shards > 0 => Ok(shards as usize),
+            Some(_) => invalid(format!("{} needs at least one shard", self.what)),
+        }
+    }
+
+    fn float(&mut self, key: &str) -> Result<Option<f64>, Error> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::Float(f)) => Ok(Some(f)),
+            Some(Value::Integer(i)) => Ok(Some(i as f64)),
+            Some(other) => self.wrong_type(key, "a number", &other),
+        }
+    }
+
+    fn required_float(&mut self, key: &str) -> Result<f64, Error> {
+        match self.float(key)? {
+            Some(f) => Ok(f),
+            None => self.missing(key),
+        }
+    }
+
+    /// The `error` of quantiles, in (0, 1).
+    fn error(&mut self) -> Result<Option<f64>, Error> {
+        match self.float("error")? {
+            Some(error) if error <= 0.0 || error >= 1.0 => {
+                invalid(format!("{} needs an error in (0, 1)", self.what))
+            }
+            error => Ok(error),
+        }
+    }
+
+    fn transport(&mut self) -> Result<Transport, Error> {
+        match self.required_string("transport")?.as_str() {
+            "udp" => Ok(Transport::Udp),
+            "tcp" => Ok(Transport::Tcp),
+            other => self.unknown("transport", other),
+        }
+    }
+
+    /// A `host:port`, of which only the port is checked here; the host is
+    /// looked up whenever it is connected to.
+    fn address(&mut self, key: &str) -> Result<Option<String>, Error> {
+        let address = match self.string(key)? {
+            Some(address) => address,
+            None => return Ok(None),
+        };
+        let port = address.rsplit_once(':').map(|(_, port)| port.parse::<u16>());
+        match port {
+            Some(Ok(_)) => Ok(Some(address)),
+            _ => invalid(format!(
+                "{} needs an address of the form host:port, not `{}`",
+                self.what, address
+            )),
+        }
+    }
+
+    fn required_address(&mut self) -> Result<String, Error> {
+        match self.address("address")? {
+            Some(address) => Ok(address),
+            None => self.missing("address"),
+        }
+    }
+
+    fn strings(&mut self, key: &str) -> Result<Vec<String>, Error> {
+        match self.table.remove(key) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(values)) => {
+                let mut strings = Vec::with_capacity(values.len());
+                for value in values {
+                    match value {
+                        Value::String(s) => strings.push(s),
+                        other => {
+                            return self.wrong_type(key, "an array of strings", &other)
+                        }
+                    }
+                }
+                Ok(strings)
+            }
+            Some(other) => self.wrong_type(key, "an array of strings", &other),
+        }
+    }
+
+    fn tables(&mut self, key: &str) -> Result<Vec<Table>, Error> {
+        let wrong = || {
+            Error::Invalid(format!("`{}` must be written as [[{}]] tables", key, key))
+        };
+        match self.table.remove(key) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(values)) => values
+                .into_iter()
+                .map(|value| match value {
+                    Value::Table(table) => Ok(table),
+                    _ => Err(wrong()),
+                })
+                .collect(),
+            Some(_) => Err(wrong()),
+        }
+    }
+
+    /// What a filter does, by its `type`.
+    fn filter_kind(&mut self) -> Result<FilterKind, Error> {
+        let kind = match self.required_string("type")?.as_str() {
+            "low" => FilterKind::Low {
+                limit: self.required_float("limit")?,
+            },
+            "high" => FilterKind::High {
+                limit: self.required_float("limit")?,
+            },
+            "range" => {
+                let min = self.float("min")?;
+                let max = self.float("max")?;
+                if min.is_none() && max.is_none() {
+                    return invalid(format!("{} needs a min or a max", self.what));
+                }
+                let min = min.unwrap_or(f64::NEG_INFINITY);
+                let max = max.unwrap_or(f64::INFINITY);
+                if min > max {
+                    return invalid(format!("{} has a min above its max", self.what));
+                }
+                FilterKind::Range { min, max }
+            }
+            "name" => FilterKind::Name {
+                allow: self.strings("allow")?,
+                deny: self.strings("deny")?,
+            },
+            "sample" => {
+                let one_in = self.integer("one_in")?;
+                let probability = self.float("probability")?;
+                let sampling = match (one_in, probability) {
+                    (Some(n), None) if n > 0 => Sampling::OneIn(n as u64),
+                    (None, Some(p)) if p > 0.0 && p <= 1.0 => Sampling::Probability(p),
+                    (Some(_), None) => {
+                        return invalid(format!(
+                            "{} needs a positive one_in",
+                            self.what
+                        ))
+                    }
+                    (None, Some(_)) => {
+                        return invalid(format!(
+                            "{} needs a probability in (0, 1]",
+                            self.what
+                        ))
+                    }
+                    _ => {
+                        return invalid(format!(
+                            "{} needs either one_in or probability",
+                            self.what
+                        ))
+                    }
+                };
+                FilterKind::Sample {
+                    sampling,
+                    seed: self.integer("seed")?.map(|seed| seed as u64),
+                }
+            }
+            "rate_limit" => {
+                let limit = match self.integer("limit")? {
+                    Some(limit) if limit > 0 => limit as usize,
+                    Some(_) => {
+                        return invalid(format!("{} needs a positive limit", self.what))
+                    }
+                    None => return self.missing("limit"),
+                };
+                match self.millis("period_ms")? {
+                    Some(period) => FilterKind::RateLimit { limit, period },
+                    None => return self.missing("period_ms"),
+                }
+            }
+            "relabel" => {
+                let mut rules = Vec::new();
+                for (i, rule) in self.tables("rules")?.into_iter().enumerate() {
+                    let what = format!("rule {} of {}", i + 1, self.what);
+                    rules.push(Fields::new(what, rule).relabel()?);
+                }
+                FilterKind::Relabel { rules }
+            }
+            "chain" => {
+                let mut filters = Vec::new();
+                for (i, filter) in self.tables("filters")?.into_iter().enumerate() {
+                    let what = format!("filter {} of {}", i + 1, self.what);
+                    let mut fields = Fields::new(what, filter);
+                    filters.push(fields.filter_kind()?);
+                    fields.finish()?;
+                }
+                FilterKind::Chain { filters }
+            }
+            other => return self.unknown("type", other),
+        };
+        Ok(kind)
+    }
+
+    /// A rule of a `relabel` filter, named by the one key of `rename`,
+    /// `prefix`, `set_tag`, `rename_tag` and `drop_tag` it has.
+    fn relabel(mut self) -> Result<Relabel, Error> {
+        let rule = if let Some(regex) = self.string("rename")? {
+            if let Err(e) = Regex::new(&regex) {
+                return invalid(format!("{} has a bad regex: {}", self.what, e));
+            }
+            Relabel::Rename {
+                regex,
+                replacement: self.required_string("replacement")?,
+            }
+        } else if let Some(prefix) = self.string("prefix")? {
+            Relabel::Prefix(prefix)
+        } else if let Some(key) = self.string("set_tag")? {
+            Relabel::SetTag {
+                key,
+                value: self.required_string("value")?,
+            }
+        } else if let Some(from) = self.string("rename_tag")? {
+            Relabel::RenameTag {
+                from,
+                to: self.required_string("to")?,
+            }
+        } else if let Some(key) = self.string("drop_tag")? {
+            Relabel::DropTag(key)
+        } else {
+            return invalid(format!(
+                "{} needs one of rename, prefix, set_tag, rename_tag or drop_tag",
+                self.what
+            ));
+        };
+        self.finish()?;
+        Ok(rule)
+    }
+
+    fn queue(&mut self) -> Result<Queue, Error> {
+        let capacity = match self.integer("capacity")? {
+            None => DEFAULT_CAPACITY,
+            Some(capacity) if capacity > 0 => capacity as usize,
+            Some(_) => {
+                return invalid(format!("{} needs a positive capacity", self.what))
+            }
+        };
+        let spill_path = self.string("spill_path")?;
+        let journal_path = self.string("journal_path")?;
+        let policy = match self.string("policy")?.as_deref() {
+            None | Some("block") => Policy::Block,
+            Some("drop-newest") => Policy::DropNewest,
+            Some("drop-oldest") => Policy::DropOldest,
+            Some("spill") => match spill_path {
+                Some(ref path) => Policy::Spill(PathBuf::from(path)),
+                None => return self.missing("spill_path"),
+            },
+            Some("journal") => match journal_path {
+                Some(ref path) => Policy::Journal(PathBuf::from(path)),
+                None => return self.missing("journal_path"),
+            },
+            Some(other) => return self.unknown("policy", other),
+        };
+        if spill_path.is_some() && !matches!(policy, Policy::Spill(_)) {
+            return invalid(format!(
+                "{} has a spill_path but does not spill",
+                self.what
+            ));
+        }
+        if journal_path.is_some() && !matches!(policy, Policy::Journal(_)) {
+            return invalid(format!(
+                "{} has a journal_path but does not journal",
+                self.what
+            ));
+        }
+        Ok(Queue { capacity, policy })
+    }
+
+    /// Fails on any key not read.
+    fn finish(self) -> Result<(), Error> {
+        match self.table.keys().next() {
+            Some(key) => invalid(format!("{} has unknown key `{}`", self.what, key)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PIPELINE: &str = r#"
+flush_interval_ms = 500
+
+[[source]]
+name = "statsd"
+transport = "tcp"
+port = 8125
+format = "statsd"
+to = ["low", "high"]
+
+[[filter]]
+name = "low"
+type = "low"
+limit = 100
+to = ["lower", "ckms"]
+
+[[filter]]
+name = "lower"
+type = "low"
+limit = 10.5
+to = ["ckms"]
+
+[[filter]]
+name = "high"
+type = "high"
+limit = 100
+to = ["ckms"]
+policy = "spill"
+spill_path = "/tmp/high.spill"
+
+[[egress]]
+name = "ckms"
+type = "ckms"
+error = 0.01
+capacity = 5
+policy = "drop-oldest"
+"#;
+
+    fn error(input: &str) -> String {
+        input.parse::<Config>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn reads_a_pipeline() {
+        let config: Config = PIPELINE.parse().unwrap();
+        assert_eq!(Duration::from_millis(500), config.flush_interval);
+        assert_eq!(
+            Source {
+                name: "statsd".to_string(),
+                transport: Transport::Tcp,
+                host: DEFAULT_HOST.to_string(),
+                port: 8125,
+                format: Format::Statsd,
+                to: vec!["low".to_string(), "high".to_string()],
+            },
+            config.sources[0]
+        );
+        assert_eq!(FilterKind::Low { limit: 10.5 }, config.filters[1].kind);
+        assert_eq!(
+            Queue {
+                capacity: DEFAULT_CAPACITY,
+                policy: Policy::Spill(PathBuf::from("/tmp/high.spill")),
+            },
+            config.filters[2].queue
+        );
+        assert_eq!(
+            EgressStage {
+                name: "ckms".to_string(),
+                kind: EgressKind::Ckms {
+                    config: CKMSConfig {
+                        error: 0.01,
+                        window: None,
+                    },
+                    shards: 1,
+                },
+                queue: Queue {
+                    capacity: 5,
+                    policy: Policy::DropOldest,
+                },
+            },
+            config.egresses[0]
+        );
+
+        assert_eq!(vec!["low", "high"], config.roots());
+        assert_eq!(1, config.upstreams("low"));
+        assert_eq!(1, config.upstreams("lower"));
+        assert_eq!(3, config.upstreams("ckms"));
+    }
+
+    #[test]
+    fn rejects_bad_topologies() {
+        assert_eq!(
+            "filter `lower` sends to `ckm`, which is not defined",
+            error(&PIPELINE.replacen("to = [\"ckms\"]", "to = [\"ckm\"]", 1))
+        );
+        assert_eq!(
+            "filter `low` sends to `statsd`, which is a source",
+            error(&PIPELINE.replace("\"lower\", \"ckms\"", "\"statsd\""))
+        );
+        assert_eq!(
+            "filters form a cycle: low -> lower -> low",
+            error(&PIPELINE.replacen("to = [\"ckms\"]", "to = [\"low\"]", 1))
+        );
+        assert_eq!(
+            "filter `lower` sends to no stage",
+            error(&PIPELINE.replacen("to = [\"ckms\"]", "to = []", 1))
+        );
+        assert_eq!(
+            "`lower` is not fed by any source",
+            error(&PIPELINE.replace("\"lower\", \"ckms\"", "\"ckms\""))
+        );
+        assert_eq!(
+            "`low` is defined twice",
+            error(&PIPELINE.replace("name = \"high\"", "name = \"low\""))
+        );
+        let no_egress = &PIPELINE[..PIPELINE.find("[[egress]]").unwrap()];
+        assert_eq!("no egress is configured", error(no_egress));
+    }
+
+    /// PIPELINE with its `lower` filter replaced by `kind`.
+    fn lower(kind: &str) -> String {
+        PIPELINE.replace("type = \"low\"\nlimit = 10.5", kind)
+    }
+
+    fn lower_kind(kind: &str) -> FilterKind {
+        lower(kind).parse::<Config>().unwrap().filters[1].kind.clone()
+    }
+
+    #[test]
+    fn reads_range_filters() {
+        assert_eq!(
+            FilterKind::Range { min: 1.0, max: 9.5 },
+            lower_kind("type = \"range\"\nmin = 1\nmax = 9.5")
+        );
+        assert_eq!(
+            FilterKind::Range {
+                min: f64::NEG_INFINITY,
+                max: 9.5
+            },
+            lower_kind("type = \"range\"\nmax = 9.5")
+        );
+        assert_eq!(
+            "filter `lower` needs a min or a max",
+            error(&lower("type = \"range\""))
+        );
+        assert_eq!(
+            "filter `lower` has a min above its max",
+            error(&lower("type = \"range\"\nmin = 2\nmax = 1"))
+        );
+    }
+
+    #[test]
+    fn reads_name_filters() {
+        assert_eq!(
+            FilterKind::Name {
+                allow: vec!["api.*".to_string()],
+                deny: vec!["*.debug".to_string()],
+            },
+            lower_kind("type = \"name\"\nallow = [\"api.*\"]\ndeny = [\"*.debug\"]")
+        );
+        assert_eq!(
+            "`allow` of filter `lower` must be an array of strings, not string",
+            error(&lower("type = \"name\"\nallow = \"api.*\""))
+        );
+    }
+
+    #[test]
+    fn reads_sample_filters() {
+        assert_eq!(
+            FilterKind::Sample {
+                sampling: Sampling::OneIn(10),
+                seed: None,
+            },
+            lower_kind("type = \"sample\"\none_in = 10")
+        );
+        assert_eq!(
+            FilterKind::Sample {
+                sampling: Sampling::Probability(0.25),
+                seed: Some(7),
+            },
+            lower_kind("type = \"sample\"\nprobability = 0.25\nseed = 7")
+        );
+        assert_eq!(
+            "filter `lower` needs either one_in or probability",
+            error(&lower("type = \"sample\"\none_in = 10\nprobability = 0.5"))
+        );
+        assert_eq!(
+            "filter `lower` needs a probability in (0, 1]",
+            error(&lower("type = \"sample\"\nprobability = 1.5"))
+        );
+        assert_eq!(
+            "filter `lower` needs a positive one_in",
+            error(&lower("type = \"sample\"\none_in = 0"))
+        );
+    }
+
+    #[test]
+    fn reads_rate_limit_filters() {
+        assert_eq!(
+            FilterKind::RateLimit {
+                limit: 100,
+                period: Duration::from_secs(1),
+            },
+            lower_kind("type = \"rate_limit\"\nlimit = 100\nperiod_ms = 1000")
+        );
+        assert_eq!(
+            "filter `lower` is missing `period_ms`",
+            error(&lower("type = \"rate_limit\"\nlimit = 100"))
+        );
+        assert_eq!(
+            "filter `lower` needs a positive limit",
+            error(&lower("type = \"rate_limit\"\nlimit = 0\nperiod_ms = 1000"))
+        );
+    }
+
+    #[test]
+    fn reads_relabel_filters() {
+        let relabel = r#"type = "relabel"
+rules = [
+    { rename = "^api\\.(.*)", replacement = "svc.$1" },
+    { prefix = "prod." },
+    { set_tag = "dc", value = "ams" },
+    { rename_tag = "host", to = "instance" },
+    { drop_tag = "pid" },
+]"#;
+        assert_eq!(
+            FilterKind::Relabel {
+                rules: vec![
+                    Relabel::Rename {
+                        regex: "^api\\.(.*)".to_string(),
+                        replacement: "svc.$1".to_string(),
+                    },
+                    Relabel::Prefix("prod.".to_string()),
+                    Relabel::SetTag {
+                        key: "dc".to_string(),
+                        value: "ams".to_string(),
+                    },
+                    Relabel::RenameTag {
+                        from: "host".to_string(),
+                        to: "instance".to_string(),
+                    },
+                    Relabel::DropTag("pid".to_string()),
+                ],
+            },
+            lower_kind(relabel)
+        );
+        assert!(error(&lower("type = \"relabel\"\nrules = [{ rename = \"(\" }]"))
+            .starts_with("rule 1 of filter `lower` has a bad regex"));
+        assert_eq!(
+            "rule 1 of filter `lower` is missing `value`",
+            error(&lower("type = \"relabel\"\nrules = [{ set_tag = \"dc\" }]"))
+        );
+        assert_eq!(
+            "rule 1 of filter `lower` has unknown key `drop_tag`",
+            error(&lower(
+                "type = \"relabel\"\nrules = [{ drop_tag = \"a\", prefix = \"b\" }]"
+            ))
+        );
+    }
+
+    #[test]
+    fn reads_filter_chains() {
+        let chain = r#"type = "chain"
+filters = [
+    { type = "name", deny = ["*.debug"] },
+    { type = "sample", one_in = 4 },
+]"#;
+        assert_eq!(
+            FilterKind::Chain {
+                filters: vec![
+                    FilterKind::Name {
+                        allow: Vec::new(),
+                        deny: vec!["*.debug".to_string()],
+                    },
+                    FilterKind::Sample {
+                        sampling: Sampling::OneIn(4),
+                        seed: None,
+                    },
+                ],
+            },
+            lower_kind(chain)
+        );
+        let unlimited = r#"type = "chain"
+filters = [{ type = "low", limit = 1 }, { type = "high" }]"#;
+        assert_eq!(
+            "filter 2 of filter `lower` is missing `limit`",
+            error(&lower(unlimited))
+        );
+        assert_eq!(
+            "filter 1 of filter `lower` has unknown key `to`",
+            error(&lower(
+                "type = \"chain\"\nfilters = [{ type = \"low\", limit = 1, to = [] }]"
+            ))
+        );
+    }
+
+    #[test]
+    fn reads_windows() {
+        let windowed = PIPELINE.replace(
+            "capacity = 5",
+            "window_ms = 60000\nslide_ms = 10000\nretain = 3",
+        );
+        let config: Config = windowed.parse().unwrap();
+        let window = Window::sliding(Duration::from_secs(60), Duration::from_secs(10));
+        assert_eq!(
+            EgressKind::Ckms {
+                config: CKMSConfig {
+                    error: 0.01,
+                    window: Some(window.retaining(3)),
+                },
+                shards: 1,
+            },
+            config.egresses[0].kind
+        );
+
+        let decaying = PIPELINE.replace(
+            "type = \"ckms\"\nerror = 0.01",
+            "type = \"cma\"\nhalf_life_ms = 30000",
+        );
+        let config: Config = decaying.parse().unwrap();
+        assert_eq!(
+            EgressKind::Cma {
+                config: CMAConfig {
+                    window: None,
+                    half_life: Some(Duration::from_secs(30)),
+                },
+                shards: 1,
+            },
+            config.egresses[0].kind
+        );
+
+        assert_eq!(
+            "egress `ckms` needs a window_ms that is a multiple of its slide_ms",
+            error(&PIPELINE.replace("capacity = 5", "window_ms = 1000\nslide_ms = 300"))
+        );
+        assert_eq!(
+            "egress `ckms` is missing `window_ms`",
+            error(&PIPELINE.replace("capacity = 5", "retain = 2"))
+        );
+    }
+
+    #[test]
+    fn reads_shards() {
+        let sharded = PIPELINE.replace("capacity = 5", "shards = 8");
+        let config: Config = sharded.parse().unwrap();
+        match config.egresses[0].kind {
+            EgressKind::Ckms { shards, .. } => assert_eq!(8, shards),
+            ref other => panic!("expected ckms, not {:?}", other),
+        }
+        assert_eq!(
+            "egress `ckms` needs at least one shard",
+            error(&PIPELINE.replace("capacity = 5", "shards = 0"))
+        );
+        assert_eq!(
+            "egress `ckms` has unknown key `shards`",
+            error(&PIPELINE.replace(
+                "type = \"ckms\"\nerror = 0.01\ncapacity = 5",
+                "type = \"set\"\nshards = 2",
+            ))
+        );
+    }
+
+    #[test]
+    fn reads_sinks() {
+        let influx = PIPELINE.replace(
+            "type = \"ckms\"\nerror = 0.01",
+            "type = \"influx\"\naddress = \"metrics:8089\"\ntransport = \"udp\"",
+        );
+        let config: Config = influx.parse().unwrap();
+        assert_eq!(
+            EgressKind::Influx {
+                address: "metrics:8089".to_string(),
+                transport: Transport::Udp,
+                error: DEFAULT_SINK_ERROR,
+            },
+            config.egresses[0].kind
+        );
+
+        let prometheus = PIPELINE.replace(
+            "type = \"ckms\"",
+            "type = \"prometheus\"\naddress = \"[::]:9102\"",
+        );
+        let config: Config = prometheus.parse().unwrap();
+        assert_eq!(
+            EgressKind::Prometheus {
+                address: "[::]:9102".to_string(),
+                error: 0.01,
+            },
+            config.egresses[0].kind
+        );
+
+        assert_eq!(
+            "egress `ckms` needs an address of the form host:port, not `graphite`",
+            error(&PIPELINE.replace(
+                "type = \"ckms\"",
+                "type = \"graphite\"\naddress = \"graphite\"",
+            ))
+        );
+        assert_eq!(
+            "egress `ckms` is missing `transport`",
+            error(&PIPELINE.replace(
+                "type = \"ckms\"",
+                "type = \"influx\"\naddress = \"localhost:8089\"",
+            ))
+        );
+    }
+
+    #[test]
+    fn reads_an_admin_address() {
+        let config: Config = PIPELINE.parse().unwrap();
+        assert_eq!(None, config.admin_address);
+        let admin = PIPELINE.replace(
+            "flush_interval_ms = 500",
+            "flush_interval_ms = 500\nadmin_address = \"127.0.0.1:8126\"",
+        );
+        let config: Config = admin.parse().unwrap();
+        assert_eq!(Some("127.0.0.1:8126".to_string()), config.admin_address);
+        assert_eq!(
+            "the top level needs an address of the form host:port, not `8126`",
+            error(&admin.replace("127.0.0.1:8126", "8126"))
+        );
+    }
+
+    #[test]
+    fn reads_a_journal() {
+        let journaled = PIPELINE.replace(
+            "policy = \"drop-oldest\"",
+            "policy = \"journal\"\njournal_path = \"/var/lib/telem/ckms\"",
+        );
+        let config: Config = journaled.parse().unwrap();
+        assert_eq!(
+            Policy::Journal(PathBuf::from("/var/lib/telem/ckms")),
+            config.egresses[0].queue.policy
+        );
+
+        assert_eq!(
+            "filter `high` cannot journal; only an egress can",
+            error(&PIPELINE.replace(
+                "policy = \"spill\"\nspill_path",
+                "policy = \"journal\"\njournal_path",
+            ))
+        );
+        assert_eq!(
+            "egress `ckms` is missing `journal_path`",
+            error(&PIPELINE.replace("\"drop-oldest\"", "\"journal\""))
+        );
+        assert_eq!(
+            "egress `ckms` has a journal_path but does not journal",
+            error(&PIPELINE.replace("capacity = 5", "journal_path = \"/tmp/ckms\""))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_fields() {
+        assert_eq!(
+            "filter `high` is missing `spill_path`",
+            error(&PIPELINE.replace("spill_path = \"/tmp/high.spill\"", ""))
+        );
+        assert_eq!(
+            "egress `ckms` has unknown key `eror`",
+            error(&PIPELINE.replace("capacity = 5", "eror = 5"))
+        );
+        assert_eq!(
+            "`port` of source `statsd` must be an integer, not string",
+            error(&PIPELINE.replace("port = 8125", "port = \"8125\""))
+        );
+        assert_eq!(
+            "source `statsd` has unknown transport `sctp`",
+            error(&PIPELINE.replace("\"tcp\"", "\"sctp\""))
+        );
+        let syntax = error(&PIPELINE.replace("500", "soon"));
+        assert!(syntax.starts_with("line 2: invalid string"), "{}", syntax);
+    }
+}
@@ -1,42 +1,204 @@
-use egress::Egress;
+use egress::{Merge, Report, Scalars, Shardable, Window, Windows};
+use emitter::Emitter;
 use event;
 use quantiles;
-use util;
+use std::sync::Arc;
+use std::time::Instant;
 
+/// The quantiles reported of each distribution.
+const QUANTILES: [f64; 6] = [0.0, 0.25, 0.5, 0.75, 0.9, 0.99];
+
+impl Merge for quantiles::ckms::CKMS<f64> {
+    fn merge(&mut self, other: &Self) {
+        *self += other.clone();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CKMSConfig {
+    /// The error bound of the quantiles reported.
+    pub error: f64,
+    /// Quantiles over all time without a window.
+    pub window: Option<Window>,
+}
+
+/// Reports quantiles of timers and histograms, alongside counters and
+/// gauges. Sets are left to `SetEgress`.
 pub struct CKMSEgress {
     error: f64,
-    data: util::HashMap<String, quantiles::ckms::CKMS<u32>>,
+    scalars: Scalars,
+    data: Windows<quantiles::ckms::CKMS<f64>>,
     new_data_since_last_report: bool,
 }
 
-impl Egress for CKMSEgress {
-    fn deliver(&mut self, event: event::Telemetry) -> () {
-        self.new_data_since_last_report = true;
-        let val = event.value;
-        let ckms = self.data
-            .entry(event.name)
-            .or_insert(quantiles::ckms::CKMS::new(self.error));
-        ckms.insert(val);
-    }
-
-    fn report(&mut self) -> () {
-        if self.new_data_since_last_report {
-            for (k, v) in &self.data {
-                for q in &[0.0, 0.25, 0.5, 0.75, 0.9, 0.99] {
-                    println!("[CKMS] {} {}:{}", k, q, v.query(*q).unwrap().1);
-                }
-            }
-            self.new_data_since_last_report = false;
+impl Emitter<CKMSConfig> for CKMSEgress {
+    fn init(config: CKMSConfig) -> Self {
+        CKMSEgress::with(config.error, config.window)
+    }
+
+    fn deliver(&mut self, event: Arc<event::Telemetry>) {
+        self.deliver_at(event, Instant::now());
+    }
+
+    fn deliver_batch(&mut self, batch: &mut Vec<Arc<event::Telemetry>>) {
+        let now = Instant::now();
+        for event in batch.drain(..) {
+            self.deliver_at(event, now);
         }
     }
+
+    fn flush(&mut self) {
+        self.report_at(Instant::now()).emit();
+    }
+}
+
+impl Shardable<CKMSConfig> for CKMSEgress {
+    fn take_report(&mut self) -> Report {
+        self.report_at(Instant::now())
+    }
 }
 
 impl CKMSEgress {
+    /// Reports quantiles over all time.
     pub fn new(error: f64) -> Self {
+        CKMSEgress::with(error, None)
+    }
+
+    /// Reports quantiles over each `window`.
+    pub fn windowed(error: f64, window: Window) -> Self {
+        CKMSEgress::with(error, Some(window))
+    }
+
+    fn with(error: f64, window: Option<Window>) -> Self {
         CKMSEgress {
-            error: error,
-            data: Default::default(),
+            error,
+            scalars: Default::default(),
+            data: Windows::new(window),
             new_data_since_last_report: false,
         }
     }
+
+    /// The `q` quantile of each retained window of `series`, newest first;
+    /// see `Window::retaining`.
+    pub fn history(&self, series: &str, q: f64) -> Vec<Option<f64>> {
+        self.data
+            .history(series, Instant::now())
+            .into_iter()
+            .map(|ckms| ckms.and_then(|ckms| ckms.query(q)).map(|(_, v)| v))
+            .collect()
+    }
+
+    fn deliver_at(&mut self, event: Arc<event::Telemetry>, now: Instant) {
+        let event = match self.scalars.deliver(event) {
+            None => {
+                self.new_data_since_last_report = true;
+                return;
+            }
+            Some(event) => event,
+        };
+        match event.kind {
+            event::MetricKind::Timer | event::MetricKind::Histogram => {
+                self.new_data_since_last_report = true;
+                let val = event.value;
+                let error = self.error;
+                self.data.update(
+                    event.series(),
+                    now,
+                    || quantiles::ckms::CKMS::new(error),
+                    |ckms| ckms.insert(val),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    /// Windowed quantiles change as their window slides, so they are
+    /// reported whether or not anything new arrived. Each earlier window
+    /// retained is reported after the current one, marked with how many
+    /// slides back it ends.
+    fn report_at(&mut self, now: Instant) -> Report {
+        let mut report = Report::new();
+        if self.new_data_since_last_report || self.data.is_windowed() {
+            for (k, v) in self.data.current(now) {
+                for q in &QUANTILES {
+                    let value = v.query(*q).unwrap().1;
+                    report.push(k, format!("[CKMS] {} {}:{}", k, q, value));
+                }
+                let history = self.data.history(k, now);
+                for (back, past) in history.iter().enumerate().skip(1) {
+                    if let Some(ref past) = *past {
+                        for q in &QUANTILES {
+                            let value = past.query(*q).unwrap().1;
+                            let line = format!("[CKMS] {} {}:{}", k, q, value);
+                            report.push(k, format!("{} window:-{}", line, back));
+                        }
+                    }
+                }
+                report.sketch(k, v);
+            }
+            self.data.expire(now);
+            self.scalars.report("CKMS", &mut report);
+            self.new_data_since_last_report = false;
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::{MetricKind, Telemetry};
+    use std::time::Duration;
+
+    #[test]
+    fn sliding_windows_report_recent_quantiles() {
+        let window = Window::sliding(Duration::from_secs(2), Duration::from_secs(1));
+        let mut egress = CKMSEgress::windowed(0.001, window);
+        let start = Instant::now();
+        for secs in 0..4 {
+            let at = start + Duration::from_secs(secs);
+            for value in 0..100 {
+                let value = (secs * 100 + value) as f64;
+                let telem = Telemetry::new("latency".to_string(), value, MetricKind::Timer);
+                egress.deliver_at(Arc::new(telem), at);
+            }
+        }
+        let now = start + Duration::from_secs(3);
+        let current = egress.data.current(now);
+        let ckms = &current[0].1;
+        assert_eq!(200, ckms.count());
+        assert_eq!(Some((1, 200.0)), ckms.query(0.0));
+        assert_eq!(399.0, ckms.query(1.0).unwrap().1);
+    }
+
+    #[test]
+    fn reports_carry_the_sketch_they_were_made_from() {
+        let mut egress = CKMSEgress::new(0.001);
+        for value in 1..5 {
+            let telem = Telemetry::new("latency", f64::from(value), MetricKind::Timer);
+            egress.deliver(Arc::new(telem));
+        }
+        let report = egress.report_at(Instant::now());
+        assert_eq!(1, report.sketches.len());
+        let (ref series, ref sketch) = report.sketches[0];
+        assert_eq!("latency", series);
+        assert_eq!(4, sketch.count());
+        assert_eq!(6, report.lines().len());
+    }
+
+    #[test]
+    fn retained_windows_are_reported_after_the_current_one() {
+        let window = Window::tumbling(Duration::from_secs(1)).retaining(3);
+        let mut egress = CKMSEgress::windowed(0.001, window);
+        let start = Instant::now();
+        for (secs, value) in [(0, 1.0), (2, 3.0)].iter() {
+            let telem = Telemetry::new("latency", *value, MetricKind::Timer);
+            egress.deliver_at(Arc::new(telem), start + Duration::from_secs(*secs));
+        }
+        let lines = egress.report_at(start + Duration::from_secs(2)).lines();
+        // The window a slide back is empty and left out.
+        assert_eq!(12, lines.len());
+        assert_eq!("[CKMS] latency 0.5:3", lines[2]);
+        assert_eq!("[CKMS] latency 0.5:1 window:-2", lines[8]);
+    }
 }
@@ -1,44 +1,266 @@
-use egress::Egress;
+use egress::{Merge, Report, Scalars, Shardable, Window, Windows};
+use emitter::Emitter;
 use event;
-use util;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-struct CMA {
-    n: u64,
+/// A cumulative moving average weighted by how many events each sample
+/// stands for.
+#[derive(Clone)]
+struct Cma {
+    weight: f64,
     cma: f64,
+    updated: Instant,
 }
 
+impl Cma {
+    fn new(now: Instant) -> Cma {
+        Cma {
+            weight: 0.0,
+            cma: 0.0,
+            updated: now,
+        }
+    }
+
+    /// Adds a sample, first decaying the weight of those before it by
+    /// half for every `half_life` since the last.
+    fn insert(&mut self, val: f64, weight: f64, now: Instant, half_life: Option<Duration>) {
+        if let Some(half_life) = half_life {
+            let elapsed = now.saturating_duration_since(self.updated);
+            self.weight *= 0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64());
+        }
+        self.updated = now;
+        self.weight += weight;
+        self.cma += weight * (val - self.cma) / self.weight;
+    }
+}
+
+impl Merge for Cma {
+    fn merge(&mut self, other: &Cma) {
+        let weight = self.weight + other.weight;
+        if weight > 0.0 {
+            self.cma = (self.weight * self.cma + other.weight * other.cma) / weight;
+        }
+        self.weight = weight;
+        self.updated = self.updated.max(other.updated);
+    }
+}
+
+/// An average over each `window`, or over all time without one, decaying
+/// if given a `half_life`. Windowed averages do not decay.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CMAConfig {
+    pub window: Option<Window>,
+    pub half_life: Option<Duration>,
+}
+
+/// Reports the mean of timers and histograms, alongside counters and
+/// gauges. Sets are left to `SetEgress`.
 pub struct CMAEgress {
-    data: util::HashMap<String, CMA>,
+    scalars: Scalars,
+    data: Windows<Cma>,
+    half_life: Option<Duration>,
     new_data_since_last_report: bool,
 }
 
-impl Egress for CMAEgress {
-    fn deliver(&mut self, event: event::Telemetry) -> () {
-        self.new_data_since_last_report = true;
-        let val = event.value;
-        let cma = self.data
-            .entry(event.name)
-            .or_insert(CMA { n: 0, cma: 0.0 });
-        cma.n += 1;
-        cma.cma = cma.cma + (((val as f64) - cma.cma) / (cma.n as f64));
+impl Emitter<CMAConfig> for CMAEgress {
+    fn init(config: CMAConfig) -> Self {
+        match (config.window, config.half_life) {
+            (Some(window), _) => CMAEgress::windowed(window),
+            (None, Some(half_life)) => CMAEgress::decaying(half_life),
+            (None, None) => CMAEgress::new(),
+        }
     }
 
-    fn report(&mut self) -> () {
-        if self.new_data_since_last_report {
-            for (k, v) in &self.data {
-                println!("[CMA] {} {}", k, v.cma);
-            }
-            self.new_data_since_last_report = false;
+    fn deliver(&mut self, event: Arc<event::Telemetry>) {
+        self.deliver_at(event, Instant::now());
+    }
+
+    fn deliver_batch(&mut self, batch: &mut Vec<Arc<event::Telemetry>>) {
+        let now = Instant::now();
+        for event in batch.drain(..) {
+            self.deliver_at(event, now);
         }
     }
+
+    fn flush(&mut self) {
+        self.report_at(Instant::now()).emit();
+    }
+}
+
+impl Shardable<CMAConfig> for CMAEgress {
+    fn take_report(&mut self) -> Report {
+        self.report_at(Instant::now())
+    }
 }
 
 impl CMAEgress {
-    /// TODO
+    /// Averages over all time.
     pub fn new() -> Self {
+        CMAEgress::with(None, None)
+    }
+
+    /// Averages over each `window`.
+    pub fn windowed(window: Window) -> Self {
+        CMAEgress::with(Some(window), None)
+    }
+
+    /// Averages over all time, with each sample's weight halving every
+    /// `half_life`, so the average follows recent behaviour.
+    ///
+    /// # Panics
+    ///
+    /// If `half_life` is zero.
+    pub fn decaying(half_life: Duration) -> Self {
+        assert!(half_life > Duration::from_secs(0), "half-life must be positive");
+        CMAEgress::with(None, Some(half_life))
+    }
+
+    fn with(window: Option<Window>, half_life: Option<Duration>) -> Self {
         CMAEgress {
-            data: Default::default(),
+            scalars: Default::default(),
+            data: Windows::new(window),
+            half_life,
             new_data_since_last_report: false,
         }
     }
+
+    /// The means of the retained windows of `series`, newest first; see
+    /// `Window::retaining`.
+    pub fn history(&self, series: &str) -> Vec<Option<f64>> {
+        self.data
+            .history(series, Instant::now())
+            .into_iter()
+            .map(|cma| cma.map(|cma| cma.cma))
+            .collect()
+    }
+
+    fn deliver_at(&mut self, event: Arc<event::Telemetry>, now: Instant) {
+        let event = match self.scalars.deliver(event) {
+            None => {
+                self.new_data_since_last_report = true;
+                return;
+            }
+            Some(event) => event,
+        };
+        match event.kind {
+            event::MetricKind::Timer | event::MetricKind::Histogram => {
+                self.new_data_since_last_report = true;
+                let val = event.value;
+                let weight = 1.0 / event.sample_rate;
+                let half_life = self.half_life;
+                self.data.update(
+                    event.series(),
+                    now,
+                    || Cma::new(now),
+                    |cma| cma.insert(val, weight, now, half_life),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    /// A windowed average changes as its window slides, so it is reported
+    /// whether or not anything new arrived. Each earlier window retained is
+    /// reported after the current one, marked with how many slides back it
+    /// ends.
+    fn report_at(&mut self, now: Instant) -> Report {
+        let mut report = Report::new();
+        if self.new_data_since_last_report || self.data.is_windowed() {
+            for (k, v) in self.data.current(now) {
+                report.push(k, format!("[CMA] {} {}", k, v.cma));
+                let history = self.data.history(k, now);
+                for (back, past) in history.iter().enumerate().skip(1) {
+                    if let Some(ref past) = *past {
+                        let line = format!("[CMA] {} {}", k, past.cma);
+                        report.push(k, format!("{} window:-{}", line, back));
+                    }
+                }
+            }
+            self.data.expire(now);
+            self.scalars.report("CMA", &mut report);
+            self.new_data_since_last_report = false;
+        }
+        report
+    }
+}
+
+impl Default for CMAEgress {
+    fn default() -> Self {
+        CMAEgress::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::{MetricKind, Telemetry};
+
+    fn timer(value: f64) -> Telemetry {
+        Telemetry::new("latency".to_string(), value, MetricKind::Timer)
+    }
+
+    fn mean(egress: &CMAEgress, now: Instant) -> Option<f64> {
+        egress.data.current(now).first().map(|(_, cma)| cma.cma)
+    }
+
+    #[test]
+    fn samples_are_weighted_by_rate() {
+        let mut egress = CMAEgress::new();
+        let mut sampled = timer(10.0);
+        sampled.sample_rate = 0.25;
+        let now = Instant::now();
+        egress.deliver_at(Arc::new(sampled), now);
+        egress.deliver_at(Arc::new(timer(0.0)), now);
+        // Four events at 10 and one at 0.
+        assert_eq!(Some(8.0), mean(&egress, now));
+    }
+
+    #[test]
+    fn windows_forget_old_samples() {
+        let window = Window::tumbling(Duration::from_secs(60)).retaining(2);
+        let mut egress = CMAEgress::windowed(window);
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+        egress.deliver_at(Arc::new(timer(10.0)), start);
+        egress.deliver_at(Arc::new(timer(20.0)), start);
+        egress.report_at(start + minute);
+        egress.deliver_at(Arc::new(timer(1.0)), start + minute);
+        assert_eq!(Some(1.0), mean(&egress, start + minute));
+        assert_eq!(
+            vec![Some(1.0), Some(15.0)],
+            egress
+                .data
+                .history("latency", start + minute)
+                .into_iter()
+                .map(|cma| cma.map(|cma| cma.cma))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn retained_windows_are_reported_after_the_current_one() {
+        let window = Window::tumbling(Duration::from_secs(60)).retaining(2);
+        let mut egress = CMAEgress::windowed(window);
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+        egress.deliver_at(Arc::new(timer(10.0)), start);
+        egress.deliver_at(Arc::new(timer(20.0)), start);
+        egress.deliver_at(Arc::new(timer(1.0)), start + minute);
+        assert_eq!(
+            vec!["[CMA] latency 1", "[CMA] latency 15 window:-1"],
+            egress.report_at(start + minute).lines()
+        );
+    }
+
+    #[test]
+    fn decay_favours_recent_samples() {
+        let mut egress = CMAEgress::decaying(Duration::from_secs(10));
+        let start = Instant::now();
+        egress.deliver_at(Arc::new(timer(0.0)), start);
+        // After one half-life the first sample weighs half the second.
+        egress.deliver_at(Arc::new(timer(30.0)), start + Duration::from_secs(10));
+        let mean = mean(&egress, start + Duration::from_secs(10)).unwrap();
+        assert!((mean - 20.0).abs() < 1e-9, "mean {}", mean);
+    }
 }
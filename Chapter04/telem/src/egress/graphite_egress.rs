@@ -0,0 +1,147 @@
+use egress::net::Connection;
+use egress::summary::{percentile, Stats, Summarizer, Summary};
+use egress::Egress;
+use event;
+use ingest_point::Transport;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Forwards each flush's summaries to a Graphite (Carbon) plaintext
+/// listener over TCP, as `name;tag=value value timestamp` lines.
+/// Distributions become several metrics, suffixed `.count`, `.sum`,
+/// `.mean`, `.min`, `.max` and one per quantile, as in `.p99`.
+pub struct GraphiteEgress {
+    summarizer: Summarizer,
+    conn: Connection,
+}
+
+impl Egress for GraphiteEgress {
+    fn deliver(&mut self, event: Arc<event::Telemetry>) {
+        self.summarizer.deliver(event);
+    }
+
+    fn report(&mut self) {
+        let timestamp = event::now();
+        let lines = self
+            .summarizer
+            .flush()
+            .iter()
+            .flat_map(|summary| lines(summary, timestamp))
+            .collect::<Vec<_>>();
+        self.conn.send(lines);
+    }
+}
+
+impl GraphiteEgress {
+    /// Forwards to `address`, a `host:port`, with quantiles accurate to
+    /// within `error`.
+    pub fn new(address: &str, error: f64) -> Self {
+        GraphiteEgress {
+            summarizer: Summarizer::new(error),
+            conn: Connection::new(address.to_string(), Transport::Tcp),
+        }
+    }
+
+    /// Retries a lost connection after `min`, doubling the wait after each
+    /// failure up to `max`.
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.conn = self.conn.with_backoff(min, max);
+        self
+    }
+
+    /// Lines dropped so far because the connection stayed down for too
+    /// long; see `MAX_PENDING`.
+    pub fn dropped(&self) -> usize {
+        self.conn.dropped()
+    }
+}
+
+/// Graphite takes neither whitespace in a path nor `;`, `=` or an empty
+/// value in a tag, so whitespace becomes `_` and bad tags are left off.
+fn lines(summary: &Summary, timestamp: u64) -> Vec<String> {
+    let name = sanitize(&summary.name);
+    let mut tags = String::new();
+    for (key, value) in &summary.tags {
+        let bad = |s: &str| s.is_empty() || s.contains([';', '=']);
+        if !bad(key) && !bad(value) {
+            tags.push(';');
+            tags.push_str(&sanitize(key));
+            tags.push('=');
+            tags.push_str(&sanitize(value));
+        }
+    }
+    let line = |suffix: &str, value: f64| {
+        format!("{}{}{} {} {}\n", name, suffix, tags, value, timestamp)
+    };
+    match summary.stats {
+        Stats::Counter(value) | Stats::Gauge(value) => vec![line("", value)],
+        Stats::Set(members) => vec![line("", members as f64)],
+        Stats::Distribution {
+            count,
+            sum,
+            min,
+            max,
+            ref quantiles,
+        } => {
+            let mut lines = vec![
+                line(".count", count),
+                line(".sum", sum),
+                line(".mean", sum / count),
+                line(".min", min),
+                line(".max", max),
+            ];
+            for &(q, value) in quantiles {
+                lines.push(line(&format!(".{}", percentile(q)), value));
+            }
+            lines
+        }
+    }
+}
+
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_whitespace() { '_' } else { c })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats_plaintext_lines() {
+        let counter = Summary {
+            name: "http hits".to_string(),
+            tags: vec![
+                ("empty".to_string(), String::new()),
+                ("host".to_string(), "web 1".to_string()),
+            ],
+            stats: Stats::Counter(3.0),
+        };
+        assert_eq!(vec!["http_hits;host=web_1 3 1000\n"], lines(&counter, 1000));
+
+        let timer = Summary {
+            name: "latency".to_string(),
+            tags: Vec::new(),
+            stats: Stats::Distribution {
+                count: 4.0,
+                sum: 10.0,
+                min: 1.0,
+                max: 4.0,
+                quantiles: vec![(0.5, 2.0), (0.99, 4.0)],
+            },
+        };
+        assert_eq!(
+            vec![
+                "latency.count 4 7\n",
+                "latency.sum 10 7\n",
+                "latency.mean 2.5 7\n",
+                "latency.min 1 7\n",
+                "latency.max 4 7\n",
+                "latency.p50 2 7\n",
+                "latency.p99 4 7\n",
+            ],
+            lines(&timer, 7)
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use egress::net::Connection;
+use egress::summary::{percentile, Stats, Summarizer, Summary};
+use egress::Egress;
+use event;
+use ingest_point::Transport;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Forwards each flush's summaries to InfluxDB, or anything else that
+/// speaks its line protocol, over UDP or TCP. Each series is one point
+/// in a measurement named for the metric: counters, gauges and sets have
+/// a `value` field, distributions `count`, `sum`, `mean`, `min`, `max`
+/// and one field per quantile, as in `p99`.
+pub struct InfluxEgress {
+    summarizer: Summarizer,
+    conn: Connection,
+}
+
+impl Egress for InfluxEgress {
+    fn deliver(&mut self, event: Arc<event::Telemetry>) {
+        self.summarizer.deliver(event);
+    }
+
+    fn report(&mut self) {
+        let timestamp = event::now();
+        let lines = self
+            .summarizer
+            .flush()
+            .iter()
+            .filter_map(|summary| line(summary, timestamp))
+            .collect::<Vec<_>>();
+        self.conn.send(lines);
+    }
+}
+
+impl InfluxEgress {
+    /// Forwards to `address`, a `host:port`, with quantiles accurate to
+    /// within `error`.
+    pub fn new(address: &str, transport: Transport, error: f64) -> Self {
+        InfluxEgress {
+            summarizer: Summarizer::new(error),
+            conn: Connection::new(address.to_string(), transport),
+        }
+    }
+
+    /// Retries a lost connection after `min`, doubling the wait after each
+    /// failure up to `max`.
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.conn = self.conn.with_backoff(min, max);
+        self
+    }
+
+    /// Lines dropped so far because the connection stayed down for too
+    /// long; see `MAX_PENDING`.
+    pub fn dropped(&self) -> usize {
+        self.conn.dropped()
+    }
+}
+
+/// The point for `summary` at `timestamp` seconds, in nanoseconds as the
+/// protocol expects by default. Tags without a value, and fields that are
+/// not finite, are left off, as the protocol has no way to write them; a
+/// point left without fields is no point at all.
+fn line(summary: &Summary, timestamp: u64) -> Option<String> {
+    let fields: Vec<(String, f64)> = match summary.stats {
+        Stats::Counter(value) | Stats::Gauge(value) => {
+            vec![("value".to_string(), value)]
+        }
+        Stats::Set(members) => vec![("value".to_string(), members as f64)],
+        Stats::Distribution {
+            count,
+            sum,
+            min,
+            max,
+            ref quantiles,
+        } => {
+            let mut fields = vec![
+                ("count".to_string(), count),
+                ("sum".to_string(), sum),
+                ("mean".to_string(), sum / count),
+                ("min".to_string(), min),
+                ("max".to_string(), max),
+            ];
+            fields.extend(quantiles.iter().map(|&(q, value)| (percentile(q), value)));
+            fields
+        }
+    };
+    let fields: Vec<String> = fields
+        .into_iter()
+        .filter(|&(_, value)| value.is_finite())
+        .map(|(key, value)| format!("{}={}", escape(&key, ",= "), value))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    let mut line = escape(&summary.name, ", ");
+    for (key, value) in &summary.tags {
+        if !key.is_empty() && !value.is_empty() {
+            line.push(',');
+            line.push_str(&escape(key, ",= "));
+            line.push('=');
+            line.push_str(&escape(value, ",= "));
+        }
+    }
+    line.push(' ');
+    line.push_str(&fields.join(","));
+    line.push_str(&format!(" {}\n", timestamp * 1_000_000_000));
+    Some(line)
+}
+
+/// Backslashes each of `special` in `s`. A newline cannot be escaped, so
+/// it becomes a space, and is escaped as one.
+fn escape(s: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        let c = if c == '\n' { ' ' } else { c };
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats_line_protocol() {
+        let gauge = Summary {
+            name: "queue depth,max".to_string(),
+            tags: vec![
+                ("empty".to_string(), String::new()),
+                ("host name".to_string(), "a=b".to_string()),
+            ],
+            stats: Stats::Gauge(-1.5),
+        };
+        assert_eq!(
+            Some("queue\\ depth\\,max,host\\ name=a\\=b value=-1.5 12000000000\n"),
+            line(&gauge, 12).as_deref()
+        );
+
+        let timer = Summary {
+            name: "latency".to_string(),
+            tags: Vec::new(),
+            stats: Stats::Distribution {
+                count: 2.0,
+                sum: 3.0,
+                min: 1.0,
+                max: 2.0,
+                quantiles: vec![(0.5, 1.0), (0.9, f64::NAN)],
+            },
+        };
+        assert_eq!(
+            Some("latency count=2,sum=3,mean=1.5,min=1,max=2,p50=1 0\n"),
+            line(&timer, 0).as_deref()
+        );
+
+        let nothing = Summary {
+            name: "x".to_string(),
+            tags: Vec::new(),
+            stats: Stats::Gauge(f64::INFINITY),
+        };
+        assert_eq!(None, line(&nothing, 0));
+    }
+}
@@ -1,23 +1,186 @@
+use admin;
+use channel;
+use emitter::{self, Step};
 use event;
-use std::sync::mpsc;
+use quantiles::ckms::CKMS;
+use std::io::{self, Write};
+use std::sync::Arc;
+use util;
 
 mod cma_egress;
 mod ckms_egress;
+mod graphite_egress;
+mod influx_egress;
+mod net;
+mod prometheus_egress;
+mod set_egress;
+mod sharded_egress;
+mod summary;
+mod window;
 
 pub use self::ckms_egress::*;
 pub use self::cma_egress::*;
+pub use self::graphite_egress::*;
+pub use self::influx_egress::*;
+pub use self::net::MAX_PENDING;
+pub use self::prometheus_egress::*;
+pub use self::set_egress::*;
+pub use self::sharded_egress::*;
+pub use self::summary::QUANTILES;
+pub use self::window::Window;
+use self::window::{Merge, Windows};
 
+/// A stage at the end of the pipeline. Egress with state of its own to
+/// configure is better written as an `emitter::Emitter`, which runs as an
+/// `Egress` in a `RunnableEmitter`.
 pub trait Egress {
-    fn deliver(&mut self, event: event::Telemetry) -> ();
+    fn deliver(&mut self, event: Arc<event::Telemetry>);
 
-    fn report(&mut self) -> ();
+    fn report(&mut self);
 
-    fn run(&mut self, recv: mpsc::Receiver<event::Event>) {
-        for event in recv.into_iter() {
-            match event {
-                event::Event::Telemetry(telem) => self.deliver(telem),
-                event::Event::Flush => self.report(),
+    /// Delivers events from `recv` until a shutdown or until nothing is
+    /// left upstream, making a final report either way.
+    fn run(&mut self, recv: &channel::Receiver) {
+        emitter::drive(recv, 1, None, |step| match step {
+            Step::Batch(batch) => {
+                for telem in batch.drain(..) {
+                    self.deliver(telem);
+                }
             }
+            Step::Flush | Step::Shutdown => self.report(),
+        });
+    }
+}
+
+/// What an egress prints on a flush, each line under the series it is
+/// about, so that the reports of several shards merge into one ordered by
+/// series. Distributions may come with the sketch they were reported from,
+/// for the admin interface to answer quantile queries with.
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    lines: Vec<(String, String)>,
+    sketches: Vec<(String, CKMS<f64>)>,
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report::default()
+    }
+
+    pub fn push(&mut self, series: &str, line: String) {
+        self.lines.push((series.to_string(), line));
+    }
+
+    pub fn sketch(&mut self, series: &str, sketch: CKMS<f64>) {
+        self.sketches.push((series.to_string(), sketch));
+    }
+
+    pub fn merge(&mut self, other: Report) {
+        self.lines.extend(other.lines);
+        self.sketches.extend(other.sketches);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// The lines ordered by series, and as pushed within a series.
+    pub fn lines(mut self) -> Vec<String> {
+        self.lines.sort_by(|a, b| a.0.cmp(&b.0));
+        self.lines.into_iter().map(|(_, line)| line).collect()
+    }
+
+    /// Publishes the series and sketches to `admin::board`, then prints
+    /// the report.
+    pub fn emit(mut self) {
+        let sketches = ::std::mem::take(&mut self.sketches);
+        let series = self.lines.iter().map(|(series, _)| series.as_str());
+        admin::board().publish(series, sketches);
+        self.print();
+    }
+
+    /// Prints the lines together, so that no other egress's are printed
+    /// among them.
+    pub fn print(self) {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        for line in self.lines() {
+            let _ = writeln!(out, "{}", line);
         }
     }
 }
+
+/// Counters and gauges, which every egress aggregates alike: counters are
+/// summed over a flush interval, scaled up by their sample rate, and gauges
+/// keep the last value written.
+#[derive(Default)]
+struct Scalars {
+    counters: util::HashMap<String, f64>,
+    gauges: util::HashMap<String, f64>,
+}
+
+impl Scalars {
+    /// Takes `telem` if it is a counter or a gauge, handing it back
+    /// otherwise.
+    fn deliver(
+        &mut self,
+        telem: Arc<event::Telemetry>,
+    ) -> Option<Arc<event::Telemetry>> {
+        match telem.kind {
+            event::MetricKind::Counter => {
+                *self.counters.entry(telem.series()).or_insert(0.0) +=
+                    telem.value / telem.sample_rate;
+                None
+            }
+            event::MetricKind::Gauge => {
+                self.gauges.insert(telem.series(), telem.value);
+                None
+            }
+            _ => Some(telem),
+        }
+    }
+
+    /// Reports this interval's counters, then starts the next interval.
+    /// Gauges are reported and kept.
+    fn report(&mut self, egress: &str, report: &mut Report) {
+        for (k, v) in self.counters.drain() {
+            report.push(&k, format!("[{}] {} counter:{}", egress, k, v));
+        }
+        for (k, v) in &self.gauges {
+            report.push(k, format!("[{}] {} gauge:{}", egress, k, v));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::{MetricKind, Telemetry};
+
+    #[test]
+    fn counters_sum_and_gauges_overwrite() {
+        let mut scalars = Scalars::default();
+        let mut sampled = Telemetry::new("hits".to_string(), 2.0, MetricKind::Counter);
+        sampled.sample_rate = 0.5;
+        assert!(scalars.deliver(Arc::new(sampled)).is_none());
+        let hits = Telemetry::new("hits".to_string(), 1.0, MetricKind::Counter);
+        assert!(scalars.deliver(Arc::new(hits)).is_none());
+        for level in &[3.0, -1.5] {
+            let depth = Telemetry::new("depth".to_string(), *level, MetricKind::Gauge);
+            assert!(scalars.deliver(Arc::new(depth)).is_none());
+        }
+        let timer = Telemetry::new("latency".to_string(), 1.0, MetricKind::Timer);
+        assert!(scalars.deliver(Arc::new(timer)).is_some());
+
+        assert_eq!(Some(&5.0), scalars.counters.get("hits"));
+        assert_eq!(Some(&-1.5), scalars.gauges.get("depth"));
+        let mut report = Report::new();
+        scalars.report("TEST", &mut report);
+        assert_eq!(
+            vec!["[TEST] depth gauge:-1.5", "[TEST] hits counter:5"],
+            report.lines()
+        );
+        assert!(scalars.counters.is_empty());
+        assert_eq!(Some(&-1.5), scalars.gauges.get("depth"));
+    }
+}
@@ -0,0 +1,256 @@
+//! Connections from egress to the systems it forwards results to.
+//!
+//! A connection that cannot be made or breaks is retried on later sends,
+//! waiting twice as long after each failure up to a limit. Lines are held
+//! until a send gets through, up to `MAX_PENDING` of them, past which the
+//! oldest are dropped.
+use ingest_point::Transport;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Lines held while a connection is down.
+pub const MAX_PENDING: usize = 100_000;
+
+/// Datagrams are packed with lines up to this many bytes, which fits an
+/// Ethernet frame after headers.
+const MAX_DATAGRAM: usize = 1_432;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The waits before retrying a connection, unless set `with_backoff`.
+const DEFAULT_MIN_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Exponential backoff between attempts at something that keeps failing.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    delay: Duration,
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Backoff {
+        Backoff {
+            min,
+            max,
+            delay: min,
+            retry_at: None,
+        }
+    }
+
+    /// Whether enough time has passed since the last failure to try again.
+    pub fn ready(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|at| now >= at)
+    }
+
+    /// How long to wait before trying again.
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    pub fn failed(&mut self, now: Instant) {
+        self.retry_at = Some(now + self.delay);
+        self.delay = (self.delay * 2).min(self.max);
+    }
+
+    pub fn succeeded(&mut self) {
+        self.delay = self.min;
+        self.retry_at = None;
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+/// A connection to `address` that reconnects as needed, holding lines
+/// while it is down. Over TCP, lines may be sent twice if a write breaks
+/// partway through.
+pub struct Connection {
+    address: String,
+    transport: Transport,
+    stream: Option<Stream>,
+    backoff: Backoff,
+    pending: VecDeque<String>,
+    dropped: usize,
+}
+
+impl Connection {
+    pub fn new(address: String, transport: Transport) -> Connection {
+        Connection {
+            address,
+            transport,
+            stream: None,
+            backoff: Backoff::new(DEFAULT_MIN_BACKOFF, DEFAULT_MAX_BACKOFF),
+            pending: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> Connection {
+        self.backoff = Backoff::new(min, max);
+        self
+    }
+
+    /// Lines dropped so far for want of a connection.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Queues `lines`, each ending in a newline, then sends everything
+    /// queued if the connection is up or may be retried.
+    pub fn send<I: IntoIterator<Item = String>>(&mut self, lines: I) {
+        for line in lines {
+            if self.pending.len() == MAX_PENDING {
+                self.pending.pop_front();
+                self.dropped += 1;
+            }
+            self.pending.push_back(line);
+        }
+        let now = Instant::now();
+        if self.pending.is_empty() || !self.backoff.ready(now) {
+            return;
+        }
+        match self.flush() {
+            Ok(()) => self.backoff.succeeded(),
+            Err(_) => {
+                self.stream = None;
+                self.backoff.failed(now);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.stream.as_mut().is_some_and(|stream| !is_open(stream)) {
+            self.stream = None;
+        }
+        if self.stream.is_none() {
+            self.stream = Some(self.connect()?);
+        }
+        match *self.stream.as_mut().unwrap() {
+            Stream::Tcp(ref mut stream) => {
+                let mut buf = String::new();
+                for line in &self.pending {
+                    buf.push_str(line);
+                }
+                stream.write_all(buf.as_bytes())?;
+                self.pending.clear();
+            }
+            Stream::Udp(ref socket) => {
+                // Lines leave the queue only once their datagram is sent.
+                while !self.pending.is_empty() {
+                    let mut datagram = String::new();
+                    let mut lines = 0;
+                    for line in &self.pending {
+                        if lines > 0 && datagram.len() + line.len() > MAX_DATAGRAM {
+                            break;
+                        }
+                        datagram.push_str(line);
+                        lines += 1;
+                    }
+                    socket.send(datagram.as_bytes())?;
+                    self.pending.drain(..lines);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn connect(&self) -> io::Result<Stream> {
+        let mut last_error = None;
+        for addr in self.address.to_socket_addrs()? {
+            let connected = match self.transport {
+                Transport::Tcp => connect_tcp(&addr).map(Stream::Tcp),
+                Transport::Udp => connect_udp(&addr).map(Stream::Udp),
+            };
+            match connected {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "address resolved to nothing")
+        }))
+    }
+}
+
+fn connect_tcp(addr: &SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(addr, CONNECT_TIMEOUT)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+fn connect_udp(addr: &SocketAddr) -> io::Result<UdpSocket> {
+    let local = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(addr)?;
+    Ok(socket)
+}
+
+/// Whether the far end of `stream` might still be listening. The sinks
+/// are never sent anything, so a TCP stream with something to read has
+/// been closed or reset by the far end; a write would vanish into it.
+fn is_open(stream: &mut Stream) -> bool {
+    let stream = match *stream {
+        Stream::Tcp(ref mut stream) => stream,
+        Stream::Udp(_) => return true,
+    };
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut byte = [0; 1];
+    let open = match stream.read(&mut byte) {
+        Err(ref e) => e.kind() == io::ErrorKind::WouldBlock,
+        Ok(_) => false,
+    };
+    open && stream.set_nonblocking(false).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_its_limit() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(3));
+        let now = Instant::now();
+        assert!(backoff.ready(now));
+        backoff.failed(now);
+        assert!(!backoff.ready(now));
+        assert!(backoff.ready(now + Duration::from_secs(1)));
+        assert_eq!(Duration::from_secs(2), backoff.delay());
+        backoff.failed(now);
+        backoff.failed(now);
+        assert_eq!(Duration::from_secs(3), backoff.delay());
+        assert!(!backoff.ready(now + Duration::from_secs(2)));
+        backoff.succeeded();
+        assert!(backoff.ready(now));
+        assert_eq!(Duration::from_secs(1), backoff.delay());
+    }
+
+    #[test]
+    fn lines_beyond_the_limit_drop_the_oldest() {
+        // Nothing listens on a port the kernel just handed out and took
+        // back, so nothing is sent.
+        let address = {
+            let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let mut conn = Connection::new(address, Transport::Tcp);
+        conn.send((0..MAX_PENDING + 2).map(|i| format!("{}\n", i)));
+        assert_eq!(MAX_PENDING, conn.pending.len());
+        assert_eq!(2, conn.dropped());
+        assert_eq!(Some("2\n"), conn.pending.front().map(|s| s.as_str()));
+    }
+}
@@ -0,0 +1,405 @@
+use egress::net::Backoff;
+use egress::summary::{Stats, Summarizer, Summary};
+use egress::Egress;
+use event;
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the listener looks for a shutdown between connections.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// The longest wait between attempts at accepting, so that a shutdown is
+/// not held up.
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+/// How long a scraper has to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// The longest request head read; scrapers send far less.
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// Serves what it aggregates at `/metrics`, in the Prometheus text
+/// exposition format, from a listener on its own thread. The page is
+/// rewritten at every flush.
+///
+/// Counters are exposed as cumulative `_total` counters and gauges and
+/// sets as gauges. Distributions become summaries: quantiles of the last
+/// flush interval, with a cumulative `_sum` and `_count`. A series stays
+/// on the page once seen, and a name already exposed as one type hides
+/// series of any other type that come to share it.
+pub struct PrometheusEgress {
+    summarizer: Summarizer,
+    families: BTreeMap<String, Family>,
+    page: Arc<Mutex<String>>,
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    listener: Option<thread::JoinHandle<()>>,
+}
+
+/// The series of one metric name, keyed by their rendered labels.
+struct Family {
+    kind: &'static str,
+    series: BTreeMap<String, Sample>,
+}
+
+enum Sample {
+    Counter(f64),
+    Gauge(f64),
+    Summary {
+        count: f64,
+        sum: f64,
+        quantiles: Vec<(f64, f64)>,
+    },
+}
+
+impl Egress for PrometheusEgress {
+    fn deliver(&mut self, event: Arc<event::Telemetry>) {
+        self.summarizer.deliver(event);
+    }
+
+    fn report(&mut self) {
+        for summary in self.summarizer.flush() {
+            self.absorb(summary);
+        }
+        let page = self.render();
+        *self.page.lock().unwrap_or_else(|e| e.into_inner()) = page;
+    }
+}
+
+impl PrometheusEgress {
+    /// Listens on `address` for scrapes, with quantiles accurate to within
+    /// `error`. Port 0 picks a free port; see `local_addr`.
+    pub fn bind<A: ToSocketAddrs>(address: A, error: f64) -> io::Result<Self> {
+        PrometheusEgress::from_listener(TcpListener::bind(address)?, error)
+    }
+
+    /// Serves scrapes from `listener`, bound already, so that an egress
+    /// restarted by its supervisor can serve from a clone of the socket its
+    /// predecessor did rather than binding again.
+    pub fn from_listener(listener: TcpListener, error: f64) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let page = Arc::new(Mutex::new(String::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (thread_page, thread_stop) = (Arc::clone(&page), Arc::clone(&stop));
+        let handle = thread::Builder::new()
+            .name(format!("prometheus {}", local_addr))
+            .spawn(move || serve(&listener, &thread_page, &thread_stop))?;
+        Ok(PrometheusEgress {
+            summarizer: Summarizer::new(error),
+            families: BTreeMap::new(),
+            page,
+            local_addr,
+            stop,
+            listener: Some(handle),
+        })
+    }
+
+    /// The address scrapes are served on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn absorb(&mut self, summary: Summary) {
+        let (name, kind) = match summary.stats {
+            Stats::Counter(_) => {
+                let name = metric_name(&summary.name);
+                if name.ends_with("_total") {
+                    (name, "counter")
+                } else {
+                    (name + "_total", "counter")
+                }
+            }
+            Stats::Gauge(_) | Stats::Set(_) => (metric_name(&summary.name), "gauge"),
+            Stats::Distribution { .. } => (metric_name(&summary.name), "summary"),
+        };
+        let family = self.families.entry(name).or_insert_with(|| Family {
+            kind,
+            series: BTreeMap::new(),
+        });
+        if family.kind != kind {
+            return;
+        }
+        let sample = family
+            .series
+            .entry(labels(&summary.tags))
+            .or_insert_with(|| match kind {
+                "counter" => Sample::Counter(0.0),
+                "gauge" => Sample::Gauge(0.0),
+                _ => Sample::Summary {
+                    count: 0.0,
+                    sum: 0.0,
+                    quantiles: Vec::new(),
+                },
+            });
+        match (sample, summary.stats) {
+            (&mut Sample::Counter(ref mut total), Stats::Counter(value)) => {
+                *total += value
+            }
+            (&mut Sample::Gauge(ref mut last), Stats::Gauge(value)) => *last = value,
+            (&mut Sample::Gauge(ref mut last), Stats::Set(members)) => {
+                *last = members as f64
+            }
+            (
+                &mut Sample::Summary {
+                    ref mut count,
+                    ref mut sum,
+                    ref mut quantiles,
+                },
+                Stats::Distribution {
+                    count: interval_count,
+                    sum: interval_sum,
+                    quantiles: interval_quantiles,
+                    ..
+                },
+            ) => {
+                *count += interval_count;
+                *sum += interval_sum;
+                *quantiles = interval_quantiles;
+            }
+            _ => unreachable!("sample and stats kinds agree"),
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut page = String::new();
+        for (name, family) in &self.families {
+            let _ = writeln!(page, "# TYPE {} {}", name, family.kind);
+            for (labels, sample) in &family.series {
+                match *sample {
+                    Sample::Counter(value) | Sample::Gauge(value) => {
+                        let _ = writeln!(
+                            page,
+                            "{}{} {}",
+                            name,
+                            braced(labels),
+                            float(value)
+                        );
+                    }
+                    Sample::Summary {
+                        count,
+                        sum,
+                        ref quantiles,
+                    } => {
+                        for &(q, value) in quantiles {
+                            let mut labels = labels.clone();
+                            if !labels.is_empty() {
+                                labels.push(',');
+                            }
+                            let _ = write!(labels, "quantile=\"{}\"", q);
+                            let _ = writeln!(
+                                page,
+                                "{}{{{}}} {}",
+                                name,
+                                labels,
+                                float(value)
+                            );
+                        }
+                        let labels = braced(labels);
+                        let _ =
+                            writeln!(page, "{}_sum{} {}", name, labels, float(sum));
+                        let _ = writeln!(
+                            page,
+                            "{}_count{} {}",
+                            name,
+                            labels,
+                            float(count)
+                        );
+                    }
+                }
+            }
+        }
+        page
+    }
+}
+
+impl Drop for PrometheusEgress {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.listener.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Answers scrapes until `stop`, backing off while accepting fails, as
+/// when the process is out of file descriptors.
+fn serve(listener: &TcpListener, page: &Mutex<String>, stop: &AtomicBool) {
+    let mut backoff = Backoff::new(POLL_INTERVAL, MAX_ACCEPT_BACKOFF);
+    while !stop.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                backoff.succeeded();
+                // A scraper that misbehaves only loses its own scrape.
+                let _ = respond(stream, page);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL)
+            }
+            Err(e) => {
+                backoff.failed(Instant::now());
+                eprintln!("[PROMETHEUS] accept failed: {}", e);
+                thread::sleep(backoff.delay());
+            }
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, page: &Mutex<String>) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(READ_TIMEOUT))?;
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut request = head.lines().next().unwrap_or("").split(' ');
+    let (method, target) = (request.next(), request.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or("");
+    let (status, body) = match (method, path) {
+        (Some("GET"), "/metrics") => (
+            "200 OK",
+            page.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+        ),
+        (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+/// `name` with every character Prometheus does not allow in a metric name
+/// made `_`.
+fn metric_name(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | ':' => c,
+            _ => '_',
+        })
+        .collect();
+    if !sanitized.starts_with(|c: char| !c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+/// Tags as Prometheus labels, `key="value"` joined by commas. Tags without
+/// a value are left off, as Prometheus treats an empty label as none.
+fn labels(tags: &[(String, String)]) -> String {
+    let mut labels = Vec::new();
+    for (key, value) in tags {
+        if value.is_empty() {
+            continue;
+        }
+        let mut key = metric_name(key).replace(':', "_");
+        if key.starts_with("__") {
+            // Reserved for Prometheus' own use.
+            key.insert_str(0, "tag");
+        }
+        let value = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        labels.push(format!("{}=\"{}\"", key, value));
+    }
+    labels.join(",")
+}
+
+fn braced(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    }
+}
+
+fn float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn summary(name: &str, tags: &[(&str, &str)], stats: Stats) -> Summary {
+        Summary {
+            name: name.to_string(),
+            tags: tags
+                .iter()
+                .map(|&(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            stats,
+        }
+    }
+
+    #[test]
+    fn renders_the_exposition_format() {
+        let mut egress = PrometheusEgress::bind("127.0.0.1:0", 0.001).unwrap();
+        for _ in 0..2 {
+            egress.absorb(summary(
+                "http.hits",
+                &[("code", "2\"00")],
+                Stats::Counter(2.0),
+            ));
+            egress.absorb(summary("users", &[], Stats::Set(3)));
+            egress.absorb(summary("users", &[], Stats::Counter(1.0)));
+            egress.absorb(summary(
+                "9lives",
+                &[("__name__", "x"), ("empty", "")],
+                Stats::Gauge(f64::INFINITY),
+            ));
+            egress.absorb(summary(
+                "latency",
+                &[("host", "a")],
+                Stats::Distribution {
+                    count: 2.0,
+                    sum: 3.0,
+                    min: 1.0,
+                    max: 2.0,
+                    quantiles: vec![(0.5, 1.0), (0.99, 2.0)],
+                },
+            ));
+        }
+        assert_eq!(
+            "# TYPE _9lives gauge\n\
+             _9lives{tag__name__=\"x\"} +Inf\n\
+             # TYPE http_hits_total counter\n\
+             http_hits_total{code=\"2\\\"00\"} 4\n\
+             # TYPE latency summary\n\
+             latency{host=\"a\",quantile=\"0.5\"} 1\n\
+             latency{host=\"a\",quantile=\"0.99\"} 2\n\
+             latency_sum{host=\"a\"} 6\n\
+             latency_count{host=\"a\"} 4\n\
+             # TYPE users gauge\n\
+             users 3\n\
+             # TYPE users_total counter\n\
+             users_total 2\n",
+            egress.render()
+        );
+    }
+}
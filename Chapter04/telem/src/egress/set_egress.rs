@@ -0,0 +1,72 @@
+use egress::{Egress, Report};
+use event;
+use std::collections::HashSet;
+use std::sync::Arc;
+use util;
+
+/// Reports how many distinct members each set metric saw over a flush
+/// interval. Other kinds are ignored.
+pub struct SetEgress {
+    data: util::HashMap<String, HashSet<u64>>,
+}
+
+impl Egress for SetEgress {
+    fn deliver(&mut self, event: Arc<event::Telemetry>) {
+        if event.kind != event::MetricKind::Set {
+            return;
+        }
+        // Members compare by value, so 0 and -0 are one member.
+        let member = if event.value == 0.0 { 0.0 } else { event.value };
+        self.data
+            .entry(event.series())
+            .or_default()
+            .insert(member.to_bits());
+    }
+
+    fn report(&mut self) {
+        let mut report = Report::new();
+        for (k, v) in self.data.drain() {
+            report.push(&k, format!("[SET] {} {}", k, v.len()));
+        }
+        report.emit();
+    }
+}
+
+impl SetEgress {
+    pub fn new() -> Self {
+        SetEgress {
+            data: Default::default(),
+        }
+    }
+}
+
+impl Default for SetEgress {
+    fn default() -> Self {
+        SetEgress::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::{MetricKind, Telemetry};
+
+    #[test]
+    fn counts_distinct_members_per_series() {
+        let mut egress = SetEgress::new();
+        for member in &[1.0, 2.0, 1.0, 0.0, -0.0] {
+            let users = Telemetry::new("users".to_string(), *member, MetricKind::Set);
+            egress.deliver(Arc::new(users));
+        }
+        let mut tagged = Telemetry::new("users".to_string(), 1.0, MetricKind::Set);
+        tagged.tags.push(("dc".to_string(), "ams".to_string()));
+        egress.deliver(Arc::new(tagged));
+        let gauge = Telemetry::new("users".to_string(), 9.0, MetricKind::Gauge);
+        egress.deliver(Arc::new(gauge));
+
+        assert_eq!(3, egress.data["users"].len());
+        assert_eq!(1, egress.data["users;dc=ams"].len());
+        egress.report();
+        assert!(egress.data.is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+use channel::{self, Policy};
+use egress::{Egress, Report};
+use emitter::{self, Emitter, Step};
+use event::{Event, Telemetry};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use util;
+
+/// Events queued for each shard before delivery blocks.
+const SHARD_CAPACITY: usize = 10_000;
+/// Telemetry a shard takes at a time. As with any emitter, a flush cuts a
+/// batch short.
+const SHARD_BATCH: usize = 64;
+
+/// An emitter whose state is kept apart per metric name, so that it can be
+/// split across the shards of a `ShardedEgress`.
+pub trait Shardable<EConfig>: Emitter<EConfig>
+where
+    EConfig: 'static + Send + Clone,
+{
+    /// Ends the interval as `flush` does, handing the report back rather
+    /// than printing it.
+    fn take_report(&mut self) -> Report;
+}
+
+struct Shard {
+    events: channel::Sender,
+    reports: mpsc::Receiver<Report>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Spreads aggregation over several threads, each running an emitter of
+/// its own over the metric names that hash to it. Every shard flushes
+/// when the egress does, and their reports are printed as one, ordered by
+/// series.
+///
+/// A shard that panics takes the egress down with it at the next event or
+/// flush sent its way, so that the stage is restarted whole.
+pub struct ShardedEgress {
+    shards: Vec<Shard>,
+}
+
+impl ShardedEgress {
+    /// `shards` threads, each running an `S` built from `config`.
+    ///
+    /// # Panics
+    ///
+    /// If `shards` is zero.
+    pub fn new<S, EConfig>(shards: usize, config: EConfig) -> ShardedEgress
+    where
+        S: Shardable<EConfig>,
+        EConfig: 'static + Send + Clone,
+    {
+        assert!(shards > 0, "an egress needs at least one shard");
+        let shards = (0..shards)
+            .map(|idx| {
+                let name = format!("shard.{}", idx);
+                let (events, recv) =
+                    channel::bounded(&name, SHARD_CAPACITY, Policy::Block);
+                let (report_snd, reports) = mpsc::channel();
+                let config = config.clone();
+                let thread = thread::Builder::new()
+                    .name(name)
+                    .spawn(move || run_shard::<S, EConfig>(config, &recv, &report_snd))
+                    .expect("cannot start a shard");
+                Shard {
+                    events,
+                    reports,
+                    thread: Some(thread),
+                }
+            })
+            .collect();
+        ShardedEgress { shards }
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+}
+
+fn run_shard<S, EConfig>(
+    config: EConfig,
+    recv: &channel::Receiver,
+    reports: &mpsc::Sender<Report>,
+) where
+    S: Shardable<EConfig>,
+    EConfig: 'static + Send + Clone,
+{
+    let mut state = S::init(config);
+    emitter::drive(recv, SHARD_BATCH, None, |step| match step {
+        Step::Batch(batch) => state.deliver_batch(batch),
+        Step::Flush => {
+            let _ = reports.send(state.take_report());
+        }
+        // The egress's last flush came before its shutdown.
+        Step::Shutdown => {}
+    });
+}
+
+impl Egress for ShardedEgress {
+    fn deliver(&mut self, event: Arc<Telemetry>) {
+        let idx = util::shard(&*event.name, self.shards.len());
+        let sent = self.shards[idx].events.send(Event::Telemetry(event));
+        if sent.is_err() {
+            panic!("shard {} has stopped", idx);
+        }
+    }
+
+    fn report(&mut self) {
+        for (idx, shard) in self.shards.iter().enumerate() {
+            if shard.events.send(Event::Flush).is_err() {
+                panic!("shard {} has stopped", idx);
+            }
+        }
+        let mut report = Report::new();
+        for (idx, shard) in self.shards.iter().enumerate() {
+            match shard.reports.recv() {
+                Ok(shard_report) => report.merge(shard_report),
+                Err(_) => panic!("shard {} stopped before reporting", idx),
+            }
+        }
+        report.emit();
+    }
+}
+
+impl Drop for ShardedEgress {
+    fn drop(&mut self) {
+        for shard in &self.shards {
+            let _ = shard.events.send(Event::Shutdown);
+        }
+        for shard in &mut self.shards {
+            if let Some(thread) = shard.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::MetricKind;
+
+    /// Reports each name it saw, the thread it saw it on and how often.
+    struct Tally {
+        names: util::HashMap<String, usize>,
+    }
+
+    impl Emitter<()> for Tally {
+        fn init(_: ()) -> Self {
+            Tally {
+                names: Default::default(),
+            }
+        }
+
+        fn deliver(&mut self, telem: Arc<Telemetry>) {
+            *self.names.entry(telem.name.to_string()).or_insert(0) += 1;
+        }
+
+        fn flush(&mut self) {}
+    }
+
+    impl Shardable<()> for Tally {
+        fn take_report(&mut self) -> Report {
+            let mut report = Report::new();
+            let thread = thread::current().name().unwrap_or("").to_string();
+            for (name, count) in self.names.drain() {
+                report.push(&name, format!("{} {} {}", name, count, thread));
+            }
+            report
+        }
+    }
+
+    #[test]
+    fn names_stay_on_one_shard_and_reports_merge_in_order() {
+        let shards = 4;
+        let mut egress = ShardedEgress::new::<Tally, ()>(shards, ());
+        let names: Vec<String> =
+            (0..32).map(|idx| format!("metric.{:02}", idx)).collect();
+        for _ in 0..3 {
+            for name in &names {
+                let telem = Telemetry::new(name.as_str(), 1.0, MetricKind::Counter);
+                egress.deliver(Arc::new(telem));
+            }
+        }
+
+        let mut report = Report::new();
+        for shard in &egress.shards {
+            shard.events.send(Event::Flush).unwrap();
+        }
+        for shard in &egress.shards {
+            report.merge(shard.reports.recv().unwrap());
+        }
+        let lines = report.lines();
+        assert_eq!(names.len(), lines.len());
+        let mut used = Vec::new();
+        for (line, name) in lines.iter().zip(&names) {
+            let expected = format!("shard.{}", util::shard(name.as_str(), shards));
+            assert_eq!(format!("{} 3 {}", name, expected), *line);
+            if !used.contains(&expected) {
+                used.push(expected);
+            }
+        }
+        assert!(used.len() > 1, "every name went to {:?}", used);
+    }
+
+    #[test]
+    fn flushes_reach_every_shard() {
+        let mut egress = ShardedEgress::new::<Tally, ()>(3, ());
+        egress.deliver(Arc::new(Telemetry::new("a", 1.0, MetricKind::Gauge)));
+        egress.report();
+        // Each shard reported once, so none has a report left over.
+        for shard in &egress.shards {
+            assert!(shard.reports.try_recv().is_err());
+        }
+        egress.report();
+        drop(egress);
+    }
+}
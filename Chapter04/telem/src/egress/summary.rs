@@ -0,0 +1,233 @@
+//! Per-interval summaries of every series, for the egresses that forward
+//! results elsewhere rather than print them.
+use event::{self, MetricKind};
+use quantiles::ckms::CKMS;
+use std::collections::HashSet;
+use std::sync::Arc;
+use util;
+
+/// The quantiles reported of every distribution.
+pub const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// What a series amounted to over one flush interval.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stats {
+    /// The sum, scaled up by sample rates.
+    Counter(f64),
+    /// The last value.
+    Gauge(f64),
+    /// Timers and histograms. The count is scaled up by sample rates.
+    Distribution {
+        count: f64,
+        sum: f64,
+        min: f64,
+        max: f64,
+        /// Each of `QUANTILES` paired with its value.
+        quantiles: Vec<(f64, f64)>,
+    },
+    /// The number of distinct members.
+    Set(usize),
+}
+
+/// How `q` is written in metric names: `p50` for 0.5, `p99_9` for 0.999.
+pub fn percentile(q: f64) -> String {
+    let percent = (q * 1000.0).round() / 10.0;
+    format!("p{}", percent).replace('.', "_")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub name: String,
+    /// Sorted by key.
+    pub tags: Vec<(String, String)>,
+    pub stats: Stats,
+}
+
+enum Accumulator {
+    Counter(f64),
+    Gauge(f64),
+    Distribution {
+        ckms: CKMS<f64>,
+        count: f64,
+        sum: f64,
+        min: f64,
+        max: f64,
+    },
+    Set(HashSet<u64>),
+}
+
+/// Accumulates telemetry by series and kind, and hands back a summary of
+/// each at every flush. Unlike `Scalars`, gauges are forgotten between
+/// flushes along with everything else; a sink keeps what it needs.
+pub struct Summarizer {
+    error: f64,
+    series: util::HashMap<(String, u8), (Arc<event::Telemetry>, Accumulator)>,
+}
+
+impl Summarizer {
+    /// `error` bounds the error of the quantiles reported.
+    pub fn new(error: f64) -> Summarizer {
+        Summarizer {
+            error,
+            series: Default::default(),
+        }
+    }
+
+    pub fn deliver(&mut self, telem: Arc<event::Telemetry>) {
+        let key = (telem.series(), telem.kind as u8);
+        let weight = 1.0 / telem.sample_rate;
+        let value = telem.value;
+        let error = self.error;
+        let entry = self.series.entry(key).or_insert_with(|| {
+            let acc = match telem.kind {
+                MetricKind::Counter => Accumulator::Counter(0.0),
+                MetricKind::Gauge => Accumulator::Gauge(0.0),
+                MetricKind::Timer | MetricKind::Histogram => {
+                    Accumulator::Distribution {
+                        ckms: CKMS::new(error),
+                        count: 0.0,
+                        sum: 0.0,
+                        min: f64::INFINITY,
+                        max: f64::NEG_INFINITY,
+                    }
+                }
+                MetricKind::Set => Accumulator::Set(HashSet::new()),
+            };
+            (telem, acc)
+        });
+        match entry.1 {
+            Accumulator::Counter(ref mut sum) => *sum += value * weight,
+            Accumulator::Gauge(ref mut last) => *last = value,
+            Accumulator::Distribution {
+                ref mut ckms,
+                ref mut count,
+                ref mut sum,
+                ref mut min,
+                ref mut max,
+            } => {
+                ckms.insert(value);
+                *count += weight;
+                *sum += value * weight;
+                *min = min.min(value);
+                *max = max.max(value);
+            }
+            Accumulator::Set(ref mut members) => {
+                let member = if value == 0.0 { 0.0 } else { value };
+                members.insert(member.to_bits());
+            }
+        }
+    }
+
+    /// Summarises the interval just ended, ordered by series, and starts
+    /// the next.
+    pub fn flush(&mut self) -> Vec<Summary> {
+        let mut summaries: Vec<(String, Summary)> = self
+            .series
+            .drain()
+            .map(|((series, _), (first, acc))| {
+                let stats = match acc {
+                    Accumulator::Counter(sum) => Stats::Counter(sum),
+                    Accumulator::Gauge(last) => Stats::Gauge(last),
+                    Accumulator::Distribution {
+                        ckms,
+                        count,
+                        sum,
+                        min,
+                        max,
+                    } => Stats::Distribution {
+                        count,
+                        sum,
+                        min,
+                        max,
+                        quantiles: QUANTILES
+                            .iter()
+                            .map(|q| (*q, ckms.query(*q).map_or(f64::NAN, |(_, v)| v)))
+                            .collect(),
+                    },
+                    Accumulator::Set(members) => Stats::Set(members.len()),
+                };
+                let mut tags = first.tags.clone();
+                tags.sort();
+                let summary = Summary {
+                    name: first.name.to_string(),
+                    tags,
+                    stats,
+                };
+                (series, summary)
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.0.cmp(&b.0));
+        summaries.into_iter().map(|(_, summary)| summary).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::Telemetry;
+
+    fn telem(name: &str, value: f64, kind: MetricKind) -> Telemetry {
+        Telemetry::new(name.to_string(), value, kind)
+    }
+
+    #[test]
+    fn summarises_each_kind() {
+        let mut summarizer = Summarizer::new(0.001);
+        let mut sampled = telem("hits", 1.0, MetricKind::Counter);
+        sampled.sample_rate = 0.5;
+        summarizer.deliver(Arc::new(sampled));
+        summarizer.deliver(Arc::new(telem("hits", 1.0, MetricKind::Counter)));
+        summarizer.deliver(Arc::new(telem("depth", 3.0, MetricKind::Gauge)));
+        summarizer.deliver(Arc::new(telem("depth", 2.0, MetricKind::Gauge)));
+        for value in 1..=100 {
+            let latency = telem("latency", f64::from(value), MetricKind::Timer);
+            summarizer.deliver(Arc::new(latency));
+        }
+        for member in &[1.0, 2.0, 1.0, -0.0, 0.0] {
+            summarizer.deliver(Arc::new(telem("users", *member, MetricKind::Set)));
+        }
+        let mut tagged = telem("hits", 5.0, MetricKind::Counter);
+        tagged.tags = vec![
+            ("z".to_string(), "1".to_string()),
+            ("a".to_string(), "2".to_string()),
+        ];
+        summarizer.deliver(Arc::new(tagged));
+
+        let summaries = summarizer.flush();
+        let names: Vec<&str> = summaries.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(vec!["depth", "hits", "hits", "latency", "users"], names);
+        assert_eq!(Stats::Gauge(2.0), summaries[0].stats);
+        assert_eq!(Stats::Counter(3.0), summaries[1].stats);
+        assert_eq!(
+            vec![
+                ("a".to_string(), "2".to_string()),
+                ("z".to_string(), "1".to_string()),
+            ],
+            summaries[2].tags
+        );
+        assert_eq!(Stats::Counter(5.0), summaries[2].stats);
+        match summaries[3].stats {
+            Stats::Distribution {
+                count,
+                sum,
+                min,
+                max,
+                ref quantiles,
+            } => {
+                assert_eq!((100.0, 5050.0, 1.0, 100.0), (count, sum, min, max));
+                assert_eq!(vec![(0.5, 50.0), (0.9, 90.0), (0.99, 99.0)], *quantiles);
+            }
+            ref other => panic!("expected a distribution, got {:?}", other),
+        }
+        assert_eq!(Stats::Set(3), summaries[4].stats);
+
+        assert!(summarizer.flush().is_empty());
+    }
+
+    #[test]
+    fn percentiles_name_quantiles() {
+        assert_eq!("p50", percentile(0.5));
+        assert_eq!("p99", percentile(0.99));
+        assert_eq!("p99_9", percentile(0.999));
+    }
+}
@@ -0,0 +1,273 @@
+//! Time windows over which egress aggregates.
+//!
+//! Samples are kept in panes, one per `slide` of time since the egress
+//! started, and a window is the last `width / slide` panes merged. A
+//! tumbling window slides by its whole width, so its windows do not
+//! overlap. Panes are kept for the last `retain` windows and then dropped.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use util;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    width: Duration,
+    slide: Duration,
+    retain: usize,
+}
+
+impl Window {
+    /// Back-to-back windows of `width`, keeping only the latest.
+    ///
+    /// # Panics
+    ///
+    /// If `width` is zero.
+    pub fn tumbling(width: Duration) -> Window {
+        Window::sliding(width, width)
+    }
+
+    /// Windows of `width` starting every `slide`, keeping only the latest.
+    ///
+    /// # Panics
+    ///
+    /// If `slide` is zero or does not divide `width`.
+    pub fn sliding(width: Duration, slide: Duration) -> Window {
+        assert!(slide > Duration::from_secs(0), "window slide must be positive");
+        assert!(
+            width.as_nanos().is_multiple_of(slide.as_nanos()) && width >= slide,
+            "window width must be a multiple of its slide"
+        );
+        Window {
+            width,
+            slide,
+            retain: 1,
+        }
+    }
+
+    /// Keeps the last `windows` windows rather than only the latest.
+    ///
+    /// # Panics
+    ///
+    /// If `windows` is zero.
+    pub fn retaining(mut self, windows: usize) -> Window {
+        assert!(windows > 0, "must retain at least the latest window");
+        self.retain = windows;
+        self
+    }
+
+    pub fn width(&self) -> Duration {
+        self.width
+    }
+
+    pub fn slide(&self) -> Duration {
+        self.slide
+    }
+
+    pub fn retain(&self) -> usize {
+        self.retain
+    }
+
+    fn panes(&self) -> u64 {
+        (self.width.as_nanos() / self.slide.as_nanos()) as u64
+    }
+}
+
+/// An aggregate that can absorb another of its kind, as panes are merged
+/// into windows.
+pub trait Merge: Clone {
+    fn merge(&mut self, other: &Self);
+}
+
+/// Aggregates per series, in panes of a window or, with no window, in one
+/// pane that never ends.
+pub struct Windows<A> {
+    window: Option<Window>,
+    epoch: Instant,
+    series: util::HashMap<String, VecDeque<(u64, A)>>,
+}
+
+impl<A: Merge> Windows<A> {
+    pub fn new(window: Option<Window>) -> Windows<A> {
+        Windows {
+            window,
+            epoch: Instant::now(),
+            series: Default::default(),
+        }
+    }
+
+    pub fn is_windowed(&self) -> bool {
+        self.window.is_some()
+    }
+
+    fn pane(&self, now: Instant) -> u64 {
+        match self.window {
+            None => 0,
+            Some(window) => {
+                let since = now.saturating_duration_since(self.epoch);
+                (since.as_nanos() / window.slide.as_nanos()) as u64
+            }
+        }
+    }
+
+    /// Applies `update` to the aggregate of `series` for the pane `now`
+    /// falls in, starting the pane from `init` if need be.
+    pub fn update<F, G>(&mut self, series: String, now: Instant, init: F, update: G)
+    where
+        F: FnOnce() -> A,
+        G: FnOnce(&mut A),
+    {
+        let pane = self.pane(now);
+        let panes = self.series.entry(series).or_default();
+        if panes.back().map(|&(idx, _)| idx) != Some(pane) {
+            panes.push_back((pane, init()));
+        }
+        update(&mut panes.back_mut().unwrap().1);
+    }
+
+    /// Drops panes no retained window covers any more, and the series left
+    /// without any.
+    pub fn expire(&mut self, now: Instant) {
+        let window = match self.window {
+            Some(window) => window,
+            None => return,
+        };
+        let keep = window.panes() + window.retain as u64 - 1;
+        let pane = self.pane(now);
+        self.series.retain(|_, panes| {
+            while panes.front().is_some_and(|&(idx, _)| idx + keep <= pane) {
+                panes.pop_front();
+            }
+            !panes.is_empty()
+        });
+    }
+
+    /// Each series' aggregate over the window ending with the pane `now`
+    /// falls in, for the series with data in it.
+    pub fn current(&self, now: Instant) -> Vec<(&str, A)> {
+        let pane = self.pane(now);
+        let mut current: Vec<(&str, A)> = self
+            .series
+            .iter()
+            .filter_map(|(series, panes)| {
+                self.merged(panes, pane).map(|agg| (series.as_str(), agg))
+            })
+            .collect();
+        current.sort_by(|a, b| a.0.cmp(b.0));
+        current
+    }
+
+    /// The retained windows of `series`, newest first: the current window,
+    /// then the one a slide before it, and so on. Windows without data are
+    /// `None`.
+    pub fn history(&self, series: &str, now: Instant) -> Vec<Option<A>> {
+        let pane = self.pane(now);
+        let retain = self.window.map_or(1, |window| window.retain as u64);
+        let panes = self.series.get(series);
+        (0..retain.min(pane + 1))
+            .map(|back| panes.and_then(|panes| self.merged(panes, pane - back)))
+            .collect()
+    }
+
+    /// The panes of the window ending with the pane `end`, merged.
+    fn merged(&self, panes: &VecDeque<(u64, A)>, end: u64) -> Option<A> {
+        let width = self.window.map_or(1, |window| window.panes());
+        let mut merged: Option<A> = None;
+        for &(idx, ref agg) in panes {
+            if idx > end || idx + width <= end {
+                continue;
+            }
+            match merged {
+                Some(ref mut merged) => merged.merge(agg),
+                None => merged = Some(agg.clone()),
+            }
+        }
+        merged
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Collects samples, to show which panes a window merged.
+    #[derive(Clone, Debug, PartialEq)]
+    struct Samples(Vec<u32>);
+
+    impl Merge for Samples {
+        fn merge(&mut self, other: &Samples) {
+            self.0.extend_from_slice(&other.0);
+        }
+    }
+
+    fn insert(windows: &mut Windows<Samples>, at: Instant, sample: u32) {
+        windows.update(
+            "x".to_string(),
+            at,
+            || Samples(Vec::new()),
+            |samples| samples.0.push(sample),
+        );
+    }
+
+    fn current(windows: &Windows<Samples>, at: Instant) -> Option<Vec<u32>> {
+        windows
+            .current(at)
+            .into_iter()
+            .next()
+            .map(|(_, samples)| samples.0)
+    }
+
+    #[test]
+    fn tumbling_windows_start_afresh() {
+        let mut windows = Windows::new(Some(Window::tumbling(Duration::from_secs(10))));
+        let at = |secs| windows.epoch + Duration::from_secs(secs);
+        let (t1, t9, t10, t25) = (at(1), at(9), at(10), at(25));
+        insert(&mut windows, t1, 1);
+        insert(&mut windows, t9, 9);
+        assert_eq!(Some(vec![1, 9]), current(&windows, t9));
+        insert(&mut windows, t10, 10);
+        assert_eq!(Some(vec![10]), current(&windows, t10));
+        assert_eq!(None, current(&windows, t25));
+
+        windows.expire(t10);
+        assert_eq!(1, windows.series["x"].len());
+        windows.expire(t25);
+        assert!(windows.series.is_empty());
+    }
+
+    #[test]
+    fn sliding_windows_overlap() {
+        let window = Window::sliding(Duration::from_secs(3), Duration::from_secs(1));
+        let mut windows = Windows::new(Some(window.retaining(2)));
+        let at = |secs| windows.epoch + Duration::from_secs(secs);
+        let times: Vec<Instant> = (0..6).map(at).collect();
+        for (secs, time) in times.iter().enumerate() {
+            insert(&mut windows, *time, secs as u32);
+        }
+        assert_eq!(Some(vec![3, 4, 5]), current(&windows, times[5]));
+        assert_eq!(
+            vec![Some(Samples(vec![3, 4, 5])), Some(Samples(vec![2, 3, 4]))],
+            windows.history("x", times[5])
+        );
+
+        // Two windows of three panes, overlapping in two, span four panes.
+        windows.expire(times[5]);
+        assert_eq!(4, windows.series["x"].len());
+    }
+
+    #[test]
+    fn without_a_window_everything_accumulates() {
+        let mut windows = Windows::new(None);
+        let (start, later) = (windows.epoch, windows.epoch + Duration::from_secs(1_000_000));
+        insert(&mut windows, start, 1);
+        insert(&mut windows, later, 2);
+        windows.expire(later);
+        assert_eq!(Some(vec![1, 2]), current(&windows, later));
+        assert_eq!(vec![Some(Samples(vec![1, 2]))], windows.history("x", later));
+        assert_eq!(vec![None], windows.history("y", later));
+    }
+
+    #[test]
+    #[should_panic(expected = "multiple of its slide")]
+    fn slides_must_divide_widths() {
+        Window::sliding(Duration::from_secs(5), Duration::from_secs(2));
+    }
+}
@@ -1,14 +1,25 @@
+//! The run loop every egress stage is built on.
+//!
+//! An `Emitter` is the state of a sink: it is built from its own config
+//! type, takes telemetry a batch at a time and is told when to flush and
+//! when the pipeline shuts down. A `RunnableEmitter` feeds one from a
+//! channel, gathering telemetry into batches and, if asked, flushing it on
+//! a timer of its own as well as on every `Event::Flush`.
+use channel;
+use egress::Egress;
+use event::{Event, Telemetry};
 use std::marker::PhantomData;
-use event::Event;
-use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub struct RunnableEmitter<S, EConfig>
 where
     S: Send + Emitter<EConfig>,
     EConfig: 'static + Send + Clone,
 {
-    recv: mpsc::Receiver<Event>,
     state: S,
+    batch_size: usize,
+    flush_interval: Option<Duration>,
     config: PhantomData<EConfig>,
 }
 
@@ -17,13 +28,77 @@ where
     S: 'static + Send + Emitter<EConfig>,
     EConfig: 'static + Clone + Send,
 {
-    pub fn new(recv: mpsc::Receiver<Event>, config: EConfig) -> RunnableEmitter<S, EConfig> {
+    /// An emitter built from `config` that takes telemetry one event at a
+    /// time and flushes only when told to.
+    pub fn new(config: EConfig) -> RunnableEmitter<S, EConfig> {
         RunnableEmitter {
-            recv: recv,
             state: S::init(config),
+            batch_size: 1,
+            flush_interval: None,
             config: PhantomData,
         }
     }
+
+    /// Hands telemetry over in batches of up to `size`. A batch is cut
+    /// short by a flush or shutdown, so nothing waits in one past either.
+    ///
+    /// # Panics
+    ///
+    /// If `size` is zero.
+    pub fn batched(mut self, size: usize) -> Self {
+        assert!(size > 0, "batches must hold at least one event");
+        self.batch_size = size;
+        self
+    }
+
+    /// Flushes whenever `interval` passes without a flush, as well as on
+    /// every `Event::Flush`.
+    pub fn flush_every(mut self, interval: Duration) -> Self {
+        self.flush_interval = Some(interval);
+        self
+    }
+
+    /// Feeds the emitter from `recv` until a shutdown, or until nothing is
+    /// left upstream, and shuts it down either way.
+    pub fn run(&mut self, recv: &channel::Receiver) {
+        let state = &mut self.state;
+        drive(
+            recv,
+            self.batch_size,
+            self.flush_interval,
+            |step| match step {
+                Step::Batch(batch) => state.deliver_batch(batch),
+                Step::Flush => state.flush(),
+                Step::Shutdown => state.shutdown(),
+            },
+        );
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn into_inner(self) -> S {
+        self.state
+    }
+}
+
+impl<S, EConfig> Egress for RunnableEmitter<S, EConfig>
+where
+    S: 'static + Send + Emitter<EConfig>,
+    EConfig: 'static + Clone + Send,
+{
+    fn deliver(&mut self, event: Arc<Telemetry>) {
+        self.state.deliver(event);
+    }
+
+    fn report(&mut self) {
+        self.state.flush();
+    }
+
+    fn run(&mut self, recv: &channel::Receiver) {
+        RunnableEmitter::run(self, recv);
+    }
 }
 
 pub trait Emitter<EConfig>
@@ -31,11 +106,226 @@ where
     Self: 'static + Send + Sized,
     EConfig: 'static + Send + Clone,
 {
-    ///
-    fn new(recv: mpsc::Receiver<Event>, config: EConfig) -> RunnableEmitter<Self, EConfig> {
-        RunnableEmitter::<Self, EConfig>::new(recv, config)
+    /// An emitter built from `config`, ready to run.
+    fn runnable(config: EConfig) -> RunnableEmitter<Self, EConfig> {
+        RunnableEmitter::<Self, EConfig>::new(config)
     }
 
     /// Constructs a new emitter.
     fn init(config: EConfig) -> Self;
+
+    fn deliver(&mut self, telem: Arc<Telemetry>);
+
+    /// Takes every event out of `batch`, which is then reused for the
+    /// next. One at a time by default.
+    fn deliver_batch(&mut self, batch: &mut Vec<Arc<Telemetry>>) {
+        for telem in batch.drain(..) {
+            self.deliver(telem);
+        }
+    }
+
+    /// Reports or forwards what the emitter holds.
+    fn flush(&mut self);
+
+    /// The pipeline is stopping and nothing more will be delivered. Flushes
+    /// by default.
+    fn shutdown(&mut self) {
+        self.flush();
+    }
+}
+
+/// What `drive` asks of whatever it runs.
+pub enum Step<'a> {
+    /// Telemetry to take out of the batch.
+    Batch(&'a mut Vec<Arc<Telemetry>>),
+    Flush,
+    /// The last step.
+    Shutdown,
+}
+
+/// Receives from `recv` until a shutdown or until nothing is left
+/// upstream, handing telemetry to `step` in batches of up to `batch_size`
+/// and passing on flushes, plus one whenever `flush_interval` passes
+/// without any, however busy the channel. Ends with `Step::Shutdown`
+/// either way.
+pub fn drive<F>(
+    recv: &channel::Receiver,
+    batch_size: usize,
+    flush_interval: Option<Duration>,
+    mut step: F,
+) where
+    F: FnMut(Step),
+{
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut flush_at = flush_interval.map(|interval| Instant::now() + interval);
+    loop {
+        // Checked before receiving, since a channel that is never empty
+        // never times out.
+        let due = flush_at.is_some_and(|at| Instant::now() >= at);
+        let event = match flush_at {
+            _ if due => Some(Event::Flush),
+            None => recv.recv().ok(),
+            Some(at) => {
+                match recv.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                    Ok(event) => Some(event),
+                    Err(channel::RecvTimeoutError::Timeout) => Some(Event::Flush),
+                    Err(channel::RecvTimeoutError::Disconnected) => None,
+                }
+            }
+        };
+        match event {
+            Some(Event::Telemetry(telem)) => {
+                batch.push(telem);
+                if batch.len() >= batch_size {
+                    step(Step::Batch(&mut batch));
+                    batch.clear();
+                }
+            }
+            Some(Event::Flush) => {
+                if !batch.is_empty() {
+                    step(Step::Batch(&mut batch));
+                    batch.clear();
+                }
+                step(Step::Flush);
+                flush_at = flush_interval.map(|interval| Instant::now() + interval);
+            }
+            Some(Event::Shutdown) | None => {
+                if !batch.is_empty() {
+                    step(Step::Batch(&mut batch));
+                }
+                step(Step::Shutdown);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use channel::{bounded, Policy};
+    use event::MetricKind;
+    use std::thread;
+
+    /// Records what it is handed, in the order it is handed it.
+    struct Recorder {
+        calls: Vec<String>,
+    }
+
+    impl Emitter<String> for Recorder {
+        fn init(config: String) -> Self {
+            Recorder {
+                calls: vec![config],
+            }
+        }
+
+        fn deliver(&mut self, telem: Arc<Telemetry>) {
+            self.calls.push(format!("deliver {}", telem.value));
+        }
+
+        fn deliver_batch(&mut self, batch: &mut Vec<Arc<Telemetry>>) {
+            self.calls.push(format!("batch of {}", batch.len()));
+            batch.clear();
+        }
+
+        fn flush(&mut self) {
+            self.calls.push("flush".to_string());
+        }
+
+        fn shutdown(&mut self) {
+            self.calls.push("shutdown".to_string());
+        }
+    }
+
+    fn telem(value: f64) -> Event {
+        let mut telem = Telemetry::new("x".to_string(), value, MetricKind::Gauge);
+        telem.timestamp = 0;
+        Event::Telemetry(Arc::new(telem))
+    }
+
+    #[test]
+    fn batches_end_at_flushes_and_shutdown() {
+        let (snd, recv) = bounded("test", 16, Policy::Block);
+        for value in 0..7 {
+            snd.send(telem(f64::from(value))).unwrap();
+        }
+        snd.send(Event::Flush).unwrap();
+        snd.send(telem(7.0)).unwrap();
+        snd.send(Event::Shutdown).unwrap();
+        snd.send(telem(8.0)).unwrap();
+
+        let mut emitter = Recorder::runnable("config".to_string()).batched(3);
+        emitter.run(&recv);
+        assert_eq!(
+            vec![
+                "config",
+                "batch of 3",
+                "batch of 3",
+                "batch of 1",
+                "flush",
+                "batch of 1",
+                "shutdown",
+            ],
+            emitter.into_inner().calls
+        );
+    }
+
+    #[test]
+    fn flushes_on_an_interval_without_being_told() {
+        let (snd, recv) = bounded("test", 16, Policy::Block);
+        let sender = thread::spawn(move || {
+            snd.send(telem(1.0)).unwrap();
+            thread::sleep(Duration::from_millis(100));
+        });
+        let mut emitter =
+            Recorder::runnable(String::new()).flush_every(Duration::from_millis(20));
+        emitter.run(&recv);
+        sender.join().unwrap();
+        let calls = &emitter.state().calls;
+        assert_eq!("batch of 1", calls[1]);
+        assert!(calls.iter().filter(|call| *call == "flush").count() >= 2);
+        // The sender hanging up ends the run.
+        assert_eq!(Some(&"shutdown".to_string()), calls.last());
+    }
+
+    #[test]
+    fn flushes_on_an_interval_while_the_channel_is_busy() {
+        let (snd, recv) = bounded("test", 128, Policy::Block);
+        for value in 0..100 {
+            snd.send(telem(f64::from(value))).unwrap();
+        }
+        snd.send(Event::Shutdown).unwrap();
+        // Each batch is slow enough that several intervals pass before the
+        // channel is first empty.
+        let mut flushes = 0;
+        drive(&recv, 1, Some(Duration::from_millis(20)), |step| match step {
+            Step::Batch(_) => thread::sleep(Duration::from_millis(2)),
+            Step::Flush => flushes += 1,
+            Step::Shutdown => {}
+        });
+        assert!(flushes >= 2, "{} flushes", flushes);
+    }
+
+    #[test]
+    fn egress_runs_on_the_same_loop() {
+        struct Counting(Vec<String>);
+
+        impl Egress for Counting {
+            fn deliver(&mut self, event: Arc<Telemetry>) {
+                self.0.push(format!("deliver {}", event.value));
+            }
+
+            fn report(&mut self) {
+                self.0.push("report".to_string());
+            }
+        }
+
+        let (snd, recv) = bounded("test", 16, Policy::Block);
+        snd.send(telem(1.0)).unwrap();
+        snd.send(Event::Flush).unwrap();
+        snd.send(Event::Shutdown).unwrap();
+        let mut egress = Counting(Vec::new());
+        egress.run(&recv);
+        assert_eq!(vec!["deliver 1", "report", "report"], egress.0);
+    }
 }
@@ -1,11 +1,243 @@
-#[derive(Clone)]
+use std::cell::RefCell;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use util;
+
+/// Names interned per thread before the table starts afresh, so a flood
+/// of distinct names cannot grow it without bound.
+const MAX_INTERNED: usize = 100_000;
+
+thread_local! {
+    static NAMES: RefCell<util::HashSet<Arc<str>>> = RefCell::new(Default::default());
+}
+
+/// `name`, shared with every other interning of it on this thread. A
+/// listener sees the same few names over and over, and after the first
+/// each costs a lookup rather than an allocation.
+pub fn intern(name: &str) -> Arc<str> {
+    NAMES.with(|names| {
+        let mut names = names.borrow_mut();
+        if let Some(interned) = names.get(name) {
+            return Arc::clone(interned);
+        }
+        if names.len() >= MAX_INTERNED {
+            names.clear();
+        }
+        let interned: Arc<str> = Arc::from(name);
+        names.insert(Arc::clone(&interned));
+        interned
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Event {
-    Telemetry(Telemetry),
+    /// Shared, so that sending one event to several stages copies a
+    /// pointer rather than the telemetry.
+    Telemetry(Arc<Telemetry>),
     Flush,
+    /// The pipeline is stopping. Filters pass it on and exit; egress makes
+    /// a final report and exits.
+    Shutdown,
+}
+
+impl Event {
+    /// Appends a binary encoding of the event to `buf`, readable by
+    /// `decode`. Numbers are little-endian, strings length-prefixed.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            Event::Telemetry(ref telem) => {
+                buf.push(0);
+                encode_str(buf, &telem.name);
+                buf.extend_from_slice(&telem.value.to_le_bytes());
+                buf.push(telem.kind as u8);
+                buf.extend_from_slice(&telem.timestamp.to_le_bytes());
+                buf.extend_from_slice(&telem.sample_rate.to_le_bytes());
+                buf.extend_from_slice(&(telem.tags.len() as u32).to_le_bytes());
+                for (key, value) in &telem.tags {
+                    encode_str(buf, key);
+                    encode_str(buf, value);
+                }
+            }
+            Event::Flush => buf.push(1),
+            Event::Shutdown => buf.push(2),
+        }
+    }
+
+    /// Reads back one event written by `encode`.
+    pub fn decode<R: Read>(r: &mut R) -> io::Result<Event> {
+        match read_array::<R, [u8; 1]>(r)?[0] {
+            0 => {
+                let name = decode_str(r)?;
+                let value = f64::from_le_bytes(read_array(r)?);
+                let kind = match read_array::<R, [u8; 1]>(r)?[0] {
+                    0 => MetricKind::Counter,
+                    1 => MetricKind::Gauge,
+                    2 => MetricKind::Timer,
+                    3 => MetricKind::Histogram,
+                    4 => MetricKind::Set,
+                    _ => return Err(invalid("unknown metric kind")),
+                };
+                let timestamp = u64::from_le_bytes(read_array(r)?);
+                let sample_rate = f64::from_le_bytes(read_array(r)?);
+                let tag_count = u32::from_le_bytes(read_array(r)?);
+                let mut tags = Vec::new();
+                for _ in 0..tag_count {
+                    tags.push((decode_str(r)?, decode_str(r)?));
+                }
+                Ok(Event::Telemetry(Arc::new(Telemetry {
+                    name: name.into(),
+                    value,
+                    kind,
+                    timestamp,
+                    sample_rate,
+                    tags,
+                })))
+            }
+            1 => Ok(Event::Flush),
+            2 => Ok(Event::Shutdown),
+            _ => Err(invalid("unknown event tag")),
+        }
+    }
+}
+
+fn invalid(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn encode_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn decode_str<R: Read>(r: &mut R) -> io::Result<String> {
+    let len = u32::from_le_bytes(read_array(r)?);
+    let mut bytes = Vec::new();
+    r.take(u64::from(len)).read_to_end(&mut bytes)?;
+    if bytes.len() != len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(bytes).map_err(|_| invalid("string is not UTF-8"))
 }
 
-#[derive(Debug, Clone)]
+fn read_array<R, A>(r: &mut R) -> io::Result<A>
+where
+    R: Read,
+    A: Default + AsMut<[u8]>,
+{
+    let mut array = A::default();
+    r.read_exact(array.as_mut())?;
+    Ok(array)
+}
+
+/// What a metric measures, which decides how egress aggregates it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MetricKind {
+    /// Occurrences, summed per flush.
+    Counter,
+    /// A level, of which only the latest matters.
+    Gauge,
+    /// A duration, aggregated into a distribution.
+    Timer,
+    /// A sample of any other distribution, aggregated like a timer.
+    Histogram,
+    /// A member of a set, whose distinct members are counted per flush.
+    Set,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Telemetry {
-    pub name: String,
-    pub value: u32,
+    /// Usually interned; see `intern`.
+    pub name: Arc<str>,
+    pub value: f64,
+    pub kind: MetricKind,
+    /// Seconds since the Unix epoch, as sent or else on arrival.
+    pub timestamp: u64,
+    /// The fraction of events the sender reports, in (0, 1]. A counter
+    /// sampled at 0.1 stands for ten times its value.
+    pub sample_rate: f64,
+    /// Key/value pairs; a tag sent without a value has an empty one.
+    pub tags: Vec<(String, String)>,
+}
+
+impl Telemetry {
+    /// An unsampled, untagged metric stamped with the current time.
+    pub fn new<N: Into<Arc<str>>>(name: N, value: f64, kind: MetricKind) -> Telemetry {
+        Telemetry {
+            name: name.into(),
+            value,
+            kind,
+            timestamp: now(),
+            sample_rate: 1.0,
+            tags: Vec::new(),
+        }
+    }
+
+    /// The name with the tags, sorted, appended in Graphite's `;key=value`
+    /// style. Metrics of one name but different tags are different series
+    /// and are aggregated apart.
+    pub fn series(&self) -> String {
+        if self.tags.is_empty() {
+            return self.name.to_string();
+        }
+        let mut tags: Vec<&(String, String)> = self.tags.iter().collect();
+        tags.sort();
+        let mut series = self.name.to_string();
+        for &(key, value) in &tags {
+            series.push(';');
+            series.push_str(key);
+            series.push('=');
+            series.push_str(value);
+        }
+        series
+    }
+}
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn interned_names_are_shared() {
+        let first = intern("requests");
+        let again = intern(&String::from("requests"));
+        assert!(Arc::ptr_eq(&first, &again));
+        assert!(!Arc::ptr_eq(&first, &intern("errors")));
+    }
+
+    #[test]
+    fn events_round_trip_through_encoding() {
+        let mut telem = Telemetry::new("a b".to_string(), -1.5, MetricKind::Counter);
+        telem.sample_rate = 0.25;
+        telem.tags = vec![
+            ("host".to_string(), "x".to_string()),
+            ("k".to_string(), String::new()),
+        ];
+        let events = vec![
+            Event::Flush,
+            Event::Telemetry(Arc::new(telem)),
+            Event::Shutdown,
+        ];
+
+        let mut buf = Vec::new();
+        for event in &events {
+            event.encode(&mut buf);
+        }
+        let mut reader = Cursor::new(&buf);
+        for event in &events {
+            assert_eq!(*event, Event::decode(&mut reader).unwrap());
+        }
+        // A truncated event is an error, not a short one.
+        let mut truncated = Cursor::new(&buf[1..buf.len() - 2]);
+        assert!(Event::decode(&mut truncated).is_err());
+    }
 }
@@ -0,0 +1,114 @@
+use event;
+use filter::Filter;
+use std::mem;
+use std::sync::Arc;
+
+/// Runs several filters in one thread, each over everything the one before
+/// it produced. An empty chain passes everything.
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn Filter + Send>>,
+    current: Vec<Arc<event::Telemetry>>,
+    next: Vec<Arc<event::Telemetry>>,
+}
+
+impl FilterChain {
+    pub fn new() -> Self {
+        FilterChain::default()
+    }
+
+    /// Appends `filter` to the end of the chain.
+    pub fn then<F>(mut self, filter: F) -> Self
+    where
+        F: 'static + Filter + Send,
+    {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+}
+
+impl Filter for FilterChain {
+    fn process(
+        &mut self,
+        event: Arc<event::Telemetry>,
+        res: &mut Vec<Arc<event::Telemetry>>,
+    ) {
+        self.current.push(event);
+        for filter in &mut self.filters {
+            for event in self.current.drain(..) {
+                filter.process(event, &mut self.next);
+            }
+            mem::swap(&mut self.current, &mut self.next);
+            if self.current.is_empty() {
+                return;
+            }
+        }
+        res.append(&mut self.current);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::{MetricKind, Telemetry};
+    use filter::{HighFilter, LowFilter, NameFilter, Pattern, RelabelFilter};
+
+    /// Emits each event twice, to exercise fan-out within the chain.
+    struct Twice;
+
+    impl Filter for Twice {
+        fn process(&mut self, event: Arc<Telemetry>, res: &mut Vec<Arc<Telemetry>>) {
+            res.push(Arc::clone(&event));
+            res.push(event);
+        }
+    }
+
+    fn run(chain: &mut FilterChain, events: &[(&str, f64)]) -> Vec<(String, f64)> {
+        let earlier = Telemetry::new("earlier".to_string(), 0.0, MetricKind::Gauge);
+        let mut res = vec![Arc::new(earlier)];
+        for &(name, value) in events {
+            let telem = Telemetry::new(name.to_string(), value, MetricKind::Gauge);
+            chain.process(Arc::new(telem), &mut res);
+        }
+        res.into_iter()
+            .map(|telem| (telem.name.to_string(), telem.value))
+            .collect()
+    }
+
+    #[test]
+    fn filters_run_in_order() {
+        let mut chain = FilterChain::new()
+            .then(NameFilter::new().allow(Pattern::glob("cpu.*")))
+            .then(HighFilter::new(10.0))
+            .then(Twice)
+            .then(RelabelFilter::new().prefix("busy."))
+            .then(LowFilter::new(90.0));
+        assert_eq!(5, chain.len());
+        assert_eq!(
+            vec![
+                ("earlier".to_string(), 0.0),
+                ("busy.cpu.a".to_string(), 50.0),
+                ("busy.cpu.a".to_string(), 50.0),
+            ],
+            run(
+                &mut chain,
+                &[("cpu.a", 50.0), ("cpu.b", 5.0), ("mem.a", 50.0), ("cpu.c", 95.0)]
+            )
+        );
+    }
+
+    #[test]
+    fn empty_chain_passes_everything() {
+        let mut chain = FilterChain::new();
+        assert!(chain.is_empty());
+        assert_eq!(3, run(&mut chain, &[("a", 1.0), ("b", 2.0)]).len());
+    }
+}
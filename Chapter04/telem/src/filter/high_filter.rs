@@ -1,22 +1,23 @@
 use event;
 use filter::Filter;
+use std::sync::Arc;
 
 pub struct HighFilter {
-    limit: u32,
+    limit: f64,
 }
 
 impl HighFilter {
-    pub fn new(limit: u32) -> Self {
-        HighFilter { limit: limit }
+    pub fn new(limit: f64) -> Self {
+        HighFilter { limit }
     }
 }
 
 impl Filter for HighFilter {
     fn process(
         &mut self,
-        event: event::Telemetry,
-        res: &mut Vec<event::Telemetry>,
-    ) -> () {
+        event: Arc<event::Telemetry>,
+        res: &mut Vec<Arc<event::Telemetry>>,
+    ) {
         if event.value >= self.limit {
             res.push(event);
         }
@@ -1,22 +1,23 @@
 use event;
 use filter::Filter;
+use std::sync::Arc;
 
 pub struct LowFilter {
-    limit: u32,
+    limit: f64,
 }
 
 impl LowFilter {
-    pub fn new(limit: u32) -> Self {
-        LowFilter { limit: limit }
+    pub fn new(limit: f64) -> Self {
+        LowFilter { limit }
     }
 }
 
 impl Filter for LowFilter {
     fn process(
         &mut self,
-        event: event::Telemetry,
-        res: &mut Vec<event::Telemetry>,
-    ) -> () {
+        event: Arc<event::Telemetry>,
+        res: &mut Vec<Arc<event::Telemetry>>,
+    ) {
         if event.value <= self.limit {
             res.push(event);
         }
@@ -1,36 +1,118 @@
+use channel;
 use event;
-use std::sync::mpsc;
+use std::sync::Arc;
 use util;
 
+mod filter_chain;
 mod high_filter;
 mod low_filter;
+mod name_filter;
+mod range_filter;
+mod rate_limit_filter;
+mod relabel_filter;
+mod sample_filter;
 
+pub use self::filter_chain::*;
 pub use self::high_filter::*;
 pub use self::low_filter::*;
+pub use self::name_filter::*;
+pub use self::range_filter::*;
+pub use self::rate_limit_filter::*;
+pub use self::relabel_filter::*;
+pub use self::sample_filter::*;
 
+/// A stage between ingest and egress. Events are shared with every other
+/// stage sent them, so a filter that passes an event unchanged passes the
+/// same `Arc`, and one that changes it does so through `Arc::make_mut`.
 pub trait Filter {
     fn process(
         &mut self,
-        event: event::Telemetry,
-        res: &mut Vec<event::Telemetry>,
-    ) -> ();
+        event: Arc<event::Telemetry>,
+        res: &mut Vec<Arc<event::Telemetry>>,
+    );
 
+    /// Filters events from `recv` into `chans` until a shutdown, which is
+    /// passed on, or until nothing is left upstream or downstream.
     fn run(
         &mut self,
-        recv: mpsc::Receiver<event::Event>,
-        chans: Vec<mpsc::Sender<event::Event>>,
+        recv: &channel::Receiver,
+        mut chans: Vec<channel::Sender>,
     ) {
         let mut telems = Vec::with_capacity(64);
-        for event in recv.into_iter() {
-            match event {
-                event::Event::Flush => util::send(&chans, event::Event::Flush),
+        for event in recv.iter() {
+            let sent = match event {
+                event::Event::Flush => util::send(&mut chans, event::Event::Flush),
+                event::Event::Shutdown => {
+                    let _ = util::send(&mut chans, event::Event::Shutdown);
+                    return;
+                }
                 event::Event::Telemetry(telem) => {
                     self.process(telem, &mut telems);
+                    let mut sent = Ok(());
                     for telem in telems.drain(..) {
-                        util::send(&chans, event::Event::Telemetry(telem))
+                        if sent.is_ok() {
+                            sent = util::send(&mut chans, event::Event::Telemetry(telem));
+                        }
                     }
+                    sent
                 }
+            };
+            if sent.is_err() {
+                return;
             }
         }
     }
 }
+
+impl<F: Filter + ?Sized> Filter for Box<F> {
+    fn process(
+        &mut self,
+        event: Arc<event::Telemetry>,
+        res: &mut Vec<Arc<event::Telemetry>>,
+    ) {
+        (**self).process(event, res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::{Event, MetricKind, Telemetry};
+
+    #[test]
+    fn shutdown_is_passed_on() {
+        let (snd, recv) = channel::bounded("in", 4, channel::Policy::Block);
+        let (out_snd, out_recv) = channel::bounded("out", 4, channel::Policy::Block);
+        for value in &[50.0, 150.0] {
+            let telem = Telemetry::new("x".to_string(), *value, MetricKind::Timer);
+            snd.send(Event::Telemetry(Arc::new(telem))).unwrap();
+        }
+        snd.send(Event::Shutdown).unwrap();
+        snd.send(Event::Flush).unwrap();
+
+        HighFilter::new(100.0).run(&recv, vec![out_snd]);
+        let out: Vec<Event> = out_recv.try_iter().collect();
+        assert_eq!(2, out.len());
+        match (&out[0], &out[1]) {
+            (Event::Telemetry(telem), Event::Shutdown) => assert_eq!(150.0, telem.value),
+            _ => panic!("expected the high value then shutdown"),
+        }
+        // The flush after the shutdown was never read.
+        assert_eq!(1, recv.try_iter().count());
+    }
+
+    #[test]
+    fn unchanged_events_are_passed_on_shared() {
+        let (snd, recv) = channel::bounded("in", 4, channel::Policy::Block);
+        let (out_snd, out_recv) = channel::bounded("out", 4, channel::Policy::Block);
+        let telem = Arc::new(Telemetry::new("x".to_string(), 1.0, MetricKind::Gauge));
+        snd.send(Event::Telemetry(Arc::clone(&telem))).unwrap();
+        snd.send(Event::Shutdown).unwrap();
+
+        LowFilter::new(100.0).run(&recv, vec![out_snd]);
+        match out_recv.try_iter().next() {
+            Some(Event::Telemetry(passed)) => assert!(Arc::ptr_eq(&telem, &passed)),
+            _ => panic!("expected the event to pass"),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use event;
+use filter::Filter;
+use regex::Regex;
+use std::sync::Arc;
+
+/// A pattern over metric names.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// `*` matches any run of characters, dots included, and `?` any one
+    /// character. Everything else matches itself.
+    Glob(String),
+    /// Matches if the regex matches anywhere in the name; anchor it with
+    /// `^` and `$` to match the whole name.
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn glob(glob: &str) -> Pattern {
+        Pattern::Glob(glob.to_string())
+    }
+
+    pub fn regex(regex: &str) -> Result<Pattern, ::regex::Error> {
+        Regex::new(regex).map(Pattern::Regex)
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match *self {
+            Pattern::Glob(ref glob) => glob_matches(glob.as_bytes(), name.as_bytes()),
+            Pattern::Regex(ref regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// Matches greedily, backtracking to the last `*` on a mismatch, which is
+/// linear in the name for each `*`.
+fn glob_matches(glob: &[u8], name: &[u8]) -> bool {
+    let (mut g, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match glob.get(g) {
+            Some(&b'*') => {
+                star = Some((g, n));
+                g += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_g, star_n)) => {
+                    star = Some((star_g, star_n + 1));
+                    g = star_g + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == b'*')
+}
+
+/// Passes telemetry by name. A name passes if it matches some allowed
+/// pattern, or there are none, and matches no denied pattern.
+#[derive(Debug, Clone, Default)]
+pub struct NameFilter {
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+}
+
+impl NameFilter {
+    pub fn new() -> Self {
+        NameFilter::default()
+    }
+
+    pub fn allow(mut self, pattern: Pattern) -> Self {
+        self.allow.push(pattern);
+        self
+    }
+
+    pub fn deny(mut self, pattern: Pattern) -> Self {
+        self.deny.push(pattern);
+        self
+    }
+
+    fn passes(&self, name: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|p| p.matches(name)))
+            && !self.deny.iter().any(|p| p.matches(name))
+    }
+}
+
+impl Filter for NameFilter {
+    fn process(
+        &mut self,
+        event: Arc<event::Telemetry>,
+        res: &mut Vec<Arc<event::Telemetry>>,
+    ) {
+        if self.passes(&event.name) {
+            res.push(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::{MetricKind, Telemetry};
+
+    fn passed(filter: &mut NameFilter, names: &[&str]) -> Vec<String> {
+        let mut res = Vec::new();
+        for name in names {
+            let telem = Telemetry::new(name.to_string(), 1.0, MetricKind::Counter);
+            filter.process(Arc::new(telem), &mut res);
+        }
+        res.into_iter().map(|telem| telem.name.to_string()).collect()
+    }
+
+    #[test]
+    fn globs() {
+        let glob = |glob: &str, name: &str| Pattern::glob(glob).matches(name);
+        assert!(glob("api.*", "api.requests"));
+        assert!(glob("api.*", "api."));
+        assert!(glob("*.p99", "api.latency.p99"));
+        assert!(glob("a*b*c", "aXbYbZc"));
+        assert!(glob("h?st", "host"));
+        assert!(glob("*", ""));
+        assert!(!glob("api.*", "apix.requests"));
+        assert!(!glob("h?st", "hst"));
+        assert!(!glob("a*b", "aXbY"));
+        assert!(!glob("", "a"));
+    }
+
+    #[test]
+    fn allow_then_deny() {
+        let mut filter = NameFilter::new()
+            .allow(Pattern::glob("api.*"))
+            .allow(Pattern::regex(r"^db\.(reads|writes)$").unwrap())
+            .deny(Pattern::glob("*.debug"));
+        assert_eq!(
+            vec!["api.requests", "db.reads"],
+            passed(
+                &mut filter,
+                &["api.requests", "api.debug", "db.reads", "db.readsx", "web.hits"]
+            )
+        );
+    }
+
+    #[test]
+    fn no_allow_list_allows_everything_not_denied() {
+        let mut filter = NameFilter::new().deny(Pattern::regex("tmp").unwrap());
+        assert_eq!(
+            vec!["a", "b"],
+            passed(&mut filter, &["a", "tmp.a", "b", "a.tmp"])
+        );
+        assert!(Pattern::regex("(").is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use event;
+use filter::Filter;
+use std::sync::Arc;
+
+/// Passes telemetry whose value lies within `min..=max`. Either bound may
+/// be infinite, making `LowFilter` and `HighFilter` special cases.
+pub struct RangeFilter {
+    min: f64,
+    max: f64,
+}
+
+impl RangeFilter {
+    pub fn new(min: f64, max: f64) -> Self {
+        RangeFilter { min, max }
+    }
+}
+
+impl Filter for RangeFilter {
+    fn process(
+        &mut self,
+        event: Arc<event::Telemetry>,
+        res: &mut Vec<Arc<event::Telemetry>>,
+    ) {
+        if self.min <= event.value && event.value <= self.max {
+            res.push(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::{MetricKind, Telemetry};
+    use std::f64;
+
+    fn passed(filter: &mut RangeFilter, values: &[f64]) -> Vec<f64> {
+        let mut res = Vec::new();
+        for value in values {
+            let telem = Telemetry::new("x".to_string(), *value, MetricKind::Gauge);
+            filter.process(Arc::new(telem), &mut res);
+        }
+        res.into_iter().map(|telem| telem.value).collect()
+    }
+
+    #[test]
+    fn bounds_are_inclusive() {
+        let mut filter = RangeFilter::new(-1.0, 1.0);
+        assert_eq!(
+            vec![-1.0, 0.0, 1.0],
+            passed(&mut filter, &[-1.5, -1.0, 0.0, 1.0, 1.5])
+        );
+    }
+
+    #[test]
+    fn bounds_may_be_open() {
+        let mut filter = RangeFilter::new(10.0, f64::INFINITY);
+        assert_eq!(vec![10.0, 1e300], passed(&mut filter, &[9.0, 10.0, 1e300]));
+    }
+}
@@ -0,0 +1,123 @@
+use event;
+use filter::Filter;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use util;
+
+/// Passes at most `limit` events of each metric name per `period`, in
+/// fixed windows that start with a name's first event. What is over the
+/// limit is dropped and counted.
+pub struct RateLimitFilter {
+    limit: usize,
+    period: Duration,
+    /// The start of each name's current window and the events passed in it.
+    windows: util::HashMap<Arc<str>, (Instant, usize)>,
+    last_prune: Option<Instant>,
+    dropped: usize,
+}
+
+impl RateLimitFilter {
+    pub fn new(limit: usize, period: Duration) -> Self {
+        RateLimitFilter {
+            limit,
+            period,
+            windows: Default::default(),
+            last_prune: None,
+            dropped: 0,
+        }
+    }
+
+    /// How many events have been over the limit.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    fn process_at(
+        &mut self,
+        event: Arc<event::Telemetry>,
+        now: Instant,
+        res: &mut Vec<Arc<event::Telemetry>>,
+    ) {
+        // Forget names whose window has ended, once a period, so that
+        // names seen once do not accumulate.
+        let period = self.period;
+        match self.last_prune {
+            Some(last) if now.duration_since(last) < period => {}
+            _ => {
+                self.windows
+                    .retain(|_, &mut (start, _)| now.duration_since(start) < period);
+                self.last_prune = Some(now);
+            }
+        }
+
+        if !self.windows.contains_key(&*event.name) {
+            self.windows.insert(Arc::clone(&event.name), (now, 0));
+        }
+        let window = self.windows.get_mut(&*event.name).unwrap();
+        if now.duration_since(window.0) >= period {
+            *window = (now, 0);
+        }
+        if window.1 < self.limit {
+            window.1 += 1;
+            res.push(event);
+        } else {
+            self.dropped += 1;
+        }
+    }
+}
+
+impl Filter for RateLimitFilter {
+    fn process(
+        &mut self,
+        event: Arc<event::Telemetry>,
+        res: &mut Vec<Arc<event::Telemetry>>,
+    ) {
+        self.process_at(event, Instant::now(), res);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::{MetricKind, Telemetry};
+
+    fn telem(name: &str) -> Arc<Telemetry> {
+        Arc::new(Telemetry::new(name.to_string(), 1.0, MetricKind::Counter))
+    }
+
+    #[test]
+    fn limits_each_name_per_window() {
+        let mut filter = RateLimitFilter::new(2, Duration::from_secs(1));
+        let start = Instant::now();
+        let mut res = Vec::new();
+        for name in &["a", "a", "b", "a", "b", "b"] {
+            filter.process_at(telem(name), start, &mut res);
+        }
+        let names: Vec<&str> = res.iter().map(|telem| &*telem.name).collect();
+        assert_eq!(vec!["a", "a", "b", "b"], names);
+        assert_eq!(2, filter.dropped());
+
+        // A new window for `a` opens a second after its first event.
+        res.clear();
+        filter.process_at(telem("a"), start + Duration::from_millis(999), &mut res);
+        filter.process_at(telem("a"), start + Duration::from_millis(1000), &mut res);
+        filter.process_at(telem("a"), start + Duration::from_millis(1001), &mut res);
+        filter.process_at(telem("a"), start + Duration::from_millis(1002), &mut res);
+        assert_eq!(2, res.len());
+        assert_eq!(4, filter.dropped());
+    }
+
+    #[test]
+    fn stale_names_are_forgotten() {
+        let mut filter = RateLimitFilter::new(1, Duration::from_secs(1));
+        let start = Instant::now();
+        let mut res = Vec::new();
+        for idx in 0..100 {
+            filter.process_at(telem(&format!("once.{}", idx)), start, &mut res);
+        }
+        assert_eq!(100, filter.windows.len());
+        filter.process_at(telem("later"), start + Duration::from_secs(2), &mut res);
+        assert_eq!(1, filter.windows.len());
+        assert_eq!(101, res.len());
+    }
+}
@@ -0,0 +1,155 @@
+use event;
+use filter::Filter;
+use regex::Regex;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+enum Rule {
+    Rename(Regex, String),
+    Prefix(String),
+    SetTag(String, String),
+    RenameTag(String, String),
+    DropTag(String),
+}
+
+/// Rewrites the name and tags of every event it passes, applying its rules
+/// in the order they were added.
+#[derive(Debug, Clone, Default)]
+pub struct RelabelFilter {
+    rules: Vec<Rule>,
+}
+
+impl RelabelFilter {
+    pub fn new() -> Self {
+        RelabelFilter::default()
+    }
+
+    /// Replaces every match of `regex` in the name with `replacement`,
+    /// which may refer to capture groups as `$1` or `$name`.
+    pub fn rename(mut self, regex: Regex, replacement: &str) -> Self {
+        self.rules.push(Rule::Rename(regex, replacement.to_string()));
+        self
+    }
+
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.rules.push(Rule::Prefix(prefix.to_string()));
+        self
+    }
+
+    /// Adds the tag, replacing any of the same key.
+    pub fn set_tag(mut self, key: &str, value: &str) -> Self {
+        self.rules.push(Rule::SetTag(key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn rename_tag(mut self, from: &str, to: &str) -> Self {
+        self.rules.push(Rule::RenameTag(from.to_string(), to.to_string()));
+        self
+    }
+
+    pub fn drop_tag(mut self, key: &str) -> Self {
+        self.rules.push(Rule::DropTag(key.to_string()));
+        self
+    }
+}
+
+impl Filter for RelabelFilter {
+    fn process(
+        &mut self,
+        mut event: Arc<event::Telemetry>,
+        res: &mut Vec<Arc<event::Telemetry>>,
+    ) {
+        // The event is copied only if another stage shares it.
+        let telem = Arc::make_mut(&mut event);
+        for rule in &self.rules {
+            match *rule {
+                Rule::Rename(ref regex, ref replacement) => {
+                    let renamed = regex.replace_all(&telem.name, replacement.as_str());
+                    telem.name = event::intern(&renamed);
+                }
+                Rule::Prefix(ref prefix) => {
+                    telem.name = event::intern(&format!("{}{}", prefix, telem.name));
+                }
+                Rule::SetTag(ref key, ref value) => {
+                    telem.tags.retain(|tag| tag.0 != *key);
+                    telem.tags.push((key.clone(), value.clone()));
+                }
+                Rule::RenameTag(ref from, ref to) => {
+                    for tag in &mut telem.tags {
+                        if tag.0 == *from {
+                            tag.0 = to.clone();
+                        }
+                    }
+                }
+                Rule::DropTag(ref key) => telem.tags.retain(|tag| tag.0 != *key),
+            }
+        }
+        res.push(event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::{MetricKind, Telemetry};
+
+    fn relabel(
+        filter: &mut RelabelFilter,
+        name: &str,
+        tags: &[(&str, &str)],
+    ) -> Arc<Telemetry> {
+        let mut telem = Telemetry::new(name.to_string(), 1.0, MetricKind::Counter);
+        telem.tags = tags
+            .iter()
+            .map(|&(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut res = Vec::new();
+        filter.process(Arc::new(telem), &mut res);
+        assert_eq!(1, res.len());
+        res.pop().unwrap()
+    }
+
+    #[test]
+    fn renames_in_order() {
+        let mut filter = RelabelFilter::new()
+            .rename(Regex::new(r"^servers\.(\w+)\.").unwrap(), "hosts.$1.")
+            .prefix("prod.");
+        let telem = relabel(&mut filter, "servers.web1.cpu", &[]);
+        assert_eq!("prod.hosts.web1.cpu", &*telem.name);
+        let telem = relabel(&mut filter, "other.cpu", &[]);
+        assert_eq!("prod.other.cpu", &*telem.name);
+    }
+
+    #[test]
+    fn relabels_tags() {
+        let mut filter = RelabelFilter::new()
+            .set_tag("env", "prod")
+            .rename_tag("host", "instance")
+            .drop_tag("debug");
+        let telem = relabel(
+            &mut filter,
+            "x",
+            &[("env", "dev"), ("host", "a"), ("debug", ""), ("zone", "1")],
+        );
+        assert_eq!(
+            vec![
+                ("instance".to_string(), "a".to_string()),
+                ("zone".to_string(), "1".to_string()),
+                ("env".to_string(), "prod".to_string()),
+            ],
+            telem.tags
+        );
+    }
+
+    #[test]
+    fn shared_events_are_copied_not_changed() {
+        let mut filter = RelabelFilter::new().prefix("prod.");
+        let cpu = Telemetry::new("cpu".to_string(), 1.0, MetricKind::Gauge);
+        let shared = Arc::new(cpu);
+        let mut res = Vec::new();
+        filter.process(Arc::clone(&shared), &mut res);
+        assert_eq!("cpu", &*shared.name);
+        assert_eq!("prod.cpu", &*res[0].name);
+        assert!(Arc::ptr_eq(&event::intern("prod.cpu"), &res[0].name));
+    }
+}
@@ -0,0 +1,137 @@
+use event;
+use filter::Filter;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sampling {
+    OneIn(u64),
+    Probability(f64),
+}
+
+/// Passes a sample of telemetry, scaling down the sample rate of what it
+/// passes so that counters are still scaled back up correctly by egress.
+pub struct SampleFilter {
+    sampling: Sampling,
+    seen: u64,
+    /// xorshift64* state, never zero.
+    rng: u64,
+}
+
+impl SampleFilter {
+    /// Passes the first of every `n` events.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is zero.
+    pub fn one_in(n: u64) -> Self {
+        assert!(n > 0, "cannot sample one in zero");
+        SampleFilter::new(Sampling::OneIn(n))
+    }
+
+    /// Passes each event independently with probability `p`.
+    ///
+    /// # Panics
+    ///
+    /// If `p` is not in (0, 1].
+    pub fn probability(p: f64) -> Self {
+        assert!(p > 0.0 && p <= 1.0, "sampling probability must be in (0, 1]");
+        SampleFilter::new(Sampling::Probability(p))
+    }
+
+    /// Seeds the random choices of a probabilistic sample, making them
+    /// repeatable. By default they are seeded from the clock.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = seed | 1;
+        self
+    }
+
+    fn new(sampling: Sampling) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let filter = SampleFilter {
+            sampling,
+            seen: 0,
+            rng: 1,
+        };
+        filter.with_seed(0x9E37_79B9_7F4A_7C15 ^ u64::from(nanos))
+    }
+
+    /// A uniform float in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let bits = self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11;
+        bits as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Filter for SampleFilter {
+    fn process(
+        &mut self,
+        mut event: Arc<event::Telemetry>,
+        res: &mut Vec<Arc<event::Telemetry>>,
+    ) {
+        let (keep, rate) = match self.sampling {
+            Sampling::OneIn(n) => {
+                let keep = self.seen.is_multiple_of(n);
+                self.seen = self.seen.wrapping_add(1);
+                (keep, 1.0 / n as f64)
+            }
+            Sampling::Probability(p) => (self.next_f64() < p, p),
+        };
+        if keep {
+            Arc::make_mut(&mut event).sample_rate *= rate;
+            res.push(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::{MetricKind, Telemetry};
+
+    fn sample(filter: &mut SampleFilter, count: usize) -> Vec<Arc<Telemetry>> {
+        let mut res = Vec::new();
+        for value in 0..count {
+            let telem = Telemetry::new("x".to_string(), value as f64, MetricKind::Counter);
+            filter.process(Arc::new(telem), &mut res);
+        }
+        res
+    }
+
+    #[test]
+    fn one_in_n_keeps_every_nth() {
+        let kept = sample(&mut SampleFilter::one_in(3), 10);
+        let values: Vec<f64> = kept.iter().map(|telem| telem.value).collect();
+        assert_eq!(vec![0.0, 3.0, 6.0, 9.0], values);
+        assert!(kept.iter().all(|telem| telem.sample_rate == 1.0 / 3.0));
+    }
+
+    #[test]
+    fn probability_keeps_about_that_fraction() {
+        let kept = sample(&mut SampleFilter::probability(0.25).with_seed(7), 10_000);
+        assert!(kept.len() > 2_300 && kept.len() < 2_700, "kept {}", kept.len());
+        assert!(kept.iter().all(|telem| telem.sample_rate == 0.25));
+
+        // The same seed makes the same choices.
+        let again = sample(&mut SampleFilter::probability(0.25).with_seed(7), 10_000);
+        let values = |telems: &[Arc<Telemetry>]| -> Vec<f64> {
+            telems.iter().map(|telem| telem.value).collect()
+        };
+        assert_eq!(values(&kept), values(&again));
+    }
+
+    #[test]
+    fn rates_compound_with_the_senders() {
+        let mut telem = Telemetry::new("x".to_string(), 1.0, MetricKind::Counter);
+        telem.sample_rate = 0.5;
+        let mut res = Vec::new();
+        SampleFilter::one_in(2).process(Arc::new(telem), &mut res);
+        assert_eq!(0.25, res[0].sample_rate);
+    }
+}
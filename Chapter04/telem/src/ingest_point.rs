@@ -1,42 +1,220 @@
+use channel;
 use event;
+use parser::{NativeParser, Parser};
 use std::{net, thread};
+use std::io::{self, BufRead, BufReader, Read};
 use std::net::ToSocketAddrs;
 use std::str;
-use std::str::FromStr;
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 use util;
 
+/// The longest line accepted, the same as the largest UDP packet read.
+const MAX_LINE: usize = 16_250;
+/// How often listeners blocked on their sockets check for a shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a listener waits after its socket fails, as when the process
+/// is out of file descriptors, so a lasting failure does not spin.
+const ERROR_RETRY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Udp,
+    /// Newline-delimited lines, one thread per connection.
+    Tcp,
+}
+
+enum Socket {
+    Udp(net::UdpSocket),
+    Tcp(net::TcpListener),
+}
+
+struct Listener {
+    socket: Socket,
+    parser: Arc<dyn Parser>,
+    chans: Vec<channel::Sender>,
+}
+
+/// Counts of what an `IngestPoint` has taken in, shared across all its
+/// listeners. Lines that fail to parse are counted rather than silently
+/// dropped.
+#[derive(Debug, Default)]
+pub struct IngestCounters {
+    /// UDP packets, each of which may carry several lines. TCP streams
+    /// are counted only by their lines.
+    pub packets: AtomicUsize,
+    pub lines: AtomicUsize,
+    pub parse_errors: AtomicUsize,
+    /// Lines dropped for not being UTF-8 or for being longer than
+    /// `MAX_LINE`.
+    pub malformed: AtomicUsize,
+    /// The totals as of the last call to `telemetry`.
+    reported: Mutex<Vec<f64>>,
+}
+
+impl IngestCounters {
+    /// The counters as telemetry about the pipeline itself: counters
+    /// `telem.ingest.packets`, `.lines`, `.parse_errors` and `.malformed`
+    /// of what came in since the last call.
+    pub fn telemetry(&self) -> Vec<event::Telemetry> {
+        let totals = self.totals();
+        let mut reported = self.reported.lock().unwrap_or_else(|e| e.into_inner());
+        reported.resize(totals.len(), 0.0);
+        totals
+            .into_iter()
+            .zip(reported.iter_mut())
+            .map(|((name, total), reported)| {
+                let since = total - ::std::mem::replace(reported, total);
+                event::Telemetry::new(name, since, event::MetricKind::Counter)
+            })
+            .collect()
+    }
+
+    /// Each counter under the name `telemetry` gives it, counted since
+    /// ingest started.
+    pub fn totals(&self) -> Vec<(String, f64)> {
+        let counters = [
+            ("packets", &self.packets),
+            ("lines", &self.lines),
+            ("parse_errors", &self.parse_errors),
+            ("malformed", &self.malformed),
+        ];
+        counters
+            .iter()
+            .map(|&(name, count)| {
+                let total = count.load(Ordering::Relaxed) as f64;
+                (format!("telem.ingest.{}", name), total)
+            })
+            .collect()
+    }
+}
+
 pub struct IngestPoint {
-    host: String,
-    port: u16,
-    chans: Vec<mpsc::Sender<event::Event>>,
+    listeners: Vec<Listener>,
+    chans: Vec<channel::Sender>,
+    counters: Arc<IngestCounters>,
+    shutdown: Arc<AtomicBool>,
 }
 
 impl IngestPoint {
+    /// An ingest point with a single UDP listener for telem's own
+    /// `<name> <value>` format.
     pub fn init(
         host: String,
         port: u16,
-        chans: Vec<mpsc::Sender<event::Event>>,
-    ) -> IngestPoint {
+        chans: Vec<channel::Sender>,
+    ) -> io::Result<IngestPoint> {
+        let mut ingest = IngestPoint::new(chans);
+        ingest.listen(Transport::Udp, host, port, NativeParser)?;
+        Ok(ingest)
+    }
+
+    /// An ingest point with no listeners; add them with `listen`.
+    pub fn new(chans: Vec<channel::Sender>) -> IngestPoint {
         IngestPoint {
-            chans: chans,
-            host: host,
-            port: port,
+            listeners: Vec::new(),
+            chans,
+            counters: Default::default(),
+            shutdown: Default::default(),
         }
     }
 
+    /// Adds a listener on `host:port`, parsing what arrives with `parser`.
+    ///
+    /// The listener is bound on every address `host` resolves to straight
+    /// away, so a host that does not resolve or a port in use is an error
+    /// here rather than in `run`. Nothing is read before `run`.
+    pub fn listen<P>(
+        &mut self,
+        transport: Transport,
+        host: String,
+        port: u16,
+        parser: P,
+    ) -> io::Result<&mut IngestPoint>
+    where
+        P: 'static + Parser,
+    {
+        let chans = self.chans.clone();
+        self.listen_into(transport, host, port, parser, chans)
+    }
+
+    /// Like `listen`, but sends what the listener parses into `chans`
+    /// rather than into the ingest point's own channels.
+    pub fn listen_into<P>(
+        &mut self,
+        transport: Transport,
+        host: String,
+        port: u16,
+        parser: P,
+        chans: Vec<channel::Sender>,
+    ) -> io::Result<&mut IngestPoint>
+    where
+        P: 'static + Parser,
+    {
+        let addrs: Vec<_> = (host.as_str(), port).to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} resolves to no address", host),
+            ));
+        }
+        let parser: Arc<dyn Parser> = Arc::new(parser);
+        for addr in addrs {
+            let socket = match transport {
+                Transport::Udp => {
+                    let socket = net::UdpSocket::bind(addr)?;
+                    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+                    Socket::Udp(socket)
+                }
+                Transport::Tcp => {
+                    let socket = net::TcpListener::bind(addr)?;
+                    socket.set_nonblocking(true)?;
+                    Socket::Tcp(socket)
+                }
+            };
+            self.listeners.push(Listener {
+                socket,
+                parser: Arc::clone(&parser),
+                chans: chans.clone(),
+            });
+        }
+        Ok(self)
+    }
+
+    pub fn counters(&self) -> Arc<IngestCounters> {
+        Arc::clone(&self.counters)
+    }
+
+    /// Setting the returned flag makes `run` return once every listener
+    /// has noticed, within `POLL_INTERVAL`. TCP connections already accepted
+    /// are not waited for; they end when their peer hangs up or when
+    /// nothing downstream is left to send to.
+    pub fn shutdown_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.shutdown)
+    }
+
+    /// Reads from every listener until a shutdown. The listeners are
+    /// handed to their threads, so a second `run` has nothing to read.
     pub fn run(&mut self) {
         let mut joins = Vec::new();
 
-        let addrs = (self.host.as_str(), self.port).to_socket_addrs();
-        if let Ok(ips) = addrs {
-            let ips: Vec<_> = ips.collect();
-            for addr in ips {
-                let listener =
-                    net::UdpSocket::bind(addr).expect("Unable to bind to UDP socket");
-                let chans = self.chans.clone();
-                joins.push(thread::spawn(move || handle_udp(chans, &listener)));
-            }
+        for listener in self.listeners.drain(..) {
+            let Listener {
+                socket,
+                parser,
+                chans,
+            } = listener;
+            let counters = Arc::clone(&self.counters);
+            let shutdown = Arc::clone(&self.shutdown);
+            joins.push(match socket {
+                Socket::Udp(socket) => thread::spawn(move || {
+                    handle_udp(chans, &socket, &*parser, &counters, &shutdown)
+                }),
+                Socket::Tcp(socket) => thread::spawn(move || {
+                    handle_tcp(&chans, &socket, &parser, &counters, &shutdown)
+                }),
+            });
         }
 
         for jh in joins {
@@ -45,33 +223,288 @@ impl IngestPoint {
     }
 }
 
-fn parse_packet(buf: &str) -> Option<event::Telemetry> {
-    let mut iter = buf.split_whitespace();
-    if let Some(name) = iter.next() {
-        if let Some(val) = iter.next() {
-            match u32::from_str(val) {
-                Ok(int) => {
-                    return Some(event::Telemetry {
-                        name: name.to_string(),
-                        value: int,
-                    })
-                }
-                Err(_) => return None,
-            };
+/// Parses packets handed to it rather than read from a socket, as a UDP
+/// listener does what it receives: a packet may carry several lines, and
+/// lines that fail to parse are counted.
+pub struct Ingester<P> {
+    chans: Vec<channel::Sender>,
+    parser: P,
+    counters: Arc<IngestCounters>,
+}
+
+impl<P: Parser> Ingester<P> {
+    pub fn new(parser: P, chans: Vec<channel::Sender>) -> Ingester<P> {
+        Ingester {
+            chans,
+            parser,
+            counters: Default::default(),
         }
     }
-    None
+
+    pub fn counters(&self) -> Arc<IngestCounters> {
+        Arc::clone(&self.counters)
+    }
+
+    /// Sends on what `packet` holds. False once nothing downstream is left
+    /// to send to.
+    pub fn packet(&mut self, packet: &[u8]) -> bool {
+        ingest_packet(&mut self.chans, packet, &self.parser, &self.counters).is_ok()
+    }
+
+    /// Sends `event`, such as a flush or a shutdown, down every channel.
+    pub fn send(&mut self, event: event::Event) -> bool {
+        util::send(&mut self.chans, event).is_ok()
+    }
 }
 
-fn handle_udp(mut chans: Vec<mpsc::Sender<event::Event>>, socket: &net::UdpSocket) {
-    let mut buf = vec![0; 16_250];
-    loop {
+/// Parses one line, sending on what parses and counting what does not.
+fn ingest_line(
+    chans: &mut Vec<channel::Sender>,
+    line: &[u8],
+    parser: &dyn Parser,
+    counters: &IngestCounters,
+) -> Result<(), util::Disconnected> {
+    let line = match line.last() {
+        Some(&b'\r') => &line[..line.len() - 1],
+        _ => line,
+    };
+    if line.is_empty() {
+        return Ok(());
+    }
+    counters.lines.fetch_add(1, Ordering::Relaxed);
+    let line = match str::from_utf8(line) {
+        Ok(line) => line,
+        Err(_) => {
+            counters.malformed.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+    };
+    match parser.parse(line) {
+        Ok(telem) => util::send(chans, event::Event::Telemetry(Arc::new(telem))),
+        Err(_) => {
+            counters.parse_errors.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+}
+
+/// A packet may carry several lines.
+fn ingest_packet(
+    chans: &mut Vec<channel::Sender>,
+    packet: &[u8],
+    parser: &dyn Parser,
+    counters: &IngestCounters,
+) -> Result<(), util::Disconnected> {
+    counters.packets.fetch_add(1, Ordering::Relaxed);
+    for line in packet.split(|b| *b == b'\n') {
+        ingest_line(chans, line, parser, counters)?;
+    }
+    Ok(())
+}
+
+fn timed_out(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+fn handle_udp(
+    mut chans: Vec<channel::Sender>,
+    socket: &net::UdpSocket,
+    parser: &dyn Parser,
+    counters: &IngestCounters,
+    shutdown: &AtomicBool,
+) {
+    // One buffer for every packet; lines are parsed straight out of it.
+    let mut buf = vec![0; MAX_LINE];
+    while !shutdown.load(Ordering::Relaxed) {
         let (len, _) = match socket.recv_from(&mut buf) {
             Ok(r) => r,
-            Err(e) => panic!(format!("Could not read UDP socket with error {:?}", e)),
+            Err(ref e) if timed_out(e) => continue,
+            Err(e) => {
+                eprintln!("Could not read UDP socket with error {:?}", e);
+                thread::sleep(ERROR_RETRY);
+                continue;
+            }
         };
-        if let Some(telem) = parse_packet(str::from_utf8(&buf[..len]).unwrap()) {
-            util::send(&mut chans, event::Event::Telemetry(telem));
+        if ingest_packet(&mut chans, &buf[..len], parser, counters).is_err() {
+            return;
+        }
+    }
+}
+
+fn handle_tcp(
+    chans: &[channel::Sender],
+    socket: &net::TcpListener,
+    parser: &Arc<dyn Parser>,
+    counters: &Arc<IngestCounters>,
+    shutdown: &AtomicBool,
+) {
+    while !shutdown.load(Ordering::Relaxed) {
+        let stream = match socket.accept() {
+            Ok((stream, _)) => stream,
+            Err(ref e) if timed_out(e) => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Could not accept TCP connection with error {:?}", e);
+                thread::sleep(ERROR_RETRY);
+                continue;
+            }
+        };
+        if stream.set_nonblocking(false).is_err() {
+            continue;
+        }
+        let mut chans = chans.to_vec();
+        let parser = Arc::clone(parser);
+        let counters = Arc::clone(counters);
+        thread::spawn(move || {
+            let _ = ingest_stream(&mut chans, BufReader::new(stream), &*parser, &counters);
+        });
+    }
+}
+
+/// Reads newline-delimited lines from `reader` until it is exhausted or
+/// nothing downstream is left. A final line without a newline still counts.
+/// Lines longer than `MAX_LINE` are skipped.
+fn ingest_stream<R: BufRead>(
+    chans: &mut Vec<channel::Sender>,
+    mut reader: R,
+    parser: &dyn Parser,
+    counters: &IngestCounters,
+) -> io::Result<()> {
+    let mut line = Vec::with_capacity(256);
+    loop {
+        line.clear();
+        let len = (&mut reader)
+            .take(MAX_LINE as u64 + 1)
+            .read_until(b'\n', &mut line)?;
+        if len == 0 {
+            return Ok(());
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        } else if line.len() > MAX_LINE {
+            counters.malformed.fetch_add(1, Ordering::Relaxed);
+            // Discard the rest of the overlong line.
+            loop {
+                line.clear();
+                let len = (&mut reader)
+                    .take(MAX_LINE as u64)
+                    .read_until(b'\n', &mut line)?;
+                if len == 0 || line.last() == Some(&b'\n') {
+                    break;
+                }
+            }
+            continue;
+        }
+        if ingest_line(chans, &line, parser, counters).is_err() {
+            return Ok(());
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parser::{GraphiteParser, StatsdParser};
+    use std::io::Cursor;
+
+    fn names(recv: &channel::Receiver) -> Vec<String> {
+        recv.try_iter()
+            .map(|event| match event {
+                event::Event::Telemetry(telem) => telem.name.to_string(),
+                _ => panic!("expected only telemetry"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn packets_split_on_newlines() {
+        let (snd, recv) = channel::bounded("test", 8, channel::Policy::Block);
+        let counters = IngestCounters::default();
+        let packet = b"a:1|c\nb:2|ms\r\n\nnonsense\nc:3|g\n";
+        ingest_packet(&mut vec![snd], packet, &StatsdParser, &counters).unwrap();
+        assert_eq!(vec!["a", "b", "c"], names(&recv));
+        assert_eq!(4, counters.lines.load(Ordering::Relaxed));
+        assert_eq!(1, counters.parse_errors.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn counters_report_what_came_in_since_last_time() {
+        let (snd, _recv) = channel::bounded("test", 8, channel::Policy::Block);
+        let mut ingester = Ingester::new(StatsdParser, vec![snd]);
+        let counters = ingester.counters();
+        let values = || -> Vec<(String, f64)> {
+            counters
+                .telemetry()
+                .into_iter()
+                .map(|telem| (telem.name.to_string(), telem.value))
+                .collect()
+        };
+        assert!(ingester.packet(b"a:1|c\nnonsense"));
+        assert!(ingester.packet(b"b:1|c"));
+        assert_eq!(
+            vec![
+                ("telem.ingest.packets".to_string(), 2.0),
+                ("telem.ingest.lines".to_string(), 3.0),
+                ("telem.ingest.parse_errors".to_string(), 1.0),
+                ("telem.ingest.malformed".to_string(), 0.0),
+            ],
+            values()
+        );
+        assert!(ingester.packet(b"c:1|c"));
+        let again: Vec<f64> = values().into_iter().map(|v| v.1).collect();
+        assert_eq!(vec![1.0, 1.0, 0.0, 0.0], again);
+        assert_eq!(3.0, counters.totals()[0].1);
+    }
+
+    #[test]
+    fn streams_are_framed_by_line() {
+        let (snd, recv) = channel::bounded("test", 8, channel::Policy::Block);
+        let counters = IngestCounters::default();
+        let mut input = b"a 1 1500000000\n\xff\xfe 1 1\n".to_vec();
+        input.extend(vec![b'x'; MAX_LINE * 2]);
+        input.extend_from_slice(b"\nb 2 1500000000");
+        let mut chans = vec![snd];
+        ingest_stream(&mut chans, Cursor::new(input), &GraphiteParser, &counters).unwrap();
+        assert_eq!(vec!["a", "b"], names(&recv));
+        assert_eq!(2, counters.malformed.load(Ordering::Relaxed));
+        assert_eq!(0, counters.parse_errors.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn listening_fails_on_a_port_in_use() {
+        let udp = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut ingest = IngestPoint::new(Vec::new());
+        let port = udp.local_addr().unwrap().port();
+        let host = "127.0.0.1".to_string();
+        let e = ingest.listen(Transport::Udp, host.clone(), port, NativeParser);
+        assert_eq!(io::ErrorKind::AddrInUse, e.err().unwrap().kind());
+        let port = tcp.local_addr().unwrap().port();
+        let e = ingest.listen(Transport::Tcp, host, port, NativeParser);
+        assert_eq!(io::ErrorKind::AddrInUse, e.err().unwrap().kind());
+        assert!(ingest.listeners.is_empty());
+    }
+
+    #[test]
+    fn ingester_shares_names_across_packets() {
+        let (snd, recv) = channel::bounded("test", 8, channel::Policy::Block);
+        let mut ingester = Ingester::new(StatsdParser, vec![snd]);
+        assert!(ingester.packet(b"hits:1|c\nhits:2|c"));
+        assert!(ingester.packet(b"hits:3|c"));
+        let names: Vec<Arc<str>> = recv
+            .try_iter()
+            .map(|event| match event {
+                event::Event::Telemetry(telem) => Arc::clone(&telem.name),
+                _ => panic!("expected only telemetry"),
+            })
+            .collect();
+        assert_eq!(3, names.len());
+        assert!(names.iter().all(|name| Arc::ptr_eq(name, &names[0])));
+        assert_eq!(3, ingester.counters().lines.load(Ordering::Relaxed));
+
+        drop(recv);
+        assert!(!ingester.packet(b"hits:4|c"));
+    }
+}
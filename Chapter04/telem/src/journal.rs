@@ -0,0 +1,414 @@
+//! A write-ahead journal of events, kept on disk across restarts.
+//!
+//! Events are appended to numbered segment files in a directory and read
+//! back in the order written. Reading an event does not forget it: that
+//! takes an acknowledgement, and the position last acknowledged is kept in
+//! the directory alongside the segments. Opening a journal reads again
+//! everything written after that position, so events read but not yet
+//! dealt with when the process died are not lost. Segments wholly before
+//! the acknowledged position are deleted.
+//!
+//! A crash partway through a write leaves part of an event at the end of
+//! the last segment, which is cut off when the journal is next opened.
+use event::Event;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A segment is closed and another started once it is this long, so that
+/// acknowledged events are deleted a segment at a time.
+const SEGMENT_BYTES: u64 = 4 * 1024 * 1024;
+const SEGMENT_SUFFIX: &str = ".journal";
+const ACK_FILE: &str = "ack";
+
+/// A place in the journal, between two events.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    segment: u64,
+    offset: u64,
+}
+
+struct Segment {
+    id: u64,
+    len: u64,
+}
+
+/// Counts the bytes read through it, which is the offset into a segment.
+struct Counted<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+/// A directory of segments written by one channel and read by its
+/// receiver. Nothing else may use the directory while it is open.
+pub struct Journal {
+    dir: PathBuf,
+    segment_bytes: u64,
+    /// Every segment on disk, oldest first. The last is written to.
+    segments: VecDeque<Segment>,
+    writer: File,
+    reader: Option<Counted<BufReader<File>>>,
+    read: Position,
+    acked: Position,
+    /// Events written and not yet read.
+    unread: usize,
+    /// The end of what the journal held when it was opened.
+    opened_at: Position,
+    buf: Vec<u8>,
+}
+
+impl Journal {
+    /// Opens the journal in the directory `dir`, creating it if need be.
+    /// Reads start at the last position acknowledged.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Journal> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let id = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(SEGMENT_SUFFIX))
+                .and_then(|id| id.parse::<u64>().ok());
+            if let Some(id) = id {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+
+        let mut acked = read_ack(&dir)?;
+        // Segments before the acknowledged one are left only by a crash
+        // between acknowledging and deleting them.
+        for id in ids.iter().filter(|id| **id < acked.segment) {
+            fs::remove_file(segment_path(&dir, *id))?;
+        }
+        ids.retain(|id| *id >= acked.segment);
+        match ids.first() {
+            Some(&first) if first > acked.segment => {
+                acked = Position {
+                    segment: first,
+                    offset: 0,
+                }
+            }
+            Some(_) => {}
+            None => ids.push(acked.segment),
+        }
+
+        let mut segments = VecDeque::new();
+        let mut unread = 0;
+        let last = *ids.last().unwrap();
+        for id in ids {
+            let start = if id == acked.segment { acked.offset } else { 0 };
+            let (events, len) = scan(&segment_path(&dir, id), start, id == last)?;
+            unread += events;
+            segments.push_back(Segment { id, len });
+        }
+        let writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&dir, last))?;
+        let end = segments.back().unwrap();
+        let opened_at = Position {
+            segment: end.id,
+            offset: end.len,
+        };
+        Ok(Journal {
+            dir,
+            segment_bytes: SEGMENT_BYTES,
+            segments,
+            writer,
+            reader: None,
+            read: acked,
+            acked,
+            unread,
+            opened_at,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Events written and not yet read.
+    pub fn unread(&self) -> usize {
+        self.unread
+    }
+
+    /// Appends `event`. It is in the journal once this returns, though
+    /// only safe from a failing machine, as opposed to a failing process,
+    /// after a `sync`.
+    pub fn append(&mut self, event: &Event) -> io::Result<()> {
+        if self.segments.back().unwrap().len >= self.segment_bytes {
+            self.start_segment()?;
+        }
+        self.buf.clear();
+        event.encode(&mut self.buf);
+        let segment = self.segments.back_mut().unwrap();
+        if let Err(e) = self.writer.write_all(&self.buf) {
+            // Leave no part of the event behind to be misread.
+            let _ = self.writer.set_len(segment.len);
+            return Err(e);
+        }
+        segment.len += self.buf.len() as u64;
+        self.unread += 1;
+        Ok(())
+    }
+
+    /// Waits until everything appended is on disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.sync_data()
+    }
+
+    fn start_segment(&mut self) -> io::Result<()> {
+        self.writer.sync_data()?;
+        let id = self.segments.back().unwrap().id + 1;
+        self.writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, id))?;
+        self.segments.push_back(Segment { id, len: 0 });
+        Ok(())
+    }
+
+    /// The oldest event not yet read and the position just past it. A
+    /// shutdown the journal held when it was opened is passed over: the
+    /// run of the pipeline it stopped is over.
+    pub fn read(&mut self) -> io::Result<Option<(Event, Position)>> {
+        while self.unread > 0 {
+            let len = self.segment(self.read.segment).map_or(0, |s| s.len);
+            if self.read.offset >= len {
+                let next = self.segments.iter().find(|s| s.id > self.read.segment);
+                self.read = match next {
+                    Some(next) => Position {
+                        segment: next.id,
+                        offset: 0,
+                    },
+                    None => return Ok(None),
+                };
+                self.reader = None;
+                continue;
+            }
+            if self.reader.is_none() {
+                let mut file = File::open(segment_path(&self.dir, self.read.segment))?;
+                file.seek(SeekFrom::Start(self.read.offset))?;
+                self.reader = Some(Counted {
+                    inner: BufReader::new(file),
+                    count: self.read.offset,
+                });
+            }
+            let reader = self.reader.as_mut().unwrap();
+            let decoded = Event::decode(reader);
+            let event = match decoded {
+                Ok(event) => event,
+                Err(e) => {
+                    self.reader = None;
+                    return Err(e);
+                }
+            };
+            self.read.offset = reader.count;
+            self.unread -= 1;
+            match event {
+                Event::Shutdown if self.read <= self.opened_at => continue,
+                event => return Ok(Some((event, self.read))),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Gives up on every unread event, as when they cannot be read,
+    /// returning how many there were.
+    pub fn skip_unread(&mut self) -> usize {
+        let end = self.segments.back().unwrap();
+        self.read = Position {
+            segment: end.id,
+            offset: end.len,
+        };
+        self.reader = None;
+        ::std::mem::replace(&mut self.unread, 0)
+    }
+
+    /// Forgets the events before `pos`, which must have been read. They
+    /// are not read again when the journal is next opened.
+    pub fn ack(&mut self, pos: Position) -> io::Result<()> {
+        if pos <= self.acked {
+            return Ok(());
+        }
+        let tmp = self.dir.join(format!("{}.tmp", ACK_FILE));
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "{} {}", pos.segment, pos.offset)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(ACK_FILE))?;
+        self.acked = pos;
+        while self.segments.front().is_some_and(|s| s.id < pos.segment) {
+            let segment = self.segments.pop_front().unwrap();
+            fs::remove_file(segment_path(&self.dir, segment.id))?;
+        }
+        Ok(())
+    }
+
+    fn segment(&self, id: u64) -> Option<&Segment> {
+        self.segments.iter().find(|s| s.id == id)
+    }
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}{}", id, SEGMENT_SUFFIX))
+}
+
+fn read_ack(dir: &Path) -> io::Result<Position> {
+    let ack = match fs::read_to_string(dir.join(ACK_FILE)) {
+        Ok(ack) => ack,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Position::default())
+        }
+        Err(e) => return Err(e),
+    };
+    let mut fields = ack.split_whitespace().map(str::parse::<u64>);
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(Ok(segment)), Some(Ok(offset)), None) => {
+            Ok(Position { segment, offset })
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the journal's acknowledged position is unreadable",
+        )),
+    }
+}
+
+/// Counts the events in the segment at `path` from `start`, returning the
+/// count and the segment's length. A partial event at the end of the last
+/// segment is cut off; anywhere else it is an error.
+fn scan(path: &Path, start: u64, last: bool) -> io::Result<(usize, u64)> {
+    let file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound && last => {
+            return Ok((0, 0))
+        }
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata()?.len();
+    let mut reader = Counted {
+        inner: BufReader::new(&file),
+        count: start,
+    };
+    reader.inner.seek(SeekFrom::Start(start))?;
+    let mut events = 0;
+    while reader.count < len {
+        let end = reader.count;
+        match Event::decode(&mut reader) {
+            Ok(_) => events += 1,
+            Err(ref e) if last && e.kind() == io::ErrorKind::UnexpectedEof => {
+                file.set_len(end)?;
+                return Ok((events, end));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((events, len))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::{MetricKind, Telemetry};
+    use std::env;
+    use std::process;
+    use std::sync::Arc;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir =
+            env::temp_dir().join(format!("telem-journal-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn telem(value: f64) -> Event {
+        let mut telem = Telemetry::new("x", value, MetricKind::Gauge);
+        telem.timestamp = 0;
+        Event::Telemetry(Arc::new(telem))
+    }
+
+    fn read_all(journal: &mut Journal) -> Vec<(Event, Position)> {
+        let mut read = Vec::new();
+        while let Some(event) = journal.read().unwrap() {
+            read.push(event);
+        }
+        read
+    }
+
+    #[test]
+    fn reads_in_order_across_segments() {
+        let dir = dir("order");
+        let mut journal = Journal::open(&dir).unwrap();
+        journal.segment_bytes = 100;
+        let events: Vec<Event> = (0..20).map(|v| telem(f64::from(v))).collect();
+        for event in &events[..10] {
+            journal.append(event).unwrap();
+        }
+        let mut read = read_all(&mut journal);
+        for event in &events[10..] {
+            journal.append(event).unwrap();
+        }
+        read.extend(read_all(&mut journal));
+        assert_eq!(events, read.iter().map(|r| r.0.clone()).collect::<Vec<_>>());
+        assert!(journal.segments.len() > 2);
+
+        journal.ack(read[15].1).unwrap();
+        assert_eq!(read[15].1.segment, journal.segments[0].id);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopening_reads_what_was_not_acknowledged() {
+        let dir = dir("reopen");
+        let mut journal = Journal::open(&dir).unwrap();
+        for value in 0..3 {
+            journal.append(&telem(f64::from(value))).unwrap();
+        }
+        journal.append(&Event::Flush).unwrap();
+        journal.append(&telem(3.0)).unwrap();
+        journal.append(&Event::Shutdown).unwrap();
+        let read = read_all(&mut journal);
+        journal.ack(read[3].1).unwrap();
+        drop(journal);
+
+        // The shutdown ended the last run, so is not read again.
+        let mut journal = Journal::open(&dir).unwrap();
+        assert_eq!(2, journal.unread());
+        let read = read_all(&mut journal);
+        assert_eq!(vec![telem(3.0)], vec![read[0].0.clone()]);
+        assert_eq!(1, read.len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_torn_write_is_cut_off() {
+        let dir = dir("torn");
+        let mut journal = Journal::open(&dir).unwrap();
+        journal.append(&telem(1.0)).unwrap();
+        journal.append(&telem(2.0)).unwrap();
+        drop(journal);
+        let path = segment_path(&dir, 0);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let mut journal = Journal::open(&dir).unwrap();
+        assert_eq!(1, journal.unread());
+        journal.append(&telem(3.0)).unwrap();
+        let read: Vec<Event> =
+            read_all(&mut journal).into_iter().map(|r| r.0).collect();
+        assert_eq!(vec![telem(1.0), telem(3.0)], read);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
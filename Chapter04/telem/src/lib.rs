@@ -2,12 +2,21 @@
 #![deny(trivial_numeric_casts, unstable_features, unused_import_braces)]
 
 extern crate quantiles;
+extern crate regex;
 extern crate seahash;
+extern crate toml;
 
 pub use ingest_point::*;
 
 mod ingest_point;
 mod util;
+pub mod admin;
+pub mod channel;
+pub mod config;
+pub mod emitter;
 pub mod event;
 pub mod filter;
+pub mod journal;
 pub mod egress;
+pub mod parser;
+pub mod supervisor;
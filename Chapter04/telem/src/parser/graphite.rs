@@ -0,0 +1,64 @@
+use event::{self, MetricKind, Telemetry};
+use parser::{parse_value, ParseError, Parser};
+use std::str::FromStr;
+
+/// Graphite's plaintext protocol, `<path>[;<key>=<value>...] <value>
+/// <timestamp>`, the timestamp a Unix time in seconds. Graphite records the
+/// value of a series at a time, so every metric is a gauge.
+pub struct GraphiteParser;
+
+impl Parser for GraphiteParser {
+    fn parse(&self, line: &str) -> Result<Telemetry, ParseError> {
+        let mut iter = line.split_whitespace();
+        let path = iter.next().ok_or(ParseError::Missing("path"))?;
+        let val = iter.next().ok_or(ParseError::Missing("value"))?;
+        let value = parse_value(val)?;
+        let timestamp = iter.next().ok_or(ParseError::Missing("timestamp"))?;
+        let timestamp =
+            u64::from_str(timestamp).map_err(|_| ParseError::Invalid("timestamp"))?;
+        if iter.next().is_some() {
+            return Err(ParseError::Trailing);
+        }
+
+        let mut parts = path.split(';');
+        let name = parts.next().unwrap_or("");
+        if name.is_empty() {
+            return Err(ParseError::Missing("path"));
+        }
+        let mut telem = Telemetry::new(event::intern(name), value, MetricKind::Gauge);
+        telem.timestamp = timestamp;
+        for tag in parts {
+            match tag.find('=') {
+                Some(idx) if idx > 0 => telem
+                    .tags
+                    .push((tag[..idx].to_string(), tag[idx + 1..].to_string())),
+                _ => return Err(ParseError::Invalid("tag")),
+            }
+        }
+        Ok(telem)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_graphite_lines() {
+        let telem = GraphiteParser.parse("servers.web1.load 0.75 1500000000").unwrap();
+        assert_eq!("servers.web1.load", &*telem.name);
+        assert_eq!(0.75, telem.value);
+        assert_eq!(MetricKind::Gauge, telem.kind);
+        assert_eq!(1_500_000_000, telem.timestamp);
+
+        let tagged = GraphiteParser.parse("disk.used;dc=ams;host=db1 12 1500000000").unwrap();
+        assert_eq!("disk.used", &*tagged.name);
+        assert_eq!("disk.used;dc=ams;host=db1", tagged.series());
+
+        let err = |line| GraphiteParser.parse(line).unwrap_err();
+        assert_eq!(ParseError::Missing("timestamp"), err("servers.web1.load 4"));
+        assert_eq!(ParseError::Invalid("timestamp"), err("servers.web1.load 4 soon"));
+        assert_eq!(ParseError::Trailing, err("servers.web1.load 4 1500000000 x"));
+        assert_eq!(ParseError::Invalid("tag"), err("disk.used;dc 12 1500000000"));
+    }
+}
@@ -0,0 +1,53 @@
+//! Line parsers for the wire formats `IngestPoint` listens for.
+//!
+//! A packet or stream is split on newlines before it reaches a parser, so a
+//! parser sees one line at a time, without its line ending.
+use event;
+use std::fmt;
+use std::str::FromStr;
+
+mod graphite;
+mod native;
+mod statsd;
+
+pub use self::graphite::*;
+pub use self::native::*;
+pub use self::statsd::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseError {
+    /// The line lacks the named field.
+    Missing(&'static str),
+    /// The named field is present but cannot be understood.
+    Invalid(&'static str),
+    /// The line carries more fields than the format allows.
+    Trailing,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Missing(field) => write!(f, "missing {}", field),
+            ParseError::Invalid(field) => write!(f, "invalid {}", field),
+            ParseError::Trailing => write!(f, "trailing data"),
+        }
+    }
+}
+
+pub trait Parser: Send + Sync {
+    fn parse(&self, line: &str) -> Result<event::Telemetry, ParseError>;
+}
+
+impl<P: Parser + ?Sized> Parser for Box<P> {
+    fn parse(&self, line: &str) -> Result<event::Telemetry, ParseError> {
+        (**self).parse(line)
+    }
+}
+
+/// Parses a metric value, which must be a finite number.
+fn parse_value(val: &str) -> Result<f64, ParseError> {
+    match f64::from_str(val) {
+        Ok(value) if value.is_finite() => Ok(value),
+        _ => Err(ParseError::Invalid("value")),
+    }
+}
@@ -0,0 +1,39 @@
+use event;
+use parser::{parse_value, ParseError, Parser};
+
+/// telem's own format, `<name> <value>` separated by whitespace. Anything
+/// after the value is ignored. Values are taken to be timers, aggregated
+/// into distributions as telem always has.
+pub struct NativeParser;
+
+impl Parser for NativeParser {
+    fn parse(&self, line: &str) -> Result<event::Telemetry, ParseError> {
+        let mut iter = line.split_whitespace();
+        let name = iter.next().ok_or(ParseError::Missing("name"))?;
+        let val = iter.next().ok_or(ParseError::Missing("value"))?;
+        let value = parse_value(val)?;
+        Ok(event::Telemetry::new(
+            event::intern(name),
+            value,
+            event::MetricKind::Timer,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_name_and_value() {
+        let telem = NativeParser.parse("requests 12").unwrap();
+        assert_eq!("requests", &*telem.name);
+        assert_eq!(12.0, telem.value);
+        assert_eq!(event::MetricKind::Timer, telem.kind);
+        assert_eq!(-0.5, NativeParser.parse("drift -0.5").unwrap().value);
+        assert_eq!(Err(ParseError::Missing("name")), NativeParser.parse("  "));
+        assert_eq!(Err(ParseError::Missing("value")), NativeParser.parse("requests"));
+        assert_eq!(Err(ParseError::Invalid("value")), NativeParser.parse("requests x"));
+        assert_eq!(Err(ParseError::Invalid("value")), NativeParser.parse("requests NaN"));
+    }
+}
@@ -0,0 +1,123 @@
+use event::{self, MetricKind, Telemetry};
+use parser::{parse_value, ParseError, Parser};
+use seahash;
+use std::str::FromStr;
+
+/// StatsD, `<name>:<value>|<type>[|@<sample rate>][|#<tags>]`, the types
+/// being `c`, `g`, `ms`, `h` and `s`. Tags follow DogStatsD,
+/// `#key:value,key`.
+///
+/// A signed gauge value sets the gauge rather than adjusting it. A set
+/// member that is not a number, `users:alice|s`, is hashed to one.
+pub struct StatsdParser;
+
+fn kind(ty: &str) -> Option<MetricKind> {
+    match ty {
+        "c" => Some(MetricKind::Counter),
+        "g" => Some(MetricKind::Gauge),
+        "ms" => Some(MetricKind::Timer),
+        "h" => Some(MetricKind::Histogram),
+        "s" => Some(MetricKind::Set),
+        _ => None,
+    }
+}
+
+/// Stands a non-numeric set member in for a number. The hash is cut to the
+/// 53 bits an `f64` holds exactly, so distinct hashes stay distinct values.
+fn member(val: &str) -> f64 {
+    (seahash::hash(val.as_bytes()) >> 11) as f64
+}
+
+impl Parser for StatsdParser {
+    fn parse(&self, line: &str) -> Result<Telemetry, ParseError> {
+        let mut fields = line.split('|');
+        let head = fields.next().unwrap_or("");
+        let colon = head.find(':').ok_or(ParseError::Missing("value"))?;
+        let (name, val) = (&head[..colon], &head[colon + 1..]);
+        if name.is_empty() {
+            return Err(ParseError::Missing("name"));
+        }
+        let ty = fields.next();
+        let value = match parse_value(val) {
+            Ok(value) => value,
+            Err(_) if ty == Some("s") && !val.is_empty() => member(val),
+            Err(e) => return Err(e),
+        };
+        let kind = match ty {
+            None => return Err(ParseError::Missing("type")),
+            Some(ty) => kind(ty).ok_or(ParseError::Invalid("type"))?,
+        };
+        let mut telem = Telemetry::new(event::intern(name), value, kind);
+        for field in fields {
+            if let Some(rate) = field.strip_prefix('@') {
+                match f64::from_str(rate) {
+                    Ok(rate) if rate > 0.0 && rate <= 1.0 => telem.sample_rate = rate,
+                    _ => return Err(ParseError::Invalid("sample rate")),
+                }
+            } else if let Some(tags) = field.strip_prefix('#') {
+                for tag in tags.split(',').filter(|tag| !tag.is_empty()) {
+                    let (key, value) = match tag.find(':') {
+                        Some(idx) => (&tag[..idx], &tag[idx + 1..]),
+                        None => (tag, ""),
+                    };
+                    telem.tags.push((key.to_string(), value.to_string()));
+                }
+            } else {
+                return Err(ParseError::Trailing);
+            }
+        }
+        Ok(telem)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_statsd_lines() {
+        let telem = StatsdParser.parse("api.hits:3|c|@0.1|#env:prod,canary").unwrap();
+        assert_eq!("api.hits", &*telem.name);
+        assert_eq!(3.0, telem.value);
+        assert_eq!(MetricKind::Counter, telem.kind);
+        assert_eq!(0.1, telem.sample_rate);
+        assert_eq!(
+            vec![
+                ("env".to_string(), "prod".to_string()),
+                ("canary".to_string(), "".to_string()),
+            ],
+            telem.tags
+        );
+        let timer = StatsdParser.parse("latency:2.5|ms").unwrap();
+        assert_eq!((2.5, MetricKind::Timer), (timer.value, timer.kind));
+        let gauge = StatsdParser.parse("temperature:-4|g").unwrap();
+        assert_eq!((-4.0, MetricKind::Gauge), (gauge.value, gauge.kind));
+        assert_eq!(MetricKind::Histogram, StatsdParser.parse("size:7|h").unwrap().kind);
+        assert_eq!(MetricKind::Set, StatsdParser.parse("users:42|s").unwrap().kind);
+    }
+
+    #[test]
+    fn hashes_set_members_that_are_not_numbers() {
+        let value = |line| StatsdParser.parse(line).unwrap().value;
+        let alice = StatsdParser.parse("users:alice|s").unwrap();
+        assert_eq!(MetricKind::Set, alice.kind);
+        assert_eq!(alice.value, value("users:alice|s"));
+        assert!(alice.value != value("users:bob|s"));
+        assert_eq!(42.0, value("users:42|s"));
+        let empty = StatsdParser.parse("users:|s").unwrap_err();
+        assert_eq!(ParseError::Invalid("value"), empty);
+    }
+
+    #[test]
+    fn rejects_malformed_statsd_lines() {
+        let err = |line| StatsdParser.parse(line).unwrap_err();
+        assert_eq!(ParseError::Missing("value"), err("api.hits 3"));
+        assert_eq!(ParseError::Missing("name"), err(":3|c"));
+        assert_eq!(ParseError::Invalid("value"), err("api.hits:x|c"));
+        assert_eq!(ParseError::Missing("type"), err("api.hits:3"));
+        assert_eq!(ParseError::Invalid("type"), err("api.hits:3|q"));
+        assert_eq!(ParseError::Invalid("sample rate"), err("api.hits:3|c|@0"));
+        assert_eq!(ParseError::Invalid("sample rate"), err("api.hits:3|c|@1.5"));
+        assert_eq!(ParseError::Trailing, err("api.hits:3|c|extra"));
+    }
+}
@@ -0,0 +1,93 @@
+//! Restarting pipeline stages that panic.
+//!
+//! A stage's receiver outlives its panics: the supervisor owns it and lends
+//! it to each incarnation of the stage in turn, so nothing queued for the
+//! stage is lost but the event it panicked on. The stage's own state is
+//! rebuilt from scratch.
+use channel;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Pause before a restart, so a stage that panics on every event does not
+/// spin.
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+
+pub struct Supervised {
+    handle: thread::JoinHandle<()>,
+    restarts: Arc<AtomicUsize>,
+}
+
+impl Supervised {
+    /// How many times the stage has been restarted so far.
+    pub fn restarts(&self) -> usize {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the stage to return without panicking.
+    pub fn join(self) {
+        self.handle.join().expect("supervisor thread panicked")
+    }
+}
+
+/// Runs `stage` on its own thread named `name`, feeding it from `recv` and
+/// running it again whenever it panics. The thread ends when `stage`
+/// returns.
+pub fn supervise<F>(name: &str, recv: channel::Receiver, mut stage: F) -> Supervised
+where
+    F: 'static + Send + FnMut(&channel::Receiver),
+{
+    let restarts = Arc::new(AtomicUsize::new(0));
+    let thread_restarts = Arc::clone(&restarts);
+    let thread_name = name.to_string();
+    let handle = thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || loop {
+            match panic::catch_unwind(AssertUnwindSafe(|| stage(&recv))) {
+                Ok(()) => return,
+                Err(_) => {
+                    thread_restarts.fetch_add(1, Ordering::Relaxed);
+                    eprintln!("[SUPERVISOR] {} panicked, restarting", thread_name);
+                    thread::sleep(RESTART_DELAY);
+                }
+            }
+        })
+        .expect("could not spawn supervised thread");
+    Supervised { handle, restarts }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event;
+    use std::sync::mpsc;
+
+    #[test]
+    fn panicked_stages_restart_with_their_queue() {
+        let (snd, recv) = channel::bounded("test", 4, channel::Policy::Block);
+        let (out_snd, out_recv) = mpsc::channel();
+        for _ in 0..3 {
+            snd.send(event::Event::Flush).unwrap();
+        }
+        snd.send(event::Event::Shutdown).unwrap();
+
+        // Panics on the first two flushes it sees, one per incarnation.
+        let mut incarnations = 0;
+        let supervised = supervise("test-stage", recv, move |recv| {
+            incarnations += 1;
+            for event in recv.iter() {
+                match event {
+                    event::Event::Flush if incarnations <= 2 => panic!("boom"),
+                    event::Event::Shutdown => return,
+                    _ => out_snd.send(incarnations).unwrap(),
+                }
+            }
+        });
+        let restarts = Arc::clone(&supervised.restarts);
+        supervised.join();
+        assert_eq!(2, restarts.load(Ordering::Relaxed));
+        assert_eq!(vec![3], out_recv.try_iter().collect::<Vec<_>>());
+    }
+}
@@ -1,19 +1,62 @@
 //! Utility module, a grab-bag of functionality
+use channel;
 use event;
 use seahash::SeaHasher;
 use std::collections;
-use std::hash;
-use std::sync::mpsc;
+use std::hash::{self, BuildHasher, Hash};
 
 pub type HashMap<K, V> =
     collections::HashMap<K, V, hash::BuildHasherDefault<SeaHasher>>;
 
-pub fn send(chans: &[mpsc::Sender<event::Event>], event: event::Event) {
+pub type HashSet<K> = collections::HashSet<K, hash::BuildHasherDefault<SeaHasher>>;
+
+/// Which of `shards` `key` belongs to, by the hash `HashMap` gives it.
+pub fn shard<K: Hash + ?Sized>(key: &K, shards: usize) -> usize {
+    let hash = hash::BuildHasherDefault::<SeaHasher>::default().hash_one(key);
+    (hash % shards as u64) as usize
+}
+
+/// Every receiver downstream has hung up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Disconnected;
+
+/// Sends `event` down every channel in `chans`, dropping the channels whose
+/// receivers have hung up. Fails once no channel is left, unless there was
+/// none to begin with.
+pub fn send(
+    chans: &mut Vec<channel::Sender>,
+    event: event::Event,
+) -> Result<(), Disconnected> {
     if chans.is_empty() {
-        return;
+        return Ok(());
     }
 
-    for chan in chans.iter() {
-        chan.send(event.clone()).unwrap();
+    chans.retain(|chan| chan.send(event.clone()).is_ok());
+    if chans.is_empty() {
+        Err(Disconnected)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn send_prunes_dead_channels() {
+        let (live_snd, live_rcv) = channel::bounded("live", 4, channel::Policy::Block);
+        let (dead_snd, dead_rcv) = channel::bounded("dead", 4, channel::Policy::Block);
+        let mut chans = vec![dead_snd, live_snd];
+        drop(dead_rcv);
+
+        assert_eq!(Ok(()), send(&mut chans, event::Event::Flush));
+        assert_eq!(1, chans.len());
+        assert_eq!(1, live_rcv.try_iter().count());
+
+        drop(live_rcv);
+        assert_eq!(Err(Disconnected), send(&mut chans, event::Event::Flush));
+        assert!(chans.is_empty());
+        assert_eq!(Ok(()), send(&mut chans, event::Event::Flush));
     }
 }
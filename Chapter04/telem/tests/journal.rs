@@ -0,0 +1,184 @@
+//! The telem binary killed with telemetry journaled and not yet flushed,
+//! then started again over the same journal.
+extern crate libc;
+extern crate telem;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use telem::event::Event;
+use telem::parser::{Parser, StatsdParser};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const LINE: &str = "hits:1|c";
+const LINES: usize = 50;
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// A pipeline from a StatsD port straight to a journaled ckms egress.
+fn write_config(dir: &Path, port: u16, flush_ms: u64) -> PathBuf {
+    let config = format!(
+        r#"
+flush_interval_ms = {}
+
+[[source]]
+name = "statsd"
+transport = "tcp"
+port = {}
+format = "statsd"
+to = ["ckms"]
+
+[[egress]]
+name = "ckms"
+type = "ckms"
+error = 0.01
+policy = "journal"
+journal_path = "{}"
+"#,
+        flush_ms,
+        port,
+        dir.join("ckms").display()
+    );
+    let path = dir.join("telem.toml");
+    fs::write(&path, config).unwrap();
+    path
+}
+
+/// Starts telem, sending each line it prints on the returned channel.
+fn start(config: &Path) -> (Child, mpsc::Receiver<String>) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_telem"))
+        .arg("--config")
+        .arg(config)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let (snd, recv) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            if snd.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+    (child, recv)
+}
+
+fn connect(port: u16) -> TcpStream {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => return stream,
+            Err(e) if Instant::now() > deadline => {
+                panic!("telem never listened: {}", e)
+            }
+            Err(_) => thread::sleep(Duration::from_millis(20)),
+        }
+    }
+}
+
+fn journaled_bytes(dir: &Path) -> u64 {
+    fs::read_dir(dir.join("ckms"))
+        .map(|entries| {
+            entries
+                .map(|entry| entry.unwrap())
+                .filter(|entry| {
+                    entry.file_name().to_string_lossy().ends_with(".journal")
+                })
+                .map(|entry| entry.metadata().unwrap().len())
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// The lines telem prints up to and including one that starts with
+/// `prefix`.
+fn wait_for(lines: &mpsc::Receiver<String>, prefix: &str) -> Vec<String> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut seen = Vec::new();
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match lines.recv_timeout(left) {
+            Ok(line) => {
+                let found = line.starts_with(prefix);
+                seen.push(line);
+                if found {
+                    return seen;
+                }
+            }
+            Err(_) => panic!("telem never printed `{}`: {:?}", prefix, seen),
+        }
+    }
+}
+
+/// Stops telem as an operator would, letting it flush and exit.
+fn interrupt(mut child: Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+    }
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn telemetry_survives_a_kill() {
+    let dir =
+        std::env::temp_dir().join(format!("telem-journal-kill-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let port = free_port();
+
+    // No flush comes before the kill, so nothing is acknowledged.
+    let (mut child, _) = start(&write_config(&dir, port, 600_000));
+    let mut stream = connect(port);
+    for _ in 0..LINES {
+        writeln!(stream, "{}", LINE).unwrap();
+    }
+    stream.flush().unwrap();
+    let mut encoded = Vec::new();
+    Event::Telemetry(Arc::new(StatsdParser.parse(LINE).unwrap())).encode(&mut encoded);
+    let expected = (LINES * encoded.len()) as u64;
+    let deadline = Instant::now() + TIMEOUT;
+    while journaled_bytes(&dir) < expected {
+        assert!(
+            Instant::now() < deadline,
+            "the telemetry was never journaled"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    drop(stream);
+
+    let config = write_config(&dir, port, 100);
+    let (child, lines) = start(&config);
+    let replayed = wait_for(&lines, "[CKMS] hits ");
+    assert_eq!(
+        format!("[CKMS] hits counter:{}", LINES),
+        *replayed.last().unwrap()
+    );
+    interrupt(child);
+
+    // That flush was acknowledged, so a third run has nothing to replay.
+    // Its first report is of the pipeline alone.
+    let (child, lines) = start(&config);
+    let mut printed = wait_for(&lines, "[CKMS] telem.channel.ckms.depth ");
+    interrupt(child);
+    printed.extend(lines.iter());
+    assert!(
+        printed.iter().all(|line| !line.starts_with("[CKMS] hits ")),
+        "replayed again: {:?}",
+        printed
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
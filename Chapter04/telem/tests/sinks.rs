@@ -0,0 +1,241 @@
+//! The network egresses against stand-ins for Graphite, InfluxDB and a
+//! Prometheus scraper, listening on loopback.
+extern crate telem;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use telem::egress::{Egress, GraphiteEgress, InfluxEgress, PrometheusEgress};
+use telem::event::{MetricKind, Telemetry};
+use telem::Transport;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn telem(name: &str, value: f64, kind: MetricKind) -> Telemetry {
+    Telemetry::new(name.to_string(), value, kind)
+}
+
+/// Accepts connections on `listener` one after another, sending on each
+/// line read with its timestamp cut off, until `connections` have closed.
+fn graphite_stand_in(
+    listener: TcpListener,
+    connections: usize,
+) -> mpsc::Receiver<String> {
+    let (snd, recv) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming().take(connections) {
+            let stream = stream.unwrap();
+            for line in BufReader::new(stream).lines() {
+                let line = line.unwrap();
+                let end = line.rfind(' ').unwrap();
+                snd.send(line[..end].to_string()).unwrap();
+            }
+        }
+    });
+    recv
+}
+
+fn next(recv: &mpsc::Receiver<String>) -> String {
+    recv.recv_timeout(TIMEOUT).expect("nothing arrived")
+}
+
+#[test]
+fn graphite_receives_each_flush() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let lines = graphite_stand_in(listener, 1);
+
+    let mut egress = GraphiteEgress::new(&address, 0.001);
+    let mut hits = telem("hits", 2.0, MetricKind::Counter);
+    hits.tags.push(("host".to_string(), "a".to_string()));
+    egress.deliver(Arc::new(hits));
+    egress.deliver(Arc::new(telem("latency", 4.0, MetricKind::Timer)));
+    egress.report();
+    assert_eq!("hits;host=a 2", next(&lines));
+    let latency: Vec<String> = (0..8).map(|_| next(&lines)).collect();
+    assert_eq!(
+        vec![
+            "latency.count 1",
+            "latency.sum 4",
+            "latency.mean 4",
+            "latency.min 4",
+            "latency.max 4",
+            "latency.p50 4",
+            "latency.p90 4",
+            "latency.p99 4",
+        ],
+        latency
+    );
+
+    egress.deliver(Arc::new(telem("depth", 7.0, MetricKind::Gauge)));
+    egress.report();
+    assert_eq!("depth 7", next(&lines));
+}
+
+#[test]
+fn graphite_reconnects_and_sends_what_it_held() {
+    // Bound and let go, so nothing listens there for now.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+
+    let mut egress = GraphiteEgress::new(&addr.to_string(), 0.001)
+        .with_backoff(Duration::from_millis(1), Duration::from_millis(1));
+    egress.deliver(Arc::new(telem("first", 1.0, MetricKind::Gauge)));
+    egress.report();
+
+    let lines = graphite_stand_in(TcpListener::bind(addr).unwrap(), 2);
+    thread::sleep(Duration::from_millis(10));
+    egress.deliver(Arc::new(telem("second", 2.0, MetricKind::Gauge)));
+    egress.report();
+    assert_eq!("first 1", next(&lines));
+    assert_eq!("second 2", next(&lines));
+    assert_eq!(0, egress.dropped());
+}
+
+#[test]
+fn graphite_notices_a_closed_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (snd, recv) = mpsc::channel();
+    thread::spawn(move || {
+        // Read one report and hang up, then take the next connection.
+        let (stream, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        snd.send(line).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        for line in BufReader::new(stream).lines() {
+            snd.send(line.unwrap() + "\n").unwrap();
+        }
+    });
+
+    let mut egress = GraphiteEgress::new(&address, 0.001)
+        .with_backoff(Duration::from_millis(1), Duration::from_millis(1));
+    egress.deliver(Arc::new(telem("before", 1.0, MetricKind::Gauge)));
+    egress.report();
+    assert!(next(&recv).starts_with("before 1 "));
+
+    // The stand-in has hung up by the time it handed the line over.
+    egress.deliver(Arc::new(telem("after", 2.0, MetricKind::Gauge)));
+    egress.report();
+    assert!(next(&recv).starts_with("after 2 "));
+}
+
+#[test]
+fn influx_receives_points_over_udp() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let address = socket.local_addr().unwrap().to_string();
+
+    let mut egress = InfluxEgress::new(&address, Transport::Udp, 0.001);
+    let mut users = telem("users", 1.0, MetricKind::Set);
+    users
+        .tags
+        .push(("region".to_string(), "eu west".to_string()));
+    egress.deliver(Arc::new(users.clone()));
+    users.value = 2.0;
+    egress.deliver(Arc::new(users));
+    egress.deliver(Arc::new(telem("hits", 3.0, MetricKind::Counter)));
+    egress.report();
+
+    let mut buf = [0; 1500];
+    let len = socket.recv(&mut buf).unwrap();
+    let datagram = String::from_utf8_lossy(&buf[..len]).into_owned();
+    let points: Vec<&str> = datagram
+        .lines()
+        .map(|line| &line[..line.rfind(' ').unwrap()])
+        .collect();
+    assert_eq!(
+        vec!["hits value=3", "users,region=eu\\ west value=2"],
+        points
+    );
+}
+
+#[test]
+fn influx_receives_points_over_tcp() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let lines = graphite_stand_in(listener, 1);
+
+    let mut egress = InfluxEgress::new(&address, Transport::Tcp, 0.001);
+    for value in 1..5 {
+        let latency = telem("latency", f64::from(value), MetricKind::Histogram);
+        egress.deliver(Arc::new(latency));
+    }
+    egress.report();
+    assert_eq!(
+        "latency count=4,sum=10,mean=2.5,min=1,max=4,p50=2,p90=4,p99=4",
+        next(&lines)
+    );
+}
+
+fn get(egress: &PrometheusEgress, request: &str) -> String {
+    let mut stream = TcpStream::connect(egress.local_addr()).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn prometheus_serves_the_last_flush() {
+    let mut egress = PrometheusEgress::bind("127.0.0.1:0", 0.001).unwrap();
+    let scrape = "GET /metrics HTTP/1.1\r\nHost: telem\r\nAccept: */*\r\n\r\n";
+    let response = get(&egress, scrape);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\n"), "{}", response);
+
+    for _ in 0..2 {
+        egress.deliver(Arc::new(telem("http.requests", 5.0, MetricKind::Counter)));
+        egress.report();
+    }
+    let mut queue = telem("queue_depth", 3.0, MetricKind::Gauge);
+    queue.tags.push(("stage".to_string(), "ckms".to_string()));
+    egress.deliver(Arc::new(queue));
+    egress.report();
+
+    let response = get(&egress, scrape);
+    let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+    assert_eq!(
+        "# TYPE http_requests_total counter\n\
+         http_requests_total 10\n\
+         # TYPE queue_depth gauge\n\
+         queue_depth{stage=\"ckms\"} 3\n",
+        body
+    );
+    assert!(response.contains(&format!("Content-Length: {}\r\n", body.len())));
+
+    let missing = get(&egress, "GET /other HTTP/1.1\r\n\r\n");
+    assert!(
+        missing.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{}",
+        missing
+    );
+    let posted = get(&egress, "POST /metrics HTTP/1.1\r\n\r\n");
+    assert!(posted.starts_with("HTTP/1.1 405 "), "{}", posted);
+}
+
+#[test]
+fn prometheus_stops_listening_when_dropped() {
+    let egress = PrometheusEgress::bind("127.0.0.1:0", 0.001).unwrap();
+    let addr = egress.local_addr();
+    drop(egress);
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn prometheus_serves_again_from_a_clone_of_its_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let scrape = "GET /metrics HTTP/1.1\r\n\r\n";
+    for _ in 0..2 {
+        let clone = listener.try_clone().unwrap();
+        let egress = PrometheusEgress::from_listener(clone, 0.001).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), egress.local_addr());
+        let response = get(&egress, scrape);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+}